@@ -0,0 +1,50 @@
+//! Serves a fixed HTTP response on 192.0.2.2:80, one connection at a time.
+//!
+//! `cargo run --example http_server` then from the host: `curl http://192.0.2.2/`
+
+use log::info;
+use rust_user_net::app::NetApp;
+use rust_user_net::protocols::ip::congestion::CongestionAlgorithm;
+use rust_user_net::protocols::ip::IPEndpoint;
+use rust_user_net::socket::TcpStream;
+use simplelog::{Config, SimpleLogger};
+use std::{io, thread};
+
+const BODY: &str = "<html><body><h1>Hello from rust-user-net</h1></body></html>\n";
+
+fn main() -> io::Result<()> {
+    SimpleLogger::init(log::LevelFilter::Info, Config::default()).unwrap();
+    let app = NetApp::with_options(false, CongestionAlgorithm::Reno);
+    app.start()?;
+
+    let stack = app.clone();
+    thread::spawn(move || {
+        let local = || IPEndpoint::new_from_str("192.0.2.2", 80);
+        while let Some(stream) = TcpStream::accept(&stack, local()) {
+            let mut request = vec![];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                match stream.read(2048) {
+                    Some(data) if !data.is_empty() => request.extend(data),
+                    _ => break,
+                }
+            }
+            let request_line = String::from_utf8_lossy(&request);
+            info!(
+                "http_server: {}",
+                request_line.lines().next().unwrap_or_default()
+            );
+            // Header and body go out in one segment, pushed by close on drop.
+            stream.set_coalesce(true);
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                BODY.len()
+            );
+            stream.write_all(header.into_bytes());
+            stream.write_all(BODY.as_bytes().to_vec());
+        }
+    });
+
+    // Till Ctrl+C
+    app.join();
+    Ok(())
+}
@@ -0,0 +1,62 @@
+//! Sends ICMP echo requests and prints RTT of each reply.
+//!
+//! `cargo run --example ping -- 192.0.2.1`
+
+use log::{info, warn};
+use rust_user_net::app::NetApp;
+use rust_user_net::protocols::ip::congestion::CongestionAlgorithm;
+use rust_user_net::protocols::ip::icmp::ICMP_TYPE_ECHOREPLY;
+use rust_user_net::protocols::ip::{icmp, ip_addr_to_bytes, ip_addr_to_str};
+use rust_user_net::socket::IcmpSocket;
+use simplelog::{Config, SimpleLogger};
+use std::{
+    env, io, process, thread,
+    time::{Duration, SystemTime},
+};
+
+const COUNT: u16 = 4;
+
+fn main() -> io::Result<()> {
+    let target = env::args()
+        .nth(1)
+        .unwrap_or_else(|| String::from("192.0.2.1"));
+    let dst = ip_addr_to_bytes(&target).expect("invalid IP address");
+
+    SimpleLogger::init(log::LevelFilter::Info, Config::default()).unwrap();
+    let app = NetApp::with_options(false, CongestionAlgorithm::Reno);
+    app.start()?;
+
+    let stack = app.clone();
+    thread::spawn(move || {
+        let socket = IcmpSocket::open(&stack, process::id() as u16);
+        for seq in 1..=COUNT {
+            let sent_at = SystemTime::now();
+            socket.echo_request(dst, seq, b"rust-user-net ping".to_vec());
+            match socket.receive_timeout(Duration::from_secs(1)) {
+                Some(entry) if entry.icmp_type == ICMP_TYPE_ECHOREPLY => {
+                    let rtt = entry
+                        .received_at
+                        .duration_since(sent_at)
+                        .unwrap_or_default();
+                    info!(
+                        "ping: reply from {}: icmp_seq={} time={:.3} ms",
+                        ip_addr_to_str(entry.src),
+                        icmp::echo_id_seq(entry.values).1,
+                        rtt.as_secs_f64() * 1000.0
+                    );
+                    thread::sleep(Duration::from_secs(1).saturating_sub(rtt));
+                }
+                Some(entry) => warn!(
+                    "ping: {} from {}",
+                    icmp::message_str(entry.icmp_type, entry.code),
+                    ip_addr_to_str(entry.src)
+                ),
+                None => warn!("ping: request timeout for icmp_seq={seq}"),
+            }
+        }
+        stack.stop();
+    });
+
+    app.join();
+    Ok(())
+}
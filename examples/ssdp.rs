@@ -0,0 +1,143 @@
+//! Answers SSDP M-SEARCH on 239.255.255.250:1900 for a canned UPnP basic device, whose
+//! description is served on http://192.0.2.2/description.xml. The device is announced with
+//! NOTIFY on start.
+//!
+//! `cargo run --example ssdp` then from the host, e.g. `gssdp-discover -i tap0 --timeout=3`
+
+use log::info;
+use rust_user_net::app::NetApp;
+use rust_user_net::protocols::ip::congestion::CongestionAlgorithm;
+use rust_user_net::protocols::ip::{ip_addr_to_bytes, IPEndpoint};
+use rust_user_net::socket::{TcpStream, UdpSocket};
+use simplelog::{Config, SimpleLogger};
+use std::{io, thread};
+
+const SSDP_GROUP: &str = "239.255.255.250";
+const SSDP_PORT: u16 = 1900;
+const UUID: &str = "uuid:5d2f0d8e-3c37-4b6e-9a4e-7275737475e1";
+const DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:Basic:1";
+const LOCATION: &str = "http://192.0.2.2/description.xml";
+const SERVER: &str = "Linux/1.0 UPnP/1.1 rust-user-net/0.1";
+
+const DESCRIPTION: &str = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+  <specVersion><major>1</major><minor>1</minor></specVersion>
+  <device>
+    <deviceType>urn:schemas-upnp-org:device:Basic:1</deviceType>
+    <friendlyName>rust-user-net</friendlyName>
+    <manufacturer>rust-user-net</manufacturer>
+    <modelName>User space TCP/IP stack</modelName>
+    <UDN>uuid:5d2f0d8e-3c37-4b6e-9a4e-7275737475e1</UDN>
+  </device>
+</root>
+"#;
+
+/// Search targets of the device with the USN of each (UPnP Device Architecture 1.1 1.1.2).
+fn targets() -> Vec<(&'static str, String)> {
+    vec![
+        ("upnp:rootdevice", format!("{UUID}::upnp:rootdevice")),
+        (UUID, UUID.to_string()),
+        (DEVICE_TYPE, format!("{UUID}::{DEVICE_TYPE}")),
+    ]
+}
+
+/// Value of a header of an SSDP message, names compared case-insensitively.
+fn header<'a>(message: &'a str, name: &str) -> Option<&'a str> {
+    message.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then_some(value.trim())
+    })
+}
+
+/// Responses to an M-SEARCH request, none for other messages and targets of other devices.
+fn responses(request: &str) -> Vec<String> {
+    if !request.starts_with("M-SEARCH * HTTP/1.1")
+        || header(request, "MAN") != Some("\"ssdp:discover\"")
+    {
+        return vec![];
+    }
+    let Some(st) = header(request, "ST") else {
+        return vec![];
+    };
+    targets()
+        .into_iter()
+        .filter(|(target, _)| st == "ssdp:all" || st == *target)
+        .map(|(target, usn)| {
+            format!(
+                "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=1800\r\nEXT:\r\nLOCATION: {LOCATION}\r\nSERVER: {SERVER}\r\nST: {target}\r\nUSN: {usn}\r\n\r\n"
+            )
+        })
+        .collect()
+}
+
+fn notify(socket: &UdpSocket, nts: &str) {
+    for (target, usn) in targets() {
+        let message = format!(
+            "NOTIFY * HTTP/1.1\r\nHOST: {SSDP_GROUP}:{SSDP_PORT}\r\nCACHE-CONTROL: max-age=1800\r\nLOCATION: {LOCATION}\r\nNT: {target}\r\nNTS: {nts}\r\nSERVER: {SERVER}\r\nUSN: {usn}\r\n\r\n"
+        );
+        let group = IPEndpoint::new_from_str(SSDP_GROUP, SSDP_PORT);
+        if socket.send_to(message.into_bytes(), group).is_err() {
+            info!("ssdp: failed to send {nts} for {target}");
+        }
+    }
+}
+
+fn main() -> io::Result<()> {
+    SimpleLogger::init(log::LevelFilter::Info, Config::default()).unwrap();
+    let app = NetApp::with_options(false, CongestionAlgorithm::Reno);
+    app.start()?;
+
+    let socket = UdpSocket::bind(&app, IPEndpoint::new_from_str("0.0.0.0", SSDP_PORT));
+    socket
+        .join_multicast(ip_addr_to_bytes(SSDP_GROUP).unwrap())
+        .expect("ssdp: failed to join the SSDP group");
+    socket.set_multicast_ttl(2); // UPnP default
+    notify(&socket, "ssdp:alive");
+
+    let stack = app.clone();
+    thread::spawn(move || {
+        let local = || IPEndpoint::new_from_str("192.0.2.2", 80);
+        while let Some(stream) = TcpStream::accept(&stack, local()) {
+            let mut request = vec![];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                match stream.read(2048) {
+                    Some(data) if !data.is_empty() => request.extend(data),
+                    _ => break,
+                }
+            }
+            stream.set_coalesce(true);
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/xml; charset=\"utf-8\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                DESCRIPTION.len()
+            );
+            stream.write_all(header.into_bytes());
+            stream.write_all(DESCRIPTION.as_bytes().to_vec());
+        }
+    });
+
+    thread::spawn(move || {
+        while let Some(entry) = socket.recv_from() {
+            let request = String::from_utf8_lossy(&entry.data);
+            let responses = responses(&request);
+            if responses.is_empty() {
+                continue;
+            }
+            info!("ssdp: M-SEARCH from {}", entry.remote_endpoint);
+            for response in responses {
+                let remote = IPEndpoint {
+                    address: entry.remote_endpoint.address,
+                    port: entry.remote_endpoint.port,
+                };
+                if socket.send_to(response.into_bytes(), remote).is_err() {
+                    info!("ssdp: failed to answer {}", entry.remote_endpoint);
+                }
+            }
+        }
+    });
+
+    // Till Ctrl+C
+    app.join();
+    Ok(())
+}
@@ -0,0 +1,43 @@
+//! Connects to a TCP server, sends a message and prints the response.
+//!
+//! `nc -nv -l 10007` on the host then `cargo run --example tcp_client -- 192.0.2.1 10007 hello`
+
+use log::{error, info};
+use rust_user_net::app::NetApp;
+use rust_user_net::protocols::ip::congestion::CongestionAlgorithm;
+use rust_user_net::protocols::ip::IPEndpoint;
+use rust_user_net::socket::TcpStream;
+use simplelog::{Config, SimpleLogger};
+use std::{env, io, thread};
+
+fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 4 {
+        eprintln!("usage: tcp_client <ip> <port> <message>");
+        return Ok(());
+    }
+    let remote = IPEndpoint::new_from_str(&args[1], args[2].parse().expect("invalid port"));
+    let message = args[3].clone();
+
+    SimpleLogger::init(log::LevelFilter::Info, Config::default()).unwrap();
+    let app = NetApp::with_options(false, CongestionAlgorithm::Reno);
+    app.start()?;
+
+    let stack = app.clone();
+    thread::spawn(move || {
+        let local = IPEndpoint::new_from_str("192.0.2.2", 7);
+        match TcpStream::connect(&stack, local, remote) {
+            Ok(stream) => {
+                stream.write_all(message.into_bytes());
+                if let Some(data) = stream.read(2048) {
+                    info!("tcp_client: received: {}", String::from_utf8_lossy(&data));
+                }
+            }
+            Err(err) => error!("tcp_client: connection failed: {err:?}"),
+        }
+        stack.stop();
+    });
+
+    app.join();
+    Ok(())
+}
@@ -0,0 +1,32 @@
+//! Echoes UDP datagrams received on 192.0.2.2:7 back to their senders.
+//!
+//! `cargo run --example udp_echo` then from the host: `nc -u 192.0.2.2 7`
+
+use log::info;
+use rust_user_net::app::NetApp;
+use rust_user_net::protocols::ip::congestion::CongestionAlgorithm;
+use rust_user_net::protocols::ip::IPEndpoint;
+use rust_user_net::socket::UdpSocket;
+use simplelog::{Config, SimpleLogger};
+use std::{io, thread};
+
+fn main() -> io::Result<()> {
+    SimpleLogger::init(log::LevelFilter::Info, Config::default()).unwrap();
+    let app = NetApp::with_options(false, CongestionAlgorithm::Reno);
+    app.start()?;
+
+    let stack = app.clone();
+    thread::spawn(move || {
+        let socket = UdpSocket::bind(&stack, IPEndpoint::new_from_str("0.0.0.0", 7));
+        while let Some(entry) = socket.recv_from() {
+            info!("udp_echo: echoing {} bytes", entry.len);
+            if socket.send_to(entry.data, entry.remote_endpoint).is_err() {
+                info!("udp_echo: failed to echo {} bytes", entry.len);
+            }
+        }
+    });
+
+    // Till Ctrl+C
+    app.join();
+    Ok(())
+}
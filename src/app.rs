@@ -1,48 +1,431 @@
+use crate::builder::StackBuilder;
+use crate::config::StackConfig;
 use crate::devices::ethernet;
+use crate::devices::ethernet::{ETH_ADDR_LEN, ETH_DEVICES_MAX};
 use crate::devices::loopback;
-use crate::devices::{NetDeviceType, NetDevices};
-use crate::protocols::arp::ArpTable;
+use crate::devices::loopback::{IRQ_LOOPBACK, LOOPBACK_CIDR};
+use crate::devices::{ConfigError, NetDevice, NetDeviceType, NetDevices};
+use crate::drivers::DriverType;
+use crate::net::NetInterfaceFamily;
+use crate::protocols::arp;
+use crate::protocols::ip::cidr::IpCidr;
+use crate::protocols::ip::congestion::CongestionAlgorithm;
+use crate::protocols::ip::dhcp::DhcpClient;
+use crate::protocols::ip::endpoint_from_str;
 use crate::protocols::ip::icmp;
 use crate::protocols::ip::ip_addr_to_bytes;
 use crate::protocols::ip::ip_addr_to_str;
+use crate::protocols::ip::l4::{self, L4Protocol};
+use crate::protocols::ip::nat::Nat;
 use crate::protocols::ip::tcp;
+use crate::protocols::ip::tcp::AckPolicy;
 use crate::protocols::ip::udp;
 use crate::protocols::ip::{
-    IPAdress, IPEndpoint, IPHeaderIdManager, IPInterface, IPRoute, IPRoutes,
+    IPAdress, IPEndpoint, IPInterface, IPRoute, IPRoutes, RouteError, IP_ADDR_ANY,
 };
-use crate::protocols::{ControlBlocks, NetProtocol, NetProtocols, ProtocolContexts, ProtocolType};
+use crate::protocols::ipv6::IPV6Interface;
+use crate::protocols::{
+    ControlBlocks, NetProtocol, NetProtocols, ProtocolContexts, ProtocolType, SocketError,
+};
+use crate::sim;
 use crate::utils::byte::le_to_be_u32;
 use clap::{Args, Parser, Subcommand};
-use log::{info, warn};
+use log::{debug, error, info, warn};
+use signal_hook::consts::signal::*;
+use signal_hook::consts::TERM_SIGNALS;
+use signal_hook::iterator::exfiltrator::origin::WithOrigin;
+use signal_hook::iterator::{Handle, SignalsInfo};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem::size_of;
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::process;
 use std::str;
 use std::sync::Mutex;
 use std::{
     sync::{
-        mpsc::{self, TryRecvError},
+        mpsc::{self, RecvTimeoutError, TryRecvError},
         Arc,
     },
     thread::{self, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 
-const LOOPBACK_IP: &str = "127.0.0.1";
-const LOOPBACK_NETMASK: &str = "255.255.255.0";
-const DEFAULT_GATEWAY: &str = "192.0.2.1";
-const ETH_TAP_IP: &str = "192.0.2.2";
-const ETH_TAP_NETMASK: &str = "255.255.255.0";
+const CONTROL_SOCKET_PATH: &str = "/tmp/rust-user-net.sock";
+const PING_DATA_LEN: usize = 56;
+const PING_INTERVAL_SEC: u64 = 1; // also the timeout of each echo reply
+const TRACEROUTE_BASE_PORT: u16 = 33434; // destination port of the first probe
+const TRACEROUTE_PROBES: u16 = 3; // probes per hop
+const TRACEROUTE_TIMEOUT_SEC: u64 = 1;
+
+// Signals registered before devices open, taken by the signal thread of a started stack
+static SIGNALS: Mutex<Option<SignalsInfo<WithOrigin>>> = Mutex::new(None);
 
+#[derive(Clone)]
 pub struct NetApp {
     pub devices: Arc<Mutex<NetDevices>>,
     pub protocols: Arc<Mutex<NetProtocols>>,
     pub contexts: Arc<Mutex<ProtocolContexts>>,
     pub pcbs: Arc<Mutex<ControlBlocks>>,
+    pub dhcp_client: Option<Arc<Mutex<DhcpClient>>>,
+    control_socket: String,
+    runtime: Arc<Mutex<Option<Runtime>>>,
+}
+
+/// Threads of a started stack: the input thread and timer threads stopped after it.
+struct Runtime {
+    input: Input,
+    input_join: JoinHandle<()>,
+    timers: Vec<(mpsc::Sender<()>, JoinHandle<()>)>,
+    listeners: Vec<mpsc::Sender<()>>, // notified of stop before sockets are closed
+}
+
+/// How a started stack gets input: a signal thread handling IRQ and protocol signals, or a
+/// thread polling devices of the pair driver, which raise no signals.
+enum Input {
+    Signals(Handle),
+    Polled(mpsc::Sender<()>),
+}
+
+impl Input {
+    fn close(&self) {
+        match self {
+            Input::Signals(handle) => handle.close(),
+            Input::Polled(stop) => {
+                stop.send(()).ok();
+            }
+        }
+    }
 }
 
 impl NetApp {
     pub fn new() -> NetApp {
         // Args
         let args = Cli::parse();
+        let mut app = if args.dhcp {
+            match args.tap_name {
+                Some(name) => NetApp::with_dhcp(&name, args.congestion),
+                None => NetApp::with_options(true, args.congestion),
+            }
+        } else {
+            let config = match args.config {
+                Some(path) => StackConfig::load(&path).map_err(|error| vec![error]),
+                None => Ok(StackConfig::default()),
+            };
+            config
+                .and_then(|mut config| {
+                    config.set_addressing(
+                        args.tap_name.as_deref(),
+                        args.ip_address.as_deref(),
+                        args.netmask.as_deref(),
+                        args.default_gateway.as_deref(),
+                    )?;
+                    NetApp::with_config(&config, args.congestion)
+                })
+                .unwrap_or_else(exit_on_config_errors)
+        };
+        if let Some(mtu) = args.mtu {
+            if let Err(err) = app.set_mtu(mtu) {
+                error!("App: invalid MTU: {err}");
+                process::exit(1);
+            }
+        }
+        for tap in args.tap {
+            if let Err(errors) = app.add_tap(&tap) {
+                for err in errors {
+                    error!("App: invalid TAP device {tap}: {err}");
+                }
+                process::exit(1);
+            }
+        }
+
+        app.set_control_socket(&args.control_socket);
+        app.set_proxy_arp(args.proxy_arp);
+        app.set_forwarding(args.forward);
+        if let Some(outside) = args.nat {
+            app.set_nat(Some(&outside));
+        }
+        app.set_egress_check(args.egress_check);
+        app.set_static_neighbors(args.static_neighbors);
+        if let Some(secs) = args.gateway_probe {
+            app.set_gateway_probe(Some(Duration::from_secs(secs)));
+        }
+
+        // TCP buffers
+        {
+            let pcbs = &mut app.pcbs.lock().unwrap();
+            if let Some(size) = args.tcp_sndbuf {
+                pcbs.tcp_pcbs.send_buf_size = size;
+            }
+            if let Some(size) = args.tcp_rcvbuf {
+                pcbs.tcp_pcbs.recv_buf_size = size.min(u16::MAX as usize);
+            }
+            if let Some(secs) = args.tcp_rto_max {
+                pcbs.tcp_pcbs.rto_max = Duration::from_secs(secs);
+            }
+            if let Some(retries) = args.tcp_syn_retries {
+                pcbs.tcp_pcbs.syn_retries = retries;
+            }
+            if let Some(millis) = args.tcp_syn_rto {
+                pcbs.tcp_pcbs.syn_rto = Duration::from_millis(millis);
+            }
+            pcbs.tcp_pcbs.ack_policy = args.tcp_ack_policy;
+            if let Some(backlog) = args.tcp_backlog {
+                pcbs.tcp_pcbs.backlog = backlog;
+            }
+            pcbs.tcp_pcbs.syn_cookies = args.tcp_syn_cookies;
+            if let Some(limit) = args.tcp_challenge_ack_limit {
+                pcbs.tcp_pcbs.challenge_ack_limit = limit;
+            }
+            pcbs.memory_limit = args.max_memory;
+        }
+
+        // Static ARP entries
+        let mut entries = args.static_arp;
+        if let Some(path) = args.arp_file {
+            let text = fs::read_to_string(&path).unwrap_or_else(|e| {
+                error!("App: failed to read ARP file {path}: {e}");
+                process::exit(1);
+            });
+            entries.extend(
+                text.lines()
+                    .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+                    .map(String::from),
+            );
+        }
+        for entry in entries {
+            match arp::static_entry_from_str(&entry) {
+                Some((ip, hw_addr)) => app.add_static_arp(ip, hw_addr),
+                None => {
+                    error!("App: invalid static ARP entry: {entry}");
+                    process::exit(1);
+                }
+            }
+        }
+        app
+    }
+
+    /// Adds a device not opened yet, e.g. of `ethernet::init` with the number of devices
+    /// registered as its index, with an interface of an address and its subnet routed to
+    /// directly. Fails without adding it when the configuration gets invalid (see
+    /// `NetDevices::validate`) or the device fails to open.
+    pub fn add_device(&self, mut device: NetDevice, cidr: IpCidr) -> Result<(), Vec<ConfigError>> {
+        let devices = &mut self.devices.lock().unwrap();
+        let contexts = &mut self.contexts.lock().unwrap();
+        let count = devices.entries.iter().count();
+        if count >= ETH_DEVICES_MAX as usize {
+            return Err(vec![ConfigError::TooManyDevices]);
+        }
+        let interface = Arc::new(IPInterface::from_cidr(cidr));
+        device.register_interface(interface.clone());
+        devices.register(device);
+        let mut result = devices.validate(&[]);
+        if result.is_ok() {
+            let device = devices.get_mut_by_index(count).unwrap();
+            if device.open().is_err() {
+                result = Err(vec![ConfigError::DeviceOpenFailed(device.name.clone())]);
+            }
+        }
+        if result.is_err() {
+            let mut index = 0;
+            devices.entries.retain(|_| {
+                index += 1;
+                index <= count
+            });
+            return result;
+        }
+        contexts
+            .ip_routes
+            .register(IPRoute::interface_route(interface));
+        Ok(())
+    }
+
+    /// Adds a TAP device of `NAME=CIDR`, e.g. "tap1=198.51.100.2/24" (see `add_device`).
+    pub fn add_tap(&self, tap: &str) -> Result<(), Vec<ConfigError>> {
+        let Some((name, cidr)) = tap.split_once('=') else {
+            return Err(vec![ConfigError::InvalidCidr(tap.to_string())]);
+        };
+        let cidr = cidr
+            .parse()
+            .map_err(|_| vec![ConfigError::InvalidCidr(cidr.to_string())])?;
+        let index = self.devices.lock().unwrap().entries.iter().count();
+        if index >= ETH_DEVICES_MAX as usize {
+            return Err(vec![ConfigError::TooManyDevices]);
+        }
+        let mut device = ethernet::init(index as u8, DriverType::Tap);
+        device.name = name.to_string();
+        self.add_device(device, cidr)
+    }
+
+    /// Adds an ARP entry which never expires nor gets overwritten by ARP replies.
+    pub fn add_static_arp(&self, ip: IPAdress, hw_addr: [u8; ETH_ADDR_LEN]) {
+        let contexts = &mut self.contexts.lock().unwrap();
+        contexts.arp_table.add_static(ip, hw_addr);
+    }
+
+    /// Registers a transport protocol over IPv4 by its protocol number, replacing the one
+    /// registered with it (ICMP, TCP and UDP by default).
+    pub fn register_l4_protocol(&self, protocol: Arc<dyn L4Protocol>) {
+        let contexts = &mut self.contexts.lock().unwrap();
+        if let Some(replaced) = contexts.l4_protocols.register(protocol) {
+            info!(
+                "App: replaced protocol {} ({})",
+                replaced.name(),
+                replaced.number()
+            );
+        }
+    }
+
+    /// Sets the MTU of the first Ethernet device, up to 9000 for jumbo frames (see
+    /// `NetDevice::set_mtu`).
+    pub fn set_mtu(&self, mtu: usize) -> Result<(), ConfigError> {
+        let devices = &mut self.devices.lock().unwrap();
+        let device = devices
+            .get_mut_by_type(NetDeviceType::Ethernet)
+            .ok_or(ConfigError::NoEthernetDevice)?;
+        device.set_mtu(mtu)
+    }
+
+    /// Enables or disables proxy ARP on the IP interfaces of Ethernet devices.
+    pub fn set_proxy_arp(&self, enabled: bool) {
+        let devices = &mut self.devices.lock().unwrap();
+        for device in devices.entries.iter_mut() {
+            if device.device_type != NetDeviceType::Ethernet {
+                continue;
+            }
+            if let Some(interface) = device.get_interface(NetInterfaceFamily::IP) {
+                interface.set_proxy_arp(enabled);
+            }
+        }
+    }
+
+    /// Enables or disables forwarding datagrams addressed to other hosts out of devices of
+    /// their routes, as a router does.
+    pub fn set_forwarding(&self, enabled: bool) {
+        let contexts = &mut self.contexts.lock().unwrap();
+        contexts.ip_forwarding = enabled;
+    }
+
+    /// Masquerades TCP and UDP flows forwarded out of a device of a name as its address. None
+    /// disables NAT, dropping mappings of flows.
+    pub fn set_nat(&self, outside: Option<&str>) {
+        let contexts = &mut self.contexts.lock().unwrap();
+        contexts.nat = outside.map(Nat::new);
+    }
+
+    /// Probes gateways of routes with ARP at an interval, marking their routes down while they
+    /// do not answer. None disables probing.
+    pub fn set_gateway_probe(&self, interval: Option<Duration>) {
+        let contexts = &mut self.contexts.lock().unwrap();
+        contexts.gateway_monitor.interval = interval;
+    }
+
+    /// Enables dropping frames transmitted with a source MAC/IP not configured on the device.
+    pub fn set_egress_check(&self, enabled: bool) {
+        let devices = &mut self.devices.lock().unwrap();
+        for device in devices.entries.iter_mut() {
+            device.egress_check = enabled;
+        }
+    }
+
+    /// Resolves next hops on Ethernet devices with static ARP entries only, failing right away
+    /// for others instead of sending ARP requests.
+    pub fn set_static_neighbors(&self, enabled: bool) {
+        let devices = &mut self.devices.lock().unwrap();
+        for device in devices.entries.iter_mut() {
+            if device.device_type == NetDeviceType::Ethernet {
+                device.static_neighbors = enabled;
+            }
+        }
+    }
+
+    /// Adds a route to a network via a gateway on the subnet of an interface, taking precedence
+    /// over routes of the same prefix length with higher metrics.
+    pub fn add_route(
+        &self,
+        network: IpCidr,
+        gateway: IPAdress,
+        metric: u32,
+    ) -> Result<(), RouteError> {
+        let devices = &self.devices.lock().unwrap();
+        let contexts = &mut self.contexts.lock().unwrap();
+        contexts.ip_routes.add(network, gateway, metric, devices)
+    }
+
+    /// Deletes routes to a network, only the one via a gateway if given.
+    pub fn delete_route(
+        &self,
+        network: IpCidr,
+        gateway: Option<IPAdress>,
+    ) -> Result<(), RouteError> {
+        let contexts = &mut self.contexts.lock().unwrap();
+        contexts.ip_routes.delete(network, gateway)
+    }
+
+    /// Returns routes: network, gateway, source interface and metric of each.
+    pub fn routes(&self) -> String {
+        let contexts = &self.contexts.lock().unwrap();
+        contexts.ip_routes.show()
+    }
+
+    /// Returns ARP table contents: IP, hardware address, state and age of each entry.
+    pub fn arp_table(&self) -> String {
+        let contexts = &self.contexts.lock().unwrap();
+        contexts.arp_table.to_string()
+    }
+
+    /// Flushes an ARP entry of an IP, or all entries but static ones. Returns the number of
+    /// entries flushed.
+    pub fn flush_arp(&self, ip: Option<IPAdress>) -> usize {
+        let contexts = &mut self.contexts.lock().unwrap();
+        contexts.arp_table.flush(ip)
+    }
+
+    /// Sets up a stack on an end of a device pair (`ethernet::init_pair`) with an interface of
+    /// an address and its subnet, e.g. "192.0.2.2/24", routed to directly, and no loopback
+    /// device. Started, it polls
+    /// the device on a thread instead of handling signals, so that stacks of this kind can talk
+    /// to each other within a process, e.g. in tests (see `sim::app_pair`).
+    pub fn with_device(device: NetDevice, cidr: &str) -> NetApp {
+        StackBuilder::new()
+            .device(device)
+            .interface(cidr)
+            .build()
+            .expect("App: invalid device configuration.")
+    }
+
+    /// Stack of devices opened with interfaces, routes and control blocks set up already, with
+    /// protocols registered by default. `StackBuilder` sets them up with checks.
+    pub fn from_parts(
+        devices: NetDevices,
+        contexts: ProtocolContexts,
+        pcbs: ControlBlocks,
+    ) -> NetApp {
+        NetApp {
+            devices: Arc::new(Mutex::new(devices)),
+            protocols: Arc::new(Mutex::new(net_protocols())),
+            contexts: Arc::new(Mutex::new(contexts)),
+            pcbs: Arc::new(Mutex::new(pcbs)),
+            dhcp_client: None,
+            control_socket: String::from(CONTROL_SOCKET_PATH),
+            runtime: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Sets up devices, routes and protocols without CLI args (e.g. for library users): those of
+    /// the default configuration (see `StackConfig`), or in DHCP mode a loopback device and
+    /// tap0 configured with DHCP.
+    pub fn with_options(dhcp: bool, congestion: CongestionAlgorithm) -> NetApp {
+        let config = StackConfig::default();
+        if dhcp {
+            return NetApp::with_dhcp(&config.devices[0].name, congestion);
+        }
+        NetApp::with_config(&config, congestion).unwrap_or_else(exit_on_config_errors)
+    }
+
+    /// Sets up a loopback device and a TAP device of the name configured with DHCP.
+    pub fn with_dhcp(name: &str, congestion: CongestionAlgorithm) -> NetApp {
+        register_signals().expect("App: failed to register signals.");
 
         // Setups
         let mut devices = NetDevices::new();
@@ -52,7 +435,7 @@ impl NetApp {
         loopback_device.open().unwrap();
 
         // Loopback interface
-        let loopback_interface = Arc::new(IPInterface::new(LOOPBACK_IP, LOOPBACK_NETMASK));
+        let loopback_interface = Arc::new(IPInterface::new(LOOPBACK_CIDR));
         loopback_device.register_interface(loopback_interface.clone());
 
         // Loopback route
@@ -63,47 +446,183 @@ impl NetApp {
 
         // Ethernet device
         let mut ethernet_device = ethernet::init(1, crate::drivers::DriverType::Tap);
+        ethernet_device.name = name.to_string();
         ethernet_device.open().unwrap();
 
-        // Ethernet Interface
-        let ethernet_interface = Arc::new(IPInterface::new(ETH_TAP_IP, ETH_TAP_NETMASK));
+        // Ethernet Interface (unconfigured till DHCP lease is bound)
+        let ethernet_interface = Arc::new(IPInterface::new_from_addr(IP_ADDR_ANY, IP_ADDR_ANY));
         ethernet_device.register_interface(ethernet_interface.clone());
 
+        // Ethernet IPv6 link-local interface
+        let ethernet_hw_addr = ethernet_device.address.ethernet().unwrap();
+        let ethernet_ipv6_interface = Arc::new(IPV6Interface::link_local(ethernet_hw_addr));
+        ethernet_device.register_ipv6_interface(ethernet_ipv6_interface);
+
         devices.register(ethernet_device);
 
-        // Default gateway route
-        let default_gw_route = IPRoute::gateway_route(DEFAULT_GATEWAY, ethernet_interface);
-        ip_routes.register(default_gw_route);
+        // Configuration check before routes are used by ARP and IP output
+        if let Err(errors) = devices.validate(&[]) {
+            exit_on_config_errors(errors);
+        }
+
+        // Interface route of unconfigured interface (0.0.0.0/0) for DHCP messages
+        ip_routes.register(IPRoute::interface_route(ethernet_interface));
 
         // Protocol setup
-        let mut protocols = NetProtocols::new();
+        let protocols = net_protocols();
 
-        // ARP
-        let arp_proto = NetProtocol::new(ProtocolType::Arp);
-        protocols.register(arp_proto);
+        // Protocol contexts
+        let contexts = ProtocolContexts::new(ip_routes);
 
-        // IP
-        let ip_proto = NetProtocol::new(ProtocolType::IP);
-        protocols.register(ip_proto);
+        let mut pcbs = ControlBlocks::new();
+        pcbs.tcp_pcbs.congestion_algorithm = congestion;
 
-        // Protocol contexts
-        let contexts = ProtocolContexts {
-            arp_table: ArpTable::new(),
-            ip_routes,
-            ip_id_manager: IPHeaderIdManager::new(),
-        };
+        // DHCP client
+        let dhcp_client = Some(Arc::new(Mutex::new(DhcpClient::new(
+            ethernet_hw_addr,
+            &mut pcbs,
+        ))));
 
         NetApp {
             devices: Arc::new(Mutex::new(devices)),
             protocols: Arc::new(Mutex::new(protocols)),
             contexts: Arc::new(Mutex::new(contexts)),
-            pcbs: Arc::new(Mutex::new(ControlBlocks::new())),
+            pcbs: Arc::new(Mutex::new(pcbs)),
+            dhcp_client,
+            control_socket: String::from(CONTROL_SOCKET_PATH),
+            runtime: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Sets up a stack of a configuration, e.g. of `StackConfig::load`. Errors of the whole
+    /// configuration are returned before any device opens.
+    pub fn with_config(
+        config: &StackConfig,
+        congestion: CongestionAlgorithm,
+    ) -> Result<NetApp, Vec<ConfigError>> {
+        register_signals().expect("App: failed to register signals.");
+        config.builder().congestion(congestion).build()
+    }
+
+    /// Starts the signal thread handling protocols and IRQs, and timer threads. Does nothing
+    /// when already started.
+    pub fn start(&self) -> io::Result<()> {
+        let mut runtime = self.runtime.lock().unwrap();
+        if runtime.is_some() {
+            return Ok(());
+        }
+        let (input, input_join) = if self.polls_input() {
+            let (sender, receiver) = mpsc::channel();
+            (
+                Input::Polled(sender),
+                self.clone().input_poll_thread(receiver),
+            )
+        } else {
+            register_signals()?;
+            let mut signals = SIGNALS.lock().unwrap().take().unwrap();
+            let handle = signals.handle();
+            let mut app = self.clone();
+            let join = thread::spawn(move || app.handle_signals(&mut signals));
+            (Input::Signals(handle), join)
+        };
+
+        let mut timers = vec![];
+        let (sender, receiver) = mpsc::channel();
+        timers.push((sender, self.clone().timer_thread(receiver)));
+        let (sender, receiver) = mpsc::channel();
+        timers.push((sender, self.clone().tcp_timer_thread(receiver)));
+        let (sender, receiver) = mpsc::channel();
+        if let Some(join) = self.clone().dhcp_thread(receiver) {
+            timers.push((sender, join));
+        }
+        *runtime = Some(Runtime {
+            input,
+            input_join,
+            timers,
+            listeners: vec![],
+        });
+        info!("App: stack started.");
+        Ok(())
+    }
+
+    /// Sends to a thread of users (e.g. a command thread) when the stack stops, before blocked
+    /// sockets are woken up. Sends right away when the stack is not started.
+    pub fn notify_on_stop(&self, sender: mpsc::Sender<()>) {
+        match self.runtime.lock().unwrap().as_mut() {
+            Some(runtime) => runtime.listeners.push(sender),
+            None => {
+                sender.send(()).ok();
+            }
+        }
+    }
+
+    /// Stops the stack started and waits for its threads. It can be started again.
+    pub fn stop(&self) {
+        if let Some(runtime) = self.runtime.lock().unwrap().as_ref() {
+            runtime.input.close();
+        }
+        self.join();
+    }
+
+    /// Waits till the stack stops on a termination signal or `stop`, then stops timer threads
+    /// and wakes up users blocked on sockets.
+    pub fn join(&self) {
+        loop {
+            match self.runtime.lock().unwrap().as_ref() {
+                None => return,
+                Some(runtime) if runtime.input_join.is_finished() => break,
+                Some(_) => {}
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        let runtime = match self.runtime.lock().unwrap().take() {
+            Some(runtime) => runtime,
+            None => return,
+        };
+        runtime.input_join.join().unwrap();
+        for sender in runtime.listeners.iter() {
+            sender.send(()).ok();
+        }
+        for (sender, _) in runtime.timers.iter() {
+            sender.send(()).ok();
         }
+        // Wakes up the TCP timer thread sleeping till a deadline.
+        self.pcbs.lock().unwrap().tcp_pcbs.set_timer_wakeup(None);
+        self.clone().close_sockets();
+        for (_, join) in runtime.timers {
+            join.join().unwrap();
+        }
+        info!("App: stack stopped.");
     }
 
     pub fn run(&mut self, receiver: mpsc::Receiver<()>) -> JoinHandle<()> {
         let args = Cli::parse();
-        match args.command {
+        if let Some(dhcp_arc) = self.dhcp_client.clone() {
+            // Commands start after the interface gets configured with a lease.
+            let mut app = self.clone();
+            return thread::spawn(move || {
+                info!("App: waiting for DHCP lease...");
+                loop {
+                    match receiver.try_recv() {
+                        Ok(_) | Err(TryRecvError::Disconnected) => {
+                            info!("App: thread terminating.");
+                            return;
+                        }
+                        Err(TryRecvError::Empty) => {}
+                    }
+                    if dhcp_arc.lock().unwrap().is_bound() {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(100));
+                }
+                app.run_command(args.command, receiver).join().unwrap();
+            });
+        }
+        self.run_command(args.command, receiver)
+    }
+
+    fn run_command(&mut self, command: Commands, receiver: mpsc::Receiver<()>) -> JoinHandle<()> {
+        match command {
             Commands::Tcp(tcp) => {
                 let tcp_command = tcp.command.unwrap();
                 match tcp_command {
@@ -111,14 +630,22 @@ impl NetApp {
                         target_ip,
                         target_port,
                         data,
+                        output,
                     } => {
-                        return self.tcp_send_command(target_ip, target_port, data, receiver);
+                        let sink = DataSink::new(output);
+                        return self.tcp_send_command(target_ip, target_port, data, sink, receiver);
                     }
                     EndPointCommand::Receive {
                         local_ip,
                         local_port,
+                        listen,
+                        output,
                     } => {
-                        return self.tcp_receive_command(receiver);
+                        let locals = receive_endpoints(&local_ip, local_port, &listen);
+                        let sink = Arc::new(Mutex::new(DataSink::new(output)));
+                        return self.receive_on_each(locals, receiver, |app, local, receiver| {
+                            app.tcp_receive_command(local, sink.clone(), receiver)
+                        });
                     }
                 };
             }
@@ -129,26 +656,66 @@ impl NetApp {
                         target_ip,
                         target_port,
                         data,
+                        output,
                     } => {
-                        return self.udp_send_command(target_ip, target_port, data, receiver);
+                        let sink = DataSink::new(output);
+                        return self.udp_send_command(target_ip, target_port, data, sink, receiver);
                     }
                     EndPointCommand::Receive {
                         local_ip,
                         local_port,
+                        listen,
+                        output,
                     } => {
-                        return self.udp_receive_command(receiver);
+                        let locals = receive_endpoints(&local_ip, local_port, &listen);
+                        let sink = Arc::new(Mutex::new(DataSink::new(output)));
+                        return self.receive_on_each(locals, receiver, |app, local, receiver| {
+                            app.udp_receive_command(local, sink.clone(), receiver)
+                        });
                     }
                 }
             }
+            Commands::Ping(ping) => self.ping_command(ping.target_ip, ping.count, receiver),
+            Commands::Arping(arping) => {
+                self.arping_command(arping.target_ip, arping.count, receiver)
+            }
+            Commands::Traceroute(traceroute) => {
+                self.traceroute_command(traceroute.target_ip, traceroute.max_hops, receiver)
+            }
+            Commands::Conn(_) | Commands::Stats | Commands::Arp(_) | Commands::Route(_) => {
+                unreachable!("App: administrative commands are sent by control client.")
+            }
         }
     }
 
     pub fn close_sockets(&mut self) {
         let mut pcbs = self.pcbs.lock().unwrap();
+        pcbs.icmp_pcbs.close_sockets();
         pcbs.udp_pcbs.close_sockets();
         pcbs.tcp_pcbs.close_sockets();
     }
 
+    /// Handles protocol and IRQ signals till a termination signal arrives.
+    pub fn handle_signals(&mut self, signals: &mut SignalsInfo<WithOrigin>) {
+        info!("App: starting signal receiver thread...");
+        for info in signals {
+            debug!("App: ----Signal Received {:?}----\n", info);
+            match info.signal {
+                SIGHUP => {}
+                SIGUSR1 => {
+                    self.handle_protocol();
+                }
+                sig => {
+                    if TERM_SIGNALS.contains(&sig) {
+                        info!("App: terminating...");
+                        break;
+                    }
+                    self.handle_irq(sig);
+                }
+            }
+        }
+    }
+
     pub fn handle_protocol(&mut self) {
         let devices = &mut self.devices.lock().unwrap();
         let protocols = &mut self.protocols.lock().unwrap();
@@ -163,33 +730,295 @@ impl NetApp {
         devices.handle_irq(irq, protocols);
     }
 
-    pub fn tcp_transmit_thread(&mut self, receiver: mpsc::Receiver<()>) -> JoinHandle<()> {
+    /// Whether devices are of the pair driver, which raise no IRQ signals on input.
+    fn polls_input(&self) -> bool {
+        let devices = self.devices.lock().unwrap();
+        let is_pair = |device: &NetDevice| matches!(device.driver_type, Some(DriverType::Pair(_)));
+        devices.entries.iter().any(is_pair)
+    }
+
+    /// Handles frames arriving on devices of the pair driver in place of the signal thread,
+    /// polling them every millisecond while idle.
+    fn input_poll_thread(&mut self, receiver: mpsc::Receiver<()>) -> JoinHandle<()> {
         let pcbs_arc = self.pcbs.clone();
         let devices_arc = self.devices.clone();
         let contexts_arc = self.contexts.clone();
         thread::spawn(move || loop {
-            // transmit check interval: 100ms
+            // Termination check
+            match receiver.try_recv() {
+                Ok(_) | Err(TryRecvError::Disconnected) => {
+                    info!("Input poll thread Terminating.");
+                    break;
+                }
+                Err(TryRecvError::Empty) => {}
+            }
+
+            let frames: usize = {
+                let devices = &mut devices_arc.lock().unwrap();
+                let contexts = &mut contexts_arc.lock().unwrap();
+                let pcbs = &mut pcbs_arc.lock().unwrap();
+                devices
+                    .entries
+                    .iter_mut()
+                    .map(|device| sim::input(device, contexts, pcbs))
+                    .sum()
+            };
+            if frames == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+        })
+    }
+
+    /// Drives maintenance timers: ARP request retransmission, resolution
+    /// timeout, cache expiry and gateway probes, and sweeps input data left by coalesced SIGUSR1.
+    pub fn timer_thread(&mut self, receiver: mpsc::Receiver<()>) -> JoinHandle<()> {
+        let pcbs_arc = self.pcbs.clone();
+        let devices_arc = self.devices.clone();
+        let protocols_arc = self.protocols.clone();
+        let contexts_arc = self.contexts.clone();
+        thread::spawn(move || loop {
+            // timer check interval: 100ms
             thread::sleep(Duration::from_millis(100));
 
             // Termination check
             match receiver.try_recv() {
                 Ok(_) | Err(TryRecvError::Disconnected) => {
-                    info!("TCP transmit thread Terminating.");
+                    info!("Timer thread Terminating.");
                     break;
                 }
                 Err(TryRecvError::Empty) => {}
             }
 
             {
+                let devices = &mut devices_arc.lock().unwrap();
+                let contexts = &mut contexts_arc.lock().unwrap();
                 let pcbs = &mut pcbs_arc.lock().unwrap();
+                l4::timer_tick_on_devices(devices, contexts, pcbs);
+                arp::timer_on_devices(devices, &mut contexts.arp_table);
+                for device in devices.entries.iter_mut() {
+                    if device.device_type == NetDeviceType::Ethernet {
+                        arp::monitor_gateways(device, contexts);
+                    }
+                }
+            }
+
+            {
+                // same lock order as handle_protocol
                 let devices = &mut devices_arc.lock().unwrap();
+                let protocols = &mut protocols_arc.lock().unwrap();
                 let contexts = &mut contexts_arc.lock().unwrap();
-                let eth_device = devices.get_mut_by_type(NetDeviceType::Ethernet).unwrap();
-                tcp::retransmit(&mut pcbs.tcp_pcbs, eth_device, contexts);
+                let pcbs = &mut pcbs_arc.lock().unwrap();
+                protocols.sweep(devices, contexts, pcbs);
             }
         })
     }
 
+    /// Drives TCP timers (retransmission, delayed ACK, zero window probe, TIME-WAIT), sleeping
+    /// till the earliest deadline of all connections instead of ticking, so that idle connections
+    /// cost nothing. PCBs scheduling an earlier deadline wake it up.
+    pub fn tcp_timer_thread(&mut self, receiver: mpsc::Receiver<()>) -> JoinHandle<()> {
+        let pcbs_arc = self.pcbs.clone();
+        let devices_arc = self.devices.clone();
+        let contexts_arc = self.contexts.clone();
+        let (wakeup, wakeups) = mpsc::channel();
+        pcbs_arc
+            .lock()
+            .unwrap()
+            .tcp_pcbs
+            .set_timer_wakeup(Some(wakeup));
+        thread::spawn(move || loop {
+            // Termination check
+            match receiver.try_recv() {
+                Ok(_) | Err(TryRecvError::Disconnected) => {
+                    info!("TCP timer thread Terminating.");
+                    break;
+                }
+                Err(TryRecvError::Empty) => {}
+            }
+
+            let deadline = pcbs_arc.lock().unwrap().tcp_pcbs.next_deadline();
+            let woken = match deadline {
+                Some(at) => {
+                    wakeups.recv_timeout(at.duration_since(SystemTime::now()).unwrap_or_default())
+                }
+                None => wakeups.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match woken {
+                Ok(()) => continue, // earlier deadline scheduled
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    info!("TCP timer thread Terminating.");
+                    break;
+                }
+            }
+
+            let devices = &mut devices_arc.lock().unwrap();
+            let contexts = &mut contexts_arc.lock().unwrap();
+            let pcbs = &mut pcbs_arc.lock().unwrap();
+            tcp::retransmit_on_devices(&mut pcbs.tcp_pcbs, devices, contexts);
+        })
+    }
+
+    /// Drives DHCP client timers (retransmission, renewal and expiry) in DHCP mode.
+    pub fn dhcp_thread(&mut self, receiver: mpsc::Receiver<()>) -> Option<JoinHandle<()>> {
+        let dhcp_arc = self.dhcp_client.clone()?;
+        let pcbs_arc = self.pcbs.clone();
+        let devices_arc = self.devices.clone();
+        let contexts_arc = self.contexts.clone();
+        Some(thread::spawn(move || loop {
+            // timer check interval: 100ms
+            thread::sleep(Duration::from_millis(100));
+
+            // Termination check
+            match receiver.try_recv() {
+                Ok(_) | Err(TryRecvError::Disconnected) => {
+                    info!("DHCP thread Terminating.");
+                    break;
+                }
+                Err(TryRecvError::Empty) => {}
+            }
+
+            {
+                let dhcp_client = &mut dhcp_arc.lock().unwrap();
+                let devices = &mut devices_arc.lock().unwrap();
+                let contexts = &mut contexts_arc.lock().unwrap();
+                let pcbs = &mut pcbs_arc.lock().unwrap();
+                let hw_addr = Some(dhcp_client.hw_addr());
+                let Some(eth_device) = devices
+                    .entries
+                    .iter_mut()
+                    .find(|device| device.address.ethernet() == hw_addr)
+                else {
+                    warn!("App: no device of the DHCP client. Skipping its timers...");
+                    continue;
+                };
+                dhcp_client.on_timer(eth_device, contexts, pcbs);
+            }
+        }))
+    }
+
+    /// Sets the path of the control socket, /tmp/rust-user-net.sock by default, e.g. to run
+    /// stacks on different TAP devices.
+    pub fn set_control_socket(&mut self, path: &str) {
+        self.control_socket = path.to_string();
+    }
+
+    /// Serves administrative commands (e.g. `conn kill`, `stats`, `arp show`) sent over the
+    /// control socket. Fails when the socket can not be bound or another stack serves it.
+    pub fn control_thread(&mut self, receiver: mpsc::Receiver<()>) -> io::Result<JoinHandle<()>> {
+        let pcbs_arc = self.pcbs.clone();
+        let devices_arc = self.devices.clone();
+        let protocols_arc = self.protocols.clone();
+        let contexts_arc = self.contexts.clone();
+        let path = self.control_socket.clone();
+        if UnixStream::connect(&path).is_ok() {
+            error!("App: control socket {path} is served by another stack: give another path with --control-socket.");
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        let _ = fs::remove_file(&path); // left by a stack not stopped cleanly
+        let listener = UnixListener::bind(&path)
+            .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+            .map_err(|e| {
+                error!("App: failed to bind control socket {path}: {e}");
+                e
+            })?;
+        Ok(thread::spawn(move || loop {
+            // accept check interval: 100ms
+            thread::sleep(Duration::from_millis(100));
+
+            // Termination check
+            match receiver.try_recv() {
+                Ok(_) | Err(TryRecvError::Disconnected) => {
+                    info!("Control thread Terminating.");
+                    let _ = fs::remove_file(&path);
+                    break;
+                }
+                Err(TryRecvError::Empty) => {}
+            }
+
+            let mut stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => {
+                    error!("App: control socket accept failed: {e}");
+                    continue;
+                }
+            };
+            stream.set_nonblocking(false).unwrap();
+            let mut line = String::new();
+            // Nothing sent, e.g. by another stack checking the socket is served
+            if !matches!(BufReader::new(&stream).read_line(&mut line), Ok(len) if len > 0) {
+                continue;
+            }
+            let latency = protocols_arc.lock().unwrap().latency.to_string();
+            let result = {
+                let devices = &mut devices_arc.lock().unwrap();
+                let contexts = &mut contexts_arc.lock().unwrap();
+                let pcbs = &mut pcbs_arc.lock().unwrap();
+                match line.split_whitespace().collect::<Vec<&str>>()[..] {
+                    ["conn", "show"] => Ok(pcbs.tcp_pcbs.show()),
+                    ["conn", "show", "netstat"] => Ok(pcbs.netstat()),
+                    ["tcp", "stats"] => Ok(pcbs.tcp_pcbs.connection_stats_table(None)),
+                    ["tcp", "stats", id] => match id.parse() {
+                        Ok(pcb_id) => Ok(pcbs.tcp_pcbs.connection_stats_table(Some(pcb_id))),
+                        Err(_) => Err(()),
+                    },
+                    ["conn", "kill", "tcp", id] => match id.parse() {
+                        Ok(pcb_id) => {
+                            let device = tcp::pcb_device(pcb_id, devices, contexts, pcbs).unwrap();
+                            tcp::abort(pcb_id, pcbs, device, contexts)
+                        }
+                        Err(_) => Err(()),
+                    }
+                    .map(|_| String::new()),
+                    ["conn", "kill", "udp", id] => match id.parse() {
+                        Ok(pcb_id) => udp::abort(&mut pcbs.udp_pcbs, pcb_id),
+                        Err(_) => Err(()),
+                    }
+                    .map(|_| String::new()),
+                    ["stats"] => Ok(devices.stats_table()
+                        + &contexts.martians.to_string()
+                        + &latency
+                        + &contexts.gateway_monitor.to_string()
+                        + &contexts
+                            .nat
+                            .as_ref()
+                            .map(Nat::to_string)
+                            .unwrap_or_default()
+                        + &pcbs.tcp_pcbs.stats()
+                        + &pcbs.udp_pcbs.stats()
+                        + &pcbs.memory_stats()),
+                    ["arp", "show"] => Ok(contexts.arp_table.to_string()),
+                    ["arp", "flush"] => {
+                        Ok(format!("flushed: {}\n", contexts.arp_table.flush(None)))
+                    }
+                    ["arp", "flush", ip] => match ip_addr_to_bytes(ip) {
+                        Some(ip) => {
+                            Ok(format!("flushed: {}\n", contexts.arp_table.flush(Some(ip))))
+                        }
+                        None => Err(()),
+                    },
+                    ["route", "show"] => Ok(contexts.ip_routes.show()),
+                    ["route", ref args @ ..] => {
+                        route_request(args, devices, &mut contexts.ip_routes).map(|_| String::new())
+                    }
+                    _ => {
+                        warn!("App: unknown control command: {:?}", line.trim());
+                        Err(())
+                    }
+                }
+            };
+            // Reply body lines followed by a status line
+            let reply = match result {
+                Ok(body) => body + "ok\n",
+                Err(_) => String::from("error\n"),
+            };
+            if stream.write_all(reply.as_bytes()).is_err() {
+                warn!("App: control client disconnected before reply.");
+            }
+        }))
+    }
+
     // CLI command implementations
 
     fn tcp_send_command(
@@ -197,6 +1026,7 @@ impl NetApp {
         target_ip: String,
         target_port: u16,
         data: String,
+        mut sink: DataSink,
         receiver: mpsc::Receiver<()>,
     ) -> JoinHandle<()> {
         let pcbs_arc = self.pcbs.clone();
@@ -214,48 +1044,83 @@ impl NetApp {
                 Err(TryRecvError::Empty) => {}
             }
             if sock_opt.is_none() {
-                sock_opt = {
-                    let local = IPEndpoint::new_from_str("192.0.2.2", 7);
-                    let remote = IPEndpoint::new_from_str(&target_ip, target_port);
-                    tcp::rfc793_open(
-                        local,
-                        Some(remote),
-                        true,
-                        pcbs_arc.clone(),
-                        devices_arc.clone(),
-                        contexts_arc.clone(),
-                    )
+                let local = IPEndpoint::new_from_str("192.0.2.2", 7);
+                let remote = IPEndpoint::new_from_str(&target_ip, target_port);
+                match tcp::rfc793_open(
+                    local,
+                    Some(remote),
+                    true,
+                    pcbs_arc.clone(),
+                    devices_arc.clone(),
+                    contexts_arc.clone(),
+                ) {
+                    Ok(pcb_id) => sock_opt = Some(pcb_id),
+                    Err(err) => {
+                        error!("App: connection failed: {err:?}");
+                        return;
+                    }
                 }
             }
             if !request_sent {
                 info!("App: sending request");
-                let devices = &mut devices_arc.lock().unwrap();
-                let contexts = &mut contexts_arc.lock().unwrap();
-                let eth_device = devices.get_mut_by_type(NetDeviceType::Ethernet).unwrap();
-
-                let req = data
-                    .replace("\\r", "\r")
-                    .replace("\\n", "\n")
-                    .as_bytes()
-                    .to_vec(); //  "GET / HTTP/1.1\r\nHost: www.google.com\r\n\r\n"
-                tcp::send(
+                let req = request_data(&data); //  "GET / HTTP/1.1\r\nHost: www.google.com\r\n\r\n"
+                tcp::send_all(
                     sock_opt.unwrap(),
                     req,
-                    eth_device,
-                    contexts,
-                    &mut pcbs_arc.clone(),
+                    pcbs_arc.clone(),
+                    devices_arc.clone(),
+                    contexts_arc.clone(),
                 );
                 request_sent = true;
             }
             info!("App: starting TCP receive...");
-            let receive_res = tcp::receive(sock_opt.unwrap(), 2048, pcbs_arc.clone());
+            let receive_res = tcp::receive(
+                sock_opt.unwrap(),
+                2048,
+                pcbs_arc.clone(),
+                devices_arc.clone(),
+                contexts_arc.clone(),
+            );
             if let Some(received) = receive_res {
-                log_data(&received[..]);
+                sink.write(&received[..]);
+            } else if tcp_killed(sock_opt.unwrap(), &pcbs_arc) {
+                return;
             }
         })
     }
 
-    fn tcp_receive_command(&mut self, receiver: mpsc::Receiver<()>) -> JoinHandle<()> {
+    /// Runs a receive command on each local endpoint in a thread of its own, and stops them all
+    /// on termination.
+    fn receive_on_each(
+        &mut self,
+        locals: Vec<IPEndpoint>,
+        receiver: mpsc::Receiver<()>,
+        mut command: impl FnMut(&mut NetApp, IPEndpoint, mpsc::Receiver<()>) -> JoinHandle<()>,
+    ) -> JoinHandle<()> {
+        let mut senders = vec![];
+        let mut joins = vec![];
+        for local in locals {
+            let (sender, receiver) = mpsc::channel();
+            senders.push(sender);
+            joins.push(command(self, local, receiver));
+        }
+        thread::spawn(move || {
+            receiver.recv().ok();
+            for sender in senders {
+                sender.send(()).ok();
+            }
+            for join in joins {
+                join.join().unwrap();
+            }
+        })
+    }
+
+    fn tcp_receive_command(
+        &mut self,
+        local: IPEndpoint,
+        sink: Arc<Mutex<DataSink>>,
+        receiver: mpsc::Receiver<()>,
+    ) -> JoinHandle<()> {
         let pcbs_arc = self.pcbs.clone();
         let devices_arc = self.devices.clone();
         let contexts_arc = self.contexts.clone();
@@ -270,8 +1135,12 @@ impl NetApp {
                 Err(TryRecvError::Empty) => {}
             }
             if sock_opt.is_none() {
+                info!("App: listening on TCP {local}...");
                 sock_opt = {
-                    let local = IPEndpoint::new_from_str("0.0.0.0", 7);
+                    let local = IPEndpoint {
+                        address: local.address,
+                        port: local.port,
+                    };
                     tcp::rfc793_open(
                         local,
                         None,
@@ -280,17 +1149,41 @@ impl NetApp {
                         devices_arc.clone(),
                         contexts_arc.clone(),
                     )
+                    .ok()
                 }
             }
             if sock_opt.is_none() {
                 info!("App: interrupted before establishing any connection.");
                 return;
             }
-            info!("App: starting TCP receive...");
-            let receive_res = tcp::receive(sock_opt.unwrap(), 2048, pcbs_arc.clone());
-            if let Some(received) = receive_res {
-                log_data(&received[..]);
+            info!("App: starting TCP receive on {local}...");
+            let pcb_id = sock_opt.unwrap();
+            let receive_res = tcp::receive(
+                pcb_id,
+                2048,
+                pcbs_arc.clone(),
+                devices_arc.clone(),
+                contexts_arc.clone(),
+            );
+            match receive_res {
+                Some(received) if !received.is_empty() => {
+                    if let Some(remote) = tcp::remote_endpoint(pcb_id, &pcbs_arc.lock().unwrap()) {
+                        info!("App: received on TCP {local} from {remote}");
+                    }
+                    sink.lock().unwrap().write(&received[..]);
+                    continue;
+                }
+                None if tcp_killed(pcb_id, &pcbs_arc) => return,
+                _ => {}
             }
+            // Closed by the peer: accepts the next connection.
+            info!("App: TCP connection on {local} ended.");
+            let devices = &mut devices_arc.lock().unwrap();
+            let contexts = &mut contexts_arc.lock().unwrap();
+            let pcbs = &mut pcbs_arc.lock().unwrap();
+            let device = tcp::pcb_device(pcb_id, devices, contexts, pcbs).unwrap();
+            tcp::close(pcb_id, pcbs, device, contexts);
+            sock_opt = None;
         })
     }
 
@@ -299,13 +1192,14 @@ impl NetApp {
         target_ip: String,
         target_port: u16,
         data: String,
+        mut sink: DataSink,
         receiver: mpsc::Receiver<()>,
     ) -> JoinHandle<()> {
         let pcbs_arc = self.pcbs.clone();
         let devices_arc = self.devices.clone();
         let contexts_arc = self.contexts.clone();
         let mut soc_opt = None;
-        let mut sent_count = 0;
+        let mut request_sent = false;
 
         thread::spawn(move || loop {
             // Termination check
@@ -325,35 +1219,344 @@ impl NetApp {
                     Some(soc)
                 }
             }
-            // send twice to wait for ARP response once
-            if sent_count < 2 {
+            if !request_sent {
                 let devices = &mut devices_arc.lock().unwrap();
                 let contexts = &mut contexts_arc.lock().unwrap();
                 let pcbs = &mut pcbs_arc.lock().unwrap();
 
                 let remote = IPEndpoint::new_from_str(&target_ip, target_port); // 192.0.2.1 10007
-                let eth_device = devices.get_mut_by_type(NetDeviceType::Ethernet).unwrap();
-                let req = data
-                    .replace("\\r", "\r")
-                    .replace("\\n", "\n")
-                    .as_bytes()
-                    .to_vec();
-
-                udp::send_to(soc_opt.unwrap(), req, remote, eth_device, contexts, pcbs);
-                sent_count += 1;
+                let eth_device = devices
+                    .get_mut_by_dst(remote.address, &contexts.ip_routes)
+                    .unwrap();
+                let req = request_data(&data);
+
+                if udp::send_to(soc_opt.unwrap(), req, remote, eth_device, contexts, pcbs).is_err()
+                {
+                    error!("App: UDP request could not be sent.");
+                }
+                request_sent = true;
             } else {
                 info!("App: starting UDP receive...");
                 let receive_res = udp::receive_from(soc_opt.unwrap(), pcbs_arc.clone());
                 if let Some(entry) = receive_res {
-                    log_data(&entry.data[..]);
+                    sink.write(&entry.data[..]);
+                } else if udp_killed(soc_opt.unwrap(), &pcbs_arc) {
+                    return;
                 }
             }
-            // TODO: fix this hack to wait for ARP reply in signal thread
-            thread::sleep(Duration::from_secs(1));
         })
     }
 
-    fn udp_receive_command(&self, receiver: mpsc::Receiver<()>) -> JoinHandle<()> {
+    fn ping_command(
+        &mut self,
+        target_ip: String,
+        count: u16,
+        receiver: mpsc::Receiver<()>,
+    ) -> JoinHandle<()> {
+        let pcbs_arc = self.pcbs.clone();
+        let devices_arc = self.devices.clone();
+        let contexts_arc = self.contexts.clone();
+        thread::spawn(move || {
+            let dst = ip_addr_to_bytes(&target_ip).expect("App: invalid target IP address.");
+            if contexts_arc
+                .lock()
+                .unwrap()
+                .ip_routes
+                .lookup_ip_route(dst)
+                .is_none()
+            {
+                error!("App: no route to {target_ip}.");
+                return;
+            }
+            let id = (process::id() % u16::MAX as u32) as u16;
+            let pcb_id = {
+                let pcbs = &mut pcbs_arc.lock().unwrap();
+                icmp::open(&mut pcbs.icmp_pcbs, icmp::ICMP_TYPE_ECHOREPLY, id)
+            };
+            info!("App: PING {target_ip} {PING_DATA_LEN} bytes of data.");
+            let mut transmitted = 0;
+            let mut received = 0;
+            for seq in 1..=count {
+                // Termination check
+                match receiver.try_recv() {
+                    Ok(_) | Err(TryRecvError::Disconnected) => {
+                        info!("App: thread terminating.");
+                        break;
+                    }
+                    Err(TryRecvError::Empty) => {}
+                }
+                let sent_at = SystemTime::now();
+                let next_at = Instant::now() + Duration::from_secs(PING_INTERVAL_SEC);
+                {
+                    let devices = &mut devices_arc.lock().unwrap();
+                    let contexts = &mut contexts_arc.lock().unwrap();
+                    let pcbs = &mut pcbs_arc.lock().unwrap();
+                    let eth_device = devices.get_mut_by_dst(dst, &contexts.ip_routes).unwrap();
+                    let data: Vec<u8> = (0..PING_DATA_LEN).map(|i| i as u8).collect();
+                    icmp::output(
+                        icmp::ICMP_TYPE_ECHO,
+                        0,
+                        le_to_be_u32((id as u32) << 16 | seq as u32),
+                        data,
+                        PING_DATA_LEN,
+                        IP_ADDR_ANY,
+                        dst,
+                        eth_device,
+                        contexts,
+                        pcbs,
+                    );
+                }
+                transmitted += 1;
+
+                // Waits for the reply to this sequence number discarding late ones.
+                loop {
+                    let timeout = next_at.saturating_duration_since(Instant::now());
+                    let entry = match icmp::receive_timeout(pcb_id, pcbs_arc.clone(), timeout) {
+                        Some(entry) => entry,
+                        None => break,
+                    };
+                    if entry.icmp_type == icmp::ICMP_TYPE_ECHOREPLY {
+                        let (_id, reply_seq) = icmp::echo_id_seq(entry.values);
+                        if reply_seq != seq {
+                            warn!("App: late reply of icmp_seq={reply_seq} discarded.");
+                            continue;
+                        }
+                        let rtt = entry
+                            .received_at
+                            .duration_since(sent_at)
+                            .unwrap_or_default();
+                        info!(
+                            "App: {} bytes from {}: icmp_seq={} time={:.3} ms",
+                            entry.data.len() + size_of::<icmp::ICMPHeader>(),
+                            ip_addr_to_str(entry.src),
+                            seq,
+                            rtt.as_secs_f64() * 1000.0
+                        );
+                        received += 1;
+                        break;
+                    }
+                    let quoted_seq = icmp::quoted_echo_id_seq(&entry.data).map(|(_, s)| s);
+                    info!(
+                        "App: from {}: icmp_seq={} {}",
+                        ip_addr_to_str(entry.src),
+                        quoted_seq.unwrap_or(seq),
+                        icmp::message_str(entry.icmp_type, entry.code)
+                    );
+                    if quoted_seq == Some(seq) {
+                        break;
+                    }
+                }
+                if seq < count {
+                    thread::sleep(next_at.saturating_duration_since(Instant::now()));
+                }
+            }
+            {
+                let pcbs = &mut pcbs_arc.lock().unwrap();
+                icmp::close(&mut pcbs.icmp_pcbs, pcb_id);
+            }
+            let loss = if transmitted > 0 {
+                (transmitted - received) * 100 / transmitted
+            } else {
+                0
+            };
+            info!("App: --- {target_ip} ping statistics ---");
+            info!(
+                "App: {transmitted} packets transmitted, {received} received, {loss}% packet loss"
+            );
+        })
+    }
+
+    /// Sends ARP requests at the ping interval reporting every reply, so that a host answering
+    /// from more than one MAC (address conflict) shows up.
+    fn arping_command(
+        &mut self,
+        target_ip: String,
+        count: u16,
+        receiver: mpsc::Receiver<()>,
+    ) -> JoinHandle<()> {
+        let devices_arc = self.devices.clone();
+        let contexts_arc = self.contexts.clone();
+        thread::spawn(move || {
+            let dst = ip_addr_to_bytes(&target_ip).expect("App: invalid target IP address.");
+            let replies = contexts_arc.lock().unwrap().arp_table.watch(dst);
+            info!("App: ARPING {target_ip}");
+            let mut transmitted = 0;
+            let mut received = 0;
+            for seq in 1..=count {
+                // Termination check
+                match receiver.try_recv() {
+                    Ok(_) | Err(TryRecvError::Disconnected) => {
+                        info!("App: thread terminating.");
+                        break;
+                    }
+                    Err(TryRecvError::Empty) => {}
+                }
+                let sent_at = SystemTime::now();
+                let next_at = Instant::now() + Duration::from_secs(PING_INTERVAL_SEC);
+                {
+                    let devices = &mut devices_arc.lock().unwrap();
+                    let contexts = &contexts_arc.lock().unwrap();
+                    let eth_device = devices.get_mut_by_dst(dst, &contexts.ip_routes).unwrap();
+                    if arp::arping(eth_device, dst).is_err() {
+                        error!(
+                            "App: {target_ip} is not on a subnet of {}.",
+                            eth_device.name
+                        );
+                        break;
+                    }
+                }
+                transmitted += 1;
+
+                // Replies till the next request, late ones included.
+                let mut answered = false;
+                loop {
+                    let timeout = next_at.saturating_duration_since(Instant::now());
+                    let reply = match replies.recv_timeout(timeout) {
+                        Ok(reply) => reply,
+                        Err(_) => break,
+                    };
+                    let latency = reply
+                        .received_at
+                        .duration_since(sent_at)
+                        .unwrap_or_default();
+                    info!(
+                        "App: reply from {} [{}]: index={} time={:.3} ms",
+                        target_ip,
+                        arp::hw_addr_to_str(&reply.hw_address),
+                        seq,
+                        latency.as_secs_f64() * 1000.0
+                    );
+                    if !answered {
+                        answered = true;
+                        received += 1;
+                    }
+                }
+                if !answered {
+                    info!("App: no reply of index={seq}");
+                }
+            }
+            contexts_arc.lock().unwrap().arp_table.unwatch(dst);
+            let loss = if transmitted > 0 {
+                (transmitted - received) * 100 / transmitted
+            } else {
+                0
+            };
+            info!("App: --- {target_ip} arping statistics ---");
+            info!(
+                "App: {transmitted} requests transmitted, {received} answered, {loss}% unanswered"
+            );
+        })
+    }
+
+    fn traceroute_command(
+        &mut self,
+        target_ip: String,
+        max_hops: u8,
+        receiver: mpsc::Receiver<()>,
+    ) -> JoinHandle<()> {
+        let pcbs_arc = self.pcbs.clone();
+        let devices_arc = self.devices.clone();
+        let contexts_arc = self.contexts.clone();
+        thread::spawn(move || {
+            let dst = ip_addr_to_bytes(&target_ip).expect("App: invalid target IP address.");
+            if contexts_arc
+                .lock()
+                .unwrap()
+                .ip_routes
+                .lookup_ip_route(dst)
+                .is_none()
+            {
+                error!("App: no route to {target_ip}.");
+                return;
+            }
+            // Source port in dynamic range identifies errors quoting the probes.
+            let src_port = 49152 + (process::id() % 16384) as u16;
+            let pcb_id = {
+                let pcbs = &mut pcbs_arc.lock().unwrap();
+                icmp::open(&mut pcbs.icmp_pcbs, icmp::ICMP_PCB_UDP_ERROR, src_port)
+            };
+            info!("App: traceroute to {target_ip}, {max_hops} hops max");
+            let mut probe_port = TRACEROUTE_BASE_PORT;
+            'hops: for ttl in 1..=max_hops {
+                let mut hop = None;
+                let mut rtts = vec![];
+                let mut reached = false;
+                for _ in 0..TRACEROUTE_PROBES {
+                    // Termination check
+                    match receiver.try_recv() {
+                        Ok(_) | Err(TryRecvError::Disconnected) => {
+                            info!("App: thread terminating.");
+                            break 'hops;
+                        }
+                        Err(TryRecvError::Empty) => {}
+                    }
+                    let sent_at = SystemTime::now();
+                    let deadline = Instant::now() + Duration::from_secs(TRACEROUTE_TIMEOUT_SEC);
+                    {
+                        let devices = &mut devices_arc.lock().unwrap();
+                        let contexts = &mut contexts_arc.lock().unwrap();
+                        let pcbs = &mut pcbs_arc.lock().unwrap();
+                        let eth_device = devices.get_mut_by_dst(dst, &contexts.ip_routes).unwrap();
+                        let src = contexts.ip_routes.get_interface(dst).unwrap().unicast;
+                        udp::output_with_ttl(
+                            IPEndpoint::new(src, src_port),
+                            IPEndpoint::new(dst, probe_port),
+                            vec![0; 32],
+                            ttl,
+                            eth_device,
+                            contexts,
+                            pcbs,
+                        );
+                    }
+
+                    // Waits for the error quoting this probe discarding late ones.
+                    let mut rtt = None;
+                    while let Some(entry) = icmp::receive_timeout(
+                        pcb_id,
+                        pcbs_arc.clone(),
+                        deadline.saturating_duration_since(Instant::now()),
+                    ) {
+                        let quoted_port = icmp::quoted_udp_ports(&entry.data).map(|(_, p)| p);
+                        if quoted_port != Some(probe_port) {
+                            continue;
+                        }
+                        hop = Some(entry.src);
+                        rtt = entry.received_at.duration_since(sent_at).ok();
+                        if entry.icmp_type == icmp::ICMP_TYPE_DEST_UNREACH {
+                            reached = true;
+                            if entry.src != dst {
+                                warn!(
+                                    "App: {} from {}",
+                                    icmp::message_str(entry.icmp_type, entry.code),
+                                    ip_addr_to_str(entry.src)
+                                );
+                            }
+                        }
+                        break;
+                    }
+                    rtts.push(match rtt {
+                        Some(rtt) => format!("{:.3} ms", rtt.as_secs_f64() * 1000.0),
+                        None => "*".to_string(),
+                    });
+                    probe_port = probe_port.wrapping_add(1);
+                }
+                let hop_str = hop.map_or("*".to_string(), ip_addr_to_str);
+                info!("App: {:>2}  {}  {}", ttl, hop_str, rtts.join("  "));
+                if reached {
+                    break;
+                }
+            }
+            let pcbs = &mut pcbs_arc.lock().unwrap();
+            icmp::close(&mut pcbs.icmp_pcbs, pcb_id);
+        })
+    }
+
+    fn udp_receive_command(
+        &self,
+        local: IPEndpoint,
+        sink: Arc<Mutex<DataSink>>,
+        receiver: mpsc::Receiver<()>,
+    ) -> JoinHandle<()> {
         let pcbs_arc = self.pcbs.clone();
         let mut soc_opt = None;
         thread::spawn(move || loop {
@@ -369,20 +1572,237 @@ impl NetApp {
                 soc_opt = {
                     let pcbs = &mut pcbs_arc.lock().unwrap();
                     let soc = udp::open(&mut pcbs.udp_pcbs);
-                    let local = IPEndpoint::new_from_str("0.0.0.0", 7);
+                    let local = IPEndpoint {
+                        address: local.address,
+                        port: local.port,
+                    };
                     udp::bind(&mut pcbs.udp_pcbs, soc, local);
                     Some(soc)
                 }
             }
-            info!("App: starting UDP receive...");
+            info!("App: starting UDP receive on {local}...");
             let receive_res = udp::receive_from(soc_opt.unwrap(), pcbs_arc.clone());
             if let Some(entry) = receive_res {
-                log_data(&entry.data[..]);
+                info!(
+                    "App: received on UDP {local} from {}",
+                    entry.remote_endpoint
+                );
+                sink.lock().unwrap().write(&entry.data[..]);
+            } else if udp_killed(soc_opt.unwrap(), &pcbs_arc) {
+                return;
             }
         })
     }
 }
 
+/// Local endpoints of a receive command: the one of the arguments, then each `--listen`.
+fn receive_endpoints(local_ip: &str, local_port: u16, listen: &[String]) -> Vec<IPEndpoint> {
+    let Some(address) = ip_addr_to_bytes(local_ip) else {
+        error!("App: invalid local IP address: {local_ip}");
+        process::exit(1);
+    };
+    let mut locals = vec![IPEndpoint::new(address, local_port)];
+    for endpoint in listen {
+        let Some(local) = endpoint_from_str(endpoint) else {
+            error!("App: invalid endpoint to listen on: {endpoint}");
+            process::exit(1);
+        };
+        if locals
+            .iter()
+            .any(|l| l.address == local.address && l.port == local.port)
+        {
+            error!("App: listening on {local} more than once.");
+            process::exit(1);
+        }
+        locals.push(local);
+    }
+    locals
+}
+
+fn tcp_killed(pcb_id: usize, pcbs_arc: &Arc<Mutex<ControlBlocks>>) -> bool {
+    let pcbs = &mut pcbs_arc.lock().unwrap();
+    if tcp::socket_error(pcb_id, pcbs) == Some(SocketError::AdministrativelyClosed) {
+        info!("App: TCP connection was administratively closed.");
+        return true;
+    }
+    false
+}
+
+fn udp_killed(pcb_id: usize, pcbs_arc: &Arc<Mutex<ControlBlocks>>) -> bool {
+    let pcbs = &pcbs_arc.lock().unwrap();
+    if udp::socket_error(&pcbs.udp_pcbs, pcb_id) == Some(SocketError::AdministrativelyClosed) {
+        info!("App: UDP PCB was administratively closed.");
+        return true;
+    }
+    false
+}
+
+fn exit_on_config_errors(errors: Vec<ConfigError>) -> NetApp {
+    for err in errors {
+        error!("App: invalid configuration: {err}");
+    }
+    process::exit(1);
+}
+
+/// Registers signals of the stack once till a start takes them. Needs to precede device setup
+/// as IRQs are raised once devices are open.
+fn register_signals() -> io::Result<()> {
+    let mut registered = SIGNALS.lock().unwrap();
+    if registered.is_none() {
+        let mut sigs = vec![SIGHUP, SIGUSR1, IRQ_LOOPBACK];
+        sigs.extend((0..ETH_DEVICES_MAX).map(ethernet::irq));
+        sigs.extend(TERM_SIGNALS);
+        *registered = Some(SignalsInfo::<WithOrigin>::new(&sigs)?);
+    }
+    Ok(())
+}
+
+/// Input queues of ARP, IP and IPv6 handled on protocol signals.
+fn net_protocols() -> NetProtocols {
+    let mut protocols = NetProtocols::new();
+    protocols.register(NetProtocol::new(ProtocolType::Arp));
+    protocols.register(NetProtocol::new(ProtocolType::IP));
+    protocols.register(NetProtocol::new(ProtocolType::IPV6));
+    protocols
+}
+
+/// Sends a connection command to a running stack over the control socket instead of
+/// starting a new stack. Returns None for the other commands.
+pub fn run_control_client() -> Option<io::Result<()>> {
+    let args = Cli::parse();
+    let path = args.control_socket;
+    let request = match args.command {
+        Commands::Conn(conn) => match conn.command.unwrap() {
+            ConnCommand::Show { netstat, watch } => {
+                let request = if netstat {
+                    "conn show netstat\n"
+                } else {
+                    "conn show\n"
+                };
+                if let Some(secs) = watch {
+                    return Some(watch_control_request(&path, request, secs));
+                }
+                String::from(request)
+            }
+            ConnCommand::Stats {
+                pcb_id: Some(pcb_id),
+            } => format!("tcp stats {pcb_id}\n"),
+            ConnCommand::Stats { pcb_id: None } => String::from("tcp stats\n"),
+            ConnCommand::Kill { pcb_id, udp } => {
+                let protocol = if udp { "udp" } else { "tcp" };
+                format!("conn kill {protocol} {pcb_id}\n")
+            }
+        },
+        Commands::Stats => String::from("stats\n"),
+        Commands::Arp(arp) => match arp.command.unwrap() {
+            ArpCommand::Show => String::from("arp show\n"),
+            ArpCommand::Flush { ip: Some(ip) } => format!("arp flush {ip}\n"),
+            ArpCommand::Flush { ip: None } => String::from("arp flush\n"),
+        },
+        Commands::Route(route) => match route.command.unwrap() {
+            RouteCommand::Show => String::from("route show\n"),
+            RouteCommand::Add {
+                network,
+                gateway,
+                metric,
+            } => format!("route add {network} {gateway} {metric}\n"),
+            RouteCommand::Del { network, gateway } => {
+                format!("route del {network} {}\n", gateway.unwrap_or_default())
+            }
+        },
+        _ => return None,
+    };
+    Some(send_control_request(&path, &request))
+}
+
+/// Handles a `route add` or `route del` control request.
+fn route_request(args: &[&str], devices: &NetDevices, routes: &mut IPRoutes) -> Result<(), ()> {
+    let network: IpCidr = args.get(1).ok_or(())?.parse().map_err(|_| ())?;
+    let gateway = args.get(2).map(|addr| ip_addr_to_bytes(addr).ok_or(()));
+    let result = match (args, gateway.transpose()?) {
+        (["add", _, _, metric], Some(gateway)) => match metric.parse() {
+            Ok(metric) => routes.add(network, gateway, metric, devices),
+            Err(_) => return Err(()),
+        },
+        (["del", _], None) => routes.delete(network, None),
+        (["del", _, _], Some(gateway)) => routes.delete(network, Some(gateway)),
+        _ => return Err(()),
+    };
+    result.map_err(|err| warn!("App: route {} failed: {err}", args.join(" ")))
+}
+
+/// Sends a request repeatedly at an interval clearing the screen till interrupted.
+fn watch_control_request(path: &str, request: &str, secs: u64) -> io::Result<()> {
+    loop {
+        print!("\x1b[2J\x1b[H");
+        send_control_request(path, request)?;
+        thread::sleep(Duration::from_secs(secs));
+    }
+}
+
+fn send_control_request(path: &str, request: &str) -> io::Result<()> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.write_all(request.as_bytes())?;
+    let mut reply = String::new();
+    let mut reader = BufReader::new(&stream);
+    loop {
+        reply.clear();
+        if reader.read_line(&mut reply)? == 0 || ["ok", "error"].contains(&reply.trim_end()) {
+            break;
+        }
+        info!("{}", reply.trim_end());
+    }
+    if reply.trim() == "ok" {
+        info!("App: {} done.", request.trim());
+    } else {
+        error!("App: {} failed.", request.trim());
+    }
+    Ok(())
+}
+
+/// Data of send commands: text with escaped CR/LF, or contents of a file when prefixed with `@`.
+fn request_data(data: &str) -> Vec<u8> {
+    if let Some(path) = data.strip_prefix('@') {
+        return fs::read(path).unwrap_or_else(|e| {
+            error!("App: failed to read data file {path}: {e}");
+            process::exit(1);
+        });
+    }
+    data.replace("\\r", "\r")
+        .replace("\\n", "\n")
+        .as_bytes()
+        .to_vec()
+}
+
+/// Destination of received data: a file specified with `--output`, or the log.
+struct DataSink {
+    file: Option<File>,
+}
+
+impl DataSink {
+    fn new(path: Option<String>) -> DataSink {
+        let file = path.map(|path| {
+            File::create(&path).unwrap_or_else(|e| {
+                error!("App: failed to create output file {path}: {e}");
+                process::exit(1);
+            })
+        });
+        DataSink { file }
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        match self.file.as_mut() {
+            Some(file) => {
+                if let Err(e) = file.write_all(data) {
+                    error!("App: failed to write output file: {e}");
+                    process::exit(1);
+                }
+            }
+            None => log_data(data),
+        }
+    }
+}
+
 fn log_data(data: &[u8]) {
     let received_utf8 = str::from_utf8(data);
     if let Ok(utf8_str) = received_utf8 {
@@ -391,25 +1811,6 @@ fn log_data(data: &[u8]) {
         warn!("App: UTF8 error. Data is {:02x?}", data);
     }
 }
-// TEST: ICMP output
-
-// let pid = process::id() % u16::MAX as u32;
-// let values = le_to_be_u32(pid << 16 | 1);
-// let icmp_type_echo: u8 = 8;
-// let ip_any = 0;
-// let dst = ip_addr_to_bytes("8.8.8.8").unwrap();
-// icmp::output(
-//     icmp_type_echo,
-//     0,
-//     values,
-//     data,
-//     data_len,
-//     ip_any,
-//     dst,
-//     eth_device,
-//     contexts,
-//     pcbs,
-// );
 
 // CLI setup
 
@@ -419,12 +1820,200 @@ fn log_data(data: &[u8]) {
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    #[arg(
+        long,
+        global = true,
+        conflicts_with = "config",
+        help = "Configures TAP interface address, netmask and gateway with DHCP."
+    )]
+    dhcp: bool,
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        help = "Sets up devices, interfaces, routes and static ARP entries of a TOML file (see rust-user-net.toml)."
+    )]
+    config: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        value_name = "NAME",
+        help = "Name of the first TAP device, tap0 by default."
+    )]
+    tap_name: Option<String>,
+    #[arg(
+        long = "ip",
+        global = true,
+        value_name = "ADDRESS",
+        conflicts_with = "dhcp",
+        help = "Address of the first TAP device, replacing its first interface."
+    )]
+    ip_address: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        value_name = "NETMASK",
+        conflicts_with = "dhcp",
+        help = "Netmask of the address of the first TAP device, given with --ip or the one of the interface replaced."
+    )]
+    netmask: Option<String>,
+    #[arg(
+        long = "gateway",
+        global = true,
+        value_name = "ADDRESS",
+        conflicts_with = "dhcp",
+        help = "Default gateway, on the subnet of an interface."
+    )]
+    default_gateway: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        value_name = "BYTES",
+        help = "MTU of the first TAP device, 576 to 9000 (jumbo frames). Set the same MTU on the TAP interface."
+    )]
+    mtu: Option<usize>,
+    #[arg(long, global = true, default_value_t = CongestionAlgorithm::Reno, help = "TCP congestion control algorithm: reno, cubic or none (peer window only).")]
+    congestion: CongestionAlgorithm,
+    #[arg(
+        long,
+        global = true,
+        help = "Send buffer size of each TCP connection in bytes."
+    )]
+    tcp_sndbuf: Option<usize>,
+    #[arg(
+        long,
+        global = true,
+        help = "Receive buffer size of each TCP connection in bytes (65535 at most)."
+    )]
+    tcp_rcvbuf: Option<usize>,
+    #[arg(
+        long,
+        global = true,
+        value_name = "BYTES",
+        help = "Limit of bytes buffered by sockets of all protocols, beyond which TCP connections and data and UDP datagrams are refused and sends block."
+    )]
+    max_memory: Option<usize>,
+    #[arg(
+        long,
+        global = true,
+        value_name = "SECS",
+        help = "Limit of TCP retransmission interval doubled on each timeout."
+    )]
+    tcp_rto_max: Option<u64>,
+    #[arg(
+        long,
+        global = true,
+        help = "SYN retransmissions before connecting times out."
+    )]
+    tcp_syn_retries: Option<u8>,
+    #[arg(
+        long,
+        global = true,
+        value_name = "MILLIS",
+        help = "Initial SYN retransmission interval, doubled on each timeout up to --tcp-rto-max."
+    )]
+    tcp_syn_rto: Option<u64>,
+    #[arg(long, global = true, default_value_t = AckPolicy::EveryOther, help = "When TCP data received is acknowledged: every (segment), every-other (or after 100-200ms) or delayed (after 100-200ms only).")]
+    tcp_ack_policy: AckPolicy,
+    #[arg(
+        long,
+        global = true,
+        help = "Connections half-open or waiting for accept per TCP listener, beyond which SYNs are dropped. 8 by default."
+    )]
+    tcp_backlog: Option<usize>,
+    #[arg(
+        long,
+        global = true,
+        help = "Answers SYNs of TCP listeners with SYN cookies, allocating connections only for completed handshakes."
+    )]
+    tcp_syn_cookies: bool,
+    #[arg(
+        long,
+        global = true,
+        help = "Challenge ACKs sent per second by all TCP connections for RSTs and SYNs that may be spoofed (RFC 5961). 1000 by default."
+    )]
+    tcp_challenge_ack_limit: Option<u32>,
+    #[arg(
+        long,
+        global = true,
+        help = "Answers ARP requests on Ethernet for addresses routed via other interfaces."
+    )]
+    proxy_arp: bool,
+    #[arg(
+        long,
+        global = true,
+        help = "Forwards datagrams addressed to other hosts out of devices of their routes with TTL decremented."
+    )]
+    forward: bool,
+    #[arg(
+        long,
+        global = true,
+        value_name = "DEVICE",
+        requires = "forward",
+        help = "Masquerades TCP and UDP flows forwarded out of DEVICE (e.g. tap0) as its address, dropping other protocols out of it."
+    )]
+    nat: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        value_name = "SECS",
+        help = "Probes gateways with unicast ARP every SECS seconds, marking their routes down after 3 missed probes till they answer again."
+    )]
+    gateway_probe: Option<u64>,
+    #[arg(
+        long,
+        global = true,
+        help = "Drops frames transmitted with a source MAC/IP not configured on the device."
+    )]
+    egress_check: bool,
+    #[arg(
+        long = "arp",
+        global = true,
+        value_name = "IP=MAC",
+        help = "Adds a static ARP entry. Can be repeated."
+    )]
+    static_arp: Vec<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "Adds static ARP entries from a file of `<IP> <MAC>` lines."
+    )]
+    arp_file: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "Resolves next hops on Ethernet only with static ARP entries, failing right away for others instead of sending ARP requests."
+    )]
+    static_neighbors: bool,
+    #[arg(
+        long,
+        global = true,
+        value_name = "NAME=CIDR",
+        help = "Adds a TAP device of a name with an interface, e.g. tap1=198.51.100.2/24. Can be repeated."
+    )]
+    tap: Vec<String>,
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        default_value = CONTROL_SOCKET_PATH,
+        help = "Path of the control socket a stack serves and administrative commands are sent to, e.g. to run stacks on different TAP devices."
+    )]
+    control_socket: String,
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
     Tcp(Tcp),
     Udp(Udp),
+    Ping(Ping),
+    Arping(Arping),
+    Traceroute(Traceroute),
+    Conn(Conn),
+    #[command(about = "Prints packet, byte, drop and error counters, frame size histograms and protocol mix of each device of a running stack.", long_about = None)]
+    Stats,
+    Arp(Arp),
+    Route(Route),
 }
 
 #[derive(Debug, Args)]
@@ -443,17 +2032,244 @@ struct Udp {
     command: Option<EndPointCommand>,
 }
 
+#[derive(Debug, Args)]
+#[command(about = "Sends ICMP echo requests printing RTT of each reply and a loss summary.", long_about = None)]
+struct Ping {
+    target_ip: String,
+    #[arg(
+        short,
+        long,
+        default_value_t = 4,
+        help = "Number of echo requests to send."
+    )]
+    count: u16,
+}
+
+#[derive(Debug, Args)]
+#[command(about = "Sends ARP requests to a host on the TAP subnet printing the MAC and latency of each reply.", long_about = None)]
+struct Arping {
+    target_ip: String,
+    #[arg(short, long, default_value_t = 4, help = "Number of requests to send.")]
+    count: u16,
+}
+
+#[derive(Debug, Args)]
+#[command(about = "Prints hops to a host sending UDP probes with increasing TTL.", long_about = None)]
+struct Traceroute {
+    target_ip: String,
+    #[arg(short, long, default_value_t = 30, help = "Max TTL of probes.")]
+    max_hops: u8,
+}
+
+#[derive(Debug, Args)]
+#[command(args_conflicts_with_subcommands = true)]
+#[command(about = "Manages connections of a running stack. `rust-user-net conn -h` for more details.", long_about = None)]
+struct Conn {
+    #[command(subcommand)]
+    command: Option<ConnCommand>,
+}
+
+#[derive(Debug, Subcommand)]
+enum ConnCommand {
+    #[command(about = "Lists TCP connections with out-of-order segment statistics.", long_about = None)]
+    Show {
+        #[arg(
+            long,
+            help = "Lists TCP connections and UDP PCBs in `proto local foreign state` columns."
+        )]
+        netstat: bool,
+        #[arg(
+            long,
+            value_name = "SECS",
+            help = "Refreshes the list every SECS seconds."
+        )]
+        watch: Option<u64>,
+    },
+    #[command(about = "Prints segments, bytes, retransmits, duplicate ACKs, state transitions and RTT of TCP connections.", long_about = None)]
+    Stats { pcb_id: Option<usize> },
+    #[command(about = "Aborts a TCP connection with RST, or closes a UDP PCB, by PCB id.", long_about = None)]
+    Kill {
+        pcb_id: usize,
+        #[arg(long, help = "Closes a UDP PCB instead of a TCP connection.")]
+        udp: bool,
+    },
+}
+
+#[derive(Debug, Args)]
+#[command(args_conflicts_with_subcommands = true)]
+#[command(about = "Inspects the ARP table of a running stack. `rust-user-net arp -h` for more details.", long_about = None)]
+struct Arp {
+    #[command(subcommand)]
+    command: Option<ArpCommand>,
+}
+
+#[derive(Debug, Subcommand)]
+enum ArpCommand {
+    #[command(about = "Prints IP, HW address, state and age of each entry.", long_about = None)]
+    Show,
+    #[command(about = "Removes an entry of an IP, or all entries but static ones.", long_about = None)]
+    Flush { ip: Option<String> },
+}
+
+#[derive(Debug, Args)]
+#[command(args_conflicts_with_subcommands = true)]
+#[command(about = "Inspects and changes routes of a running stack. `rust-user-net route -h` for more details.", long_about = None)]
+struct Route {
+    #[command(subcommand)]
+    command: Option<RouteCommand>,
+}
+
+#[derive(Debug, Subcommand)]
+enum RouteCommand {
+    #[command(about = "Prints network, gateway, source interface and metric of each route.", long_about = None)]
+    Show,
+    #[command(about = "Adds a route to a network via a gateway on the subnet of an interface.", long_about = None)]
+    Add {
+        #[arg(help = "Network in CIDR notation, e.g. 198.51.100.0/24.")]
+        network: IpCidr,
+        gateway: String,
+        #[arg(
+            long,
+            default_value_t = 0,
+            help = "Lower wins among routes of the same prefix length."
+        )]
+        metric: u32,
+    },
+    #[command(about = "Deletes routes to a network, only the one via a gateway if given.", long_about = None)]
+    Del {
+        #[arg(help = "Network in CIDR notation, e.g. 198.51.100.0/24.")]
+        network: IpCidr,
+        gateway: Option<String>,
+    },
+}
+
 #[derive(Debug, Subcommand)]
 enum EndPointCommand {
     #[command(about = "Sends a request with data and starts a receive loop printing each segment received. Ctrl+C to end.", long_about = None)]
     Send {
         target_ip: String,
         target_port: u16,
+        #[arg(help = "Data to send. `@<path>` sends contents of a file.")]
         data: String,
+        #[arg(long, help = "Writes received data to a file instead of printing it.")]
+        output: Option<String>,
     },
     #[command(about = "Starts a receive loop printing out each segment received. Ctrl+C to end.", long_about = None)]
     Receive {
         local_ip: String,
-        local_port: String,
+        local_port: u16,
+        #[arg(
+            long,
+            value_name = "IP:PORT",
+            help = "Receives on another local endpoint too. Can be repeated."
+        )]
+        listen: Vec<String>,
+        #[arg(long, help = "Writes received data to a file instead of printing it.")]
+        output: Option<String>,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Cli, NetApp};
+    use crate::devices::{ethernet, ConfigError};
+    use crate::drivers::DriverType;
+    use crate::protocols::ip::ip_addr_to_bytes;
+    use clap::Parser;
+    use std::{env, io, process, sync::mpsc};
+
+    #[test]
+    fn test_start_stop() {
+        // Input of a pair device is polled, so that no process-wide signal handler is touched.
+        let (device, _) = ethernet::init_pair(0);
+        let app = NetApp::with_device(device, "192.0.2.2/24");
+
+        // Restartable within a process and a second start is no-op.
+        for _ in 0..2 {
+            app.start().unwrap();
+            app.start().unwrap();
+            let (sender, receiver) = mpsc::channel();
+            app.notify_on_stop(sender);
+            app.clone().stop();
+            assert!(app.runtime.lock().unwrap().is_none());
+            assert!(receiver.try_recv().is_ok());
+        }
+        app.join();
+    }
+
+    #[test]
+    fn test_control_socket() {
+        let (device, _) = ethernet::init_pair(0);
+        let mut app = NetApp::with_device(device, "192.0.2.2/24");
+        app.set_control_socket("/nonexistent/rust-user-net.sock");
+        assert!(app.control_thread(mpsc::channel().1).is_err());
+
+        // Not taken over from a stack serving it, and removed when stopped
+        let path = env::temp_dir().join(format!("rust-user-net-{}.sock", process::id()));
+        app.set_control_socket(path.to_str().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        let join = app.control_thread(receiver).unwrap();
+        let error = app.control_thread(mpsc::channel().1).err().unwrap();
+        assert_eq!(io::ErrorKind::AddrInUse, error.kind());
+        sender.send(()).unwrap();
+        join.join().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_add_device() {
+        let (device, _) = ethernet::init_pair(0);
+        let app = NetApp::with_device(device, "192.0.2.2/24");
+        let tap = |name: &str| {
+            let mut device = ethernet::init(1, DriverType::Memory);
+            device.name = name.to_string();
+            device
+        };
+        let cidr = |cidr: &str| cidr.parse().unwrap();
+        app.add_device(tap("tap1"), cidr("198.51.100.2/24"))
+            .unwrap();
+
+        let errors = app
+            .add_device(tap("tap1"), cidr("203.0.113.2/24"))
+            .unwrap_err();
+        assert_eq!(
+            vec![ConfigError::DuplicateDeviceName("tap1".to_string())],
+            errors
+        );
+        let errors = app
+            .add_device(tap("tap2"), cidr("192.0.2.3/25"))
+            .unwrap_err();
+        assert!(matches!(
+            errors[..],
+            [ConfigError::OverlappingSubnets { .. }]
+        ));
+        assert!(app.add_tap("tap2").is_err());
+
+        let devices = &mut app.devices.lock().unwrap();
+        assert_eq!(2, devices.entries.iter().count());
+        assert!(devices.get_by_name("tap1").unwrap().state.up);
+        let contexts = &app.contexts.lock().unwrap();
+        let dst = ip_addr_to_bytes("198.51.100.7").unwrap();
+        assert_eq!(Some(1), devices.index_by_dst(dst, &contexts.ip_routes));
+    }
+
+    #[test]
+    fn test_dhcp_conflicts() {
+        // Addresses of the first TAP device are rejected with DHCP, before or after the subcommand.
+        for flag in [
+            "--ip=192.0.2.2",
+            "--netmask=255.255.255.0",
+            "--gateway=192.0.2.1",
+        ] {
+            let error =
+                Cli::try_parse_from(["rust-user-net", "--dhcp", flag, "stats"]).unwrap_err();
+            assert_eq!(clap::error::ErrorKind::ArgumentConflict, error.kind());
+            let error =
+                Cli::try_parse_from(["rust-user-net", "stats", flag, "--dhcp"]).unwrap_err();
+            assert_eq!(clap::error::ErrorKind::ArgumentConflict, error.kind());
+        }
+        assert!(
+            Cli::try_parse_from(["rust-user-net", "--dhcp", "--tap-name=tap1", "stats"]).is_ok()
+        );
+    }
+}
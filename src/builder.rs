@@ -0,0 +1,304 @@
+use crate::{
+    app::NetApp,
+    devices::{
+        ethernet::{self, ETH_ADDR_LEN, ETH_DEVICES_MAX},
+        loopback::{self, LOOPBACK_CIDR},
+        ConfigError, NetDevice, NetDeviceType, NetDevices,
+    },
+    drivers::DriverType,
+    protocols::{
+        arp,
+        ip::{
+            cidr::IpCidr, congestion::CongestionAlgorithm, ip_addr_to_bytes, IPAdress, IPInterface,
+            IPRoute, IPRoutes,
+        },
+        ipv6::IPV6Interface,
+        ControlBlocks, ProtocolContexts,
+    },
+};
+use std::sync::Arc;
+
+/// Sets up a stack from code: devices in the order added, each with the interfaces added after
+/// it and routes to their subnets, routes via gateways and protocol options. `build` checks the
+/// configuration as a whole (see `NetDevices::validate`) before opening devices, and returns
+/// every error found instead of a stack.
+///
+/// ```no_run
+/// use rust_user_net::{builder::StackBuilder, devices::ethernet, drivers::DriverType};
+///
+/// let app = StackBuilder::new()
+///     .loopback()
+///     .device(ethernet::init(1, DriverType::Tap))
+///     .interface("192.0.2.2/24")
+///     .gateway("192.0.2.1")
+///     .build()
+///     .unwrap();
+/// app.start().unwrap();
+/// ```
+pub struct StackBuilder {
+    devices: Vec<(NetDevice, Vec<Arc<IPInterface>>)>,
+    gateway: Option<IPAdress>,
+    routes: Vec<(IpCidr, IPAdress, u32)>,
+    neighbors: Vec<(IPAdress, [u8; ETH_ADDR_LEN])>,
+    static_neighbors: bool,
+    forwarding: bool,
+    congestion: CongestionAlgorithm,
+    errors: Vec<ConfigError>,
+}
+
+impl StackBuilder {
+    pub fn new() -> StackBuilder {
+        StackBuilder {
+            devices: vec![],
+            gateway: None,
+            routes: vec![],
+            neighbors: vec![],
+            static_neighbors: false,
+            forwarding: false,
+            congestion: CongestionAlgorithm::Reno,
+            errors: vec![],
+        }
+    }
+
+    /// Adds a loopback device with an interface of 127.0.0.1.
+    pub fn loopback(self) -> StackBuilder {
+        let index = self.devices.len() as u8;
+        self.device(loopback::init(index)).interface(LOOPBACK_CIDR)
+    }
+
+    /// Adds a device not opened yet, e.g. of `ethernet::init` or `ethernet::init_pair`.
+    pub fn device(mut self, device: NetDevice) -> StackBuilder {
+        self.devices.push((device, vec![]));
+        self
+    }
+
+    /// Adds an Ethernet device of a name and a driver, e.g. a TAP device of the interface name.
+    pub fn ethernet(mut self, name: &str, driver_type: DriverType) -> StackBuilder {
+        let index = self.devices.len();
+        if index >= ETH_DEVICES_MAX as usize {
+            self.errors.push(ConfigError::TooManyDevices);
+            return self;
+        }
+        let mut device = ethernet::init(index as u8, driver_type);
+        device.name = name.to_string();
+        self.device(device)
+    }
+
+    /// Sets the MTU of the device added last, e.g. 9000 for jumbo frames.
+    pub fn mtu(mut self, mtu: usize) -> StackBuilder {
+        match self.devices.last_mut() {
+            Some((device, _)) => device.mtu = mtu,
+            None => self.errors.push(ConfigError::MtuWithoutDevice),
+        }
+        self
+    }
+
+    /// Adds an interface of an address and its subnet, e.g. "192.0.2.2/24", to the device added
+    /// last, routed to directly.
+    pub fn interface(mut self, cidr: &str) -> StackBuilder {
+        let Ok(cidr) = cidr.parse() else {
+            self.errors.push(ConfigError::InvalidCidr(cidr.to_string()));
+            return self;
+        };
+        match self.devices.last_mut() {
+            Some((_, interfaces)) => interfaces.push(Arc::new(IPInterface::from_cidr(cidr))),
+            None => self.errors.push(ConfigError::InterfaceWithoutDevice),
+        }
+        self
+    }
+
+    /// Routes datagrams to other networks via a gateway on the subnet of an interface.
+    pub fn gateway(mut self, gateway: &str) -> StackBuilder {
+        self.gateway = self.address(gateway);
+        self
+    }
+
+    /// Routes datagrams to a network, e.g. "198.51.100.0/24", via a gateway on the subnet of an
+    /// interface, taking precedence over routes of the same prefix length with higher metrics.
+    pub fn route(mut self, destination: &str, gateway: &str, metric: u32) -> StackBuilder {
+        let gateway = self.address(gateway);
+        let Ok(destination) = destination.parse() else {
+            self.errors
+                .push(ConfigError::InvalidCidr(destination.to_string()));
+            return self;
+        };
+        if let Some(gateway) = gateway {
+            self.routes.push((destination, gateway, metric));
+        }
+        self
+    }
+
+    /// Adds a static ARP entry of an IP address and a HW address, e.g. "02:00:00:00:00:01".
+    pub fn neighbor(mut self, ip: &str, hw_addr: &str) -> StackBuilder {
+        let ip = self.address(ip);
+        let Some(hw) = arp::hw_addr_from_str(hw_addr) else {
+            self.errors
+                .push(ConfigError::InvalidHwAddress(hw_addr.to_string()));
+            return self;
+        };
+        if let Some(ip) = ip {
+            self.neighbors.push((ip, hw));
+        }
+        self
+    }
+
+    /// Resolves next hops on Ethernet devices with static entries only (see
+    /// `NetApp::set_static_neighbors`).
+    pub fn static_neighbors(mut self, enabled: bool) -> StackBuilder {
+        self.static_neighbors = enabled;
+        self
+    }
+
+    /// Forwards datagrams addressed to other hosts (see `NetApp::set_forwarding`).
+    pub fn forwarding(mut self, enabled: bool) -> StackBuilder {
+        self.forwarding = enabled;
+        self
+    }
+
+    pub fn congestion(mut self, algorithm: CongestionAlgorithm) -> StackBuilder {
+        self.congestion = algorithm;
+        self
+    }
+
+    /// Registers and opens devices, and sets up routes, protocols and control blocks into a
+    /// stack to start. Fails with errors of the configuration, or of devices failing to open.
+    pub fn build(self) -> Result<NetApp, Vec<ConfigError>> {
+        let mut errors = self.errors;
+        let mut devices = NetDevices::new();
+        let mut ip_routes = IPRoutes::new();
+        let mut gateway_interface = None;
+        for (mut device, interfaces) in self.devices {
+            if device.device_type == NetDeviceType::Ethernet {
+                device.static_neighbors = self.static_neighbors;
+            }
+            for interface in interfaces {
+                let on_subnet = |gateway: IPAdress| interface.cidr().contains(gateway);
+                if gateway_interface.is_none() && self.gateway.is_some_and(on_subnet) {
+                    gateway_interface = Some(interface.clone());
+                }
+                device.register_interface(interface.clone());
+                ip_routes.register(IPRoute::interface_route(interface));
+            }
+            devices.register(device);
+        }
+        let is_ethernet = |device: &NetDevice| device.device_type == NetDeviceType::Ethernet;
+        if !devices.entries.iter().any(is_ethernet) {
+            errors.push(ConfigError::NoEthernetDevice);
+        }
+        let mut gateways: Vec<IPAdress> = self.gateway.into_iter().collect();
+        gateways.extend(self.routes.iter().map(|(_, gateway, _)| *gateway));
+        if let Err(config_errors) = devices.validate(&gateways) {
+            errors.extend(config_errors);
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        for (destination, gateway, metric) in self.routes {
+            if let Err(error) = ip_routes.add(destination, gateway, metric, &devices) {
+                errors.push(ConfigError::InvalidRoute { destination, error });
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        for device in devices.entries.iter_mut() {
+            if device.open().is_err() {
+                errors.push(ConfigError::DeviceOpenFailed(device.name.clone()));
+                continue;
+            }
+            // IPv6 link-local interface of the HW address known once open
+            if let Some(hw_addr) = device.address.ethernet() {
+                device.register_ipv6_interface(Arc::new(IPV6Interface::link_local(hw_addr)));
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        if let (Some(gateway), Some(interface)) = (self.gateway, gateway_interface) {
+            ip_routes.register(IPRoute::gateway_route_from_addr(gateway, interface));
+        }
+        let mut contexts = ProtocolContexts::new(ip_routes);
+        contexts.ip_forwarding = self.forwarding;
+        for (ip, hw_addr) in self.neighbors {
+            contexts.arp_table.add_static(ip, hw_addr);
+        }
+        let mut pcbs = ControlBlocks::new();
+        pcbs.tcp_pcbs.congestion_algorithm = self.congestion;
+        Ok(NetApp::from_parts(devices, contexts, pcbs))
+    }
+
+    fn address(&mut self, addr: &str) -> Option<IPAdress> {
+        let parsed = ip_addr_to_bytes(addr);
+        if parsed.is_none() {
+            self.errors
+                .push(ConfigError::InvalidAddress(addr.to_string()));
+        }
+        parsed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StackBuilder;
+    use crate::{
+        devices::{ethernet, ConfigError},
+        protocols::ip::ip_addr_to_bytes,
+    };
+
+    #[test]
+    fn test_build() {
+        let (device, _) = ethernet::init_pair(1);
+        let app = StackBuilder::new()
+            .device(device)
+            .interface("192.0.2.2/24")
+            .gateway("192.0.2.1")
+            .neighbor("192.0.2.1", "02:00:00:00:00:01")
+            .static_neighbors(true)
+            .forwarding(true)
+            .build()
+            .unwrap();
+        let mut contexts = app.contexts.lock().unwrap();
+        assert!(contexts.ip_forwarding);
+        let gateway = ip_addr_to_bytes("192.0.2.1").unwrap();
+        assert_eq!(
+            Some([0x02, 0, 0, 0, 0, 0x01]),
+            contexts.arp_table.get(gateway)
+        );
+        let dst = ip_addr_to_bytes("198.51.100.1").unwrap();
+        let route = contexts.ip_routes.lookup_ip_route(dst).unwrap();
+        assert_eq!(ip_addr_to_bytes("192.0.2.2"), Some(route.interface.unicast));
+        let devices = app.devices.lock().unwrap();
+        assert!(devices
+            .entries
+            .iter()
+            .all(|device| device.state.up && device.static_neighbors));
+    }
+
+    #[test]
+    fn test_build_errors() {
+        let (device, _) = ethernet::init_pair(1);
+        let errors = StackBuilder::new()
+            .interface("192.0.2.2/24")
+            .device(device)
+            .interface("192.0.2.256/24")
+            .interface("192.0.2.2/24")
+            .gateway("198.51.100.1")
+            .neighbor("192.0.2.1", "02:00:00:00:00")
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(
+            vec![
+                ConfigError::InterfaceWithoutDevice,
+                ConfigError::InvalidCidr("192.0.2.256/24".to_string()),
+                ConfigError::InvalidHwAddress("02:00:00:00:00".to_string()),
+                ConfigError::GatewayNotConnected(ip_addr_to_bytes("198.51.100.1").unwrap()),
+            ],
+            errors
+        );
+
+        let errors = StackBuilder::new().loopback().build().err().unwrap();
+        assert_eq!(vec![ConfigError::NoEthernetDevice], errors);
+    }
+}
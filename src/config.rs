@@ -0,0 +1,380 @@
+//! Stack configuration of a TOML file: devices with their drivers and interfaces, routes and
+//! static ARP entries (see `rust-user-net.toml`). Values are checked as `StackBuilder` builds
+//! the stack, so that errors of the whole file are reported before any device opens.
+
+use crate::{
+    builder::StackBuilder,
+    devices::ConfigError,
+    drivers::DriverType,
+    protocols::ip::{cidr::IpCidr, ip_addr_to_bytes},
+};
+use serde::Deserialize;
+use std::fs;
+
+// Configuration of a stack started without one
+const DEFAULT_CONFIG: &str = include_str!("../rust-user-net.toml");
+const DEFAULT_ROUTE: &str = "0.0.0.0/0";
+const PREFIX_LEN_DEFAULT: u8 = 24; // of an address given to a device without interfaces
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StackConfig {
+    #[serde(default = "loopback_default")]
+    pub loopback: bool,
+    #[serde(default)]
+    pub devices: Vec<DeviceConfig>,
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    #[serde(default)]
+    pub neighbors: Vec<NeighborConfig>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    pub name: String,
+    #[serde(default)]
+    pub driver: DriverConfig,
+    #[serde(default)]
+    pub interfaces: Vec<String>, // CIDR, e.g. 192.0.2.2/24
+    pub mtu: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DriverConfig {
+    #[default]
+    Tap,
+    Pcap,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub destination: String, // CIDR, e.g. 0.0.0.0/0
+    pub gateway: String,
+    #[serde(default)]
+    pub metric: u32,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NeighborConfig {
+    pub ip: String,
+    pub hw_address: String, // e.g. 02:00:00:00:00:01
+}
+
+fn loopback_default() -> bool {
+    true
+}
+
+impl StackConfig {
+    pub fn parse(text: &str) -> Result<StackConfig, ConfigError> {
+        toml::from_str(text).map_err(|e| ConfigError::ConfigSyntax(e.message().to_string()))
+    }
+
+    pub fn load(path: &str) -> Result<StackConfig, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::ConfigUnreadable {
+            path: path.to_string(),
+            reason: e.to_string(),
+        })?;
+        StackConfig::parse(&text)
+    }
+
+    /// Replaces the name and the first interface of the first device and the default gateway,
+    /// e.g. with CLI flags to run instances on different TAP devices. An address without a netmask
+    /// keeps the prefix length of the interface replaced, and a netmask without an address keeps
+    /// its address.
+    pub fn set_addressing(
+        &mut self,
+        name: Option<&str>,
+        ip: Option<&str>,
+        netmask: Option<&str>,
+        gateway: Option<&str>,
+    ) -> Result<(), Vec<ConfigError>> {
+        let Some(device) = self.devices.first_mut() else {
+            return Err(vec![ConfigError::NoEthernetDevice]);
+        };
+        let mut errors = vec![];
+        if let Some(name) = name {
+            device.name = name.to_string();
+        }
+        let interface = device
+            .interfaces
+            .first()
+            .and_then(|cidr| cidr.parse::<IpCidr>().ok());
+        if ip.is_some() || netmask.is_some() {
+            let address = match (ip, interface) {
+                (Some(ip), _) => {
+                    let address = ip_addr_to_bytes(ip);
+                    if address.is_none() {
+                        errors.push(ConfigError::InvalidAddress(ip.to_string()));
+                    }
+                    address
+                }
+                (None, Some(interface)) => Some(interface.address),
+                (None, None) => {
+                    errors.push(ConfigError::NetmaskWithoutAddress);
+                    None
+                }
+            };
+            let prefix_len = match netmask {
+                Some(netmask) => {
+                    let cidr = ip_addr_to_bytes(netmask)
+                        .and_then(|netmask| IpCidr::from_netmask(address.unwrap_or(0), netmask));
+                    if cidr.is_none() {
+                        errors.push(ConfigError::InvalidNetmask(netmask.to_string()));
+                    }
+                    cidr.map(|cidr| cidr.prefix_len)
+                }
+                None => interface
+                    .map(|cidr| cidr.prefix_len)
+                    .or(Some(PREFIX_LEN_DEFAULT)),
+            };
+            if let (Some(address), Some(prefix_len)) = (address, prefix_len) {
+                let cidr = IpCidr::new(address, prefix_len).unwrap().to_string();
+                match device.interfaces.first_mut() {
+                    Some(interface) => *interface = cidr,
+                    None => device.interfaces.push(cidr),
+                }
+            }
+        }
+        if let Some(gateway) = gateway {
+            let default_route = self.routes.iter_mut().find(|route| {
+                route
+                    .destination
+                    .parse::<IpCidr>()
+                    .is_ok_and(|cidr| cidr.prefix_len == 0)
+            });
+            match default_route {
+                Some(route) => route.gateway = gateway.to_string(),
+                None => self.routes.push(RouteConfig {
+                    destination: DEFAULT_ROUTE.to_string(),
+                    gateway: gateway.to_string(),
+                    metric: 0,
+                }),
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Builder of the stack configured, for protocol options to be set before `build`.
+    pub fn builder(&self) -> StackBuilder {
+        let mut builder = StackBuilder::new();
+        if self.loopback {
+            builder = builder.loopback();
+        }
+        for device in self.devices.iter() {
+            let driver_type = match device.driver {
+                DriverConfig::Tap => DriverType::Tap,
+                DriverConfig::Pcap => DriverType::Pcap,
+            };
+            builder = builder.ethernet(&device.name, driver_type);
+            if let Some(mtu) = device.mtu {
+                builder = builder.mtu(mtu);
+            }
+            for interface in device.interfaces.iter() {
+                builder = builder.interface(interface);
+            }
+        }
+        for route in self.routes.iter() {
+            builder = builder.route(&route.destination, &route.gateway, route.metric);
+        }
+        for neighbor in self.neighbors.iter() {
+            builder = builder.neighbor(&neighbor.ip, &neighbor.hw_address);
+        }
+        builder
+    }
+}
+
+impl Default for StackConfig {
+    fn default() -> StackConfig {
+        StackConfig::parse(DEFAULT_CONFIG).expect("Config: invalid default configuration.")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DriverConfig, StackConfig};
+    use crate::{
+        devices::ConfigError,
+        protocols::ip::{ip_addr_to_bytes, RouteError},
+    };
+
+    #[test]
+    fn test_parse() {
+        let config = StackConfig::default();
+        assert!(config.loopback);
+        assert_eq!("tap0", config.devices[0].name);
+        assert_eq!(DriverConfig::Tap, config.devices[0].driver);
+        assert_eq!(vec!["192.0.2.2/24"], config.devices[0].interfaces);
+        assert_eq!("0.0.0.0/0", config.routes[0].destination);
+        assert_eq!(0, config.routes[0].metric);
+
+        let config = StackConfig::parse(
+            r#"
+            loopback = false
+            [[devices]]
+            name = "eth0"
+            driver = "pcap"
+            mtu = 9000
+            [[neighbors]]
+            ip = "192.0.2.1"
+            hw_address = "02:00:00:00:00:01"
+            "#,
+        )
+        .unwrap();
+        assert!(!config.loopback && config.routes.is_empty());
+        assert_eq!(DriverConfig::Pcap, config.devices[0].driver);
+        assert_eq!(Some(9000), config.devices[0].mtu);
+        assert_eq!("02:00:00:00:00:01", config.neighbors[0].hw_address);
+
+        for invalid in [
+            "loopback = 1",
+            "[[devices]]\nname = \"tap0\"\ndriver = \"tun\"",
+            "[[routes]]\ndestination = \"0.0.0.0/0\"",
+            "gateway = \"192.0.2.1\"",
+        ] {
+            let result = StackConfig::parse(invalid);
+            assert!(
+                matches!(result, Err(ConfigError::ConfigSyntax(_))),
+                "{invalid}"
+            );
+        }
+        assert!(matches!(
+            StackConfig::load("/nonexistent/rust-user-net.toml"),
+            Err(ConfigError::ConfigUnreadable { .. })
+        ));
+    }
+
+    #[test]
+    fn test_set_addressing() {
+        let mut config = StackConfig::default();
+        config
+            .set_addressing(
+                Some("tap1"),
+                Some("198.51.100.2"),
+                None,
+                Some("198.51.100.1"),
+            )
+            .unwrap();
+        assert_eq!("tap1", config.devices[0].name);
+        assert_eq!(vec!["198.51.100.2/24"], config.devices[0].interfaces);
+        assert_eq!(1, config.routes.len());
+        assert_eq!("198.51.100.1", config.routes[0].gateway);
+
+        config
+            .set_addressing(None, Some("198.51.100.130"), Some("255.255.255.128"), None)
+            .unwrap();
+        assert_eq!(vec!["198.51.100.130/25"], config.devices[0].interfaces);
+        config
+            .set_addressing(None, None, Some("255.255.255.0"), None)
+            .unwrap();
+        assert_eq!(vec!["198.51.100.130/24"], config.devices[0].interfaces);
+
+        // Added to a device without interfaces or a default route
+        let mut config = StackConfig::parse("[[devices]]\nname = \"tap0\"").unwrap();
+        config
+            .set_addressing(None, Some("192.0.2.2"), None, Some("192.0.2.1"))
+            .unwrap();
+        assert_eq!(vec!["192.0.2.2/24"], config.devices[0].interfaces);
+        assert_eq!("0.0.0.0/0", config.routes[0].destination);
+
+        let errors = StackConfig::default()
+            .set_addressing(None, Some("192.0.2.300"), Some("255.0.255.0"), None)
+            .err()
+            .unwrap();
+        assert_eq!(
+            vec![
+                ConfigError::InvalidAddress("192.0.2.300".to_string()),
+                ConfigError::InvalidNetmask("255.0.255.0".to_string()),
+            ],
+            errors
+        );
+        let errors = StackConfig::parse("loopback = true")
+            .unwrap()
+            .set_addressing(Some("tap1"), None, None, None)
+            .err()
+            .unwrap();
+        assert_eq!(vec![ConfigError::NoEthernetDevice], errors);
+        let errors = StackConfig::parse("[[devices]]\nname = \"tap0\"")
+            .unwrap()
+            .set_addressing(None, None, Some("255.255.255.0"), None)
+            .err()
+            .unwrap();
+        assert_eq!(vec![ConfigError::NetmaskWithoutAddress], errors);
+    }
+
+    #[test]
+    fn test_validate() {
+        // Errors of the whole file come before any device opens.
+        let config = StackConfig::parse(
+            r#"
+            [[devices]]
+            name = "tap0"
+            interfaces = ["192.0.2.2/24", "192.0.2.300/24"]
+            [[devices]]
+            name = "tap0"
+            interfaces = ["192.0.2.3/25"]
+            mtu = 10000
+            [[routes]]
+            destination = "198.51.100.0/24"
+            gateway = "203.0.113.1"
+            [[neighbors]]
+            ip = "192.0.2.1"
+            hw_address = "02:00:00"
+            "#,
+        )
+        .unwrap();
+        let errors = config.builder().build().err().unwrap();
+        assert_eq!(6, errors.len(), "{errors:?}");
+        assert_eq!(
+            ConfigError::InvalidCidr("192.0.2.300/24".to_string()),
+            errors[0]
+        );
+        assert_eq!(
+            ConfigError::InvalidHwAddress("02:00:00".to_string()),
+            errors[1]
+        );
+        assert_eq!(
+            ConfigError::DuplicateDeviceName("tap0".to_string()),
+            errors[2]
+        );
+        assert_eq!(
+            ConfigError::MtuTooLarge {
+                device: "tap0".to_string(),
+                mtu: 10000
+            },
+            errors[3]
+        );
+        assert!(matches!(errors[4], ConfigError::OverlappingSubnets { .. }));
+        assert_eq!(
+            ConfigError::GatewayNotConnected(ip_addr_to_bytes("203.0.113.1").unwrap()),
+            errors[5]
+        );
+
+        let config = StackConfig::parse(
+            r#"
+            [[devices]]
+            name = "tap0"
+            interfaces = ["192.0.2.2/24"]
+            [[routes]]
+            destination = "198.51.100.1/24"
+            gateway = "192.0.2.1"
+            "#,
+        )
+        .unwrap();
+        let errors = config.builder().build().err().unwrap();
+        assert_eq!(
+            vec![ConfigError::InvalidRoute {
+                destination: "198.51.100.1/24".parse().unwrap(),
+                error: RouteError::HostBitsSet("198.51.100.1/24".parse().unwrap()),
+            }],
+            errors
+        );
+    }
+}
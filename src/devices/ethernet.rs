@@ -1,27 +1,34 @@
-use super::{
-    NetDevice, NetDeviceType, DEVICE_FLAG_BROADCAST, DEVICE_FLAG_NEED_ARP, NET_DEVICE_ADDR_LEN,
-};
+use super::{DeviceCapabilities, HwAddress, NetDevice, NetDeviceType};
+#[cfg(feature = "fault-injection")]
+use crate::fault::{self, Fault};
 use crate::{
-    drivers::{pcap, tap, DriverType},
+    drivers::{memory, pair, pcap, tap, DriverType},
     interrupt::{self, IRQEntry},
     protocols::ProtocolType,
     utils::byte::{be_to_le_u16, le_to_be_u16},
     utils::{bytes_to_struct, to_u8_slice},
 };
-use log::{debug, trace};
-use std::{convert::TryInto, mem::size_of};
+use log::{debug, error, trace};
+use std::mem::size_of;
 
-pub const IRQ_ETHERNET: i32 = interrupt::INTR_IRQ_BASE + 2;
+pub const IRQ_ETHERNET: i32 = interrupt::INTR_IRQ_BASE + 2; // IRQ of a device of index 0
+pub const ETH_DEVICES_MAX: u8 = 16; // indexes of Ethernet devices within IRQs registered
 
-const ETH_HDR_SIZE: usize = 14;
+pub const ETH_HDR_SIZE: usize = 14;
 const ETH_FRAME_MIN: usize = 60; // without FCS
-pub const ETH_FRAME_MAX: usize = 1514; // without FCS
-const ETH_PAYLOAD_MIN: usize = ETH_FRAME_MIN - ETH_HDR_SIZE;
-const ETH_PAYLOAD_MAX: usize = ETH_FRAME_MAX - ETH_HDR_SIZE;
+pub const ETH_MTU_DEFAULT: usize = 1500;
+pub const ETH_MTU_MAX: usize = 9000; // of jumbo frames
+
+pub const ETH_TYPE_VLAN: u16 = 0x8100; // TPID of 802.1Q tags
+pub const VLAN_TAG_SIZE: usize = 4;
+pub const VLAN_ID_MAX: u16 = 4094; // 0 (priority tag) and 4095 are reserved
+pub const VLAN_PCP_MAX: u8 = 7;
 
 pub const ETH_ADDR_ANY: [u8; 6] = [0x00; 6];
 pub const ETH_ADDR_BROADCAST: [u8; 6] = [0xff; 6];
 pub const ETH_ADDR_LEN: usize = 6;
+const ETH_ADDR_IPV6_MULTICAST_PREFIX: [u8; 2] = [0x33, 0x33];
+const ETH_ADDR_IPV4_MULTICAST_PREFIX: [u8; 3] = [0x01, 0x00, 0x5e]; // groups filtered by IP
 
 /// Ethernet Header (unit: octet)
 /// [ Preamble: 7 | SDF: 1 | Dst MAC: 6 | Src MAC: 6 | EtherType: 2 | Payload: to 1500 | FCS: 4 ]
@@ -41,34 +48,90 @@ pub struct EthernetHeader {
     pub eth_type: u16,           // ethernet type : 2 octets IEEE 802.3
 }
 
+/// 802.1Q tag of a VLAN inserted after the source address: TPID 0x8100 and TCI.
+/// [ TPID: 16 | PCP: 3 | DEI: 1 | VID: 12 ]
+/// PCP: priority code point (0: best effort to 7: network control) / DEI: drop eligible
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VlanTag {
+    pub id: u16,
+    pub pcp: u8,
+}
+
+impl VlanTag {
+    /// Tag of a VLAN id from 1 to 4094 and a priority from 0 to 7.
+    pub fn new(id: u16, pcp: u8) -> Option<VlanTag> {
+        if id == 0 || id > VLAN_ID_MAX || pcp > VLAN_PCP_MAX {
+            return None;
+        }
+        Some(VlanTag { id, pcp })
+    }
+
+    fn from_tci(tci: u16) -> VlanTag {
+        VlanTag {
+            id: tci & 0x0fff,
+            pcp: (tci >> 13) as u8,
+        }
+    }
+
+    fn tci(&self) -> u16 {
+        (self.pcp as u16) << 13 | self.id
+    }
+}
+
 pub fn open(device: &mut NetDevice) -> Result<(), ()> {
     match device.driver_type.as_ref().unwrap() {
         DriverType::Tap => {
             tap::open(device);
         }
         DriverType::Pcap => {}
+        DriverType::Memory => {
+            memory::open(device);
+        }
+        DriverType::Pair(_) => {
+            pair::open(device);
+        }
     }
     Ok(())
 }
 
-pub fn read_data(device: &mut NetDevice) -> Option<(ProtocolType, Vec<u8>, usize)> {
+/// Payload of a frame received with its type and the VLAN id of its tag, if tagged. Tagged
+/// frames of VLANs without interfaces on the device are dropped.
+pub fn read_data(device: &mut NetDevice) -> Option<(ProtocolType, Vec<u8>, usize, Option<u16>)> {
     let (len, buf) = match device.driver_type.as_ref().unwrap() {
         DriverType::Tap => tap::read_data(device),
         DriverType::Pcap => pcap::read_data(device),
+        DriverType::Memory => memory::read_data(device),
+        DriverType::Pair(_) => pair::read_data(device),
     };
 
-    let hdr_len = size_of::<EthernetHeader>();
+    let mut hdr_len = size_of::<EthernetHeader>();
+    if len == 0 {
+        return None;
+    }
     if len < hdr_len {
-        panic!("Ethernet: data is smaller than eth header.")
+        debug!("Ethernet: dropped frame of {len} bytes shorter than header.");
+        device.stats.rx_errors += 1;
+        return None;
     }
 
     let hdr = unsafe { bytes_to_struct::<EthernetHeader>(&buf) };
 
+    // Frame transmitted by this device reflected back, e.g. by a bridge of a TAP device
+    if device.address.as_bytes() == hdr.src {
+        debug!("Ethernet: dropped frame with own source address.");
+        device.stats.reflected += 1;
+        return None;
+    }
+
     // Check if address matches with this device.
-    if device.address[..ETH_ADDR_LEN] != hdr.dst[..ETH_ADDR_LEN]
+    if !device.state.promiscuous
+        && device.address.as_bytes() != hdr.dst
         && ETH_ADDR_BROADCAST != hdr.dst[..ETH_ADDR_LEN]
+        && ETH_ADDR_IPV6_MULTICAST_PREFIX != hdr.dst[..2]
+        && ETH_ADDR_IPV4_MULTICAST_PREFIX != hdr.dst[..3]
     {
         debug!("Ethernet: not my route.");
+        device.stats.filtered += 1;
         return None;
     }
 
@@ -78,7 +141,32 @@ pub fn read_data(device: &mut NetDevice) -> Option<(ProtocolType, Vec<u8>, usize
         &buf[..len]
     );
 
-    let eth_type = be_to_le_u16(hdr.eth_type);
+    let mut eth_type = be_to_le_u16(hdr.eth_type);
+    let mut vlan = None;
+    if eth_type == ETH_TYPE_VLAN {
+        if len < hdr_len + VLAN_TAG_SIZE {
+            debug!("Ethernet: dropped frame with truncated VLAN tag.");
+            device.stats.rx_errors += 1;
+            return None;
+        }
+        let tci = u16::from_be_bytes([buf[hdr_len], buf[hdr_len + 1]]);
+        let tag = VlanTag::from_tci(tci);
+        if !device
+            .interfaces
+            .iter()
+            .any(|iface| iface.vlan_id() == Some(tag.id))
+        {
+            debug!(
+                "Ethernet: dropped frame of VLAN {} without interfaces.",
+                tag.id
+            );
+            device.stats.unknown_vlan += 1;
+            return None;
+        }
+        eth_type = u16::from_be_bytes([buf[hdr_len + 2], buf[hdr_len + 3]]);
+        hdr_len += VLAN_TAG_SIZE;
+        vlan = Some(tag.id);
+    }
     let data = (&buf[hdr_len..len]).to_vec();
     let data_len = len - hdr_len;
 
@@ -90,38 +178,57 @@ pub fn read_data(device: &mut NetDevice) -> Option<(ProtocolType, Vec<u8>, usize
         eth_type
     );
 
-    Some((ProtocolType::from_u16(eth_type), data, data_len))
+    Some((ProtocolType::from_u16(eth_type), data, data_len, vlan))
 }
 
+/// Sends a frame of data, with the 802.1Q tag of a VLAN if given.
 pub fn transmit(
     device: &mut NetDevice,
     ether_type: ProtocolType,
     data: Vec<u8>,
     len: usize,
     dst: [u8; ETH_ADDR_LEN],
+    vlan: Option<VlanTag>,
 ) -> Result<(), ()> {
-    let src_address: [u8; 6] = device.address[..ETH_ADDR_LEN]
-        .try_into()
+    let src_address = device
+        .address
+        .ethernet()
         .expect("Ethernet: device address size error.");
 
+    if data.len() > device.mtu {
+        error!(
+            "Ethernet: data of {} bytes is over MTU {} of device: {}",
+            data.len(),
+            device.mtu,
+            device.name
+        );
+        return Err(());
+    }
+    let ether_type = ether_type as u16;
+    let outer_type = match vlan {
+        Some(_) => ETH_TYPE_VLAN,
+        None => ether_type,
+    };
     let hdr = EthernetHeader {
         dst,
         src: src_address,
-        eth_type: le_to_be_u16(ether_type as u16),
+        eth_type: le_to_be_u16(outer_type),
     };
     let hdr_bytes = unsafe { to_u8_slice::<EthernetHeader>(&hdr) };
 
-    let mut frame: [u8; ETH_FRAME_MAX] = [0; ETH_FRAME_MAX];
-    let mut pad_len: usize = 0;
+    let mut frame = vec![0; frame_max(device.mtu)];
     let data_len = data.len();
-    let hdr_len = hdr_bytes.len();
+    let mut hdr_len = hdr_bytes.len();
 
     frame[..hdr_len].copy_from_slice(hdr_bytes);
+    if let Some(tag) = vlan {
+        frame[hdr_len..hdr_len + 2].copy_from_slice(&tag.tci().to_be_bytes());
+        frame[hdr_len + 2..hdr_len + 4].copy_from_slice(&ether_type.to_be_bytes());
+        hdr_len += VLAN_TAG_SIZE;
+    }
     frame[hdr_len..(hdr_len + data_len)].copy_from_slice(&data[..]);
 
-    if data_len < ETH_PAYLOAD_MIN {
-        pad_len = ETH_PAYLOAD_MIN - data_len;
-    }
+    let pad_len = ETH_FRAME_MIN.saturating_sub(hdr_len + data_len);
     let frame_len = hdr_len + data_len + pad_len;
 
     trace!(
@@ -129,26 +236,66 @@ pub fn transmit(
         &frame[..frame_len]
     );
 
+    #[cfg(feature = "fault-injection")]
+    if fault::hit(Fault::DriverWrite) {
+        return Err(());
+    }
     match device.driver_type.as_ref().unwrap() {
         DriverType::Tap => tap::write_data(device, &frame[..frame_len]),
         DriverType::Pcap => Ok(()),
+        DriverType::Memory => memory::write_data(device, &frame[..frame_len]),
+        DriverType::Pair(_) => pair::write_data(device, &frame[..frame_len]),
     }
 }
 
+/// Ethernet device of an index among devices of a stack, raising an IRQ of its own (`irq`).
+/// TAP devices open the interface of the device name, "tap0" unless renamed before opening.
 pub fn init(i: u8, driver_type: DriverType) -> NetDevice {
-    let irq_entry = IRQEntry::new(IRQ_ETHERNET, 0);
+    let irq_entry = IRQEntry::new(irq(i), 0);
     let mut device = NetDevice::new(
         i,
         NetDeviceType::Ethernet,
         String::from("tap0"),
-        ETH_PAYLOAD_MAX,
-        DEVICE_FLAG_BROADCAST | DEVICE_FLAG_NEED_ARP,
+        ETH_MTU_DEFAULT,
+        DeviceCapabilities {
+            broadcast: true,
+            needs_arp: true,
+            ..Default::default()
+        },
         ETH_HDR_SIZE as u16,
-        ETH_ADDR_LEN as u16,
-        [0; NET_DEVICE_ADDR_LEN],
-        [0xff; NET_DEVICE_ADDR_LEN],
+        HwAddress::from(ETH_ADDR_ANY),
+        HwAddress::from(ETH_ADDR_BROADCAST),
         irq_entry,
     );
     device.driver_type = Some(driver_type);
     device
 }
+
+/// Size of the largest frame of a device of an MTU: header, VLAN tag and payload without FCS,
+/// e.g. of RX buffers.
+pub fn frame_max(mtu: usize) -> usize {
+    ETH_HDR_SIZE + VLAN_TAG_SIZE + mtu
+}
+
+/// IRQ of an Ethernet device of an index, so that input of each device is handled apart.
+pub fn irq(i: u8) -> i32 {
+    assert!(
+        i < ETH_DEVICES_MAX,
+        "Ethernet: device index {i} is over {}.",
+        ETH_DEVICES_MAX - 1
+    );
+    IRQ_ETHERNET + i as i32
+}
+
+/// Ethernet devices cross-connected in memory (see `drivers::pair`) with locally administered
+/// addresses ending with 1 and 2.
+pub fn init_pair(i: u8) -> (NetDevice, NetDevice) {
+    let veth = |end, n: u8| {
+        let mut device = init(i, DriverType::Pair(end));
+        device.name = format!("veth{}", n - 1);
+        device.address = HwAddress::from([0x02, 0, 0, 0, 0, n]);
+        device
+    };
+    let (a, b) = pair::pair();
+    (veth(a, 1), veth(b, 2))
+}
@@ -1,12 +1,14 @@
-use super::{NetDevice, NetDeviceType, IRQ_FLAG_SHARED, NET_DEVICE_ADDR_LEN};
+use super::{HwAddress, NetDevice, NetDeviceType, IRQ_FLAG_SHARED};
 use crate::{interrupt, protocols::ProtocolType};
 use log::info;
 use signal_hook::low_level::raise;
 use std::sync::Arc;
 
-pub const IRQ_LOOPBACK: i32 = interrupt::INTR_IRQ_BASE + 5;
+pub const IRQ_LOOPBACK: i32 = interrupt::INTR_IRQ_BASE + 1;
 const LOOPBACK_MTU: usize = u16::MAX as usize;
 
+pub const LOOPBACK_CIDR: &str = "127.0.0.1/24";
+
 pub fn open(_device: &mut NetDevice) -> Result<(), ()> {
     Ok(())
 }
@@ -30,11 +32,13 @@ pub fn init(i: u8) -> NetDevice {
         NetDeviceType::Loopback,
         String::from("lo"),
         LOOPBACK_MTU,
-        super::DEVICE_FLAG_LOOPBACK,
-        0,
+        super::DeviceCapabilities {
+            loopback: true,
+            ..Default::default()
+        },
         0,
-        [0; NET_DEVICE_ADDR_LEN],
-        [0; NET_DEVICE_ADDR_LEN],
+        HwAddress::default(),
+        HwAddress::default(),
         irq_entry,
     )
 }
@@ -1,27 +1,91 @@
 pub mod ethernet;
 pub mod loopback;
+pub mod stats;
 
 use crate::{
     drivers::{DriverData, DriverType},
     interrupt,
     net::NetInterfaceFamily,
-    protocols::{ip::IPInterface, NetProtocols, ProtocolData, ProtocolType},
+    protocols::{
+        arp,
+        ip::{
+            self, cidr::IpCidr, igmp, ip_addr_to_str, IPAdress, IPInterface, IPRoutes, RouteError,
+            IP_ADDR_ANY, IP_ADDR_BROADCAST,
+        },
+        ipv6::IPV6Interface,
+        NetProtocols, ProtocolData, ProtocolType,
+    },
     utils::list::List,
 };
-use log::{debug, info};
+use log::{debug, info, warn};
 use signal_hook::{consts::SIGUSR1, low_level::raise};
-use std::sync::Arc;
+use std::{fmt, sync::Arc};
 
-use self::ethernet::ETH_ADDR_LEN;
-
-const DEVICE_FLAG_UP: u16 = 0x0001;
-pub const DEVICE_FLAG_LOOPBACK: u16 = 0x0010;
-pub const DEVICE_FLAG_BROADCAST: u16 = 0x0020;
-pub const DEVICE_FLAG_P2P: u16 = 0x0040;
-pub const DEVICE_FLAG_NEED_ARP: u16 = 0x0100;
+use self::ethernet::{VlanTag, ETH_ADDR_LEN, ETH_DEVICES_MAX, ETH_MTU_MAX, VLAN_TAG_SIZE};
+use self::stats::{DeviceStats, DeviceStatsSnapshot, FrameProtocol};
 
 pub const IRQ_FLAG_SHARED: u8 = 0x0001;
-pub const NET_DEVICE_ADDR_LEN: usize = 14;
+pub const HW_ADDR_MAX_LEN: usize = 16;
+pub const IP_MTU_MIN: usize = 576; // datagram size every host must accept (RFC 791)
+
+/// Hardware address of a device, as long as addresses of its type: 6 octets of Ethernet MACs
+/// or none on loopback.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct HwAddress {
+    len: u8,
+    bytes: [u8; HW_ADDR_MAX_LEN],
+}
+
+impl HwAddress {
+    pub fn new(bytes: &[u8]) -> HwAddress {
+        assert!(
+            bytes.len() <= HW_ADDR_MAX_LEN,
+            "Device: hardware address of {} octets is too long.",
+            bytes.len()
+        );
+        let mut address = HwAddress {
+            len: bytes.len() as u8,
+            ..Default::default()
+        };
+        address.bytes[..bytes.len()].copy_from_slice(bytes);
+        address
+    }
+
+    /// Address of `len` octets all of `byte`, e.g. Ethernet broadcast of 0xff.
+    pub fn filled(byte: u8, len: usize) -> HwAddress {
+        HwAddress::new(&[byte; HW_ADDR_MAX_LEN][..len])
+    }
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len()]
+    }
+
+    /// Ethernet MAC, or none for addresses of other lengths.
+    pub fn ethernet(&self) -> Option<[u8; ETH_ADDR_LEN]> {
+        self.as_bytes().try_into().ok()
+    }
+}
+
+impl From<[u8; ETH_ADDR_LEN]> for HwAddress {
+    fn from(mac: [u8; ETH_ADDR_LEN]) -> HwAddress {
+        HwAddress::new(&mac)
+    }
+}
+
+impl fmt::Debug for HwAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let octets: Vec<String> = self.as_bytes().iter().map(|b| format!("{b:02x}")).collect();
+        write!(f, "{}", octets.join(":"))
+    }
+}
 
 #[derive(Debug, PartialEq)]
 pub enum NetDeviceType {
@@ -29,20 +93,40 @@ pub enum NetDeviceType {
     Ethernet,
 }
 
+/// What a device can do, fixed by its type.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DeviceCapabilities {
+    pub loopback: bool,
+    pub broadcast: bool,
+    pub p2p: bool,
+    pub needs_arp: bool, // resolves hardware addresses of next hops
+}
+
+/// Run-time state of a device.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DeviceState {
+    pub up: bool,
+    pub promiscuous: bool, // receives frames to any hardware address
+}
+
 pub struct NetDevice {
     index: u8,
     pub device_type: NetDeviceType,
     pub name: String,
     pub mtu: usize,
-    pub flags: u16,
+    pub capabilities: DeviceCapabilities,
+    pub state: DeviceState,
     pub header_len: u16,
-    pub address_len: u16,
-    pub address: [u8; NET_DEVICE_ADDR_LEN],
-    pub broadcast: [u8; NET_DEVICE_ADDR_LEN],
+    pub address: HwAddress,
+    pub broadcast: HwAddress,
     pub irq_entry: interrupt::IRQEntry,
     pub interfaces: List<Arc<IPInterface>>,
+    pub ipv6_interfaces: List<Arc<IPV6Interface>>,
     pub driver_type: Option<DriverType>,
     pub driver_data: Option<DriverData>,
+    pub stats: DeviceStats,
+    pub egress_check: bool, // drops frames of source addresses not configured on the device
+    pub static_neighbors: bool, // resolves next hops with static ARP entries only, without requests
 }
 
 impl NetDevice {
@@ -51,11 +135,10 @@ impl NetDevice {
         device_type: NetDeviceType,
         name: String,
         mtu: usize,
-        flags: u16,
+        capabilities: DeviceCapabilities,
         header_len: u16,
-        address_len: u16,
-        address: [u8; NET_DEVICE_ADDR_LEN],
-        broadcast: [u8; NET_DEVICE_ADDR_LEN],
+        address: HwAddress,
+        broadcast: HwAddress,
         irq_entry: interrupt::IRQEntry,
     ) -> NetDevice {
         NetDevice {
@@ -63,15 +146,19 @@ impl NetDevice {
             device_type,
             name,
             mtu,
-            flags,
+            capabilities,
+            state: DeviceState::default(),
             header_len,
-            address_len,
             address,
             broadcast,
             irq_entry,
             interfaces: List::<Arc<IPInterface>>::new(),
+            ipv6_interfaces: List::<Arc<IPV6Interface>>::new(),
             driver_type: None,
             driver_data: None,
+            stats: DeviceStats::default(),
+            egress_check: false,
+            static_neighbors: false,
         }
     }
 
@@ -81,7 +168,8 @@ impl NetDevice {
             interface.interface.family, self.name
         );
         // TODO: check duplicate inteface family type (IP or IPv6)
-        self.interfaces.push(interface);
+        self.interfaces.push(interface.clone());
+        self.announce(interface);
     }
 
     pub fn get_interface(&self, family: NetInterfaceFamily) -> Option<Arc<IPInterface>> {
@@ -93,12 +181,91 @@ impl NetDevice {
         None
     }
 
+    /// Sets the largest payload of frames, e.g. 9000 for jumbo frames, which sizes RX buffers
+    /// and TCP segments. The other end of the link needs the same MTU, e.g. a TAP interface of
+    /// `ip link set tap0 mtu 9000`.
+    pub fn set_mtu(&mut self, mtu: usize) -> Result<(), ConfigError> {
+        if let Some(error) = self.mtu_error(mtu) {
+            return Err(error);
+        }
+        self.mtu = mtu;
+        Ok(())
+    }
+
+    /// Error of an MTU out of the range of the device: at least IP_MTU_MIN, and ETH_MTU_MAX at
+    /// most on Ethernet.
+    fn mtu_error(&self, mtu: usize) -> Option<ConfigError> {
+        if mtu < IP_MTU_MIN {
+            return Some(ConfigError::MtuTooSmall {
+                device: self.name.clone(),
+                mtu,
+            });
+        }
+        if self.device_type == NetDeviceType::Ethernet && mtu > ETH_MTU_MAX {
+            return Some(ConfigError::MtuTooLarge {
+                device: self.name.clone(),
+                mtu,
+            });
+        }
+        None
+    }
+
+    /// IP interface receiving frames of a VLAN id, or untagged frames without one.
+    pub fn get_vlan_interface(&self, vlan: Option<u16>) -> Option<Arc<IPInterface>> {
+        self.interfaces
+            .iter()
+            .find(|iface| {
+                iface.interface.family == NetInterfaceFamily::IP && iface.vlan_id() == vlan
+            })
+            .cloned()
+    }
+
+    /// Replaces an interface of the same family and returns the old one.
+    pub fn replace_interface(&mut self, interface: Arc<IPInterface>) -> Option<Arc<IPInterface>> {
+        for ip_iface in self.interfaces.iter_mut() {
+            if ip_iface.interface.family == interface.interface.family {
+                let old = std::mem::replace(ip_iface, interface.clone());
+                self.announce(interface);
+                return Some(old);
+            }
+        }
+        None
+    }
+
+    /// Sends gratuitous ARP for a configured interface so that peers update their caches.
+    fn announce(&mut self, interface: Arc<IPInterface>) {
+        if !self.capabilities.needs_arp || !self.is_open() {
+            return;
+        }
+        if interface.unicast == IP_ADDR_ANY {
+            return;
+        }
+        if arp::arp_announce(self, interface).is_err() {
+            warn!(
+                "Device: failed to send gratuitous ARP on device: {}",
+                self.name
+            );
+        }
+    }
+
+    pub fn register_ipv6_interface(&mut self, interface: Arc<IPV6Interface>) {
+        info!(
+            "Device: registering {:?} interface on device: {}\n",
+            interface.interface.family, self.name
+        );
+        self.ipv6_interfaces.push(interface);
+    }
+
+    pub fn get_ipv6_interface(&self) -> Option<Arc<IPV6Interface>> {
+        self.ipv6_interfaces.iter().next().cloned()
+    }
+
     fn is_open(&self) -> bool {
-        self.flags & DEVICE_FLAG_UP > 0
+        self.state.up
     }
 
     pub fn open(&mut self) -> Result<(), ()> {
-        self.flags |= DEVICE_FLAG_UP;
+        self.state.up = true;
         match self.device_type {
             NetDeviceType::Loopback => loopback::open(self),
             NetDeviceType::Ethernet => ethernet::open(self),
@@ -119,20 +286,85 @@ impl NetDevice {
         data: Vec<u8>,
         len: usize,
         dst: [u8; ETH_ADDR_LEN],
+    ) -> Result<(), ()> {
+        self.transmit_on_vlan(proto_type, data, len, dst, None)
+    }
+
+    /// Sends data to a device in a frame tagged for a VLAN, e.g. of the interface sending it.
+    /// Devices other than Ethernet ignore the tag.
+    pub fn transmit_on_vlan(
+        &mut self,
+        proto_type: ProtocolType,
+        data: Vec<u8>,
+        len: usize,
+        dst: [u8; ETH_ADDR_LEN],
+        vlan: Option<VlanTag>,
     ) -> Result<(), ()> {
         if !self.is_open() {
             panic!("Device: device is not open.")
         }
-        match self.device_type {
+        if self.egress_check {
+            if let Some(violation) = self.egress_violation(&proto_type, &data) {
+                // Dropped like a frame lost on the wire so that callers carry on.
+                warn!("Device: dropping frame on {}: {violation}", self.name);
+                self.stats.spoofed += 1;
+                return Ok(());
+            }
+        }
+        let protocol = FrameProtocol::classify(&proto_type, &data);
+        let tag_len = vlan.map_or(0, |_| VLAN_TAG_SIZE);
+        let result = match self.device_type {
             NetDeviceType::Loopback => loopback::transmit(self, data),
-            NetDeviceType::Ethernet => ethernet::transmit(self, proto_type, data, len, dst),
+            NetDeviceType::Ethernet => ethernet::transmit(self, proto_type, data, len, dst, vlan),
+        };
+        match result {
+            Ok(()) => self
+                .stats
+                .tx
+                .record(len + self.header_len as usize + tag_len, protocol),
+            Err(()) => self.stats.tx_errors += 1,
+        }
+        result
+    }
+
+    /// Source address of outgoing IPv4 or ARP data not configured on the device, if any.
+    /// Unconfigured source (0.0.0.0) of DHCP messages and ARP probes is allowed, and so is
+    /// ARP sender IP of proxy ARP replies.
+    fn egress_violation(&self, proto_type: &ProtocolType, data: &[u8]) -> Option<String> {
+        let own_ip = |ip: IPAdress| {
+            ip == IP_ADDR_ANY || self.interfaces.iter().any(|iface| iface.unicast == ip)
+        };
+        let ip_at = |offset: usize| {
+            data.get(offset..offset + 4)
+                .map(|b| IPAdress::from_ne_bytes(b.try_into().unwrap()))
+        };
+        match proto_type {
+            ProtocolType::IP => match ip_at(12) {
+                Some(src) if !own_ip(src) => Some(format!("source IP {}", ip_addr_to_str(src))),
+                _ => None,
+            },
+            ProtocolType::Arp => {
+                let sender_hw = data.get(8..8 + ETH_ADDR_LEN)?;
+                if sender_hw != self.address.as_bytes() {
+                    return Some(format!("ARP sender MAC {:02x?}", sender_hw));
+                }
+                let proxy_arp = self.interfaces.iter().any(|iface| iface.proxy_arp());
+                match ip_at(14) {
+                    Some(sender) if !own_ip(sender) && !proxy_arp => {
+                        Some(format!("ARP sender IP {}", ip_addr_to_str(sender)))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
         }
     }
 
     /// ISR (interrupt service routine) for registered IRQs. Handles inputs and raises SIGUSR1.
     pub fn isr(&mut self, irq: i32, protocols: &mut NetProtocols) {
         let incoming_data = match self.device_type {
-            NetDeviceType::Loopback => loopback::read_data(self),
+            NetDeviceType::Loopback => loopback::read_data(self)
+                .map(|(proto_type, data, len)| (proto_type, data, len, None)),
             NetDeviceType::Ethernet => ethernet::read_data(self),
         };
 
@@ -141,14 +373,27 @@ impl NetDevice {
             return;
         }
 
-        let (proto_type, data, len) = incoming_data.unwrap();
-        for protocol in protocols.entries.iter_mut() {
-            if protocol.protocol_type == proto_type {
-                let data_entry: ProtocolData = ProtocolData::new(irq, Some(Arc::new(data)), len);
-                protocol.input_head.push_back(data_entry);
-                break;
-            }
-        }
+        let (proto_type, data, len, vlan) = incoming_data.unwrap();
+        let protocol = FrameProtocol::classify(&proto_type, &data);
+        let tag_len = vlan.map_or(0, |_| VLAN_TAG_SIZE);
+        self.stats
+            .rx
+            .record(len + self.header_len as usize + tag_len, protocol);
+        let flow_hash = match proto_type {
+            ProtocolType::IP => ip::flow_hash(&data[..len.min(data.len())]),
+            _ => None,
+        };
+        let Some(protocol) = protocols
+            .entries
+            .iter_mut()
+            .find(|protocol| protocol.protocol_type == proto_type)
+        else {
+            debug!("Device: no protocol of type: {:x?}", proto_type);
+            self.stats.no_protocol += 1;
+            return;
+        };
+        let data_entry = ProtocolData::new(irq, Some(Arc::new(data)), len, flow_hash, vlan);
+        protocol.input_head.push_back(data_entry);
 
         debug!(
             "Device: ISR done: received protocol type: {:x?}",
@@ -189,4 +434,588 @@ impl NetDevices {
         }
         None
     }
+
+    pub fn get_by_name(&self, name: &str) -> Option<&NetDevice> {
+        self.entries.iter().find(|device| device.name == name)
+    }
+
+    pub fn get_mut_by_name(&mut self, name: &str) -> Option<&mut NetDevice> {
+        self.entries.iter_mut().find(|device| device.name == name)
+    }
+
+    /// Device of an index in the order registered.
+    pub fn get_mut_by_index(&mut self, index: usize) -> Option<&mut NetDevice> {
+        self.entries.iter_mut().nth(index)
+    }
+
+    /// Index of the device datagrams to a destination go out of: the one of the interface
+    /// routed to. The first Ethernet device takes the limited broadcast, multicast groups and
+    /// destinations without a route.
+    pub fn index_by_dst(&self, dst: IPAdress, routes: &IPRoutes) -> Option<usize> {
+        let routed = dst != IP_ADDR_BROADCAST && !igmp::is_multicast(dst);
+        let has = |device: &NetDevice, interface: &Arc<IPInterface>| {
+            device
+                .interfaces
+                .iter()
+                .any(|iface| Arc::ptr_eq(iface, interface))
+        };
+        routes
+            .get_interface(dst)
+            .filter(|_| routed)
+            .and_then(|interface| {
+                self.entries
+                    .iter()
+                    .position(|device| has(device, &interface))
+            })
+            .or_else(|| {
+                self.entries
+                    .iter()
+                    .position(|device| device.device_type == NetDeviceType::Ethernet)
+            })
+    }
+
+    /// Device of `index_by_dst`.
+    pub fn get_mut_by_dst(&mut self, dst: IPAdress, routes: &IPRoutes) -> Option<&mut NetDevice> {
+        let index = self.index_by_dst(dst, routes)?;
+        self.get_mut_by_index(index)
+    }
+
+    /// Counters of all devices in the order registered.
+    pub fn stats(&self) -> Vec<DeviceStatsSnapshot> {
+        self.entries
+            .iter()
+            .map(|device| device.stats.snapshot(&device.name))
+            .collect()
+    }
+
+    /// Counters of all devices in a table, followed by frame statistics of each device.
+    pub fn stats_table(&self) -> String {
+        let mut text = format!(
+            "{:<8} {:>10} {:>12} {:>8} {:>8} {:>10} {:>12} {:>8} {:>8}\n",
+            "Device",
+            "RX Pkts",
+            "RX Bytes",
+            "RX Drop",
+            "RX Err",
+            "TX Pkts",
+            "TX Bytes",
+            "TX Drop",
+            "TX Err"
+        );
+        for stats in self.stats() {
+            text += &format!(
+                "{:<8} {:>10} {:>12} {:>8} {:>8} {:>10} {:>12} {:>8} {:>8}\n",
+                stats.name,
+                stats.rx_packets,
+                stats.rx_bytes,
+                stats.rx_dropped,
+                stats.rx_errors,
+                stats.tx_packets,
+                stats.tx_bytes,
+                stats.tx_dropped,
+                stats.tx_errors
+            );
+        }
+        for device in self.entries.iter() {
+            text += &format!("device: {}\n{}", device.name, device.stats);
+        }
+        text
+    }
+
+    /// Validates device names, MTUs, subnets of configured interfaces and gateways of routes.
+    pub fn validate(&self, gateways: &[IPAdress]) -> Result<(), Vec<ConfigError>> {
+        let mut errors = vec![];
+        let mut names: Vec<&str> = vec![];
+        let mut ifaces: Vec<(&str, &Arc<IPInterface>)> = vec![];
+        for device in self.entries.iter() {
+            if names.contains(&device.name.as_str()) {
+                errors.push(ConfigError::DuplicateDeviceName(device.name.clone()));
+            } else {
+                names.push(&device.name);
+            }
+            if let Some(error) = device.mtu_error(device.mtu) {
+                errors.push(error);
+            }
+            // Interfaces waiting for DHCP lease are not configured yet.
+            for iface in device.interfaces.iter() {
+                if iface.unicast != IP_ADDR_ANY {
+                    ifaces.push((&device.name, iface));
+                }
+            }
+        }
+        for (i, (device, iface)) in ifaces.iter().enumerate() {
+            for (other_device, other) in ifaces[i + 1..].iter() {
+                let netmask = iface.netmask & other.netmask;
+                if iface.unicast & netmask == other.unicast & netmask {
+                    errors.push(ConfigError::OverlappingSubnets {
+                        device: device.to_string(),
+                        interface: iface.unicast,
+                        other_device: other_device.to_string(),
+                        other_interface: other.unicast,
+                    });
+                }
+            }
+        }
+        for gateway in gateways {
+            let connected = ifaces
+                .iter()
+                .any(|(_, iface)| iface.cidr().contains(*gateway));
+            if !connected {
+                errors.push(ConfigError::GatewayNotConnected(*gateway));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Misconfiguration of devices and interfaces detected at startup.
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    DuplicateDeviceName(String),
+    MtuTooSmall {
+        device: String,
+        mtu: usize,
+    },
+    MtuTooLarge {
+        device: String,
+        mtu: usize,
+    },
+    MtuWithoutDevice,
+    OverlappingSubnets {
+        device: String,
+        interface: IPAdress,
+        other_device: String,
+        other_interface: IPAdress,
+    },
+    GatewayNotConnected(IPAdress),
+    InvalidAddress(String),
+    InvalidCidr(String),
+    InvalidNetmask(String),
+    NetmaskWithoutAddress,
+    InvalidHwAddress(String),
+    InterfaceWithoutDevice,
+    NoEthernetDevice,
+    DeviceOpenFailed(String),
+    TooManyDevices,
+    InvalidRoute {
+        destination: IpCidr,
+        error: RouteError,
+    },
+    ConfigUnreadable {
+        path: String,
+        reason: String,
+    },
+    ConfigSyntax(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::DuplicateDeviceName(name) => write!(
+                f,
+                "device name {name} is registered more than once: give each device a unique name"
+            ),
+            ConfigError::MtuTooSmall { device, mtu } => write!(
+                f,
+                "MTU {mtu} of device {device} is below {IP_MTU_MIN}: raise it to at least {IP_MTU_MIN}"
+            ),
+            ConfigError::MtuTooLarge { device, mtu } => write!(
+                f,
+                "MTU {mtu} of device {device} is over {ETH_MTU_MAX}: lower it to at most {ETH_MTU_MAX} (jumbo frames)"
+            ),
+            ConfigError::MtuWithoutDevice => write!(
+                f,
+                "MTU is set before any device: add the device of it first"
+            ),
+            ConfigError::OverlappingSubnets {
+                device,
+                interface,
+                other_device,
+                other_interface,
+            } => write!(
+                f,
+                "subnet of {} on {device} overlaps with {} on {other_device}: route lookups become ambiguous, use disjoint subnets",
+                ip_addr_to_str(*interface),
+                ip_addr_to_str(*other_interface)
+            ),
+            ConfigError::GatewayNotConnected(gateway) => write!(
+                f,
+                "gateway {} is not on any connected network: ARP can not resolve it, use an address inside an interface subnet",
+                ip_addr_to_str(*gateway)
+            ),
+            ConfigError::InvalidAddress(addr) => write!(
+                f,
+                "address {addr} is not valid: use dotted decimal notation, e.g. 192.0.2.2"
+            ),
+            ConfigError::InvalidCidr(cidr) => write!(
+                f,
+                "CIDR {cidr} is not valid: use an address and a prefix length, e.g. 192.0.2.2/24"
+            ),
+            ConfigError::InvalidNetmask(netmask) => write!(
+                f,
+                "netmask {netmask} is not valid: use leading one bits in dotted decimal notation, e.g. 255.255.255.0"
+            ),
+            ConfigError::InvalidHwAddress(addr) => write!(
+                f,
+                "HW address {addr} is not valid: use colon-separated hex octets, e.g. 02:00:00:00:00:01"
+            ),
+            ConfigError::NetmaskWithoutAddress => write!(
+                f,
+                "netmask is given without an address: give the address with it"
+            ),
+            ConfigError::InterfaceWithoutDevice => write!(
+                f,
+                "interface is added before any device: add the device of it first"
+            ),
+            ConfigError::NoEthernetDevice => write!(
+                f,
+                "no Ethernet device is added: add one, as protocols send through it"
+            ),
+            ConfigError::DeviceOpenFailed(name) => write!(
+                f,
+                "device {name} failed to open: check the driver (e.g. permission of /dev/net/tun)"
+            ),
+            ConfigError::TooManyDevices => write!(
+                f,
+                "devices are more than {ETH_DEVICES_MAX}: each needs an IRQ of its own, remove some"
+            ),
+            ConfigError::InvalidRoute { destination, error } => {
+                write!(f, "route to {destination} is not valid: {error}")
+            }
+            ConfigError::ConfigUnreadable { path, reason } => write!(
+                f,
+                "config file {path} can not be read ({reason}): check the path and its permission"
+            ),
+            ConfigError::ConfigSyntax(reason) => write!(
+                f,
+                "config is not valid ({reason}): see rust-user-net.toml for the format"
+            ),
+        }
+    }
+}
+
+/// Ethernet device on memory driver with an IP interface for tests.
+#[cfg(test)]
+pub fn test_device(cidr: &str) -> NetDevice {
+    let mut device = ethernet::init(1, crate::drivers::DriverType::Memory);
+    device.address = HwAddress::from([0x02, 0x00, 0x00, 0x00, 0x00, 0x02]);
+    device.open().unwrap();
+    device.register_interface(Arc::new(IPInterface::new(cidr)));
+    // Gratuitous ARP of the bring-up
+    device.driver_data.as_mut().unwrap().tx_frames.clear();
+    device
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ethernet::{self, VlanTag},
+        loopback, test_device, ConfigError, HwAddress, NetDevice, NetDevices,
+    };
+    use crate::{
+        drivers::DriverType,
+        protocols::{
+            ip::{ip_addr_to_bytes, IPInterface, IPRoute, IPRoutes},
+            ProtocolType,
+        },
+    };
+    use std::sync::Arc;
+
+    fn devices(ethernet: NetDevice) -> NetDevices {
+        let mut devices = NetDevices::new();
+        let mut loopback_device = loopback::init(0);
+        loopback_device.register_interface(Arc::new(IPInterface::new("127.0.0.1/8")));
+        devices.register(loopback_device);
+        devices.register(ethernet);
+        devices
+    }
+
+    #[test]
+    fn test_validate() {
+        let devices = devices(test_device("192.0.2.2/24"));
+        let gateway = ip_addr_to_bytes("192.0.2.1").unwrap();
+        assert_eq!(Ok(()), devices.validate(&[gateway]));
+
+        let gateway = ip_addr_to_bytes("198.51.100.1").unwrap();
+        assert_eq!(
+            Err(vec![ConfigError::GatewayNotConnected(gateway)]),
+            devices.validate(&[gateway])
+        );
+    }
+
+    #[test]
+    fn test_validate_devices() {
+        let mut ethernet = test_device("127.0.1.2/24");
+        ethernet.name = String::from("lo");
+        ethernet.mtu = 500;
+        let errors = devices(ethernet).validate(&[]).unwrap_err();
+        assert_eq!(3, errors.len());
+        assert_eq!(
+            ConfigError::DuplicateDeviceName(String::from("lo")),
+            errors[0]
+        );
+        assert_eq!(
+            ConfigError::MtuTooSmall {
+                device: String::from("lo"),
+                mtu: 500
+            },
+            errors[1]
+        );
+        assert!(matches!(errors[2], ConfigError::OverlappingSubnets { .. }));
+    }
+
+    #[test]
+    fn test_egress_check() {
+        let mut device = test_device("192.0.2.2/24");
+        device.egress_check = true;
+        let dst = [0xff; 6];
+        let mut datagram = vec![0; 20];
+        datagram[12..16].copy_from_slice(&[192, 0, 2, 2]);
+        device
+            .transmit(ProtocolType::IP, datagram.clone(), 20, dst)
+            .unwrap();
+        datagram[12..16].copy_from_slice(&[198, 51, 100, 1]);
+        device
+            .transmit(ProtocolType::IP, datagram, 20, dst)
+            .unwrap();
+
+        // ARP with sender MAC of another host
+        let mut arp = vec![0; 28];
+        arp[8..14].copy_from_slice(&[0x02, 0, 0, 0, 0, 0x09]);
+        arp[14..18].copy_from_slice(&[192, 0, 2, 2]);
+        device
+            .transmit(ProtocolType::Arp, arp.clone(), 28, dst)
+            .unwrap();
+        arp[8..14].copy_from_slice(&[0x02, 0, 0, 0, 0, 0x02]);
+        device.transmit(ProtocolType::Arp, arp, 28, dst).unwrap();
+
+        assert_eq!(2, device.driver_data.as_ref().unwrap().tx_frames.len());
+        assert_eq!(2, device.stats.spoofed);
+        assert!(device
+            .stats
+            .to_string()
+            .contains("dropped by egress check: 2"));
+    }
+
+    #[test]
+    fn test_lookup() {
+        let mut devices = devices(test_device("192.0.2.2/24"));
+        let mut tap1 = ethernet::init(2, DriverType::Memory);
+        tap1.name = String::from("tap1");
+        tap1.register_interface(Arc::new(IPInterface::new("198.51.100.2/24")));
+        devices.register(tap1);
+        let mut routes = IPRoutes::new();
+        for device in devices.entries.iter() {
+            for interface in device.interfaces.iter() {
+                routes.register(IPRoute::interface_route(interface.clone()));
+            }
+        }
+        let irqs: Vec<i32> = devices.entries.iter().map(|d| d.irq_entry.irq).collect();
+        assert_eq!(
+            vec![loopback::IRQ_LOOPBACK, ethernet::irq(1), ethernet::irq(2)],
+            irqs
+        );
+
+        assert_eq!("tap1", devices.get_by_name("tap1").unwrap().name);
+        assert!(devices.get_mut_by_name("tap2").is_none());
+        assert_eq!("tap1", devices.get_mut_by_index(2).unwrap().name);
+        let index = |dst: &str| devices.index_by_dst(ip_addr_to_bytes(dst).unwrap(), &routes);
+        assert_eq!(Some(2), index("198.51.100.7"));
+        assert_eq!(Some(1), index("192.0.2.1"));
+        assert_eq!(Some(0), index("127.0.0.1"));
+        // No route, the limited broadcast and multicast go out of the first Ethernet device.
+        assert_eq!(Some(1), index("203.0.113.1"));
+        assert_eq!(Some(1), index("255.255.255.255"));
+        assert_eq!(Some(1), index("224.0.0.1"));
+        let dst = ip_addr_to_bytes("198.51.100.7").unwrap();
+        assert_eq!("tap1", devices.get_mut_by_dst(dst, &routes).unwrap().name);
+    }
+
+    #[test]
+    fn test_promiscuous() {
+        let mut device = test_device("192.0.2.2/24");
+        assert!(device.capabilities.needs_arp && !device.capabilities.loopback);
+        let mut frame = vec![0x02, 0, 0, 0, 0, 0x09, 0x02, 0, 0, 0, 0, 0x01, 0x08, 0x00];
+        frame.extend_from_slice(&[0; 46]);
+        let mut receive = |device: &mut NetDevice| {
+            let rx_frames = &mut device.driver_data.as_mut().unwrap().rx_frames;
+            rx_frames.push_back(frame.clone());
+            ethernet::read_data(device).is_some()
+        };
+        assert!(!receive(&mut device));
+        device.state.promiscuous = true;
+        assert!(receive(&mut device));
+    }
+
+    #[test]
+    fn test_reflected_frame() {
+        let mut device = test_device("192.0.2.2/24");
+        let mut frame = vec![0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        frame.extend_from_slice(device.address.as_bytes());
+        frame.extend_from_slice(&[0x08, 0x06]);
+        frame.extend_from_slice(&[0; 46]);
+        let rx_frames = &mut device.driver_data.as_mut().unwrap().rx_frames;
+        rx_frames.push_back(frame.clone());
+        frame[11] = 0x09;
+        rx_frames.push_back(frame);
+
+        assert!(ethernet::read_data(&mut device).is_none());
+        assert!(ethernet::read_data(&mut device).is_some());
+        assert_eq!(1, device.stats.reflected);
+        assert!(device.stats.to_string().contains("dropped as reflected: 1"));
+    }
+
+    #[test]
+    fn test_vlan() {
+        let mut device = test_device("192.0.2.2/24");
+        let tag = VlanTag::new(10, 5).unwrap();
+        device.register_interface(Arc::new(IPInterface::new("198.51.100.2/24").with_vlan(tag)));
+        device.driver_data.as_mut().unwrap().tx_frames.clear();
+        assert!(VlanTag::new(0, 0).is_none() && VlanTag::new(4095, 0).is_none());
+        assert!(VlanTag::new(10, 8).is_none());
+
+        // Tag of PCP 5 and VID 10 between the source address and the type
+        let dst = [0x02, 0, 0, 0, 0, 0x01];
+        device
+            .transmit_on_vlan(ProtocolType::IP, vec![0x45; 20], 20, dst, Some(tag))
+            .unwrap();
+        let frame = device
+            .driver_data
+            .as_mut()
+            .unwrap()
+            .tx_frames
+            .pop_front()
+            .unwrap();
+        assert_eq!([0x81, 0x00, 0xa0, 0x0a, 0x08, 0x00], frame[12..18]);
+        assert_eq!(0x45, frame[18]);
+        assert_eq!(60, frame.len());
+
+        // Frames of VLAN 10 and untagged ones are received, those of VLAN 20 dropped.
+        let rx_frames = &mut device.driver_data.as_mut().unwrap().rx_frames;
+        let mut tagged = frame.clone();
+        tagged[..6].copy_from_slice(&[0x02, 0, 0, 0, 0, 0x02]);
+        tagged[6..12].copy_from_slice(&dst);
+        rx_frames.push_back(tagged.clone());
+        tagged[15] = 20;
+        rx_frames.push_back(tagged.clone());
+        tagged.drain(12..16);
+        rx_frames.push_back(tagged);
+        let (proto_type, data, _, vlan) = ethernet::read_data(&mut device).unwrap();
+        assert_eq!((ProtocolType::IP, Some(10)), (proto_type, vlan));
+        assert_eq!(0x45, data[0]);
+        assert!(ethernet::read_data(&mut device).is_none());
+        assert_eq!(1, device.stats.unknown_vlan);
+        let (_, _, _, vlan) = ethernet::read_data(&mut device).unwrap();
+        assert_eq!(None, vlan);
+
+        assert_eq!(
+            "198.51.100.2/24",
+            device
+                .get_vlan_interface(Some(10))
+                .unwrap()
+                .cidr()
+                .to_string()
+        );
+        assert_eq!(
+            "192.0.2.2/24",
+            device.get_vlan_interface(None).unwrap().cidr().to_string()
+        );
+    }
+
+    #[test]
+    fn test_mtu() {
+        let mut device = test_device("192.0.2.2/24");
+        assert_eq!(1500, device.mtu);
+        assert!(matches!(
+            device.set_mtu(500),
+            Err(ConfigError::MtuTooSmall { mtu: 500, .. })
+        ));
+        assert!(matches!(
+            device.set_mtu(9001),
+            Err(ConfigError::MtuTooLarge { mtu: 9001, .. })
+        ));
+        assert!(loopback::init(0).set_mtu(u16::MAX as usize).is_ok());
+
+        // Jumbo frames of the MTU are sent and received whole, larger data is refused.
+        device.set_mtu(9000).unwrap();
+        let dst = [0x02, 0, 0, 0, 0, 0x01];
+        device
+            .transmit(ProtocolType::IP, vec![0x45; 9000], 9000, dst)
+            .unwrap();
+        assert!(device
+            .transmit(ProtocolType::IP, vec![0x45; 9001], 9001, dst)
+            .is_err());
+        let driver_data = device.driver_data.as_mut().unwrap();
+        let mut frame = driver_data.tx_frames.pop_front().unwrap();
+        assert_eq!(9014, frame.len());
+        frame[..6].copy_from_slice(&[0x02, 0, 0, 0, 0, 0x02]);
+        frame[6..12].copy_from_slice(&dst);
+        driver_data.rx_frames.push_back(frame);
+        let (_, data, len, _) = ethernet::read_data(&mut device).unwrap();
+        assert_eq!((9000, 9000), (data.len(), len));
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut device = test_device("192.0.2.2/24");
+        let dst = [0x02, 0, 0, 0, 0, 0x01];
+        device
+            .transmit(ProtocolType::IP, vec![0x45; 100], 100, dst)
+            .unwrap();
+        assert!(device
+            .transmit(ProtocolType::IP, vec![0x45; 1501], 1501, dst)
+            .is_err());
+
+        // A frame for another host and one shorter than the header
+        let driver_data = device.driver_data.as_mut().unwrap();
+        let mut frame = driver_data.tx_frames.pop_front().unwrap();
+        frame[6..12].copy_from_slice(&dst);
+        driver_data.rx_frames.push_back(frame);
+        driver_data.rx_frames.push_back(vec![0xff; 10]);
+        assert!(ethernet::read_data(&mut device).is_none());
+        assert!(ethernet::read_data(&mut device).is_none());
+
+        let devices = devices(device);
+        let stats = devices.stats();
+        assert_eq!(2, stats.len());
+        // Along with the gratuitous ARP of the bring-up
+        let ethernet_stats = &stats[1];
+        assert_eq!(
+            (2, 42 + 114, 0),
+            (
+                ethernet_stats.tx_packets,
+                ethernet_stats.tx_bytes,
+                ethernet_stats.tx_dropped
+            )
+        );
+        assert_eq!(1, ethernet_stats.tx_errors);
+        assert_eq!(
+            (0, 1, 1),
+            (
+                ethernet_stats.rx_packets,
+                ethernet_stats.rx_dropped,
+                ethernet_stats.rx_errors
+            )
+        );
+        assert!(devices.stats_table().starts_with("Device "));
+    }
+
+    #[test]
+    fn test_hw_address() {
+        let device = test_device("192.0.2.2/24");
+        assert_eq!(6, device.address.len());
+        assert_eq!(Some([0x02, 0, 0, 0, 0, 0x02]), device.address.ethernet());
+        assert_eq!("ff:ff:ff:ff:ff:ff", format!("{:?}", device.broadcast));
+        assert!(loopback::init(0).address.is_empty());
+
+        // e.g. EUI-64 of IEEE 1394 or 802.15.4 devices
+        let eui64 = HwAddress::new(&[0x02, 0, 0, 0xff, 0xfe, 0, 0, 0x01]);
+        assert_eq!(8, eui64.as_bytes().len());
+        assert_eq!(None, eui64.ethernet());
+        assert_ne!(HwAddress::filled(0xff, 8), HwAddress::filled(0xff, 6));
+    }
 }
@@ -0,0 +1,210 @@
+use crate::protocols::{ip::IPProtocolType, ipv6::IPV6NextHeader, ProtocolType};
+use std::fmt;
+
+// Upper bounds of frame size buckets in bytes (link header included)
+const SIZE_BUCKETS: [usize; 7] = [64, 128, 256, 512, 1024, 1518, usize::MAX];
+const IP_PROTOCOL_OFFSET: usize = 9;
+const IPV6_NEXT_HEADER_OFFSET: usize = 6;
+
+/// Protocol of a frame by its innermost known header.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FrameProtocol {
+    Arp,
+    IP, // IPv4 / IPv6 with other upper protocols
+    Icmp,
+    Tcp,
+    Udp,
+    Other,
+}
+
+const FRAME_PROTOCOLS: [FrameProtocol; 6] = [
+    FrameProtocol::Arp,
+    FrameProtocol::IP,
+    FrameProtocol::Icmp,
+    FrameProtocol::Tcp,
+    FrameProtocol::Udp,
+    FrameProtocol::Other,
+];
+
+impl FrameProtocol {
+    /// Classifies a frame with its link protocol type and payload.
+    pub fn classify(proto_type: &ProtocolType, payload: &[u8]) -> FrameProtocol {
+        match proto_type {
+            ProtocolType::Arp => FrameProtocol::Arp,
+            ProtocolType::IP => match payload.get(IP_PROTOCOL_OFFSET) {
+                Some(&p) if p == IPProtocolType::Icmp as u8 => FrameProtocol::Icmp,
+                Some(&p) if p == IPProtocolType::Tcp as u8 => FrameProtocol::Tcp,
+                Some(&p) if p == IPProtocolType::Udp as u8 => FrameProtocol::Udp,
+                _ => FrameProtocol::IP,
+            },
+            ProtocolType::IPV6 => match payload.get(IPV6_NEXT_HEADER_OFFSET) {
+                Some(&p) if p == IPV6NextHeader::Icmpv6 as u8 => FrameProtocol::Icmp,
+                Some(&p) if p == IPProtocolType::Tcp as u8 => FrameProtocol::Tcp,
+                Some(&p) if p == IPProtocolType::Udp as u8 => FrameProtocol::Udp,
+                _ => FrameProtocol::IP,
+            },
+            ProtocolType::Unknown => FrameProtocol::Other,
+        }
+    }
+}
+
+impl fmt::Display for FrameProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FrameProtocol::Arp => "ARP",
+            FrameProtocol::IP => "IP",
+            FrameProtocol::Icmp => "ICMP",
+            FrameProtocol::Tcp => "TCP",
+            FrameProtocol::Udp => "UDP",
+            FrameProtocol::Other => "other",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Frame size histogram and protocol mix of one direction.
+#[derive(Default)]
+pub struct FrameCounters {
+    pub frames: u64,
+    pub bytes: u64,
+    pub sizes: [u64; SIZE_BUCKETS.len()],
+    pub protocols: [u64; FRAME_PROTOCOLS.len()],
+}
+
+impl FrameCounters {
+    pub fn record(&mut self, size: usize, protocol: FrameProtocol) {
+        self.frames += 1;
+        self.bytes += size as u64;
+        let bucket = SIZE_BUCKETS.iter().position(|&max| size <= max).unwrap();
+        self.sizes[bucket] += 1;
+        let index = FRAME_PROTOCOLS.iter().position(|&p| p == protocol).unwrap();
+        self.protocols[index] += 1;
+    }
+
+    pub fn protocol_count(&self, protocol: FrameProtocol) -> u64 {
+        let index = FRAME_PROTOCOLS.iter().position(|&p| p == protocol).unwrap();
+        self.protocols[index]
+    }
+}
+
+impl fmt::Display for FrameCounters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "  frames: {} bytes: {}", self.frames, self.bytes)?;
+        writeln!(f, "  sizes:")?;
+        let mut min = 0;
+        for (max, count) in SIZE_BUCKETS.iter().zip(self.sizes) {
+            let range = if *max == usize::MAX {
+                format!("{min}-")
+            } else {
+                format!("{min}-{max}")
+            };
+            writeln!(f, "    {range:>10} {count:>10} {}", bar(count, self.frames))?;
+            min = max.saturating_add(1);
+        }
+        writeln!(f, "  protocols:")?;
+        for protocol in FRAME_PROTOCOLS {
+            let count = self.protocol_count(protocol);
+            writeln!(
+                f,
+                "    {:>10} {count:>10} {}",
+                protocol.to_string(),
+                bar(count, self.frames)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Bar of 40 columns at most proportional to a share of total
+fn bar(count: u64, total: u64) -> String {
+    if total == 0 {
+        return String::new();
+    }
+    "#".repeat((count * 40 / total) as usize)
+}
+
+/// Received and transmitted frame statistics of a device. Frames dropped or failed are not
+/// counted in `rx` and `tx`.
+#[derive(Default)]
+pub struct DeviceStats {
+    pub rx: FrameCounters,
+    pub tx: FrameCounters,
+    pub spoofed: u64,      // transmitted frames dropped by egress check
+    pub reflected: u64,    // received frames dropped for the source address of the device
+    pub unknown_vlan: u64, // received frames dropped for a VLAN without interfaces
+    pub filtered: u64,     // received frames dropped for HW addresses of other hosts
+    pub no_protocol: u64,  // received frames dropped for types without protocols registered
+    pub rx_errors: u64,    // frames failing to be read, or shorter than their headers
+    pub tx_errors: u64,    // frames failing to be written, or over the MTU
+}
+
+impl DeviceStats {
+    pub fn snapshot(&self, name: &str) -> DeviceStatsSnapshot {
+        DeviceStatsSnapshot {
+            name: name.to_string(),
+            rx_packets: self.rx.frames,
+            rx_bytes: self.rx.bytes,
+            rx_dropped: self.reflected + self.unknown_vlan + self.filtered + self.no_protocol,
+            rx_errors: self.rx_errors,
+            tx_packets: self.tx.frames,
+            tx_bytes: self.tx.bytes,
+            tx_dropped: self.spoofed,
+            tx_errors: self.tx_errors,
+        }
+    }
+}
+
+impl fmt::Display for DeviceStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, " RX")?;
+        write!(f, "{}", self.rx)?;
+        writeln!(f, "  dropped as reflected: {}", self.reflected)?;
+        writeln!(f, "  dropped for unknown VLAN: {}", self.unknown_vlan)?;
+        writeln!(f, "  dropped for other hosts: {}", self.filtered)?;
+        writeln!(f, "  dropped without protocol: {}", self.no_protocol)?;
+        writeln!(f, "  errors: {}", self.rx_errors)?;
+        writeln!(f, " TX")?;
+        write!(f, "{}", self.tx)?;
+        writeln!(f, "  dropped by egress check: {}", self.spoofed)?;
+        writeln!(f, "  errors: {}", self.tx_errors)
+    }
+}
+
+/// Counters of a device at a point in time, e.g. for the CLI or metrics exporters.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceStatsSnapshot {
+    pub name: String,
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub rx_dropped: u64,
+    pub rx_errors: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_dropped: u64,
+    pub tx_errors: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FrameCounters, FrameProtocol};
+    use crate::protocols::ProtocolType;
+
+    #[test]
+    fn test_record() {
+        let mut ip_header = [0; 20];
+        ip_header[9] = 0x06;
+        let mut counters = FrameCounters::default();
+        counters.record(60, FrameProtocol::classify(&ProtocolType::Arp, &[]));
+        counters.record(1514, FrameProtocol::classify(&ProtocolType::IP, &ip_header));
+        ip_header[9] = 0x2f;
+        counters.record(1600, FrameProtocol::classify(&ProtocolType::IP, &ip_header));
+
+        assert_eq!(3, counters.frames);
+        assert_eq!(3174, counters.bytes);
+        assert_eq!([1, 0, 0, 0, 0, 1, 1], counters.sizes);
+        assert_eq!(1, counters.protocol_count(FrameProtocol::Arp));
+        assert_eq!(1, counters.protocol_count(FrameProtocol::Tcp));
+        assert_eq!(1, counters.protocol_count(FrameProtocol::IP));
+        assert_eq!(0, counters.protocol_count(FrameProtocol::Udp));
+    }
+}
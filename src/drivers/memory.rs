@@ -0,0 +1,27 @@
+use super::DriverData;
+use crate::devices::{ethernet, NetDevice};
+
+/// In-memory driver without kernel device. Frames written are kept in a TX queue and
+/// frames pushed to an RX queue are read as inputs, which lets tests craft frames.
+pub fn open(device: &mut NetDevice) {
+    device.driver_data = Some(DriverData::new(None, device.irq_entry.irq));
+}
+
+pub fn read_data(device: &mut NetDevice) -> (usize, Vec<u8>) {
+    let mut buf = vec![0; ethernet::frame_max(device.mtu)];
+    let driver_data = device.driver_data.as_mut().unwrap();
+    match driver_data.rx_frames.pop_front() {
+        Some(frame) => {
+            let len = frame.len().min(buf.len());
+            buf[..len].copy_from_slice(&frame[..len]);
+            (len, buf)
+        }
+        None => (0, buf),
+    }
+}
+
+pub fn write_data(device: &mut NetDevice, data: &[u8]) -> Result<(), ()> {
+    let driver_data = device.driver_data.as_mut().unwrap();
+    driver_data.tx_frames.push_back(data.to_vec());
+    Ok(())
+}
@@ -1,23 +1,34 @@
+pub mod memory;
+pub mod pair;
 pub mod pcap;
 pub mod tap;
 
-use std::fs::File;
+use std::{collections::VecDeque, fs::File};
 
 #[derive(Debug)]
 pub enum DriverType {
     Tap,
     Pcap,
+    Memory,
+    Pair(pair::PairEnd),
 }
 
 #[derive(Debug)]
 pub struct DriverData {
     // pub fd: i32,
-    pub file: File,
+    pub file: Option<File>,
     irq: i32,
+    pub rx_frames: VecDeque<Vec<u8>>, // memory driver only
+    pub tx_frames: VecDeque<Vec<u8>>, // memory driver only
 }
 
 impl DriverData {
-    pub fn new(file: File, irq: i32) -> DriverData {
-        DriverData { file, irq }
+    pub fn new(file: Option<File>, irq: i32) -> DriverData {
+        DriverData {
+            file,
+            irq,
+            rx_frames: VecDeque::new(),
+            tx_frames: VecDeque::new(),
+        }
     }
 }
@@ -1,5 +1,5 @@
-use crate::devices::{ethernet::ETH_FRAME_MAX, NetDevice};
+use crate::devices::{ethernet, NetDevice};
 
-pub fn read_data(device: &NetDevice) -> (usize, [u8; ETH_FRAME_MAX]) {
-    (0, [0; ETH_FRAME_MAX])
+pub fn read_data(device: &NetDevice) -> (usize, Vec<u8>) {
+    (0, vec![0; ethernet::frame_max(device.mtu)])
 }
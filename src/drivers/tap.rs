@@ -1,10 +1,7 @@
 use super::DriverData;
-use crate::{
-    devices::{
-        ethernet::{ETH_ADDR_ANY, ETH_FRAME_MAX},
-        NetDevice, NET_DEVICE_ADDR_LEN,
-    },
-    interrupt::INTR_IRQ_BASE,
+use crate::devices::{
+    ethernet::{self, ETH_ADDR_ANY},
+    HwAddress, NetDevice,
 };
 use core::slice;
 use ifstructs::ifreq;
@@ -26,8 +23,6 @@ const AF_INET_RAW: u16 = 2;
 
 // const SOCK_IOC_TYPE: u8 = 0x89; // uapi/linux/sockios.h
 
-const ETH_TAP_IRQ: i32 = INTR_IRQ_BASE + 2;
-
 // Network device allocation (registers a device on kernel)
 ioctl!(write tun_set_iff with TUN_IOC_MAGIC, TUN_IOC_SET_IFF; c_int);
 
@@ -54,13 +49,13 @@ fn set_tap_address(device: &mut NetDevice) {
 
         let hw_addr_u8 = slice::from_raw_parts(
             ifr.ifr_ifru.ifr_hwaddr.sa_data.as_ptr() as *const u8,
-            NET_DEVICE_ADDR_LEN,
+            device.address.len(),
         );
 
         let name = ifr.get_name().unwrap();
         info!("TAP: retrieved HW Address for {name}: {:x?}", hw_addr_u8);
 
-        device.address.copy_from_slice(hw_addr_u8);
+        device.address = HwAddress::new(hw_addr_u8);
     }
 }
 
@@ -101,26 +96,34 @@ pub fn open(device: &mut NetDevice) {
         if res == -1 {
             panic!("TAP: F_SETSIG failed.");
         }
-        if device.address[..6] == ETH_ADDR_ANY {
+        if device.address.as_bytes() == ETH_ADDR_ANY {
             set_tap_address(device);
         }
     };
-    device.driver_data = Some(DriverData::new(file, ETH_TAP_IRQ))
+    device.driver_data = Some(DriverData::new(Some(file), device.irq_entry.irq))
 }
 
-pub fn read_data(device: &mut NetDevice) -> (usize, [u8; ETH_FRAME_MAX]) {
+pub fn read_data(device: &mut NetDevice) -> (usize, Vec<u8>) {
+    let mut buf = vec![0; ethernet::frame_max(device.mtu)];
     let driver_data = device.driver_data.as_mut().unwrap();
 
-    let mut buf: [u8; ETH_FRAME_MAX] = [0; ETH_FRAME_MAX];
-    let res = driver_data.file.read(&mut buf);
-    let s = res.unwrap();
-    (s, buf)
+    match driver_data.file.as_mut().unwrap().read(&mut buf) {
+        Ok(len) => (len, buf),
+        Err(e) => {
+            error!("TAP: read data failed: {e}");
+            device.stats.rx_errors += 1;
+            (0, buf)
+        }
+    }
 }
 
 pub fn write_data(device: &mut NetDevice, data: &[u8]) -> Result<(), ()> {
-    let result = device.driver_data.as_mut().unwrap().file.write(data);
+    let file = device.driver_data.as_mut().unwrap().file.as_mut().unwrap();
+    let result = file.write(data);
+    // Lost like a frame on the wire so that callers carry on.
     if let Err(e) = result {
         error!("TAP: write data failed: {e}");
+        device.stats.tx_errors += 1;
     }
     Ok(())
 }
@@ -0,0 +1,137 @@
+//! Failures of internal operations on demand, enabled by the `fault-injection` feature, so that
+//! tests can exercise error paths, e.g. of a write to the driver failing.
+//!
+//! Faults are injected for the thread injecting them, like the clock of `timer` set by a
+//! simulation, so that tests running in parallel do not see each other's faults. Stacks driven
+//! on the test thread (`sim::SimStack`) see them, while threads of a started `NetApp` do not.
+
+use log::warn;
+use std::{cell::RefCell, collections::HashMap};
+
+thread_local! {
+    // Remaining number of failures (None for every time) and failures so far of each fault
+    static FAULTS: RefCell<HashMap<Fault, (Option<usize>, usize)>> = RefCell::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Fault {
+    DriverWrite, // writing a frame to the driver of an Ethernet device fails
+    PcbAlloc,    // no free TCP PCB is found
+    TxChecksum,  // IP header checksum of a datagram sent is inverted
+}
+
+/// Injected fault, cleared when dropped.
+pub struct FaultHandle {
+    fault: Fault,
+}
+
+impl FaultHandle {
+    /// Number of times the operation failed so far.
+    pub fn hits(&self) -> usize {
+        FAULTS.with(|faults| {
+            faults
+                .borrow()
+                .get(&self.fault)
+                .map_or(0, |(_, hits)| *hits)
+        })
+    }
+}
+
+impl Drop for FaultHandle {
+    fn drop(&mut self) {
+        FAULTS.with(|faults| faults.borrow_mut().remove(&self.fault));
+    }
+}
+
+/// Makes an operation fail the next `times` times, or every time with None, till the handle is
+/// dropped. Injecting a fault again replaces the previous one.
+pub fn inject(fault: Fault, times: Option<usize>) -> FaultHandle {
+    FAULTS.with(|faults| faults.borrow_mut().insert(fault, (times, 0)));
+    FaultHandle { fault }
+}
+
+/// Whether an operation is to fail now, counting the failure. Called where the operation
+/// happens.
+pub fn hit(fault: Fault) -> bool {
+    let hit = FAULTS.with(|faults| match faults.borrow_mut().get_mut(&fault) {
+        Some((Some(0), _)) | None => false,
+        Some((remaining, hits)) => {
+            if let Some(remaining) = remaining {
+                *remaining -= 1;
+            }
+            *hits += 1;
+            true
+        }
+    });
+    if hit {
+        warn!("Fault: injected {fault:?}.");
+    }
+    hit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hit, inject, Fault};
+    use crate::{
+        devices::{ethernet::ETH_ADDR_BROADCAST, test_device},
+        protocols::{
+            ip::{tcp, IPEndpoint},
+            ControlBlocks, ProtocolType,
+        },
+        sim::{pair, settle},
+    };
+    use std::time::SystemTime;
+
+    #[test]
+    fn test_inject() {
+        assert!(!hit(Fault::PcbAlloc));
+        let handle = inject(Fault::PcbAlloc, Some(2));
+        assert!(hit(Fault::PcbAlloc) && hit(Fault::PcbAlloc));
+        assert!(!hit(Fault::PcbAlloc));
+        assert!(!hit(Fault::DriverWrite));
+        assert_eq!(2, handle.hits());
+        drop(handle);
+        assert!(!hit(Fault::PcbAlloc));
+    }
+
+    #[test]
+    fn test_fault_sites() {
+        let mut device = test_device("192.0.2.2/24");
+        let write = inject(Fault::DriverWrite, Some(1));
+        let data = vec![0; 46];
+        assert!(device
+            .transmit(ProtocolType::IP, data.clone(), 46, ETH_ADDR_BROADCAST)
+            .is_err());
+        assert!(device
+            .transmit(ProtocolType::IP, data, 46, ETH_ADDR_BROADCAST)
+            .is_ok());
+        assert_eq!(1, write.hits());
+
+        let mut pcbs = ControlBlocks::new();
+        let alloc = inject(Fault::PcbAlloc, None);
+        assert!(pcbs.tcp_pcbs.new_entry().is_none());
+        drop(alloc);
+        assert!(pcbs.tcp_pcbs.new_entry().is_some());
+
+        // Datagrams of a corrupted checksum are dropped by the peer.
+        let (mut a, mut b) = pair("192.0.2.1/24", "192.0.2.2/24");
+        let now = SystemTime::now();
+        settle(&mut [&mut a, &mut b], now);
+        let checksum = inject(Fault::TxChecksum, None);
+        let pcb_id = tcp::open(&mut a.pcbs);
+        let events = tcp::subscribe(pcb_id, &mut a.pcbs);
+        let listener = tcp::open(&mut b.pcbs);
+        let server = IPEndpoint::new_from_str("192.0.2.2", 7);
+        tcp::bind(
+            listener,
+            IPEndpoint::new_from_str("192.0.2.2", 7),
+            &mut b.pcbs,
+        );
+        tcp::listen(listener, &mut b.pcbs);
+        tcp::start_connect(pcb_id, &server, &mut a.device, &mut a.contexts, &mut a.pcbs);
+        settle(&mut [&mut a, &mut b], now);
+        assert!(checksum.hits() > 0);
+        assert!(events.try_recv().is_err());
+        assert_eq!(Ok(None), tcp::try_accept(listener, &mut b.pcbs));
+    }
+}
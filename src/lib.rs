@@ -0,0 +1,20 @@
+//! Network protocol stack in user space: devices, ARP/IP/ICMP/UDP/TCP and a blocking socket
+//! facade for applications (see `examples/`).
+
+// Protocol functions report failures with logs and `Err(())`, and tables are built with `new`.
+#![allow(clippy::result_unit_err, clippy::new_without_default)]
+
+pub mod app;
+pub mod builder;
+pub mod config;
+pub mod devices;
+pub mod drivers;
+#[cfg(feature = "fault-injection")]
+pub mod fault;
+pub mod interrupt;
+pub mod net;
+pub mod protocols;
+pub mod sim;
+pub mod socket;
+pub mod timer;
+pub mod utils;
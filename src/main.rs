@@ -1,66 +1,35 @@
-mod app;
-mod devices;
-mod drivers;
-mod interrupt;
-mod net;
-mod protocols;
-mod utils;
-
-use crate::app::NetApp;
-use crate::devices::ethernet::IRQ_ETHERNET;
-use crate::devices::loopback::IRQ_LOOPBACK;
-use log::debug;
 use log::info;
-use signal_hook::consts::signal::*;
-use signal_hook::consts::TERM_SIGNALS;
-use signal_hook::iterator::exfiltrator::origin::WithOrigin;
-use signal_hook::iterator::SignalsInfo;
+use rust_user_net::app::{self, NetApp};
 use simplelog::Config;
 use simplelog::SimpleLogger;
 use std::io::Error;
 use std::sync::mpsc;
 
 fn main() -> Result<(), Error> {
-    // Signal setup
-    let mut sigs = vec![SIGHUP, SIGUSR1, IRQ_LOOPBACK, IRQ_ETHERNET];
-    sigs.extend(TERM_SIGNALS);
-    let mut signals = SignalsInfo::<WithOrigin>::new(&sigs)?;
-
     // Log setup
     SimpleLogger::init(log::LevelFilter::Info, Config::default()).unwrap();
 
+    // Connection commands go to a running stack
+    if let Some(result) = app::run_control_client() {
+        return result;
+    }
+
     let (app_sender, app_receiver) = mpsc::channel();
-    let (tcp_sender, tcp_receiver) = mpsc::channel();
+    let (control_sender, control_receiver) = mpsc::channel();
 
     // Protocol stack start
     let mut app = NetApp::new();
+    let control_join = app.control_thread(control_receiver)?;
+    app.start()?;
     let app_join = app.run(app_receiver);
-    let tcp_join = app.tcp_transmit_thread(tcp_receiver);
+    app.notify_on_stop(app_sender);
+    app.notify_on_stop(control_sender);
 
-    // Interrupt thread
-    info!("App: starting signal receiver thread...");
-    for info in &mut signals {
-        debug!("App: ----Signal Received {:?}----\n", info);
-        match info.signal {
-            SIGHUP => {}
-            SIGUSR1 => {
-                app.handle_protocol();
-            }
-            sig => {
-                if TERM_SIGNALS.contains(&sig) {
-                    info!("App: terminating...");
-                    break;
-                }
-                app.handle_irq(sig);
-            }
-        }
-    }
-    info!("App: closing app/TCP retransmission thread...");
-    app_sender.send(()).unwrap();
-    tcp_sender.send(()).unwrap();
-    app.close_sockets();
+    // Till a termination signal
+    app.join();
+    info!("App: closing app/control thread...");
     app_join.join().unwrap();
-    tcp_join.join().unwrap();
-    info!("App: closed app/TCP retransmission thread.");
+    control_join.join().unwrap();
+    info!("App: closed app/control thread.");
     Ok(())
 }
@@ -1,14 +1,26 @@
 use super::ip::{IPAdress, IPInterface, IP_ADDR_LEN};
 use super::{ProtocolContexts, ProtocolType};
-use crate::protocols::ip::ip_addr_to_str;
+use crate::protocols::ip::{ip_addr_to_bytes, ip_addr_to_str};
 use crate::{
-    devices::{ethernet::ETH_ADDR_LEN, NetDevice, NetDeviceType},
+    devices::{
+        ethernet::{VlanTag, ETH_ADDR_LEN},
+        NetDevice, NetDeviceType, NetDevices,
+    },
     net::NetInterfaceFamily,
-    utils::byte::{be_to_le_u16, le_to_be_u16},
+    timer::{self, TimerQueue},
+    utils::byte::{be_to_le_u16, le_to_be_u16, le_to_be_u32},
     utils::{bytes_to_struct, to_u8_slice},
 };
 use log::{debug, error, info, trace, warn};
-use std::{collections::HashMap, convert::TryInto, sync::Arc, time::SystemTime};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{
+        mpsc::{self, Sender},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
 
 const ARP_HW_SPACE_ETHER: u16 = 0x0001;
 const ARP_PROTO_SPACE_IP: u16 = 0x0800;
@@ -16,12 +28,18 @@ const ARP_OP_REQUEST: u16 = 0x0001;
 const ARP_OP_REPLY: u16 = 0x0002;
 
 const ARP_CACHE_TIMEOUT_SECS: u64 = 60 * 60 * 4; // timeout: 4hr
+const ARP_PENDING_QUEUE_LEN: usize = 32; // datagrams held per next hop till resolution
+const ARP_REQUEST_RETRIES: u8 = 3;
+const ARP_REQUEST_INTERVAL_SECS: u64 = 1;
+const ARP_FAILED_TIMEOUT_SECS: u64 = 20; // resolution of a failed IP is not retried till timeout
+const ARP_PROBE_MISSES: u8 = 3; // unanswered probes before a gateway is considered down
 
-#[derive(PartialEq, Eq, Hash)]
+#[derive(PartialEq, Eq, Hash, Debug)]
 enum ArpTableEntryState {
-    Incomplete,
+    Incomplete, // request sent and waiting for reply
     Resolved,
     Static,
+    Failed, // no reply after retries
 }
 
 #[derive(PartialEq, Eq, Hash)]
@@ -29,26 +47,120 @@ pub struct ArpTableEntry {
     state: ArpTableEntryState,
     proto_address: IPAdress,
     hw_address: [u8; ETH_ADDR_LEN],
-    timestamp: SystemTime,
+    timestamp: SystemTime, // time of update or last request when incomplete
+    retries: u8,
+}
+
+impl ArpTableEntry {
+    fn expired(&self) -> bool {
+        self.state == ArpTableEntryState::Resolved
+            && timer::since(self.timestamp).as_secs() > ARP_CACHE_TIMEOUT_SECS
+    }
+}
+
+/// ARP reply from an IP watched with `ArpTable::watch`.
+#[derive(Debug, Clone, Copy)]
+pub struct ArpReply {
+    pub hw_address: [u8; ETH_ADDR_LEN],
+    pub received_at: SystemTime,
 }
 
 pub struct ArpTable {
     entries: HashMap<IPAdress, ArpTableEntry>,
+    pending: HashMap<IPAdress, VecDeque<Vec<u8>>>, // IP datagrams waiting for ARP reply
+    watchers: HashMap<IPAdress, Sender<ArpReply>>, // replies reported e.g. to arping
+    pub evicted: u64,                              // resolved entries removed on timeout
+    timers: TimerQueue<IPAdress>,                  // next deadline of each entry
 }
 
 impl ArpTable {
     pub fn new() -> ArpTable {
         ArpTable {
             entries: HashMap::<IPAdress, ArpTableEntry>::new(),
+            pending: HashMap::<IPAdress, VecDeque<Vec<u8>>>::new(),
+            watchers: HashMap::new(),
+            evicted: 0,
+            timers: TimerQueue::new(),
         }
     }
 
+    fn insert(&mut self, entry: ArpTableEntry) {
+        let ip = entry.proto_address;
+        self.entries.insert(ip, entry);
+        self.arm(ip);
+    }
+
+    /// Schedules the next deadline of an entry: request retransmission while incomplete,
+    /// removal after failure and eviction once resolved. Static entries have none.
+    fn arm(&mut self, ip: IPAdress) {
+        let timeout = match self.entries.get(&ip).map(|entry| &entry.state) {
+            Some(ArpTableEntryState::Incomplete) => ARP_REQUEST_INTERVAL_SECS,
+            Some(ArpTableEntryState::Failed) => ARP_FAILED_TIMEOUT_SECS,
+            // expired strictly after the cache timeout
+            Some(ArpTableEntryState::Resolved) => ARP_CACHE_TIMEOUT_SECS + 1,
+            Some(ArpTableEntryState::Static) | None => {
+                self.timers.cancel(ip);
+                return;
+            }
+        };
+        let timestamp = self.entries[&ip].timestamp;
+        self.timers
+            .schedule(ip, timestamp + Duration::from_secs(timeout));
+    }
+
+    /// Reports ARP replies from an IP till `unwatch`, replacing the previous watcher of it.
+    pub fn watch(&mut self, ip: IPAdress) -> mpsc::Receiver<ArpReply> {
+        let (sender, receiver) = mpsc::channel();
+        self.watchers.insert(ip, sender);
+        receiver
+    }
+
+    pub fn unwatch(&mut self, ip: IPAdress) {
+        self.watchers.remove(&ip);
+    }
+
+    fn replied(&mut self, ip: IPAdress, hw_address: [u8; ETH_ADDR_LEN]) {
+        let Some(watcher) = self.watchers.get(&ip) else {
+            return;
+        };
+        let reply = ArpReply {
+            hw_address,
+            received_at: timer::now(),
+        };
+        if watcher.send(reply).is_err() {
+            self.watchers.remove(&ip);
+        }
+    }
+
+    /// Holds an IP datagram till the hardware address of the next hop is resolved.
+    /// The oldest datagram is dropped when the queue of the next hop is full.
+    pub fn enqueue(&mut self, next_hop: IPAdress, data: Vec<u8>) {
+        let queue = self.pending.entry(next_hop).or_default();
+        if queue.len() >= ARP_PENDING_QUEUE_LEN {
+            warn!(
+                "ARP: pending queue for IP = {} is full. Dropping oldest datagram...",
+                ip_addr_to_str(next_hop)
+            );
+            queue.pop_front();
+        }
+        queue.push_back(data);
+    }
+
+    /// Takes IP datagrams waiting for the next hop.
+    pub fn take_pending(&mut self, next_hop: IPAdress) -> VecDeque<Vec<u8>> {
+        self.pending.remove(&next_hop).unwrap_or_default()
+    }
+
     pub fn get(&mut self, ip: IPAdress) -> Option<[u8; 6]> {
         let map_entry = self.entries.get(&ip);
         if let Some(entry) = map_entry {
-            let dur = entry.timestamp.elapsed().unwrap();
-            if dur.as_secs() > ARP_CACHE_TIMEOUT_SECS {
-                self.entries.remove(&ip);
+            if entry.state == ArpTableEntryState::Incomplete
+                || entry.state == ArpTableEntryState::Failed
+            {
+                return None;
+            }
+            if entry.expired() {
+                self.evict(ip);
                 return None;
             } else {
                 return Some(entry.hw_address);
@@ -59,21 +171,187 @@ impl ArpTable {
 
     pub fn update(&mut self, ip: IPAdress, resolved: [u8; ETH_ADDR_LEN]) {
         let map_entry = self.entries.get(&ip);
-        if let Some(_entry) = map_entry {
+        if let Some(entry) = map_entry {
+            if entry.state == ArpTableEntryState::Static {
+                debug!("ARP: static entry for IP = {} kept.", ip_addr_to_str(ip));
+                return;
+            }
             self.entries.remove(&ip);
         }
-        self.entries.insert(
-            ip,
-            ArpTableEntry {
-                state: ArpTableEntryState::Resolved,
-                proto_address: ip,
-                hw_address: resolved,
-                timestamp: SystemTime::now(),
-            },
+        self.insert(ArpTableEntry {
+            state: ArpTableEntryState::Resolved,
+            proto_address: ip,
+            hw_address: resolved,
+            timestamp: timer::now(),
+            retries: 0,
+        });
+    }
+
+    fn evict(&mut self, ip: IPAdress) {
+        if let Some(entry) = self.entries.remove(&ip) {
+            self.evicted += 1;
+            info!(
+                "ARP: evicted expired entry IP = {} HW Addr = {:x?} (evicted: {})",
+                ip_addr_to_str(ip),
+                entry.hw_address,
+                self.evicted
+            );
+        }
+    }
+
+    /// Removes an entry of an IP, or all entries but static ones, dropping datagrams waiting
+    /// for them. Returns the number of entries removed.
+    pub fn flush(&mut self, ip: Option<IPAdress>) -> usize {
+        let flushed: Vec<IPAdress> = match ip {
+            Some(ip) => self.entries.keys().filter(|k| **k == ip).copied().collect(),
+            None => self
+                .entries
+                .iter()
+                .filter(|(_, entry)| entry.state != ArpTableEntryState::Static)
+                .map(|(ip, _)| *ip)
+                .collect(),
+        };
+        for ip in flushed.iter() {
+            self.entries.remove(ip);
+            self.pending.remove(ip);
+        }
+        info!("ARP: flushed {} entries.", flushed.len());
+        flushed.len()
+    }
+
+    pub fn contains(&self, ip: IPAdress) -> bool {
+        self.entries.contains_key(&ip)
+    }
+
+    /// Adds an entry which never expires and is never overwritten by ARP messages.
+    pub fn add_static(&mut self, ip: IPAdress, hw_address: [u8; ETH_ADDR_LEN]) {
+        info!(
+            "ARP: static entry for IP = {} HW Addr is {:x?}",
+            ip_addr_to_str(ip),
+            hw_address
         );
+        self.insert(ArpTableEntry {
+            state: ArpTableEntryState::Static,
+            proto_address: ip,
+            hw_address,
+            timestamp: timer::now(),
+            retries: 0,
+        });
     }
 }
 
+#[derive(Default)]
+struct GatewayLiveness {
+    probed_at: Option<SystemTime>, // probe waiting for reply
+    last_probe: Option<SystemTime>,
+    missed: u8,
+    down: bool,
+    down_events: u64,
+}
+
+/// Liveness of gateways of routes probed with ARP requests, unicast to the known HW address,
+/// at intervals. Routes via a gateway missing probes in a row are marked down till it answers.
+pub struct GatewayMonitor {
+    pub interval: Option<Duration>, // None disables probing
+    gateways: HashMap<IPAdress, GatewayLiveness>,
+}
+
+impl GatewayMonitor {
+    pub fn new() -> GatewayMonitor {
+        GatewayMonitor {
+            interval: None,
+            gateways: HashMap::new(),
+        }
+    }
+
+    /// Records an ARP message from an IP. Returns true when it is a gateway down till now.
+    fn heard(&mut self, ip: IPAdress) -> bool {
+        let Some(gateway) = self.gateways.get_mut(&ip) else {
+            return false;
+        };
+        gateway.probed_at = None;
+        gateway.missed = 0;
+        let was_down = gateway.down;
+        gateway.down = false;
+        was_down
+    }
+}
+
+impl fmt::Display for GatewayMonitor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.gateways.is_empty() {
+            return Ok(());
+        }
+        writeln!(f, "gateways:")?;
+        let mut gateways: Vec<(&IPAdress, &GatewayLiveness)> = self.gateways.iter().collect();
+        gateways.sort_by_key(|(ip, _)| le_to_be_u32(**ip));
+        for (ip, gateway) in gateways {
+            writeln!(
+                f,
+                "  {} {} missed probes: {} down events: {}",
+                ip_addr_to_str(*ip),
+                if gateway.down { "down" } else { "up" },
+                gateway.missed,
+                gateway.down_events
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ArpTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<16} {:<18} {:<11} {:>8}",
+            "IP", "HW Addr", "State", "Age(s)"
+        )?;
+        let mut entries: Vec<&ArpTableEntry> = self.entries.values().collect();
+        entries.sort_by_key(|entry| le_to_be_u32(entry.proto_address));
+        for entry in entries {
+            writeln!(
+                f,
+                "{:<16} {:<18} {:<11} {:>8}",
+                ip_addr_to_str(entry.proto_address),
+                hw_addr_to_str(&entry.hw_address),
+                format!("{:?}", entry.state),
+                timer::since(entry.timestamp).as_secs()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats a hardware address like `02:00:00:00:00:01`.
+pub fn hw_addr_to_str(hw_addr: &[u8]) -> String {
+    hw_addr
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+/// Parses a hardware address like `02:00:00:00:00:01`.
+pub fn hw_addr_from_str(addr: &str) -> Option<[u8; ETH_ADDR_LEN]> {
+    let mut hw_addr = [0; ETH_ADDR_LEN];
+    let mut parts = addr.split(':');
+    for byte in hw_addr.iter_mut() {
+        *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(hw_addr)
+}
+
+/// Parses a static entry of `<IP>=<MAC>` or `<IP> <MAC>` (a line of an ARP file).
+pub fn static_entry_from_str(entry: &str) -> Option<(IPAdress, [u8; ETH_ADDR_LEN])> {
+    let (ip, hw_addr) = entry
+        .trim()
+        .split_once(|c: char| c == '=' || c.is_whitespace())?;
+    Some((ip_addr_to_bytes(ip)?, hw_addr_from_str(hw_addr.trim())?))
+}
+
 #[repr(packed)]
 struct ArpHeader {
     hw_addr_space: u16,    // Hardware address space: 0x0001 for Ethernet
@@ -96,6 +374,20 @@ pub fn arp_request(
     device: &mut NetDevice,
     interface: Arc<IPInterface>,
     target_ip: IPAdress,
+) -> Result<(), ()> {
+    let broadcast = device
+        .broadcast
+        .ethernet()
+        .expect("ARP: request failure with broadcast address.");
+    arp_request_to(device, interface, target_ip, broadcast)
+}
+
+/// Sends an ARP request to a HW address, unicast to verify a known neighbor.
+fn arp_request_to(
+    device: &mut NetDevice,
+    interface: Arc<IPInterface>,
+    target_ip: IPAdress,
+    dst: [u8; ETH_ADDR_LEN],
 ) -> Result<(), ()> {
     let request_header = ArpHeader {
         hw_addr_space: le_to_be_u16(ARP_HW_SPACE_ETHER),
@@ -106,33 +398,63 @@ pub fn arp_request(
     };
     let request_msg = ArpMessage {
         header: request_header,
-        sender_hw_addr: device.address[..6]
-            .try_into()
+        sender_hw_addr: device
+            .address
+            .ethernet()
             .expect("ARP: request failure with sender hw address."),
-        sender_proto_addr: interface.unicast.to_le_bytes(),
+        sender_proto_addr: interface.unicast.to_ne_bytes(),
         target_hw_addr: [0; 6],
-        target_proto_addr: target_ip.to_le_bytes(),
+        target_proto_addr: target_ip.to_ne_bytes(),
     };
     let data = unsafe { to_u8_slice::<ArpMessage>(&request_msg) };
     let ip_str = ip_addr_to_str(target_ip);
     info!("ARP: sending ARP request for IP: {ip_str}");
     trace!("ARP: data = {:x?}", data);
-    device.transmit(
+    device.transmit_on_vlan(
         ProtocolType::Arp,
         data.to_vec(),
         data.len(),
-        device.broadcast[..6]
-            .try_into()
-            .expect("ARP: reply failure with broadcast address."),
+        dst,
+        interface.vlan,
     )
 }
 
+/// Sends an ARP request for an IP on a subnet of the device regardless of the ARP table, e.g.
+/// to check reachability on the link. Fails for IPs of other subnets.
+pub fn arping(device: &mut NetDevice, target_ip: IPAdress) -> Result<(), ()> {
+    let interface = device
+        .interfaces
+        .iter()
+        .find(|iface| {
+            iface.interface.family == NetInterfaceFamily::IP
+                && iface.unicast != target_ip
+                && iface.cidr().contains(target_ip)
+        })
+        .cloned()
+        .ok_or(())?;
+    arp_request(device, interface, target_ip)
+}
+
+/// Sends gratuitous ARP announcing the address of an interface: a request targeting itself.
+pub fn arp_announce(device: &mut NetDevice, interface: Arc<IPInterface>) -> Result<(), ()> {
+    info!(
+        "ARP: announcing IP: {} on device: {}",
+        ip_addr_to_str(interface.unicast),
+        device.name
+    );
+    let unicast = interface.unicast;
+    arp_request(device, interface, unicast)
+}
+
+/// Sends a reply resolving `sender_ip` to the device address: an address of an interface, or
+/// an address answered by proxy. Replies are tagged for the VLAN of the request.
 pub fn arp_reply(
     device: &mut NetDevice,
-    interface: Arc<IPInterface>,
+    sender_ip: IPAdress,
     target_hw_addr: [u8; ETH_ADDR_LEN],
     target_ip: IPAdress,
     destination_hw_addr: [u8; ETH_ADDR_LEN],
+    vlan: Option<VlanTag>,
 ) -> Result<(), ()> {
     let reply_header = ArpHeader {
         hw_addr_space: le_to_be_u16(ARP_HW_SPACE_ETHER),
@@ -144,29 +466,34 @@ pub fn arp_reply(
 
     let reply_msg = ArpMessage {
         header: reply_header,
-        sender_hw_addr: device.address[..6]
-            .try_into()
+        sender_hw_addr: device
+            .address
+            .ethernet()
             .expect("ARP: reply failure with sender hw address."),
-        sender_proto_addr: interface.unicast.to_le_bytes(),
+        sender_proto_addr: sender_ip.to_ne_bytes(),
         target_hw_addr,
-        target_proto_addr: target_ip.to_le_bytes(),
+        target_proto_addr: target_ip.to_ne_bytes(),
     };
 
     let data = unsafe { to_u8_slice::<ArpMessage>(&reply_msg) };
     let ip_str = ip_addr_to_str(target_ip);
     info!("ARP: sending ARP reply to IP: {ip_str}");
     trace!("ARP: data = {:x?}", data);
-    device.transmit(
+    device.transmit_on_vlan(
         ProtocolType::Arp,
         data.to_vec(),
         data.len(),
         destination_hw_addr,
+        vlan,
     )
 }
 
+/// Handles an ARP message of a frame tagged for a VLAN id if any: only interfaces of the VLAN
+/// answer it.
 pub fn input(
     data: &[u8],
     _len: usize,
+    vlan: Option<u16>,
     device: &mut NetDevice,
     contexts: &mut ProtocolContexts,
 ) -> Result<(), ()> {
@@ -194,42 +521,106 @@ pub fn input(
         return Err(());
     }
 
-    let target_ip = unsafe { bytes_to_struct::<u32>(&msg.target_proto_addr) };
-    let interface = device.get_interface(NetInterfaceFamily::IP).unwrap();
-    if interface.unicast != target_ip {
-        warn!(
-            "ARP: input target IP = {:?} not matching with interface unicast IP: {:?}",
-            ip_addr_to_str(target_ip),
-            ip_addr_to_str(interface.unicast)
-        );
-    } else {
+    let sender_ip = u32::from_ne_bytes(msg.sender_proto_addr);
+    let target_ip = u32::from_ne_bytes(msg.target_proto_addr);
+    let ip_str = ip_addr_to_str(sender_ip);
+
+    if be_to_le_u16(msg.header.op) == ARP_OP_REPLY {
+        contexts.arp_table.replied(sender_ip, msg.sender_hw_addr);
+    }
+    if contexts.gateway_monitor.heard(sender_ip) {
+        info!("ARP: gateway IP = {ip_str} answering again. Marking its routes up...");
+        contexts.ip_routes.set_gateway_down(sender_ip, false);
+    }
+
+    // Only interfaces of the receiving device answer (RFC 826 packet reception).
+    let interface = device
+        .interfaces
+        .iter()
+        .find(|iface| {
+            iface.interface.family == NetInterfaceFamily::IP
+                && iface.vlan_id() == vlan
+                && iface.unicast == target_ip
+        })
+        .cloned();
+    let tag = device.get_vlan_interface(vlan).and_then(|iface| iface.vlan);
+    let merged = contexts.arp_table.contains(sender_ip);
+    if merged || interface.is_some() {
         // Update or insert ARP Table with sender addresses
-        let sender_ip = unsafe { bytes_to_struct::<u32>(&msg.sender_proto_addr) };
         contexts.arp_table.update(sender_ip, msg.sender_hw_addr);
-        let ip_str = ip_addr_to_str(sender_ip);
-
         info!(
-            "ARP: received ARP response for IP = {ip_str} HW Addr is {:x?}",
+            "ARP: received ARP message from IP = {ip_str} HW Addr is {:x?}",
             msg.sender_hw_addr
         );
 
-        // Reply in case of ARP Request
-        if be_to_le_u16(msg.header.op) == ARP_OP_REQUEST {
-            let sender_ip = unsafe { bytes_to_struct::<u32>(&msg.sender_proto_addr) };
-            info!("ARP: replying ARP...");
-            return arp_reply(
-                device,
-                interface,
-                msg.sender_hw_addr,
-                sender_ip,
-                msg.sender_hw_addr,
-            );
+        // Flush datagrams waiting for the resolution
+        for ip_data in contexts.arp_table.take_pending(sender_ip) {
+            info!("ARP: sending pending datagram to IP = {ip_str}");
+            let len = ip_data.len();
+            if device
+                .transmit_on_vlan(ProtocolType::IP, ip_data, len, msg.sender_hw_addr, tag)
+                .is_err()
+            {
+                error!("ARP: failed to send pending datagram to IP = {ip_str}");
+            }
         }
     }
 
+    if interface.is_none() && !proxies(device, contexts, vlan, sender_ip, target_ip) {
+        debug!(
+            "ARP: input target IP = {:?} is not on device: {}",
+            ip_addr_to_str(target_ip),
+            device.name
+        );
+        return Ok(());
+    }
+
+    // Reply in case of ARP Request
+    if be_to_le_u16(msg.header.op) == ARP_OP_REQUEST {
+        info!("ARP: replying ARP...");
+        return arp_reply(
+            device,
+            target_ip,
+            msg.sender_hw_addr,
+            sender_ip,
+            msg.sender_hw_addr,
+            tag,
+        );
+    }
+
     Ok(())
 }
 
+/// Whether a proxy-ARP interface of the device answers for the target: the sender is on the
+/// subnet of the interface and the target is routed via another interface.
+fn proxies(
+    device: &NetDevice,
+    contexts: &ProtocolContexts,
+    vlan: Option<u16>,
+    sender_ip: IPAdress,
+    target_ip: IPAdress,
+) -> bool {
+    let route = match contexts.ip_routes.lookup_ip_route(target_ip) {
+        Some(route) => route,
+        None => return false,
+    };
+    let proxied = device.interfaces.iter().any(|iface| {
+        iface.interface.family == NetInterfaceFamily::IP
+            && iface.proxy_arp()
+            && iface.vlan_id() == vlan
+            && iface.cidr().contains(sender_ip)
+            && !Arc::ptr_eq(&route.interface, iface)
+    });
+    if proxied {
+        info!(
+            "ARP: answering for IP = {} by proxy on device: {}",
+            ip_addr_to_str(target_ip),
+            device.name
+        );
+    }
+    proxied
+}
+
 pub fn arp_resolve(
     device: &mut NetDevice,
     interface: Arc<IPInterface>,
@@ -243,10 +634,599 @@ pub fn arp_resolve(
     if let Some(hw_addr) = arp_table.get(target_ip) {
         let ip_str = ip_addr_to_str(target_ip);
         debug!("ARP: resolved for IP = {ip_str} HW Addr is {:x?}", hw_addr);
-        Ok(Some(hw_addr))
-    } else if arp_request(device, interface, target_ip).is_ok() {
-        Ok(None)
-    } else {
-        Err(())
+        return Ok(Some(hw_addr));
+    }
+    if device.static_neighbors {
+        debug!(
+            "ARP: no static entry for IP = {} on {}, not resolving.",
+            ip_addr_to_str(target_ip),
+            device.name
+        );
+        return Err(());
+    }
+    match arp_table.entries.get(&target_ip).map(|entry| &entry.state) {
+        // Requests are retransmitted by timer.
+        Some(ArpTableEntryState::Incomplete) => Ok(None),
+        Some(ArpTableEntryState::Failed) => {
+            debug!(
+                "ARP: resolution failed for IP = {}",
+                ip_addr_to_str(target_ip)
+            );
+            Err(())
+        }
+        _ => {
+            arp_table.insert(ArpTableEntry {
+                state: ArpTableEntryState::Incomplete,
+                proto_address: target_ip,
+                hw_address: [0; ETH_ADDR_LEN],
+                timestamp: timer::now(),
+                retries: 0,
+            });
+            arp_request(device, interface, target_ip).map(|_| None)
+        }
+    }
+}
+
+/// Retransmits requests of incomplete entries at intervals. Entries without reply after
+/// retries fail dropping datagrams waiting for them, and are removed after a while so that
+/// resolution is tried again. Resolved entries past the cache timeout are evicted. Only
+/// entries with a deadline due in the timer queue are visited.
+pub fn timer(device: &mut NetDevice, arp_table: &mut ArpTable) {
+    let Some(interface) = device.get_interface(NetInterfaceFamily::IP) else {
+        expire(arp_table, false);
+        return;
+    };
+    for ip in expire(arp_table, true) {
+        retransmit_request(device, interface.clone(), ip);
+    }
+}
+
+/// Same as timer on a stack of several devices, retransmitting each request out of the device
+/// with an interface on the subnet of its IP.
+pub fn timer_on_devices(devices: &mut NetDevices, arp_table: &mut ArpTable) {
+    for ip in expire(arp_table, true) {
+        let out = devices.entries.iter_mut().find_map(|device| {
+            let interface = device
+                .interfaces
+                .iter()
+                .find(|iface| iface.cidr().contains(ip))?
+                .clone();
+            Some((device, interface))
+        });
+        if let Some((device, interface)) = out {
+            retransmit_request(device, interface, ip);
+        }
+    }
+}
+
+/// Visits entries due, returning IPs of requests to retransmit. Incomplete entries are left as
+/// they are when requests cannot be sent.
+fn expire(arp_table: &mut ArpTable, can_request: bool) -> Vec<IPAdress> {
+    let mut requests = vec![];
+    let mut failed = vec![];
+    for ip in arp_table.timers.expired(timer::now()) {
+        let Some(entry) = arp_table.entries.get_mut(&ip) else {
+            continue;
+        };
+        let elapsed = timer::since(entry.timestamp).as_secs();
+        match entry.state {
+            ArpTableEntryState::Resolved if entry.expired() => {
+                arp_table.evict(ip);
+                continue;
+            }
+            ArpTableEntryState::Incomplete
+                if can_request && elapsed >= ARP_REQUEST_INTERVAL_SECS =>
+            {
+                entry.timestamp = timer::now();
+                if entry.retries < ARP_REQUEST_RETRIES {
+                    entry.retries += 1;
+                    requests.push(ip);
+                } else {
+                    error!(
+                        "ARP: no reply for IP = {} after {} retries.",
+                        ip_addr_to_str(ip),
+                        ARP_REQUEST_RETRIES
+                    );
+                    entry.state = ArpTableEntryState::Failed;
+                    failed.push(ip);
+                }
+            }
+            ArpTableEntryState::Failed if elapsed >= ARP_FAILED_TIMEOUT_SECS => {
+                arp_table.entries.remove(&ip);
+                continue;
+            }
+            _ => {}
+        }
+        arp_table.arm(ip);
+    }
+    for ip in failed {
+        let dropped = arp_table.take_pending(ip).len();
+        warn!(
+            "ARP: dropped {dropped} datagrams waiting for IP = {}",
+            ip_addr_to_str(ip)
+        );
+    }
+    requests
+}
+
+fn retransmit_request(device: &mut NetDevice, interface: Arc<IPInterface>, ip: IPAdress) {
+    info!(
+        "ARP: retransmitting request for IP = {}",
+        ip_addr_to_str(ip)
+    );
+    if arp_request(device, interface, ip).is_err() {
+        error!("ARP: failed to retransmit request.");
+    }
+}
+
+/// Probes gateways of routes on the device at the interval of the monitor, when enabled.
+/// Routes via a gateway are marked down when probes go unanswered `ARP_PROBE_MISSES` times in
+/// a row, a probe being retried every `ARP_REQUEST_INTERVAL_SECS` after a miss.
+pub fn monitor_gateways(device: &mut NetDevice, contexts: &mut ProtocolContexts) {
+    let Some(interval) = contexts.gateway_monitor.interval else {
+        return;
+    };
+    let retry = Duration::from_secs(ARP_REQUEST_INTERVAL_SECS);
+    let gateways = contexts.ip_routes.gateways();
+    let monitor = &mut contexts.gateway_monitor;
+    monitor
+        .gateways
+        .retain(|ip, _| gateways.iter().any(|(gateway, _)| gateway == ip));
+    for (ip, interface) in gateways {
+        if !device
+            .interfaces
+            .iter()
+            .any(|iface| Arc::ptr_eq(iface, &interface))
+        {
+            continue;
+        }
+        let gateway = monitor.gateways.entry(ip).or_default();
+        let elapsed = |time: Option<SystemTime>| time.map_or(Duration::MAX, timer::since);
+        if gateway.probed_at.is_some() && elapsed(gateway.probed_at) >= retry {
+            gateway.probed_at = None;
+            gateway.missed = gateway.missed.saturating_add(1);
+            if gateway.missed >= ARP_PROBE_MISSES && !gateway.down {
+                gateway.down = true;
+                gateway.down_events += 1;
+                warn!(
+                    "ARP: gateway IP = {} missed {} probes. Marking its routes down...",
+                    ip_addr_to_str(ip),
+                    gateway.missed
+                );
+                contexts.ip_routes.set_gateway_down(ip, true);
+            }
+        }
+        let due = if gateway.missed > 0 { retry } else { interval };
+        if gateway.probed_at.is_some() || elapsed(gateway.last_probe) < due {
+            continue;
+        }
+        gateway.probed_at = Some(timer::now());
+        gateway.last_probe = gateway.probed_at;
+        let dst = match contexts.arp_table.entries.get(&ip) {
+            Some(entry)
+                if entry.state == ArpTableEntryState::Resolved
+                    || entry.state == ArpTableEntryState::Static =>
+            {
+                entry.hw_address
+            }
+            _ => device
+                .broadcast
+                .ethernet()
+                .expect("ARP: probe failure with broadcast address."),
+        };
+        debug!("ARP: probing gateway IP = {}", ip_addr_to_str(ip));
+        if arp_request_to(device, interface, ip, dst).is_err() {
+            error!("ARP: failed to probe gateway.");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        arp_resolve, arping, input, monitor_gateways, static_entry_from_str, timer, ArpHeader,
+        ArpMessage, ArpTable, ArpTableEntryState, ARP_CACHE_TIMEOUT_SECS, ARP_FAILED_TIMEOUT_SECS,
+        ARP_HW_SPACE_ETHER, ARP_OP_REPLY, ARP_OP_REQUEST, ARP_PROBE_MISSES, ARP_PROTO_SPACE_IP,
+        ARP_REQUEST_INTERVAL_SECS, ARP_REQUEST_RETRIES,
+    };
+    use crate::{
+        devices::{
+            ethernet::{self, VlanTag, ETH_ADDR_LEN, ETH_HDR_SIZE, VLAN_TAG_SIZE},
+            test_device, NetDevice,
+        },
+        drivers::DriverType,
+        net::NetInterfaceFamily,
+        protocols::ip::{
+            self, ip_addr_to_bytes, IPAdress, IPInterface, IPProtocolType, IPRoute, IPRoutes,
+            IP_ADDR_LEN,
+        },
+        protocols::ProtocolContexts,
+        utils::{byte::le_to_be_u16, bytes_to_struct, to_u8_slice},
+    };
+    use std::{sync::Arc, time::Duration};
+
+    fn message(
+        op: u16,
+        sender_hw_addr: [u8; ETH_ADDR_LEN],
+        sender_ip: IPAdress,
+        target_ip: IPAdress,
+    ) -> Vec<u8> {
+        let msg = ArpMessage {
+            header: ArpHeader {
+                hw_addr_space: le_to_be_u16(ARP_HW_SPACE_ETHER),
+                proto_addr_space: le_to_be_u16(ARP_PROTO_SPACE_IP),
+                hw_addr_len: ETH_ADDR_LEN as u8,
+                proto_addr_len: IP_ADDR_LEN as u8,
+                op: le_to_be_u16(op),
+            },
+            sender_hw_addr,
+            sender_proto_addr: sender_ip.to_ne_bytes(),
+            target_hw_addr: [0; ETH_ADDR_LEN],
+            target_proto_addr: target_ip.to_ne_bytes(),
+        };
+        unsafe { to_u8_slice(&msg) }.to_vec()
+    }
+
+    #[test]
+    fn test_reply_per_device() {
+        let mut device_a = test_device("192.0.2.2/24");
+        let mut device_b = test_device("198.51.100.2/24");
+        device_b.name = String::from("tap1");
+        let mut contexts = ProtocolContexts::new(IPRoutes::new());
+        let peer_hw_addr = [0x02, 0x00, 0x00, 0x00, 0x00, 0x01];
+        let peer_ip = ip_addr_to_bytes("192.0.2.1").unwrap();
+        let target_ip = ip_addr_to_bytes("192.0.2.2").unwrap();
+        let request = message(ARP_OP_REQUEST, peer_hw_addr, peer_ip, target_ip);
+
+        // Device of the other subnet neither replies nor learns the sender.
+        input(&request, request.len(), None, &mut device_b, &mut contexts).unwrap();
+        assert!(device_b.driver_data.as_ref().unwrap().tx_frames.is_empty());
+        assert!(!contexts.arp_table.contains(peer_ip));
+
+        input(&request, request.len(), None, &mut device_a, &mut contexts).unwrap();
+        let frame = device_a
+            .driver_data
+            .as_mut()
+            .unwrap()
+            .tx_frames
+            .pop_front()
+            .unwrap();
+        assert_eq!(peer_hw_addr, frame[..ETH_ADDR_LEN]);
+        let reply = unsafe { bytes_to_struct::<ArpMessage>(&frame[ETH_HDR_SIZE..]) };
+        assert_eq!(target_ip.to_ne_bytes(), reply.sender_proto_addr);
+        assert_eq!(device_a.address.as_bytes(), reply.sender_hw_addr);
+        assert_eq!(Some(peer_hw_addr), contexts.arp_table.get(peer_ip));
+    }
+
+    #[test]
+    fn test_reply_on_vlan() {
+        let mut device = test_device("192.0.2.2/24");
+        let tag = VlanTag::new(10, 3).unwrap();
+        let vlan_interface = IPInterface::new("198.51.100.2/24").with_vlan(tag);
+        device.register_interface(Arc::new(vlan_interface));
+        let tx_frames = |device: &mut NetDevice| {
+            std::mem::take(&mut device.driver_data.as_mut().unwrap().tx_frames)
+        };
+        tx_frames(&mut device);
+        let mut contexts = ProtocolContexts::new(IPRoutes::new());
+        let peer_hw_addr = [0x02, 0x00, 0x00, 0x00, 0x00, 0x01];
+        let peer_ip = ip_addr_to_bytes("198.51.100.1").unwrap();
+        let target_ip = ip_addr_to_bytes("198.51.100.2").unwrap();
+        let request = message(ARP_OP_REQUEST, peer_hw_addr, peer_ip, target_ip);
+
+        // Untagged requests are not answered by the interface of the VLAN.
+        input(&request, request.len(), None, &mut device, &mut contexts).unwrap();
+        assert!(tx_frames(&mut device).is_empty());
+
+        input(
+            &request,
+            request.len(),
+            Some(10),
+            &mut device,
+            &mut contexts,
+        )
+        .unwrap();
+        let frame = tx_frames(&mut device).pop_front().unwrap();
+        assert_eq!([0x81, 0x00, 0x60, 0x0a, 0x08, 0x06], frame[12..18]);
+        let reply =
+            unsafe { bytes_to_struct::<ArpMessage>(&frame[ETH_HDR_SIZE + VLAN_TAG_SIZE..]) };
+        assert_eq!(target_ip.to_ne_bytes(), reply.sender_proto_addr);
+    }
+
+    #[test]
+    fn test_arping() {
+        let mut device = test_device("192.0.2.2/24");
+        let mut contexts = ProtocolContexts::new(IPRoutes::new());
+        let peer_hw_addr = [0x02, 0x00, 0x00, 0x00, 0x00, 0x01];
+        let peer_ip = ip_addr_to_bytes("192.0.2.1").unwrap();
+        let own_ip = ip_addr_to_bytes("192.0.2.2").unwrap();
+        assert!(arping(&mut device, ip_addr_to_bytes("198.51.100.1").unwrap()).is_err());
+        let replies = contexts.arp_table.watch(peer_ip);
+
+        arping(&mut device, peer_ip).unwrap();
+        let tx_frames = &mut device.driver_data.as_mut().unwrap().tx_frames;
+        let frame = tx_frames.pop_front().unwrap();
+        assert_eq!([0xff; ETH_ADDR_LEN], frame[..ETH_ADDR_LEN]);
+        let request = unsafe { bytes_to_struct::<ArpMessage>(&frame[ETH_HDR_SIZE..]) };
+        assert_eq!(peer_ip.to_ne_bytes(), request.target_proto_addr);
+
+        // Requests of the peer are not replies.
+        let request = message(ARP_OP_REQUEST, peer_hw_addr, peer_ip, own_ip);
+        input(&request, request.len(), None, &mut device, &mut contexts).unwrap();
+        assert!(replies.try_recv().is_err());
+        let reply = message(ARP_OP_REPLY, peer_hw_addr, peer_ip, own_ip);
+        input(&reply, reply.len(), None, &mut device, &mut contexts).unwrap();
+        assert_eq!(peer_hw_addr, replies.try_recv().unwrap().hw_address);
+
+        contexts.arp_table.unwatch(peer_ip);
+        input(&reply, reply.len(), None, &mut device, &mut contexts).unwrap();
+        assert!(replies.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_proxy_arp() {
+        let mut device = test_device("192.0.2.2/24");
+        let interface = device.get_interface(NetInterfaceFamily::IP).unwrap();
+        let other = Arc::new(IPInterface::new("198.51.100.2/24"));
+        let mut routes = IPRoutes::new();
+        routes.register(IPRoute::interface_route(interface.clone()));
+        routes.register(IPRoute::interface_route(other));
+        let mut contexts = ProtocolContexts::new(routes);
+        let peer_hw_addr = [0x02, 0x00, 0x00, 0x00, 0x00, 0x01];
+        let peer_ip = ip_addr_to_bytes("192.0.2.1").unwrap();
+        let remote_ip = ip_addr_to_bytes("198.51.100.5").unwrap();
+        let local_ip = ip_addr_to_bytes("192.0.2.5").unwrap();
+        let request = |target_ip| message(ARP_OP_REQUEST, peer_hw_addr, peer_ip, target_ip);
+
+        input(&request(remote_ip), 28, None, &mut device, &mut contexts).unwrap();
+        assert!(device.driver_data.as_ref().unwrap().tx_frames.is_empty());
+
+        // Hosts on the other segment are answered with our address, local ones are not.
+        interface.set_proxy_arp(true);
+        input(&request(local_ip), 28, None, &mut device, &mut contexts).unwrap();
+        assert!(device.driver_data.as_ref().unwrap().tx_frames.is_empty());
+        input(&request(remote_ip), 28, None, &mut device, &mut contexts).unwrap();
+        let frame = device
+            .driver_data
+            .as_mut()
+            .unwrap()
+            .tx_frames
+            .pop_front()
+            .unwrap();
+        let reply = unsafe { bytes_to_struct::<ArpMessage>(&frame[ETH_HDR_SIZE..]) };
+        assert_eq!(remote_ip.to_ne_bytes(), reply.sender_proto_addr);
+        assert_eq!(device.address.as_bytes(), reply.sender_hw_addr);
+    }
+
+    #[test]
+    fn test_static_entry() {
+        let (ip, hw_addr) = static_entry_from_str("192.0.2.1=02:00:00:00:00:01").unwrap();
+        assert_eq!(ip_addr_to_bytes("192.0.2.1").unwrap(), ip);
+        assert_eq!([0x02, 0, 0, 0, 0, 0x01], hw_addr);
+        assert_eq!(
+            Some((ip, hw_addr)),
+            static_entry_from_str("192.0.2.1  02:00:00:00:00:01")
+        );
+        assert!(static_entry_from_str("192.0.2.1=02:00:00:00:00").is_none());
+        assert!(static_entry_from_str("192.0.2.1=02:00:00:00:00:01:02").is_none());
+
+        // Replies never overwrite static entries.
+        let mut table = ArpTable::new();
+        table.add_static(ip, hw_addr);
+        table.update(ip, [0x02, 0, 0, 0, 0, 0x09]);
+        assert_eq!(Some(hw_addr), table.get(ip));
+    }
+
+    #[test]
+    fn test_static_neighbors() {
+        let mut device = test_device("192.0.2.2/24");
+        device.static_neighbors = true;
+        let interface = device.get_interface(NetInterfaceFamily::IP).unwrap();
+        let mut table = ArpTable::new();
+        let (ip, hw_addr) = static_entry_from_str("192.0.2.1=02:00:00:00:00:01").unwrap();
+        table.add_static(ip, hw_addr);
+
+        let resolved = arp_resolve(&mut device, interface.clone(), &mut table, ip);
+        assert_eq!(Ok(Some(hw_addr)), resolved);
+        // Other next hops fail right away without requests.
+        let unknown = ip_addr_to_bytes("192.0.2.3").unwrap();
+        assert!(arp_resolve(&mut device, interface, &mut table, unknown).is_err());
+        assert!(!table.contains(unknown));
+        assert!(device.driver_data.as_ref().unwrap().tx_frames.is_empty());
+    }
+
+    #[test]
+    fn test_flush() {
+        let mut table = ArpTable::new();
+        let resolved_ip = ip_addr_to_bytes("192.0.2.1").unwrap();
+        let static_ip = ip_addr_to_bytes("192.0.2.3").unwrap();
+        table.update(resolved_ip, [0x02, 0, 0, 0, 0, 0x01]);
+        table.add_static(static_ip, [0x02, 0, 0, 0, 0, 0x03]);
+        table.enqueue(resolved_ip, b"hello".to_vec());
+
+        let text = table.to_string();
+        assert!(text.contains("192.0.2.1        02:00:00:00:00:01  Resolved"));
+        assert!(text.contains("192.0.2.3        02:00:00:00:00:03  Static"));
+
+        // Static entries are flushed only by IP.
+        assert_eq!(1, table.flush(None));
+        assert!(table.take_pending(resolved_ip).is_empty());
+        assert!(table.contains(static_ip));
+        assert_eq!(1, table.flush(Some(static_ip)));
+        assert_eq!(0, table.flush(Some(static_ip)));
+    }
+
+    /// Moves the timestamp of an entry back rescheduling its deadline.
+    fn backdate(table: &mut ArpTable, ip: IPAdress, secs: u64) {
+        table.entries.get_mut(&ip).unwrap().timestamp -= Duration::from_secs(secs);
+        table.arm(ip);
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut device = test_device("192.0.2.2/24");
+        let mut table = ArpTable::new();
+        let resolved_ip = ip_addr_to_bytes("192.0.2.1").unwrap();
+        let static_ip = ip_addr_to_bytes("192.0.2.3").unwrap();
+        table.update(resolved_ip, [0x02, 0, 0, 0, 0, 0x01]);
+        table.add_static(static_ip, [0x02, 0, 0, 0, 0, 0x03]);
+        for ip in [resolved_ip, static_ip] {
+            backdate(&mut table, ip, ARP_CACHE_TIMEOUT_SECS + 1);
+        }
+
+        // Expired entries are pruned by timer without lookups.
+        timer(&mut device, &mut table);
+        assert!(!table.contains(resolved_ip));
+        assert!(table.contains(static_ip));
+        assert_eq!(1, table.evicted);
+        assert!(table.timers.is_empty());
+    }
+
+    #[test]
+    fn test_resolution_timeout() {
+        let mut device = test_device("192.0.2.2/24");
+        let interface = device.get_interface(NetInterfaceFamily::IP).unwrap();
+        let mut routes = IPRoutes::new();
+        routes.register(IPRoute::interface_route(interface.clone()));
+        let mut contexts = ProtocolContexts::new(routes);
+        let peer_ip = ip_addr_to_bytes("192.0.2.1").unwrap();
+        let send = |device: &mut NetDevice, contexts: &mut ProtocolContexts| {
+            ip::output(
+                IPProtocolType::Udp,
+                b"hello".to_vec(),
+                interface.unicast,
+                peer_ip,
+                device,
+                contexts,
+            )
+        };
+
+        send(&mut device, &mut contexts).unwrap();
+        for _ in 0..ARP_REQUEST_RETRIES + 1 {
+            let entry = &contexts.arp_table.entries[&peer_ip];
+            assert_eq!(ArpTableEntryState::Incomplete, entry.state);
+            backdate(&mut contexts.arp_table, peer_ip, ARP_REQUEST_INTERVAL_SECS);
+            timer(&mut device, &mut contexts.arp_table);
+        }
+        let tx_frames = &device.driver_data.as_ref().unwrap().tx_frames;
+        assert_eq!(1 + ARP_REQUEST_RETRIES as usize, tx_frames.len());
+
+        // Failed resolution drops pending datagrams and fails sends till timeout.
+        let entry = contexts.arp_table.entries.get_mut(&peer_ip).unwrap();
+        assert_eq!(ArpTableEntryState::Failed, entry.state);
+        assert!(contexts.arp_table.take_pending(peer_ip).is_empty());
+        assert!(send(&mut device, &mut contexts).is_err());
+
+        backdate(&mut contexts.arp_table, peer_ip, ARP_FAILED_TIMEOUT_SECS);
+        timer(&mut device, &mut contexts.arp_table);
+        assert!(send(&mut device, &mut contexts).is_ok());
+    }
+
+    #[test]
+    fn test_gratuitous_arp_on_bring_up() {
+        let mut device = ethernet::init(1, DriverType::Memory);
+        device.open().unwrap();
+        let interface = Arc::new(IPInterface::new("192.0.2.2/24"));
+        device.register_interface(interface.clone());
+
+        let frame = device
+            .driver_data
+            .as_mut()
+            .unwrap()
+            .tx_frames
+            .pop_front()
+            .unwrap();
+        assert_eq!([0xff; ETH_ADDR_LEN], frame[..ETH_ADDR_LEN]);
+        assert_eq!([0x08, 0x06], frame[12..14]);
+        let msg = unsafe { bytes_to_struct::<ArpMessage>(&frame[ETH_HDR_SIZE..]) };
+        assert_eq!(interface.unicast.to_ne_bytes(), msg.sender_proto_addr);
+        assert_eq!(interface.unicast.to_ne_bytes(), msg.target_proto_addr);
+    }
+
+    #[test]
+    fn test_pending_datagrams_flushed_on_reply() {
+        let mut device = test_device("192.0.2.2/24");
+        let interface = device.get_interface(NetInterfaceFamily::IP).unwrap();
+        let mut routes = IPRoutes::new();
+        routes.register(IPRoute::interface_route(interface.clone()));
+        let mut contexts = ProtocolContexts::new(routes);
+        let peer_ip = ip_addr_to_bytes("192.0.2.1").unwrap();
+        let peer_hw_addr = [0x02, 0x00, 0x00, 0x00, 0x00, 0x01];
+
+        for data in [b"first".to_vec(), b"second".to_vec()] {
+            ip::output(
+                IPProtocolType::Udp,
+                data,
+                interface.unicast,
+                peer_ip,
+                &mut device,
+                &mut contexts,
+            )
+            .unwrap();
+        }
+        // Only an ARP request goes out while the next hop is unresolved.
+        let tx_frames = &mut device.driver_data.as_mut().unwrap().tx_frames;
+        assert_eq!(1, tx_frames.len());
+        tx_frames.clear();
+
+        let reply = message(ARP_OP_REPLY, peer_hw_addr, peer_ip, interface.unicast);
+        input(&reply, reply.len(), None, &mut device, &mut contexts).unwrap();
+
+        let tx_frames = &mut device.driver_data.as_mut().unwrap().tx_frames;
+        assert_eq!(2, tx_frames.len());
+        for (frame, data) in tx_frames.iter().zip([&b"first"[..], &b"second"[..]]) {
+            assert_eq!(peer_hw_addr, frame[..ETH_ADDR_LEN]);
+            assert_eq!([0x08, 0x00], frame[12..14]);
+            assert!(frame[ETH_HDR_SIZE..].windows(data.len()).any(|w| w == data));
+        }
+        assert!(contexts.arp_table.take_pending(peer_ip).is_empty());
+    }
+
+    #[test]
+    fn test_gateway_probe() {
+        let mut device = test_device("192.0.2.2/24");
+        let interface = device.get_interface(NetInterfaceFamily::IP).unwrap();
+        let mut routes = IPRoutes::new();
+        routes.register(IPRoute::interface_route(interface.clone()));
+        routes.register(IPRoute::gateway_route("192.0.2.1", interface.clone()));
+        let mut contexts = ProtocolContexts::new(routes);
+        contexts.gateway_monitor.interval = Some(Duration::from_secs(10));
+        let gateway_ip = ip_addr_to_bytes("192.0.2.1").unwrap();
+        let gateway_hw_addr = [0x02, 0x00, 0x00, 0x00, 0x00, 0x01];
+        contexts.arp_table.update(gateway_ip, gateway_hw_addr);
+        let remote_ip = ip_addr_to_bytes("198.51.100.1").unwrap();
+
+        // Unicast to the HW address known, and not repeated before the interval.
+        monitor_gateways(&mut device, &mut contexts);
+        monitor_gateways(&mut device, &mut contexts);
+        let tx_frames = &mut device.driver_data.as_mut().unwrap().tx_frames;
+        assert_eq!(1, tx_frames.len());
+        assert_eq!(gateway_hw_addr, tx_frames[0][..ETH_ADDR_LEN]);
+        assert_eq!([0x08, 0x06], tx_frames[0][12..14]);
+
+        for _ in 0..ARP_PROBE_MISSES {
+            assert!(contexts.ip_routes.lookup_ip_route(remote_ip).is_some());
+            let gateway = contexts
+                .gateway_monitor
+                .gateways
+                .get_mut(&gateway_ip)
+                .unwrap();
+            *gateway.probed_at.as_mut().unwrap() -= Duration::from_secs(ARP_REQUEST_INTERVAL_SECS);
+            *gateway.last_probe.as_mut().unwrap() -= Duration::from_secs(ARP_REQUEST_INTERVAL_SECS);
+            monitor_gateways(&mut device, &mut contexts);
+        }
+        assert!(contexts.ip_routes.lookup_ip_route(remote_ip).is_none());
+        assert!(contexts
+            .gateway_monitor
+            .to_string()
+            .contains("192.0.2.1 down missed probes: 3 down events: 1"));
+
+        let reply = message(ARP_OP_REPLY, gateway_hw_addr, gateway_ip, interface.unicast);
+        input(&reply, reply.len(), None, &mut device, &mut contexts).unwrap();
+        assert!(contexts.ip_routes.lookup_ip_route(remote_ip).is_some());
+        assert!(contexts
+            .gateway_monitor
+            .to_string()
+            .contains("192.0.2.1 up"));
     }
 }
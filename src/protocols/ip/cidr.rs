@@ -0,0 +1,130 @@
+use super::{ip_addr_to_bytes, ip_addr_to_str, IPAdress};
+use crate::utils::byte::{be_to_le_u32, le_to_be_u32};
+use std::{fmt, str::FromStr};
+
+const PREFIX_LEN_MAX: u8 = 32;
+
+/// Address with a prefix length as in `192.0.2.2/24` (RFC 4632). Bits of the address outside
+/// the prefix are kept, so that it describes an interface address and its subnet as well as a
+/// network.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpCidr {
+    pub address: IPAdress,
+    pub prefix_len: u8,
+}
+
+impl IpCidr {
+    pub fn new(address: IPAdress, prefix_len: u8) -> Option<IpCidr> {
+        if prefix_len > PREFIX_LEN_MAX {
+            return None;
+        }
+        Some(IpCidr {
+            address,
+            prefix_len,
+        })
+    }
+
+    /// From a netmask of leading one bits, e.g. 255.255.255.0.
+    pub fn from_netmask(address: IPAdress, netmask: IPAdress) -> Option<IpCidr> {
+        let host_mask = !be_to_le_u32(netmask);
+        if host_mask & host_mask.wrapping_add(1) != 0 {
+            return None;
+        }
+        IpCidr::new(address, netmask.count_ones() as u8)
+    }
+
+    pub fn netmask(&self) -> IPAdress {
+        match self.prefix_len {
+            0 => 0,
+            len => le_to_be_u32(u32::MAX << (PREFIX_LEN_MAX - len)),
+        }
+    }
+
+    /// Address with the bits outside the prefix cleared.
+    pub fn network(&self) -> IPAdress {
+        self.address & self.netmask()
+    }
+
+    /// Address with the bits outside the prefix set.
+    pub fn broadcast(&self) -> IPAdress {
+        self.network() | !self.netmask()
+    }
+
+    /// Network of the prefix, e.g. `192.0.2.0/24` of `192.0.2.2/24`.
+    pub fn trunc(&self) -> IpCidr {
+        IpCidr {
+            address: self.network(),
+            prefix_len: self.prefix_len,
+        }
+    }
+
+    pub fn contains(&self, address: IPAdress) -> bool {
+        address & self.netmask() == self.network()
+    }
+
+    /// Whether bits of the address outside the prefix are cleared.
+    pub fn is_network(&self) -> bool {
+        self.address == self.network()
+    }
+}
+
+impl FromStr for IpCidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let cidr = s.trim().split_once('/').and_then(|(address, prefix_len)| {
+            IpCidr::new(ip_addr_to_bytes(address)?, prefix_len.parse().ok()?)
+        });
+        cidr.ok_or(format!("invalid CIDR (e.g. 192.0.2.0/24): {s}"))
+    }
+}
+
+impl fmt::Display for IpCidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", ip_addr_to_str(self.address), self.prefix_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IpCidr;
+    use crate::protocols::ip::ip_addr_to_bytes;
+
+    #[test]
+    fn test_cidr() {
+        let addr = |addr: &str| ip_addr_to_bytes(addr).unwrap();
+        let cidr: IpCidr = "192.0.2.130/25".parse().unwrap();
+        assert_eq!(addr("192.0.2.130"), cidr.address);
+        assert_eq!(addr("255.255.255.128"), cidr.netmask());
+        assert_eq!(addr("192.0.2.128"), cidr.network());
+        assert_eq!(addr("192.0.2.255"), cidr.broadcast());
+        assert!(cidr.contains(addr("192.0.2.129")));
+        assert!(!cidr.contains(addr("192.0.2.127")));
+        assert!(!cidr.is_network());
+        assert_eq!("192.0.2.128/25", cidr.trunc().to_string());
+        assert_eq!("192.0.2.130/25", cidr.to_string());
+
+        let default: IpCidr = "0.0.0.0/0".parse().unwrap();
+        assert_eq!(0, default.netmask());
+        assert!(default.contains(addr("198.51.100.1")));
+        assert!(default.is_network());
+        let host: IpCidr = "192.0.2.1/32".parse().unwrap();
+        assert!(host.contains(addr("192.0.2.1")) && !host.contains(addr("192.0.2.2")));
+
+        for invalid in [
+            "192.0.2.0",
+            "192.0.2.0/33",
+            "192.0.2/24",
+            "192.0.2.0/",
+            "/24",
+        ] {
+            assert!(invalid.parse::<IpCidr>().is_err(), "{invalid}");
+        }
+
+        assert_eq!(
+            Some(cidr),
+            IpCidr::from_netmask(addr("192.0.2.130"), addr("255.255.255.128"))
+        );
+        assert_eq!(None, IpCidr::from_netmask(0, addr("255.0.255.0")));
+    }
+}
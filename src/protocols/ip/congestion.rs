@@ -0,0 +1,296 @@
+use std::{fmt, str::FromStr, time::SystemTime};
+
+const CUBIC_C: f64 = 0.4; // scaling constant in segments / second^3 (RFC 8312)
+const CUBIC_BETA: f64 = 0.7; // multiplicative decrease factor (RFC 8312)
+
+/// Congestion control algorithm driven by TCP ACK / loss / retransmission timeout events.
+/// Window sizes are in bytes.
+pub trait CongestionControl: Send {
+    /// Called with bytes newly acknowledged by an ACK advancing send.una.
+    fn on_ack(&mut self, acked: u32, now: SystemTime);
+    /// Called on loss detected by duplicate ACKs with bytes in flight.
+    fn on_loss(&mut self, in_flight: u32, now: SystemTime);
+    /// Called on each duplicate ACK after the one reporting loss.
+    fn on_dup_ack(&mut self) {}
+    /// Called on retransmission timeout with bytes in flight.
+    fn on_rto(&mut self, in_flight: u32);
+    fn cwnd(&self) -> u32;
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum CongestionAlgorithm {
+    Reno,
+    Cubic,
+    None,
+}
+
+impl CongestionAlgorithm {
+    pub fn create(self, mss: u32) -> Box<dyn CongestionControl> {
+        match self {
+            CongestionAlgorithm::Reno => Box::new(Reno::new(mss)),
+            CongestionAlgorithm::Cubic => Box::new(Cubic::new(mss)),
+            CongestionAlgorithm::None => Box::new(Unlimited),
+        }
+    }
+}
+
+impl FromStr for CongestionAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reno" => Ok(CongestionAlgorithm::Reno),
+            "cubic" => Ok(CongestionAlgorithm::Cubic),
+            "none" => Ok(CongestionAlgorithm::None),
+            _ => Err(format!("unknown congestion control algorithm: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for CongestionAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CongestionAlgorithm::Reno => write!(f, "reno"),
+            CongestionAlgorithm::Cubic => write!(f, "cubic"),
+            CongestionAlgorithm::None => write!(f, "none"),
+        }
+    }
+}
+
+// RFC 5681 section 3.1
+fn initial_window(mss: u32) -> u32 {
+    if mss > 2190 {
+        2 * mss
+    } else if mss > 1095 {
+        3 * mss
+    } else {
+        4 * mss
+    }
+}
+
+/// Slow start, congestion avoidance, fast retransmit and fast recovery of RFC 5681.
+pub struct Reno {
+    mss: u32,
+    cwnd: u32,
+    ssthresh: u32,
+    recovering: bool, // in fast recovery until an ACK of new data
+}
+
+impl Reno {
+    pub fn new(mss: u32) -> Reno {
+        Reno {
+            mss,
+            cwnd: initial_window(mss),
+            ssthresh: u32::MAX,
+            recovering: false,
+        }
+    }
+}
+
+impl CongestionControl for Reno {
+    fn on_ack(&mut self, acked: u32, _now: SystemTime) {
+        if self.recovering {
+            // Deflate the window inflated by duplicate ACKs.
+            self.recovering = false;
+            self.cwnd = self.ssthresh;
+        } else if self.cwnd < self.ssthresh {
+            self.cwnd = self.cwnd.saturating_add(acked.min(self.mss));
+        } else {
+            let increase = (self.mss * self.mss / self.cwnd).max(1);
+            self.cwnd = self.cwnd.saturating_add(increase);
+        }
+    }
+
+    fn on_loss(&mut self, in_flight: u32, _now: SystemTime) {
+        self.ssthresh = (in_flight / 2).max(2 * self.mss);
+        // Segments having left the network with the three duplicate ACKs
+        self.cwnd = self.ssthresh + 3 * self.mss;
+        self.recovering = true;
+    }
+
+    fn on_dup_ack(&mut self) {
+        if self.recovering {
+            self.cwnd = self.cwnd.saturating_add(self.mss);
+        }
+    }
+
+    fn on_rto(&mut self, in_flight: u32) {
+        self.ssthresh = (in_flight / 2).max(2 * self.mss);
+        self.cwnd = self.mss;
+        self.recovering = false;
+    }
+
+    fn cwnd(&self) -> u32 {
+        self.cwnd
+    }
+}
+
+/// CUBIC window growth of RFC 8312 with TCP-friendly region and fast convergence.
+pub struct Cubic {
+    mss: f64,
+    cwnd: f64,
+    ssthresh: f64,
+    w_max: f64,
+    k: f64,
+    w_est: f64,
+    epoch_start: Option<SystemTime>,
+}
+
+impl Cubic {
+    pub fn new(mss: u32) -> Cubic {
+        Cubic {
+            mss: mss as f64,
+            cwnd: initial_window(mss) as f64,
+            ssthresh: f64::MAX,
+            w_max: 0.0,
+            k: 0.0,
+            w_est: 0.0,
+            epoch_start: None,
+        }
+    }
+
+    fn reduce(&mut self) {
+        self.epoch_start = None;
+        // Fast convergence: releases bandwidth for new flows when the window keeps shrinking.
+        self.w_max = if self.cwnd < self.w_max {
+            self.cwnd * (1.0 + CUBIC_BETA) / 2.0
+        } else {
+            self.cwnd
+        };
+        self.ssthresh = (self.cwnd * CUBIC_BETA).max(2.0 * self.mss);
+    }
+}
+
+impl CongestionControl for Cubic {
+    fn on_ack(&mut self, acked: u32, now: SystemTime) {
+        let acked = acked as f64;
+        if self.cwnd < self.ssthresh {
+            self.cwnd += acked.min(self.mss);
+            return;
+        }
+        let epoch_start = match self.epoch_start {
+            Some(epoch_start) => epoch_start,
+            None => {
+                if self.w_max < self.cwnd {
+                    self.w_max = self.cwnd;
+                }
+                self.k = ((self.w_max - self.cwnd) / self.mss / CUBIC_C).cbrt();
+                self.w_est = self.cwnd;
+                self.epoch_start = Some(now);
+                now
+            }
+        };
+        let t = now
+            .duration_since(epoch_start)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let target = CUBIC_C * (t - self.k).powi(3) * self.mss + self.w_max;
+        if target > self.cwnd {
+            self.cwnd += (target - self.cwnd) / self.cwnd * acked;
+        }
+        // TCP-friendly region: grows at least as fast as Reno would.
+        self.w_est += 3.0 * (1.0 - CUBIC_BETA) / (1.0 + CUBIC_BETA) * acked / self.cwnd * self.mss;
+        if self.w_est > self.cwnd {
+            self.cwnd = self.w_est;
+        }
+    }
+
+    fn on_loss(&mut self, _in_flight: u32, _now: SystemTime) {
+        self.reduce();
+        self.cwnd = self.ssthresh;
+    }
+
+    fn on_rto(&mut self, _in_flight: u32) {
+        self.reduce();
+        self.cwnd = self.mss;
+    }
+
+    fn cwnd(&self) -> u32 {
+        self.cwnd as u32
+    }
+}
+
+/// No congestion control: sending is limited by the peer's advertised window only.
+pub struct Unlimited;
+
+impl CongestionControl for Unlimited {
+    fn on_ack(&mut self, _acked: u32, _now: SystemTime) {}
+
+    fn on_loss(&mut self, _in_flight: u32, _now: SystemTime) {}
+
+    fn on_rto(&mut self, _in_flight: u32) {}
+
+    fn cwnd(&self) -> u32 {
+        u32::MAX
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CongestionAlgorithm, CongestionControl, Cubic, Reno};
+    use std::time::{Duration, SystemTime};
+
+    const MSS: u32 = 1000;
+
+    #[test]
+    fn test_reno() {
+        let now = SystemTime::now();
+        let mut reno = Reno::new(MSS);
+        assert_eq!(4 * MSS, reno.cwnd());
+
+        // Slow start: one MSS per ACK
+        reno.on_ack(MSS, now);
+        assert_eq!(5 * MSS, reno.cwnd());
+
+        // Fast recovery: inflated by three segments, then one per further duplicate ACK
+        reno.on_loss(8 * MSS, now);
+        assert_eq!(7 * MSS, reno.cwnd());
+        reno.on_dup_ack();
+        assert_eq!(8 * MSS, reno.cwnd());
+        // ACK of new data deflates the window to ssthresh.
+        reno.on_ack(4 * MSS, now);
+        assert_eq!(4 * MSS, reno.cwnd());
+
+        // Congestion avoidance: MSS * MSS / cwnd per ACK
+        reno.on_ack(MSS, now);
+        assert_eq!(4 * MSS + MSS / 4, reno.cwnd());
+
+        reno.on_rto(8 * MSS);
+        assert_eq!(MSS, reno.cwnd());
+    }
+
+    #[test]
+    fn test_cubic() {
+        let now = SystemTime::now();
+        let mut cubic = Cubic::new(MSS);
+        cubic.on_ack(MSS, now);
+        assert_eq!(5 * MSS, cubic.cwnd());
+
+        cubic.on_loss(5 * MSS, now);
+        assert_eq!(3500, cubic.cwnd());
+
+        // Window grows back to W_max around K seconds after the reduction.
+        cubic.on_ack(MSS, now);
+        let k = cubic.k;
+        let mut t = now;
+        while cubic.cwnd() < 5 * MSS {
+            t += Duration::from_millis(10);
+            cubic.on_ack(MSS, t);
+        }
+        assert!(t.duration_since(now).unwrap().as_secs_f64() <= k + 0.5);
+
+        cubic.on_rto(5 * MSS);
+        assert_eq!(MSS, cubic.cwnd());
+    }
+
+    #[test]
+    fn test_algorithm_from_str() {
+        assert_eq!(Ok(CongestionAlgorithm::Cubic), "CUBIC".parse());
+        assert!("vegas".parse::<CongestionAlgorithm>().is_err());
+        assert_eq!(MSS * 4, CongestionAlgorithm::Reno.create(MSS).cwnd());
+
+        let mut none = "none".parse::<CongestionAlgorithm>().unwrap().create(MSS);
+        none.on_rto(8 * MSS);
+        assert_eq!(u32::MAX, none.cwnd());
+    }
+}
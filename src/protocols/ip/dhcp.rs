@@ -0,0 +1,472 @@
+use super::{
+    ip_addr_to_str, udp, ControlBlocks, IPAdress, IPEndpoint, IPInterface, ProtocolContexts,
+    IP_ADDR_ANY, IP_ADDR_BROADCAST,
+};
+use crate::{
+    devices::{ethernet::ETH_ADDR_LEN, NetDevice},
+    net::NetInterfaceFamily,
+    utils::byte::{be_to_le_u32, le_to_be_u16, le_to_be_u32},
+    utils::{bytes_to_struct, to_u8_slice},
+};
+use log::{debug, info, warn};
+use rand::Rng;
+use std::{
+    convert::TryInto,
+    mem::size_of,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+pub const DHCP_CLIENT_PORT: u16 = 68;
+pub const DHCP_SERVER_PORT: u16 = 67;
+
+const DHCP_OP_REQUEST: u8 = 1;
+const DHCP_OP_REPLY: u8 = 2;
+const DHCP_HTYPE_ETHER: u8 = 1;
+const DHCP_FLAG_BROADCAST: u16 = 0x8000;
+const DHCP_MAGIC_COOKIE: u32 = 0x63825363;
+
+const DHCP_OPT_PAD: u8 = 0;
+const DHCP_OPT_SUBNET_MASK: u8 = 1;
+const DHCP_OPT_ROUTER: u8 = 3;
+const DHCP_OPT_REQUESTED_IP: u8 = 50;
+const DHCP_OPT_LEASE_TIME: u8 = 51;
+const DHCP_OPT_MSG_TYPE: u8 = 53;
+const DHCP_OPT_SERVER_ID: u8 = 54;
+const DHCP_OPT_PARAM_REQUEST: u8 = 55;
+const DHCP_OPT_RENEWAL_TIME: u8 = 58;
+const DHCP_OPT_REBINDING_TIME: u8 = 59;
+const DHCP_OPT_END: u8 = 255;
+
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+const DHCP_NAK: u8 = 6;
+
+const DHCP_RETRY_SECS: u64 = 4;
+const DHCP_RENEW_RETRY_SECS: u64 = 60;
+
+/// DHCP message fixed part (RFC 2131) followed by magic cookie and options.
+#[repr(packed)]
+struct DhcpMessage {
+    op: u8,
+    htype: u8,
+    hlen: u8,
+    hops: u8,
+    xid: u32,
+    secs: u16,
+    flags: u16,
+    ciaddr: IPAdress, // client address (renewing / rebinding)
+    yiaddr: IPAdress, // "your" (offered) address
+    siaddr: IPAdress, // next server address
+    giaddr: IPAdress, // relay agent address
+    chaddr: [u8; 16],
+    sname: [u8; 64],
+    file: [u8; 128],
+    cookie: u32,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum DhcpState {
+    Init,
+    Selecting,
+    Requesting,
+    Bound,
+    Renewing,
+    Rebinding,
+}
+
+#[derive(Debug, Clone)]
+pub struct DhcpLease {
+    pub address: IPAdress,
+    pub netmask: IPAdress,
+    pub gateway: Option<IPAdress>,
+    pub server: IPAdress,
+    pub lease_secs: u32,
+    pub renewal_secs: u32,
+    pub rebinding_secs: u32,
+    acquired_at: SystemTime,
+}
+
+struct DhcpReply {
+    msg_type: u8,
+    xid: u32,
+    yiaddr: IPAdress,
+    chaddr: [u8; ETH_ADDR_LEN],
+    netmask: Option<IPAdress>,
+    router: Option<IPAdress>,
+    server: Option<IPAdress>,
+    lease_secs: Option<u32>,
+    renewal_secs: Option<u32>,
+    rebinding_secs: Option<u32>,
+}
+
+pub struct DhcpClient {
+    state: DhcpState,
+    pcb_id: usize,
+    xid: u32,
+    hw_addr: [u8; ETH_ADDR_LEN],
+    offered: IPAdress,
+    server: IPAdress,
+    retry_at: SystemTime,
+    lease: Option<DhcpLease>,
+}
+
+impl DhcpClient {
+    /// Hardware address of the device the client configures.
+    pub fn hw_addr(&self) -> [u8; ETH_ADDR_LEN] {
+        self.hw_addr
+    }
+
+    /// Opens UDP PCB on client port for a device with a hardware address.
+    pub fn new(hw_addr: [u8; ETH_ADDR_LEN], pcbs: &mut ControlBlocks) -> DhcpClient {
+        let pcb_id = udp::open(&mut pcbs.udp_pcbs);
+        udp::bind(
+            &mut pcbs.udp_pcbs,
+            pcb_id,
+            IPEndpoint::new(IP_ADDR_ANY, DHCP_CLIENT_PORT),
+        );
+        // Messages are broadcast till a lease is bound.
+        udp::set_broadcast(&mut pcbs.udp_pcbs, pcb_id, true);
+        DhcpClient {
+            state: DhcpState::Init,
+            pcb_id,
+            xid: 0,
+            hw_addr,
+            offered: IP_ADDR_ANY,
+            server: IP_ADDR_ANY,
+            retry_at: SystemTime::now(),
+            lease: None,
+        }
+    }
+
+    pub fn is_bound(&self) -> bool {
+        self.lease.is_some()
+    }
+
+    /// Processes received replies and timer events (retransmission, renewal, rebinding and expiry).
+    pub fn on_timer(
+        &mut self,
+        device: &mut NetDevice,
+        contexts: &mut ProtocolContexts,
+        pcbs: &mut ControlBlocks,
+    ) {
+        while let Some(entry) = udp::try_receive_from(&mut pcbs.udp_pcbs, self.pcb_id) {
+            if let Some(reply) = parse_reply(&entry.data) {
+                self.handle_reply(reply, device, contexts, pcbs);
+            }
+        }
+
+        let now = SystemTime::now();
+        let retry_due = now >= self.retry_at;
+        match self.state {
+            DhcpState::Init => {
+                self.xid = rand::thread_rng().gen();
+                info!("DHCP: sending DISCOVER...");
+                self.send(DHCP_DISCOVER, IP_ADDR_BROADCAST, device, contexts, pcbs);
+                self.state = DhcpState::Selecting;
+                self.retry_at = now + Duration::from_secs(DHCP_RETRY_SECS);
+            }
+            DhcpState::Selecting | DhcpState::Requesting => {
+                if retry_due {
+                    warn!("DHCP: no reply in {:?} state. Restarting...", self.state);
+                    self.state = DhcpState::Init;
+                }
+            }
+            DhcpState::Bound | DhcpState::Renewing | DhcpState::Rebinding => {
+                let lease = self.lease.as_ref().unwrap();
+                let elapsed = lease.acquired_at.elapsed().unwrap_or_default().as_secs();
+                let server = lease.server;
+                if elapsed >= lease.lease_secs as u64 {
+                    warn!("DHCP: lease expired. Releasing address...");
+                    self.lease = None;
+                    deconfigure(device, contexts);
+                    self.state = DhcpState::Init;
+                } else if elapsed >= lease.rebinding_secs as u64 {
+                    if self.state != DhcpState::Rebinding || retry_due {
+                        info!("DHCP: rebinding lease...");
+                        self.state = DhcpState::Rebinding;
+                        self.send(DHCP_REQUEST, IP_ADDR_BROADCAST, device, contexts, pcbs);
+                        self.retry_at = now + Duration::from_secs(DHCP_RENEW_RETRY_SECS);
+                    }
+                } else if elapsed >= lease.renewal_secs as u64
+                    && (self.state == DhcpState::Bound || retry_due)
+                {
+                    info!("DHCP: renewing lease...");
+                    self.state = DhcpState::Renewing;
+                    self.send(DHCP_REQUEST, server, device, contexts, pcbs);
+                    self.retry_at = now + Duration::from_secs(DHCP_RENEW_RETRY_SECS);
+                }
+            }
+        }
+    }
+
+    fn handle_reply(
+        &mut self,
+        reply: DhcpReply,
+        device: &mut NetDevice,
+        contexts: &mut ProtocolContexts,
+        pcbs: &mut ControlBlocks,
+    ) {
+        if reply.xid != self.xid || reply.chaddr != self.hw_addr {
+            debug!("DHCP: reply for another transaction.");
+            return;
+        }
+        match (reply.msg_type, self.state) {
+            (DHCP_OFFER, DhcpState::Selecting) => {
+                let server = match reply.server {
+                    Some(server) => server,
+                    None => return,
+                };
+                info!(
+                    "DHCP: OFFER of {} from {}. Sending REQUEST...",
+                    ip_addr_to_str(reply.yiaddr),
+                    ip_addr_to_str(server)
+                );
+                self.offered = reply.yiaddr;
+                self.server = server;
+                self.send(DHCP_REQUEST, IP_ADDR_BROADCAST, device, contexts, pcbs);
+                self.state = DhcpState::Requesting;
+                self.retry_at = SystemTime::now() + Duration::from_secs(DHCP_RETRY_SECS);
+            }
+            (DHCP_ACK, DhcpState::Requesting)
+            | (DHCP_ACK, DhcpState::Renewing)
+            | (DHCP_ACK, DhcpState::Rebinding) => {
+                let lease_secs = reply.lease_secs.unwrap_or(u32::MAX);
+                let lease = DhcpLease {
+                    address: reply.yiaddr,
+                    netmask: reply.netmask.unwrap_or(IP_ADDR_BROADCAST),
+                    gateway: reply.router,
+                    server: reply.server.unwrap_or(self.server),
+                    lease_secs,
+                    renewal_secs: reply.renewal_secs.unwrap_or(lease_secs / 2),
+                    rebinding_secs: reply
+                        .rebinding_secs
+                        .unwrap_or((lease_secs as u64 * 7 / 8) as u32),
+                    acquired_at: SystemTime::now(),
+                };
+                info!(
+                    "DHCP: ACK. Bound to {} netmask {} lease {} secs.",
+                    ip_addr_to_str(lease.address),
+                    ip_addr_to_str(lease.netmask),
+                    lease.lease_secs
+                );
+                configure(&lease, device, contexts);
+                self.lease = Some(lease);
+                self.state = DhcpState::Bound;
+            }
+            (DHCP_NAK, _) => {
+                warn!("DHCP: NAK received. Restarting...");
+                if self.lease.take().is_some() {
+                    deconfigure(device, contexts);
+                }
+                self.state = DhcpState::Init;
+            }
+            (msg_type, state) => {
+                debug!(
+                    "DHCP: ignoring message type {msg_type} in {:?} state.",
+                    state
+                );
+            }
+        }
+    }
+
+    fn send(
+        &self,
+        msg_type: u8,
+        dst: IPAdress,
+        device: &mut NetDevice,
+        contexts: &mut ProtocolContexts,
+        pcbs: &mut ControlBlocks,
+    ) {
+        let ciaddr = match self.state {
+            DhcpState::Bound | DhcpState::Renewing | DhcpState::Rebinding => {
+                self.lease.as_ref().map_or(IP_ADDR_ANY, |l| l.address)
+            }
+            _ => IP_ADDR_ANY,
+        };
+        let mut chaddr = [0; 16];
+        chaddr[..ETH_ADDR_LEN].copy_from_slice(&self.hw_addr);
+        let msg = DhcpMessage {
+            op: DHCP_OP_REQUEST,
+            htype: DHCP_HTYPE_ETHER,
+            hlen: ETH_ADDR_LEN as u8,
+            hops: 0,
+            xid: le_to_be_u32(self.xid),
+            secs: 0,
+            flags: if ciaddr == IP_ADDR_ANY {
+                le_to_be_u16(DHCP_FLAG_BROADCAST)
+            } else {
+                0
+            },
+            ciaddr,
+            yiaddr: IP_ADDR_ANY,
+            siaddr: IP_ADDR_ANY,
+            giaddr: IP_ADDR_ANY,
+            chaddr,
+            sname: [0; 64],
+            file: [0; 128],
+            cookie: le_to_be_u32(DHCP_MAGIC_COOKIE),
+        };
+        let mut data = unsafe { to_u8_slice(&msg) }.to_vec();
+        data.extend_from_slice(&[DHCP_OPT_MSG_TYPE, 1, msg_type]);
+        if msg_type == DHCP_REQUEST && self.state == DhcpState::Selecting {
+            data.extend_from_slice(&[DHCP_OPT_REQUESTED_IP, 4]);
+            data.extend_from_slice(&self.offered.to_ne_bytes());
+            data.extend_from_slice(&[DHCP_OPT_SERVER_ID, 4]);
+            data.extend_from_slice(&self.server.to_ne_bytes());
+        }
+        data.extend_from_slice(&[
+            DHCP_OPT_PARAM_REQUEST,
+            4,
+            DHCP_OPT_SUBNET_MASK,
+            DHCP_OPT_ROUTER,
+            DHCP_OPT_RENEWAL_TIME,
+            DHCP_OPT_REBINDING_TIME,
+        ]);
+        data.push(DHCP_OPT_END);
+
+        let remote = IPEndpoint::new(dst, DHCP_SERVER_PORT);
+        if udp::send_to(self.pcb_id, data, remote, device, contexts, pcbs).is_err() {
+            warn!("DHCP: failed to send message type {msg_type}.");
+        }
+    }
+}
+
+fn parse_reply(data: &[u8]) -> Option<DhcpReply> {
+    let fixed_len = size_of::<DhcpMessage>();
+    if data.len() < fixed_len {
+        return None;
+    }
+    let msg = unsafe { bytes_to_struct::<DhcpMessage>(data) };
+    if msg.op != DHCP_OP_REPLY || be_to_le_u32(msg.cookie) != DHCP_MAGIC_COOKIE {
+        return None;
+    }
+    let mut reply = DhcpReply {
+        msg_type: 0,
+        xid: be_to_le_u32(msg.xid),
+        yiaddr: msg.yiaddr,
+        chaddr: msg.chaddr[..ETH_ADDR_LEN].try_into().unwrap(),
+        netmask: None,
+        router: None,
+        server: None,
+        lease_secs: None,
+        renewal_secs: None,
+        rebinding_secs: None,
+    };
+    let addr = |v: &[u8]| -> Option<IPAdress> {
+        Some(IPAdress::from_ne_bytes(v.get(..4)?.try_into().ok()?))
+    };
+    let secs = |v: &[u8]| -> Option<u32> { Some(u32::from_be_bytes(v.get(..4)?.try_into().ok()?)) };
+
+    let opts = &data[fixed_len..];
+    let mut i = 0;
+    while i < opts.len() {
+        let code = opts[i];
+        if code == DHCP_OPT_END {
+            break;
+        }
+        if code == DHCP_OPT_PAD {
+            i += 1;
+            continue;
+        }
+        let len = *opts.get(i + 1)? as usize;
+        let value = opts.get(i + 2..i + 2 + len)?;
+        match code {
+            DHCP_OPT_MSG_TYPE => reply.msg_type = *value.first()?,
+            DHCP_OPT_SUBNET_MASK => reply.netmask = addr(value),
+            DHCP_OPT_ROUTER => reply.router = addr(value),
+            DHCP_OPT_SERVER_ID => reply.server = addr(value),
+            DHCP_OPT_LEASE_TIME => reply.lease_secs = secs(value),
+            DHCP_OPT_RENEWAL_TIME => reply.renewal_secs = secs(value),
+            DHCP_OPT_REBINDING_TIME => reply.rebinding_secs = secs(value),
+            _ => {}
+        }
+        i += 2 + len;
+    }
+    if reply.msg_type == 0 {
+        return None;
+    }
+    Some(reply)
+}
+
+/// Replaces IP interface of a device with leased address and sets default route.
+fn configure(lease: &DhcpLease, device: &mut NetDevice, contexts: &mut ProtocolContexts) {
+    let interface = Arc::new(IPInterface::new_from_addr(lease.address, lease.netmask));
+    replace_interface(interface.clone(), device, contexts);
+    contexts
+        .ip_routes
+        .set_default_gateway(lease.gateway, interface);
+}
+
+/// Resets IP interface of a device to unconfigured address (0.0.0.0/0).
+fn deconfigure(device: &mut NetDevice, contexts: &mut ProtocolContexts) {
+    let interface = Arc::new(IPInterface::new_from_addr(IP_ADDR_ANY, IP_ADDR_ANY));
+    replace_interface(interface.clone(), device, contexts);
+    contexts.ip_routes.set_default_gateway(None, interface);
+}
+
+fn replace_interface(
+    interface: Arc<IPInterface>,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+) {
+    if let Some(current) = device.get_interface(NetInterfaceFamily::IP) {
+        interface.set_proxy_arp(current.proxy_arp());
+    }
+    match device.replace_interface(interface.clone()) {
+        Some(old) => contexts.ip_routes.replace_interface(&old, interface),
+        None => {
+            if device.get_interface(NetInterfaceFamily::IP).is_none() {
+                device.register_interface(interface);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse_reply, DhcpMessage, DHCP_ACK, DHCP_MAGIC_COOKIE, DHCP_OPT_END, DHCP_OPT_LEASE_TIME,
+        DHCP_OPT_MSG_TYPE, DHCP_OPT_ROUTER, DHCP_OPT_SUBNET_MASK, DHCP_OP_REPLY,
+    };
+    use crate::{protocols::ip::ip_addr_to_bytes, utils::byte::le_to_be_u32, utils::to_u8_slice};
+
+    #[test]
+    fn test_parse_reply() {
+        let mut chaddr = [0; 16];
+        chaddr[..6].copy_from_slice(&[0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+        let msg = DhcpMessage {
+            op: DHCP_OP_REPLY,
+            htype: 1,
+            hlen: 6,
+            hops: 0,
+            xid: le_to_be_u32(0x12345678),
+            secs: 0,
+            flags: 0,
+            ciaddr: 0,
+            yiaddr: ip_addr_to_bytes("192.0.2.10").unwrap(),
+            siaddr: 0,
+            giaddr: 0,
+            chaddr,
+            sname: [0; 64],
+            file: [0; 128],
+            cookie: le_to_be_u32(DHCP_MAGIC_COOKIE),
+        };
+        let mut data = unsafe { to_u8_slice(&msg) }.to_vec();
+        data.extend_from_slice(&[DHCP_OPT_MSG_TYPE, 1, DHCP_ACK]);
+        data.extend_from_slice(&[DHCP_OPT_SUBNET_MASK, 4, 255, 255, 255, 0]);
+        data.extend_from_slice(&[DHCP_OPT_ROUTER, 4, 192, 0, 2, 1]);
+        data.extend_from_slice(&[DHCP_OPT_LEASE_TIME, 4, 0, 0, 0x0e, 0x10]);
+        data.push(DHCP_OPT_END);
+
+        let reply = parse_reply(&data).unwrap();
+        assert_eq!(DHCP_ACK, reply.msg_type);
+        assert_eq!(0x12345678, reply.xid);
+        assert_eq!(ip_addr_to_bytes("192.0.2.10").unwrap(), reply.yiaddr);
+        assert_eq!(ip_addr_to_bytes("255.255.255.0"), reply.netmask);
+        assert_eq!(ip_addr_to_bytes("192.0.2.1"), reply.router);
+        assert_eq!(Some(3600), reply.lease_secs);
+    }
+}
@@ -1,31 +1,55 @@
-use super::{IPAdress, IPInterface, IPProtocolType};
+use super::{
+    igmp, ip_addr_to_str, l4::L4Protocol, IPAdress, IPEndpoint, IPHeader, IPInterface,
+    IPProtocolType, IP_ADDR_ANY, IP_ADDR_BROADCAST,
+};
 use crate::{
     devices::NetDevice,
     protocols::ip::{ControlBlocks, ProtocolContexts},
+    timer,
+    utils::byte::be_to_le_u32,
     utils::{bytes_to_struct, cksum16, to_u8_slice},
 };
-use log::{error, info};
-use std::mem::size_of;
+use log::{debug, error, info, warn};
+use std::{
+    collections::{HashMap, VecDeque},
+    mem::size_of,
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+const ICMP_PCB_COUNT: usize = 16;
+/// PCB type receiving errors quoting UDP datagrams, identified by the UDP source port.
+pub const ICMP_PCB_UDP_ERROR: u8 = 0xff;
+// Error messages sent per second and at once, to all destinations and to each (cf. Linux
+// icmp_msgs_per_sec, icmp_msgs_burst and icmp_ratelimit)
+const ICMP_ERROR_RATE: u32 = 1000;
+const ICMP_ERROR_BURST: u32 = 50;
+const ICMP_ERROR_DEST_RATE: u32 = 1;
+const ICMP_ERROR_DEST_BURST: u32 = 6;
+const ICMP_ERROR_DESTS_MAX: usize = 1024; // buckets kept before full ones are dropped
 
-const ICMP_TYPE_ECHOREPLY: u8 = 0;
-const ICMP_TYPE_ECHO: u8 = 8;
+pub const ICMP_TYPE_ECHOREPLY: u8 = 0;
+pub const ICMP_TYPE_ECHO: u8 = 8;
 
-// const ICMP_TYPE_DEST_UNREACH: u8 = 3;
-// const ICMP_TYPE_SOURCE_QUENCH: u8 = 4;
-// const ICMP_TYPE_REDIRECT: u8 = 5;
-// const ICMP_TYPE_TIME_EXCEEDED: u8 = 11;
-// const ICMP_TYPE_PARAM_PROBLEM: u8 = 12;
+pub const ICMP_TYPE_DEST_UNREACH: u8 = 3;
+pub const ICMP_TYPE_SOURCE_QUENCH: u8 = 4;
+const ICMP_TYPE_REDIRECT: u8 = 5;
+pub const ICMP_TYPE_TIME_EXCEEDED: u8 = 11;
+pub const ICMP_TYPE_PARAM_PROBLEM: u8 = 12;
 // const ICMP_TYPE_TIMESTAMP: u8 = 13;
 // const ICMP_TYPE_TIMESTAMPREPLY: u8 = 14;
 // const ICMP_TYPE_INFO_REQUEST: u8 = 15;
 // const ICMP_TYPE_INFO_REPLY: u8 = 16;
 
 // // UNREACH
-// const ICMP_CODE_NET_UNREACH: u8 = 0;
+pub const ICMP_CODE_NET_UNREACH: u8 = 0;
 // const ICMP_CODE_HOST_UNREACH: u8 = 1;
-// const ICMP_CODE_PROTO_UNREACH: u8 = 2;
-// const ICMP_CODE_PORT_UNREACH: u8 = 3;
-// const ICMP_CODE_FRAGMENT_NEEDED: u8 = 4;
+pub const ICMP_CODE_PROTO_UNREACH: u8 = 2;
+pub const ICMP_CODE_PORT_UNREACH: u8 = 3;
+pub const ICMP_CODE_FRAGMENT_NEEDED: u8 = 4;
 // const ICMP_CODE_SOURCE_ROUTE_FAILED: u8 = 5;
 
 // // REDIRECT
@@ -35,7 +59,7 @@ const ICMP_TYPE_ECHO: u8 = 8;
 // const ICMP_CODE_REDIRECT_TOS_HOST: u8 = 3;
 
 // // TIME_EXEEDED
-// const ICMP_CODE_EXCEEDED_TTL: u8 = 0;
+pub const ICMP_CODE_EXCEEDED_TTL: u8 = 0;
 // const ICMP_CODE_EXCEEDED_FRAGMENT: u8 = 1;
 
 #[repr(packed)]
@@ -54,6 +78,298 @@ pub struct ICMPHeader {
 //     seq: u16,
 // }
 
+/// Splits values field of echo / echo reply header into identifier and sequence number.
+pub fn echo_id_seq(values: u32) -> (u16, u16) {
+    let v = be_to_le_u32(values);
+    ((v >> 16) as u16, (v & 0xffff) as u16)
+}
+
+#[derive(PartialEq)]
+enum IcmpPcbState {
+    Free,
+    Open,
+}
+
+// Protocol control block: waits for messages of a type with an identifier.
+pub struct IcmpPcb {
+    state: IcmpPcbState,
+    icmp_type: u8,
+    id: u16,
+    pub sender: Option<Sender<bool>>,
+    data_entries: VecDeque<IcmpDataEntry>,
+}
+
+impl IcmpPcb {
+    pub fn new() -> IcmpPcb {
+        IcmpPcb {
+            state: IcmpPcbState::Free,
+            icmp_type: 0,
+            id: 0,
+            sender: None,
+            data_entries: VecDeque::new(),
+        }
+    }
+}
+
+pub struct IcmpDataEntry {
+    pub icmp_type: u8,
+    pub code: u8,
+    pub values: u32,
+    pub src: IPAdress,
+    pub data: Vec<u8>,
+    pub received_at: SystemTime,
+}
+
+/// Tokens for messages, refilled at a rate per second up to a burst.
+struct TokenBucket {
+    tokens: f64,
+    refilled_at: SystemTime,
+}
+
+impl TokenBucket {
+    fn new(burst: u32) -> TokenBucket {
+        TokenBucket {
+            tokens: burst as f64,
+            refilled_at: timer::now(),
+        }
+    }
+
+    fn refill(&mut self, rate: u32, burst: u32) {
+        let elapsed = timer::since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(burst as f64);
+        self.refilled_at = timer::now();
+    }
+
+    fn is_full(&mut self, rate: u32, burst: u32) -> bool {
+        self.refill(rate, burst);
+        self.tokens >= burst as f64
+    }
+}
+
+/// Limits error messages sent globally and per destination with token buckets, so that a flood
+/// of datagrams in error doesn't turn the stack into an amplifier (RFC 1812 4.3.2.8).
+pub struct IcmpErrorLimit {
+    pub rate: u32,
+    pub burst: u32,
+    pub dest_rate: u32,
+    pub dest_burst: u32,
+    pub limited: usize,
+    global: TokenBucket,
+    dests: HashMap<IPAdress, TokenBucket>,
+}
+
+impl IcmpErrorLimit {
+    pub fn new() -> IcmpErrorLimit {
+        IcmpErrorLimit {
+            rate: ICMP_ERROR_RATE,
+            burst: ICMP_ERROR_BURST,
+            dest_rate: ICMP_ERROR_DEST_RATE,
+            dest_burst: ICMP_ERROR_DEST_BURST,
+            limited: 0,
+            global: TokenBucket::new(ICMP_ERROR_BURST),
+            dests: HashMap::new(),
+        }
+    }
+
+    /// Takes tokens of both buckets for an error message to a destination, or counts it limited
+    /// when either is empty.
+    pub fn allow(&mut self, dst: IPAdress) -> bool {
+        let (rate, burst) = (self.dest_rate, self.dest_burst);
+        if self.dests.len() >= ICMP_ERROR_DESTS_MAX && !self.dests.contains_key(&dst) {
+            self.dests.retain(|_, bucket| !bucket.is_full(rate, burst));
+        }
+        self.global.refill(self.rate, self.burst);
+        let dest = self
+            .dests
+            .entry(dst)
+            .or_insert_with(|| TokenBucket::new(burst));
+        dest.refill(rate, burst);
+        if self.global.tokens < 1.0 || dest.tokens < 1.0 {
+            self.limited += 1;
+            return false;
+        }
+        self.global.tokens -= 1.0;
+        dest.tokens -= 1.0;
+        true
+    }
+}
+
+pub struct IcmpPcbs {
+    pub entries: Vec<IcmpPcb>,
+    pub unmatched: usize,
+    pub error_limit: IcmpErrorLimit,
+}
+
+impl IcmpPcbs {
+    pub fn new() -> IcmpPcbs {
+        let mut entries = Vec::with_capacity(ICMP_PCB_COUNT);
+        for _ in 0..ICMP_PCB_COUNT {
+            entries.push(IcmpPcb::new());
+        }
+        IcmpPcbs {
+            entries,
+            unmatched: 0,
+            error_limit: IcmpErrorLimit::new(),
+        }
+    }
+
+    pub fn get_mut_by_id(&mut self, pcb_id: usize) -> Option<&mut IcmpPcb> {
+        self.entries.get_mut(pcb_id)
+    }
+
+    /// PCB IDs in use with bytes of messages queued to read on each.
+    pub fn memory_usage(&self) -> Vec<(usize, usize)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|(_, pcb)| pcb.state != IcmpPcbState::Free)
+            .map(|(id, pcb)| (id, pcb.data_entries.iter().map(|e| e.data.len()).sum()))
+            .collect()
+    }
+
+    pub fn select(&mut self, icmp_type: u8, id: u16) -> Option<&mut IcmpPcb> {
+        self.entries.iter_mut().find(|pcb| {
+            pcb.state == IcmpPcbState::Open && pcb.icmp_type == icmp_type && pcb.id == id
+        })
+    }
+
+    /// Queues an entry on the PCB registered for (type, identifier). Unmatched entries are counted and dropped.
+    pub fn deliver(&mut self, icmp_type: u8, id: u16, entry: IcmpDataEntry) -> bool {
+        match self.select(icmp_type, id) {
+            Some(pcb) => {
+                pcb.data_entries.push_back(entry);
+                if let Some(sender) = pcb.sender.as_ref() {
+                    if sender.send(true).is_err() {
+                        warn!("ICMP: PCB channel not listening.");
+                    }
+                }
+                true
+            }
+            None => {
+                self.unmatched += 1;
+                debug!(
+                    "ICMP: no PCB for type = {icmp_type} id = {id}. Dropped (unmatched = {}).",
+                    self.unmatched
+                );
+                false
+            }
+        }
+    }
+
+    pub fn close_sockets(&mut self) {
+        for pcb in self.entries.iter() {
+            if let Some(sender) = pcb.sender.as_ref() {
+                sender.send(false).ok();
+            }
+        }
+    }
+}
+
+/// Describes a message type (and code) delivered to echo PCBs.
+pub fn message_str(icmp_type: u8, code: u8) -> &'static str {
+    match (icmp_type, code) {
+        (ICMP_TYPE_ECHOREPLY, _) => "Echo Reply",
+        (ICMP_TYPE_DEST_UNREACH, 0) => "Destination Net Unreachable",
+        (ICMP_TYPE_DEST_UNREACH, 1) => "Destination Host Unreachable",
+        (ICMP_TYPE_DEST_UNREACH, 2) => "Destination Protocol Unreachable",
+        (ICMP_TYPE_DEST_UNREACH, 3) => "Destination Port Unreachable",
+        (ICMP_TYPE_DEST_UNREACH, _) => "Destination Unreachable",
+        (ICMP_TYPE_SOURCE_QUENCH, _) => "Source Quench",
+        (ICMP_TYPE_TIME_EXCEEDED, 0) => "Time to live exceeded",
+        (ICMP_TYPE_TIME_EXCEEDED, _) => "Fragment reassembly time exceeded",
+        (ICMP_TYPE_PARAM_PROBLEM, _) => "Parameter problem",
+        _ => "Unknown",
+    }
+}
+
+/// Finds the echo identifier and sequence number of the original datagram quoted in an ICMP error message.
+pub fn quoted_echo_id_seq(quoted: &[u8]) -> Option<(u16, u16)> {
+    let ip_hdr_size = size_of::<IPHeader>();
+    let icmp_hdr_size = size_of::<ICMPHeader>();
+    if quoted.len() < ip_hdr_size {
+        return None;
+    }
+    let ip_hdr = unsafe { bytes_to_struct::<IPHeader>(quoted) };
+    let ip_hdr_len = ((ip_hdr.ver_len & 0x0f) << 2) as usize;
+    if ip_hdr.protocol != IPProtocolType::Icmp as u8 || quoted.len() < ip_hdr_len + icmp_hdr_size {
+        return None;
+    }
+    let quoted_icmp = unsafe { bytes_to_struct::<ICMPHeader>(&quoted[ip_hdr_len..]) };
+    if quoted_icmp.icmp_type != ICMP_TYPE_ECHO {
+        return None;
+    }
+    Some(echo_id_seq(quoted_icmp.values))
+}
+
+/// Finds the source and destination ports (host byte order) of the UDP datagram quoted in an ICMP error message.
+pub fn quoted_udp_ports(quoted: &[u8]) -> Option<(u16, u16)> {
+    if quoted.len() < size_of::<IPHeader>() {
+        return None;
+    }
+    let ip_hdr = unsafe { bytes_to_struct::<IPHeader>(quoted) };
+    let ip_hdr_len = ((ip_hdr.ver_len & 0x0f) << 2) as usize;
+    // Source port + destination port at the head of UDP header
+    if ip_hdr.protocol != IPProtocolType::Udp as u8 || quoted.len() < ip_hdr_len + 4 {
+        return None;
+    }
+    let ports = &quoted[ip_hdr_len..];
+    let src_port = u16::from_be_bytes([ports[0], ports[1]]);
+    let dst_port = u16::from_be_bytes([ports[2], ports[3]]);
+    Some((src_port, dst_port))
+}
+
+/// Finds the local and remote endpoints and the sequence number of the TCP segment quoted in an
+/// ICMP error message.
+pub fn quoted_tcp_segment(quoted: &[u8]) -> Option<(IPEndpoint, IPEndpoint, u32)> {
+    if quoted.len() < size_of::<IPHeader>() {
+        return None;
+    }
+    let ip_hdr = unsafe { bytes_to_struct::<IPHeader>(quoted) };
+    let ip_hdr_len = ((ip_hdr.ver_len & 0x0f) << 2) as usize;
+    // Ports and sequence number: the first 8 bytes of TCP header are always quoted
+    if ip_hdr.protocol != IPProtocolType::Tcp as u8 || quoted.len() < ip_hdr_len + 8 {
+        return None;
+    }
+    let tcp = &quoted[ip_hdr_len..];
+    let local = IPEndpoint {
+        address: ip_hdr.src,
+        port: u16::from_ne_bytes([tcp[0], tcp[1]]),
+    };
+    let remote = IPEndpoint {
+        address: ip_hdr.dst,
+        port: u16::from_ne_bytes([tcp[2], tcp[3]]),
+    };
+    let seq = u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]);
+    Some((local, remote, seq))
+}
+
+/// ICMP registered in `l4::L4Protocols`.
+pub struct IcmpProtocol;
+
+impl L4Protocol for IcmpProtocol {
+    fn number(&self) -> u8 {
+        IPProtocolType::Icmp as u8
+    }
+
+    fn name(&self) -> &str {
+        "icmp"
+    }
+
+    fn input(
+        &self,
+        data: &[u8],
+        len: usize,
+        src: IPAdress,
+        dst: IPAdress,
+        device: &mut NetDevice,
+        iface: &IPInterface,
+        contexts: &mut ProtocolContexts,
+        pcbs: &mut ControlBlocks,
+    ) -> Result<(), ()> {
+        input(data, len, src, dst, device, iface, contexts, pcbs)
+    }
+}
+
 pub fn input(
     data: &[u8],
     len: usize,
@@ -93,6 +409,63 @@ pub fn input(
             contexts,
             pcbs,
         );
+        return Ok(());
+    }
+
+    // Errors quoting datagrams of protocols handling them, e.g. TCP segments of connections.
+    if matches!(
+        hdr.icmp_type,
+        ICMP_TYPE_DEST_UNREACH | ICMP_TYPE_TIME_EXCEEDED
+    ) {
+        let quoted = &data[icmp_hdr_size..len];
+        // Protocol field of the quoted IP header
+        let protocol = quoted
+            .get(9)
+            .and_then(|&number| contexts.l4_protocols.get(number));
+        if let Some(protocol) = protocol {
+            if protocol.icmp_error(hdr.icmp_type, hdr.code, quoted, pcbs) {
+                return Ok(());
+            }
+        }
+    }
+
+    // Replies and errors are delivered to PCBs waiting for echo replies with the identifier.
+    // Errors quoting UDP datagrams are delivered to PCBs waiting for them with the source port.
+    let key = match hdr.icmp_type {
+        ICMP_TYPE_ECHOREPLY => Some((ICMP_TYPE_ECHOREPLY, echo_id_seq(hdr.values).0)),
+        ICMP_TYPE_DEST_UNREACH
+        | ICMP_TYPE_SOURCE_QUENCH
+        | ICMP_TYPE_TIME_EXCEEDED
+        | ICMP_TYPE_PARAM_PROBLEM => {
+            let quoted = &data[icmp_hdr_size..len];
+            match quoted_echo_id_seq(quoted) {
+                Some((id, _seq)) => Some((ICMP_TYPE_ECHOREPLY, id)),
+                None => {
+                    quoted_udp_ports(quoted).map(|(src_port, _)| (ICMP_PCB_UDP_ERROR, src_port))
+                }
+            }
+        }
+        _ => None,
+    };
+    let entry = IcmpDataEntry {
+        icmp_type: hdr.icmp_type,
+        code: hdr.code,
+        values: hdr.values,
+        src,
+        data: data[icmp_hdr_size..len].to_vec(),
+        received_at: SystemTime::now(),
+    };
+    match key {
+        Some((pcb_type, id)) => {
+            pcbs.icmp_pcbs.deliver(pcb_type, id, entry);
+        }
+        None => {
+            pcbs.icmp_pcbs.unmatched += 1;
+            debug!(
+                "ICMP: type = {} not deliverable. Dropped (unmatched = {}).",
+                hdr.icmp_type, pcbs.icmp_pcbs.unmatched
+            );
+        }
     }
     Ok(())
 }
@@ -126,5 +499,288 @@ pub fn output(
     data[2] = ((check_sum & 0xff00) >> 8) as u8;
     data[3] = (check_sum & 0xff) as u8;
 
-    super::output(IPProtocolType::Icmp, data, src, dst, device, contexts).unwrap();
+    if super::output(IPProtocolType::Icmp, data, src, dst, device, contexts).is_err() {
+        warn!("ICMP: no route to {} for output.", ip_addr_to_str(dst));
+    }
+}
+
+/// Sends an error message to the source of a datagram received, quoting its IP header and the
+/// first 8 bytes of its data (RFC 792). Nothing is sent about ICMP errors, or datagrams from
+/// addresses not of a single host (RFC 1122 3.2.2), and messages over the rate limit are dropped.
+#[allow(clippy::too_many_arguments)]
+pub fn error(
+    icmp_type: u8,
+    code: u8,
+    values: u32,
+    datagram: &[u8],
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+    pcbs: &mut ControlBlocks,
+) {
+    let header = unsafe { bytes_to_struct::<IPHeader>(datagram) };
+    let header_len = ((header.ver_len & 0x0f) << 2) as usize;
+    let src = header.src;
+    if src == IP_ADDR_ANY || src == IP_ADDR_BROADCAST || igmp::is_multicast(src) {
+        return;
+    }
+    let quoted_type = datagram.get(header_len).copied();
+    if header.protocol == IPProtocolType::Icmp as u8 && quoted_type.is_some_and(is_error_type) {
+        return;
+    }
+    if !pcbs.icmp_pcbs.error_limit.allow(src) {
+        debug!(
+            "ICMP: error type = {icmp_type} to {} over the rate limit. Dropped.",
+            ip_addr_to_str(src)
+        );
+        return;
+    }
+    let quoted = datagram[..datagram.len().min(header_len + 8)].to_vec();
+    let len = quoted.len();
+    // From the interface the error goes out of, also for datagrams not addressed to it
+    output(
+        icmp_type,
+        code,
+        values,
+        quoted,
+        len,
+        IP_ADDR_ANY,
+        src,
+        device,
+        contexts,
+        pcbs,
+    );
+}
+
+fn is_error_type(icmp_type: u8) -> bool {
+    matches!(
+        icmp_type,
+        ICMP_TYPE_DEST_UNREACH
+            | ICMP_TYPE_SOURCE_QUENCH
+            | ICMP_TYPE_REDIRECT
+            | ICMP_TYPE_TIME_EXCEEDED
+            | ICMP_TYPE_PARAM_PROBLEM
+    )
+}
+
+// Public APIs
+
+/// Registers a PCB receiving messages of a type with an identifier.
+pub fn open(pcbs: &mut IcmpPcbs, icmp_type: u8, id: u16) -> usize {
+    if pcbs.select(icmp_type, id).is_some() {
+        panic!("ICMP: type {icmp_type} with identifier {id} is already in use.");
+    }
+    for (i, entry) in pcbs.entries.iter_mut().enumerate() {
+        if entry.state == IcmpPcbState::Free {
+            entry.state = IcmpPcbState::Open;
+            entry.icmp_type = icmp_type;
+            entry.id = id;
+            return i;
+        }
+    }
+    panic!("ICMP: there's no open PCB entry.");
+}
+
+pub fn close(pcbs: &mut IcmpPcbs, pcb_id: usize) {
+    let pcb = pcbs
+        .get_mut_by_id(pcb_id)
+        .expect("ICMP: no specified PCB entry for close.");
+    if let Some(sender) = pcb.sender.take() {
+        sender.send(false).ok();
+    }
+    pcb.state = IcmpPcbState::Free;
+    pcb.icmp_type = 0;
+    pcb.id = 0;
+    pcb.data_entries.clear();
+}
+
+/// Blocks till a message is queued for the PCB. Returns None when the PCB gets closed.
+pub fn receive(pcb_id: usize, pcbs_arc: Arc<Mutex<ControlBlocks>>) -> Option<IcmpDataEntry> {
+    wait_entry(pcb_id, pcbs_arc, None)
+}
+
+/// Same as receive, but returns None also when nothing is queued within the timeout.
+pub fn receive_timeout(
+    pcb_id: usize,
+    pcbs_arc: Arc<Mutex<ControlBlocks>>,
+    timeout: Duration,
+) -> Option<IcmpDataEntry> {
+    wait_entry(pcb_id, pcbs_arc, Some(Instant::now() + timeout))
+}
+
+fn wait_entry(
+    pcb_id: usize,
+    pcbs_arc: Arc<Mutex<ControlBlocks>>,
+    deadline: Option<Instant>,
+) -> Option<IcmpDataEntry> {
+    let (sender, receiver) = mpsc::channel();
+    {
+        let pcbs = &mut pcbs_arc.lock().unwrap();
+        let pcb = pcbs
+            .icmp_pcbs
+            .get_mut_by_id(pcb_id)
+            .expect("ICMP: no specified PCB entry for receive.");
+        if let Some(entry) = pcb.data_entries.pop_front() {
+            return Some(entry);
+        }
+        pcb.sender = Some(sender);
+    }
+    loop {
+        let wakeup = match deadline {
+            Some(deadline) => {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                receiver.recv_timeout(timeout).unwrap_or(false)
+            }
+            None => receiver.recv().unwrap_or(false),
+        };
+        if !wakeup {
+            return None;
+        }
+        let pcbs = &mut pcbs_arc.lock().unwrap();
+        let pcb = pcbs
+            .icmp_pcbs
+            .get_mut_by_id(pcb_id)
+            .expect("ICMP: no specified PCB entry for receive.");
+        if pcb.state != IcmpPcbState::Open {
+            warn!("ICMP: PCB got closed for receive.");
+            return None;
+        }
+        if let Some(entry) = pcb.data_entries.pop_front() {
+            return Some(entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        echo_id_seq, input, open, quoted_udp_ports, receive_timeout, IcmpDataEntry, IcmpErrorLimit,
+        IcmpPcbs, ICMP_PCB_UDP_ERROR, ICMP_TYPE_ECHOREPLY, ICMP_TYPE_TIME_EXCEEDED,
+    };
+    use crate::{
+        devices::test_device,
+        net::NetInterfaceFamily,
+        protocols::ip::{ip_addr_to_bytes, IPRoutes},
+        protocols::{ControlBlocks, ProtocolContexts},
+        timer,
+        utils::{byte::le_to_be_u32, cksum16},
+    };
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+        time::{Duration, SystemTime},
+    };
+
+    fn entry(values: u32) -> IcmpDataEntry {
+        IcmpDataEntry {
+            icmp_type: ICMP_TYPE_ECHOREPLY,
+            code: 0,
+            values,
+            src: 0,
+            data: vec![],
+            received_at: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn test_echo_id_seq() {
+        assert_eq!((0x1234, 7), echo_id_seq(le_to_be_u32(0x1234 << 16 | 7)));
+    }
+
+    #[test]
+    fn test_deliver() {
+        let mut pcbs = IcmpPcbs::new();
+        let pcb_id = open(&mut pcbs, ICMP_TYPE_ECHOREPLY, 0x1234);
+
+        assert!(pcbs.deliver(ICMP_TYPE_ECHOREPLY, 0x1234, entry(1)));
+        assert!(!pcbs.deliver(ICMP_TYPE_ECHOREPLY, 0x4321, entry(2)));
+        assert_eq!(1, pcbs.entries[pcb_id].data_entries.len());
+        assert_eq!(1, pcbs.unmatched);
+    }
+
+    #[test]
+    fn test_receive_timeout() {
+        let pcbs_arc = Arc::new(Mutex::new(ControlBlocks::new()));
+        let pcb_id = open(
+            &mut pcbs_arc.lock().unwrap().icmp_pcbs,
+            ICMP_TYPE_ECHOREPLY,
+            0x1234,
+        );
+        assert!(receive_timeout(pcb_id, pcbs_arc.clone(), Duration::from_millis(10)).is_none());
+
+        let pcbs_clone = pcbs_arc.clone();
+        let delivery = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            let pcbs = &mut pcbs_clone.lock().unwrap();
+            pcbs.icmp_pcbs.deliver(
+                ICMP_TYPE_ECHOREPLY,
+                0x1234,
+                entry(le_to_be_u32(0x1234 << 16 | 1)),
+            );
+        });
+        let received = receive_timeout(pcb_id, pcbs_arc, Duration::from_secs(5)).unwrap();
+        assert_eq!((0x1234, 1), echo_id_seq(received.values));
+        delivery.join().unwrap();
+    }
+
+    #[test]
+    fn test_udp_error_delivery() {
+        let mut device = test_device("192.0.2.2/24");
+        let iface = device.get_interface(NetInterfaceFamily::IP).unwrap();
+        let mut contexts = ProtocolContexts::new(IPRoutes::new());
+        let mut pcbs = ControlBlocks::new();
+        let pcb_id = open(&mut pcbs.icmp_pcbs, ICMP_PCB_UDP_ERROR, 50000);
+
+        // Time Exceeded quoting IP header and UDP header of a probe 50000 -> 33434
+        let mut data = vec![ICMP_TYPE_TIME_EXCEEDED, 0, 0, 0, 0, 0, 0, 0];
+        let mut quoted = vec![
+            0x45, 0, 0, 60, 0, 0, 0, 0, 1, 17, 0, 0, 192, 0, 2, 2, 8, 8, 8, 8,
+        ];
+        quoted.extend_from_slice(&[0xc3, 0x50, 0x82, 0x9a, 0, 40, 0, 0]);
+        assert_eq!(Some((50000, 33434)), quoted_udp_ports(&quoted));
+        data.extend_from_slice(&quoted);
+        let sum = cksum16(&data, data.len(), 0);
+        data[2] = (sum >> 8) as u8;
+        data[3] = (sum & 0xff) as u8;
+
+        let src = ip_addr_to_bytes("192.0.2.1").unwrap();
+        input(
+            &data,
+            data.len(),
+            src,
+            iface.unicast,
+            &mut device,
+            &iface,
+            &mut contexts,
+            &mut pcbs,
+        )
+        .unwrap();
+        let entry = pcbs.icmp_pcbs.entries[pcb_id]
+            .data_entries
+            .pop_front()
+            .unwrap();
+        assert_eq!(ICMP_TYPE_TIME_EXCEEDED, entry.icmp_type);
+        assert_eq!(src, entry.src);
+    }
+
+    #[test]
+    fn test_error_limit() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        timer::set_simulated_now(Some(start));
+        let mut limit = IcmpErrorLimit::new();
+        (limit.rate, limit.burst) = (1, 8);
+        let (a, b) = (1, 2);
+
+        // Burst per destination, then one a second
+        assert_eq!(6, (0..10).filter(|_| limit.allow(a)).count());
+        timer::set_simulated_now(Some(start + Duration::from_millis(999)));
+        assert!(!limit.allow(a));
+        timer::set_simulated_now(Some(start + Duration::from_secs(1)));
+        assert!(limit.allow(a));
+        assert!(!limit.allow(a));
+
+        // What is left of the global burst for another destination
+        assert_eq!(2, (0..10).filter(|_| limit.allow(b)).count());
+        assert_eq!(14, limit.limited);
+        timer::set_simulated_now(None);
+    }
 }
@@ -0,0 +1,493 @@
+use super::{l4::L4Protocol, output_protocol, IPAdress, IPInterface, IPProtocolType, IP_ADDR_ANY};
+use crate::{
+    devices::{ethernet::ETH_ADDR_LEN, NetDevice},
+    protocols::{ip::ip_addr_to_str, ControlBlocks, ProtocolContexts},
+    timer,
+    utils::{bytes_to_struct, cksum16, to_u8_slice},
+};
+use log::{debug, info, warn};
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    mem::size_of,
+    time::{Duration, SystemTime},
+};
+
+const IGMP_TYPE_MEMBERSHIP_QUERY: u8 = 0x11;
+const IGMP_TYPE_V1_MEMBERSHIP_REPORT: u8 = 0x12;
+const IGMP_TYPE_V2_MEMBERSHIP_REPORT: u8 = 0x16;
+const IGMP_TYPE_LEAVE_GROUP: u8 = 0x17;
+
+const IGMP_TTL: u8 = 1;
+const IGMP_V1_MAX_RESP_TIME: u8 = 100; // 10s in 1/10 second of queries without one (RFC 2236 4)
+
+pub const IP_ADDR_ALL_HOSTS: IPAdress = u32::from_ne_bytes([224, 0, 0, 1]);
+pub const IP_ADDR_ALL_ROUTERS: IPAdress = u32::from_ne_bytes([224, 0, 0, 2]);
+
+#[repr(packed)]
+struct IgmpMessage {
+    msg_type: u8,
+    max_resp_time: u8, // in 1/10 second
+    check_sum: u16,
+    group: IPAdress,
+}
+
+/// Whether an address is of class D (224.0.0.0/4).
+pub fn is_multicast(addr: IPAdress) -> bool {
+    addr.to_ne_bytes()[0] & 0xf0 == 0xe0
+}
+
+/// Ethernet address of a group: 01:00:5e followed by the low 23 bits of it (RFC 1112 6.4).
+pub fn multicast_hw_addr(group: IPAdress) -> [u8; ETH_ADDR_LEN] {
+    let octets = group.to_ne_bytes();
+    [0x01, 0x00, 0x5e, octets[1] & 0x7f, octets[2], octets[3]]
+}
+
+struct IgmpGroup {
+    members: usize,                // joins not left yet, e.g. of sockets
+    report_at: Option<SystemTime>, // report delayed in reply to a query
+    report_device: String,         // device the query arrived on, which the report goes out of
+    last_reporter: bool,           // no report of others heard since ours, so Leave is sent
+}
+
+/// Multicast groups the host is a member of on the interface, with reports pending in reply to
+/// queries. All-hosts group is always joined.
+pub struct IgmpGroups {
+    entries: HashMap<IPAdress, IgmpGroup>,
+    pub reports_sent: u64,
+    pub queries_received: u64,
+}
+
+impl IgmpGroups {
+    pub fn new() -> IgmpGroups {
+        IgmpGroups {
+            entries: HashMap::new(),
+            reports_sent: 0,
+            queries_received: 0,
+        }
+    }
+
+    pub fn contains(&self, group: IPAdress) -> bool {
+        group == IP_ADDR_ALL_HOSTS || self.entries.contains_key(&group)
+    }
+
+    /// Groups joined, all-hosts group excluded.
+    pub fn groups(&self) -> Vec<IPAdress> {
+        let mut groups: Vec<IPAdress> = self.entries.keys().copied().collect();
+        groups.sort_by_key(|group| u32::from_be(*group));
+        groups
+    }
+
+    /// Delays a report of a group out of the device of a query to a random time within the max
+    /// response time of the query, unless one is due earlier.
+    fn delay_report(&mut self, group: IPAdress, max_resp: Duration, device: &str) {
+        let Some(entry) = self.entries.get_mut(&group) else {
+            return;
+        };
+        let at = timer::now() + rand::thread_rng().gen_range(Duration::ZERO..=max_resp);
+        if entry.report_at.is_none_or(|report_at| at < report_at) {
+            entry.report_at = Some(at);
+            entry.report_device = device.to_string();
+        }
+    }
+}
+
+/// Joins a multicast group. The first join sends an unsolicited report.
+pub fn join(
+    group: IPAdress,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+) -> Result<(), ()> {
+    if !is_multicast(group) || group == IP_ADDR_ALL_HOSTS {
+        warn!("IGMP: {} is not a group to join.", ip_addr_to_str(group));
+        return Err(());
+    }
+    let entry = contexts
+        .igmp_groups
+        .entries
+        .entry(group)
+        .or_insert(IgmpGroup {
+            members: 0,
+            report_at: None,
+            report_device: String::new(),
+            last_reporter: true,
+        });
+    entry.members += 1;
+    if entry.members == 1 {
+        info!("IGMP: joining group {}", ip_addr_to_str(group));
+        output(
+            IGMP_TYPE_V2_MEMBERSHIP_REPORT,
+            group,
+            group,
+            device,
+            contexts,
+        )?;
+    }
+    Ok(())
+}
+
+/// Leaves a multicast group. The last leave sends Leave Group when our report was the last one.
+pub fn leave(
+    group: IPAdress,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+) -> Result<(), ()> {
+    let Some(entry) = contexts.igmp_groups.entries.get_mut(&group) else {
+        warn!("IGMP: group {} is not joined.", ip_addr_to_str(group));
+        return Err(());
+    };
+    entry.members -= 1;
+    if entry.members > 0 {
+        return Ok(());
+    }
+    info!("IGMP: leaving group {}", ip_addr_to_str(group));
+    let entry = contexts.igmp_groups.entries.remove(&group).unwrap();
+    if entry.last_reporter {
+        output(
+            IGMP_TYPE_LEAVE_GROUP,
+            group,
+            IP_ADDR_ALL_ROUTERS,
+            device,
+            contexts,
+        )?;
+    }
+    Ok(())
+}
+
+fn output(
+    msg_type: u8,
+    group: IPAdress,
+    dst: IPAdress,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+) -> Result<(), ()> {
+    let msg = IgmpMessage {
+        msg_type,
+        max_resp_time: 0,
+        check_sum: 0,
+        group,
+    };
+    let mut data = unsafe { to_u8_slice(&msg) }.to_vec();
+    let check_sum = cksum16(&data, data.len(), 0);
+    data[2..4].copy_from_slice(&check_sum.to_be_bytes());
+    if msg_type == IGMP_TYPE_V2_MEMBERSHIP_REPORT {
+        contexts.igmp_groups.reports_sent += 1;
+    }
+    output_protocol(
+        IPProtocolType::Igmp as u8,
+        data,
+        IP_ADDR_ANY,
+        dst,
+        IGMP_TTL,
+        device,
+        contexts,
+    )
+}
+
+/// IGMPv2 host part (RFC 2236): answers queries with reports delayed at random, suppressed by
+/// reports of other members heard meanwhile.
+pub struct IgmpProtocol;
+
+impl L4Protocol for IgmpProtocol {
+    fn number(&self) -> u8 {
+        IPProtocolType::Igmp as u8
+    }
+
+    fn name(&self) -> &str {
+        "igmp"
+    }
+
+    fn input(
+        &self,
+        data: &[u8],
+        len: usize,
+        src: IPAdress,
+        _dst: IPAdress,
+        device: &mut NetDevice,
+        _iface: &IPInterface,
+        contexts: &mut ProtocolContexts,
+        _pcbs: &mut ControlBlocks,
+    ) -> Result<(), ()> {
+        if len < size_of::<IgmpMessage>() || cksum16(data, len, 0) != 0 {
+            warn!("IGMP: malformed message from {}", ip_addr_to_str(src));
+            return Err(());
+        }
+        let msg = unsafe { bytes_to_struct::<IgmpMessage>(data) };
+        let groups = &mut contexts.igmp_groups;
+        match msg.msg_type {
+            IGMP_TYPE_MEMBERSHIP_QUERY => {
+                groups.queries_received += 1;
+                let max_resp_time = match msg.max_resp_time {
+                    0 => IGMP_V1_MAX_RESP_TIME,
+                    time => time,
+                };
+                let max_resp = Duration::from_millis(max_resp_time as u64 * 100);
+                debug!(
+                    "IGMP: query for {} from {}",
+                    ip_addr_to_str(msg.group),
+                    ip_addr_to_str(src)
+                );
+                let targets = match msg.group {
+                    IP_ADDR_ANY => groups.groups(),
+                    group => vec![group],
+                };
+                for group in targets {
+                    groups.delay_report(group, max_resp, &device.name);
+                }
+            }
+            IGMP_TYPE_V1_MEMBERSHIP_REPORT | IGMP_TYPE_V2_MEMBERSHIP_REPORT => {
+                let group = msg.group;
+                if let Some(entry) = groups.entries.get_mut(&group) {
+                    if entry.report_at.take().is_some() {
+                        debug!("IGMP: report of {} suppressed", ip_addr_to_str(group));
+                    }
+                    entry.last_reporter = false;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Sends reports due out of the device, those in reply to queries arrived on it.
+    fn timer_tick(
+        &self,
+        device: &mut NetDevice,
+        contexts: &mut ProtocolContexts,
+        _pcbs: &mut ControlBlocks,
+    ) {
+        let mut due = vec![];
+        for (group, entry) in contexts.igmp_groups.entries.iter_mut() {
+            if entry.report_at.is_some_and(timer::is_due) && entry.report_device == device.name {
+                entry.report_at = None;
+                entry.last_reporter = true;
+                due.push(*group);
+            }
+        }
+        for group in due {
+            if output(
+                IGMP_TYPE_V2_MEMBERSHIP_REPORT,
+                group,
+                group,
+                device,
+                contexts,
+            )
+            .is_err()
+            {
+                warn!("IGMP: failed to report group {}", ip_addr_to_str(group));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        is_multicast, join, leave, multicast_hw_addr, IgmpMessage, IGMP_TYPE_LEAVE_GROUP,
+        IGMP_TYPE_MEMBERSHIP_QUERY, IGMP_TYPE_V2_MEMBERSHIP_REPORT, IP_ADDR_ALL_HOSTS,
+        IP_ADDR_ALL_ROUTERS,
+    };
+    use crate::{
+        devices::{ethernet::EthernetHeader, test_device, NetDevice, NetDeviceType, NetDevices},
+        net::NetInterfaceFamily,
+        protocols::ip::{
+            self, create_ip_header, ip_addr_to_bytes, l4, IPAdress, IPRoute, IPRoutes, IP_ADDR_ANY,
+        },
+        protocols::{ControlBlocks, ProtocolContexts},
+        timer,
+        utils::{bytes_to_struct, cksum16, to_u8_slice},
+    };
+    use std::{
+        mem::size_of,
+        time::{Duration, SystemTime},
+    };
+
+    fn setup() -> (NetDevice, ProtocolContexts) {
+        let device = test_device("192.0.2.2/24");
+        let mut routes = IPRoutes::new();
+        let interface = device.get_interface(NetInterfaceFamily::IP).unwrap();
+        routes.register(IPRoute::interface_route(interface));
+        (device, ProtocolContexts::new(routes))
+    }
+
+    /// Message sent last with the destination MAC and IP address.
+    fn sent(device: &mut NetDevice) -> Option<([u8; 6], IPAdress, u8, IPAdress)> {
+        let frame = device.driver_data.as_mut().unwrap().tx_frames.pop_front()?;
+        let eth = unsafe { bytes_to_struct::<EthernetHeader>(&frame) };
+        let ip_data = &frame[size_of::<EthernetHeader>()..];
+        let ip_header = unsafe { bytes_to_struct::<ip::IPHeader>(ip_data) };
+        assert_eq!(1, ip_header.ttl);
+        let msg = unsafe { bytes_to_struct::<IgmpMessage>(&ip_data[ip::IP_HEADER_MIN_SIZE..]) };
+        Some((eth.dst, ip_header.dst, msg.msg_type, msg.group))
+    }
+
+    fn receive(
+        msg_type: u8,
+        max_resp_time: u8,
+        group: IPAdress,
+        dst: IPAdress,
+        device: &mut NetDevice,
+        contexts: &mut ProtocolContexts,
+    ) {
+        let msg = IgmpMessage {
+            msg_type,
+            max_resp_time,
+            check_sum: 0,
+            group,
+        };
+        let mut data = unsafe { to_u8_slice(&msg) }.to_vec();
+        let check_sum = cksum16(&data, data.len(), 0);
+        data[2..4].copy_from_slice(&check_sum.to_be_bytes());
+        let src = ip_addr_to_bytes("192.0.2.1").unwrap();
+        let header = create_ip_header(2, src, dst, &data, 1, 1);
+        let mut datagram = unsafe { to_u8_slice(&header) }.to_vec();
+        datagram.extend_from_slice(&data);
+        let len = datagram.len();
+        ip::input(
+            &datagram,
+            len,
+            None,
+            device,
+            contexts,
+            &mut ControlBlocks::new(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_multicast_address() {
+        let group = ip_addr_to_bytes("239.255.255.250").unwrap();
+        assert!(is_multicast(group));
+        assert!(!is_multicast(ip_addr_to_bytes("192.0.2.1").unwrap()));
+        assert_eq!(
+            [0x01, 0x00, 0x5e, 0x7f, 0xff, 0xfa],
+            multicast_hw_addr(group)
+        );
+        // High bit of the second octet is not mapped.
+        let other = ip_addr_to_bytes("224.128.0.1").unwrap();
+        assert_eq!(
+            [0x01, 0x00, 0x5e, 0x00, 0x00, 0x01],
+            multicast_hw_addr(other)
+        );
+    }
+
+    #[test]
+    fn test_join_leave() {
+        let (mut device, mut contexts) = setup();
+        let group = ip_addr_to_bytes("239.1.2.3").unwrap();
+        assert!(join(
+            ip_addr_to_bytes("192.0.2.1").unwrap(),
+            &mut device,
+            &mut contexts
+        )
+        .is_err());
+
+        join(group, &mut device, &mut contexts).unwrap();
+        let report = (
+            multicast_hw_addr(group),
+            group,
+            IGMP_TYPE_V2_MEMBERSHIP_REPORT,
+            group,
+        );
+        assert_eq!(Some(report), sent(&mut device));
+        // Second member does not report again, and the group is kept till it leaves.
+        join(group, &mut device, &mut contexts).unwrap();
+        assert_eq!(None, sent(&mut device));
+        leave(group, &mut device, &mut contexts).unwrap();
+        assert!(contexts.igmp_groups.contains(group));
+        assert_eq!(None, sent(&mut device));
+        leave(group, &mut device, &mut contexts).unwrap();
+        assert!(!contexts.igmp_groups.contains(group));
+        let leave_msg = (
+            multicast_hw_addr(IP_ADDR_ALL_ROUTERS),
+            IP_ADDR_ALL_ROUTERS,
+            IGMP_TYPE_LEAVE_GROUP,
+            group,
+        );
+        assert_eq!(Some(leave_msg), sent(&mut device));
+        assert!(leave(group, &mut device, &mut contexts).is_err());
+    }
+
+    #[test]
+    fn test_query_report() {
+        let (mut device, mut contexts) = setup();
+        let (a, b) = (
+            ip_addr_to_bytes("239.1.2.3").unwrap(),
+            ip_addr_to_bytes("239.1.2.4").unwrap(),
+        );
+        join(a, &mut device, &mut contexts).unwrap();
+        join(b, &mut device, &mut contexts).unwrap();
+        device.driver_data.as_mut().unwrap().tx_frames.clear();
+
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        timer::set_simulated_now(Some(start));
+        // General query with 1s max response time
+        receive(
+            IGMP_TYPE_MEMBERSHIP_QUERY,
+            10,
+            IP_ADDR_ANY,
+            IP_ADDR_ALL_HOSTS,
+            &mut device,
+            &mut contexts,
+        );
+        assert_eq!(1, contexts.igmp_groups.queries_received);
+        // Another member reports b first, suppressing ours.
+        receive(
+            IGMP_TYPE_V2_MEMBERSHIP_REPORT,
+            0,
+            b,
+            b,
+            &mut device,
+            &mut contexts,
+        );
+
+        timer::set_simulated_now(Some(start + Duration::from_secs(1)));
+        l4::timer_tick(&mut device, &mut contexts, &mut ControlBlocks::new());
+        let report = (multicast_hw_addr(a), a, IGMP_TYPE_V2_MEMBERSHIP_REPORT, a);
+        assert_eq!(Some(report), sent(&mut device));
+        assert_eq!(None, sent(&mut device));
+
+        // Not the last reporter of b, so leaving it is silent.
+        leave(b, &mut device, &mut contexts).unwrap();
+        assert_eq!(None, sent(&mut device));
+        timer::set_simulated_now(None);
+    }
+
+    #[test]
+    fn test_report_out_of_queried_device() {
+        let (device, mut contexts) = setup();
+        let mut other = test_device("198.51.100.2/24");
+        other.name = String::from("tap1");
+        let mut devices = NetDevices::new();
+        devices.register(device);
+        devices.register(other);
+        let group = ip_addr_to_bytes("239.1.2.3").unwrap();
+        let tap1 = devices.get_mut_by_name("tap1").unwrap();
+        join(group, tap1, &mut contexts).unwrap();
+        tap1.driver_data.as_mut().unwrap().tx_frames.clear();
+
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        timer::set_simulated_now(Some(start));
+        receive(
+            IGMP_TYPE_MEMBERSHIP_QUERY,
+            10,
+            IP_ADDR_ANY,
+            IP_ADDR_ALL_HOSTS,
+            tap1,
+            &mut contexts,
+        );
+        timer::set_simulated_now(Some(start + Duration::from_secs(1)));
+        l4::timer_tick_on_devices(&mut devices, &mut contexts, &mut ControlBlocks::new());
+        let report = (
+            multicast_hw_addr(group),
+            group,
+            IGMP_TYPE_V2_MEMBERSHIP_REPORT,
+            group,
+        );
+        assert_eq!(Some(report), sent(devices.get_mut_by_name("tap1").unwrap()));
+        let first = devices.get_mut_by_type(NetDeviceType::Ethernet).unwrap();
+        assert_ne!("tap1", first.name);
+        assert_eq!(None, sent(first));
+        timer::set_simulated_now(None);
+    }
+}
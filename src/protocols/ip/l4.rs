@@ -0,0 +1,190 @@
+use super::{
+    icmp::IcmpProtocol, igmp::IgmpProtocol, tcp::TcpProtocol, udp::UdpProtocol, IPAdress,
+    IPInterface,
+};
+use crate::{
+    devices::{NetDevice, NetDevices},
+    protocols::{ControlBlocks, ProtocolContexts},
+};
+use std::{collections::HashMap, sync::Arc};
+
+/// Transport protocol over IPv4 registered by its IP protocol number. Implementations keep
+/// their state in PCBs or behind their own locks as they are shared by input and timer threads.
+pub trait L4Protocol: Send + Sync {
+    /// IP protocol number of datagrams handled (e.g. 6 for TCP).
+    fn number(&self) -> u8;
+
+    fn name(&self) -> &str;
+
+    /// Handles data of a datagram addressed to the interface, of `len` bytes without IP header.
+    #[allow(clippy::too_many_arguments)]
+    fn input(
+        &self,
+        data: &[u8],
+        len: usize,
+        src: IPAdress,
+        dst: IPAdress,
+        device: &mut NetDevice,
+        iface: &IPInterface,
+        contexts: &mut ProtocolContexts,
+        pcbs: &mut ControlBlocks,
+    ) -> Result<(), ()>;
+
+    /// Handles an ICMP error quoting a datagram of the protocol from its IP header. Returns
+    /// false to leave it to ICMP PCBs waiting for errors.
+    fn icmp_error(
+        &self,
+        _icmp_type: u8,
+        _code: u8,
+        _quoted: &[u8],
+        _pcbs: &mut ControlBlocks,
+    ) -> bool {
+        false
+    }
+
+    /// Called every 100ms by the timer thread for each device, e.g. for retransmissions.
+    fn timer_tick(
+        &self,
+        _device: &mut NetDevice,
+        _contexts: &mut ProtocolContexts,
+        _pcbs: &mut ControlBlocks,
+    ) {
+    }
+}
+
+/// Protocols `ip::input` dispatches datagrams to, ICMP, IGMP, TCP and UDP by default.
+pub struct L4Protocols {
+    entries: HashMap<u8, Arc<dyn L4Protocol>>,
+}
+
+impl L4Protocols {
+    pub fn new() -> L4Protocols {
+        let mut protocols = L4Protocols {
+            entries: HashMap::new(),
+        };
+        protocols.register(Arc::new(IcmpProtocol));
+        protocols.register(Arc::new(IgmpProtocol));
+        protocols.register(Arc::new(TcpProtocol));
+        protocols.register(Arc::new(UdpProtocol));
+        protocols
+    }
+
+    /// Registers a protocol replacing one with the same number, which is returned.
+    pub fn register(&mut self, protocol: Arc<dyn L4Protocol>) -> Option<Arc<dyn L4Protocol>> {
+        self.entries.insert(protocol.number(), protocol)
+    }
+
+    pub fn unregister(&mut self, number: u8) -> Option<Arc<dyn L4Protocol>> {
+        self.entries.remove(&number)
+    }
+
+    /// Protocol of a number, shared so that it can be called with the contexts it is kept in.
+    pub fn get(&self, number: u8) -> Option<Arc<dyn L4Protocol>> {
+        self.entries.get(&number).cloned()
+    }
+
+    /// Protocols by number.
+    pub fn all(&self) -> Vec<Arc<dyn L4Protocol>> {
+        let mut protocols: Vec<Arc<dyn L4Protocol>> = self.entries.values().cloned().collect();
+        protocols.sort_by_key(|protocol| protocol.number());
+        protocols
+    }
+}
+
+/// Ticks timers of all registered protocols.
+pub fn timer_tick(
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+    pcbs: &mut ControlBlocks,
+) {
+    for protocol in contexts.l4_protocols.all() {
+        protocol.timer_tick(device, contexts, pcbs);
+    }
+}
+
+/// Same as timer_tick on a stack of several devices, ticking protocols for each of them.
+pub fn timer_tick_on_devices(
+    devices: &mut NetDevices,
+    contexts: &mut ProtocolContexts,
+    pcbs: &mut ControlBlocks,
+) {
+    for device in devices.entries.iter_mut() {
+        timer_tick(device, contexts, pcbs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::L4Protocol;
+    use crate::{
+        devices::{test_device, NetDevice},
+        protocols::{
+            ip::{self, create_ip_header, ip_addr_to_bytes, IPAdress, IPInterface, IPRoutes},
+            ControlBlocks, ProtocolContexts,
+        },
+        utils::to_u8_slice,
+    };
+    use std::sync::{Arc, Mutex};
+
+    /// Experimental protocol number (RFC 3692) recording data received.
+    struct Recorder {
+        received: Mutex<Vec<Vec<u8>>>,
+    }
+
+    impl L4Protocol for Recorder {
+        fn number(&self) -> u8 {
+            253
+        }
+
+        fn name(&self) -> &str {
+            "recorder"
+        }
+
+        fn input(
+            &self,
+            data: &[u8],
+            len: usize,
+            _src: IPAdress,
+            _dst: IPAdress,
+            _device: &mut NetDevice,
+            _iface: &IPInterface,
+            _contexts: &mut ProtocolContexts,
+            _pcbs: &mut ControlBlocks,
+        ) -> Result<(), ()> {
+            self.received.lock().unwrap().push(data[..len].to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_custom_protocol() {
+        let mut device = test_device("192.0.2.2/24");
+        let mut contexts = ProtocolContexts::new(IPRoutes::new());
+        let mut pcbs = ControlBlocks::new();
+        let recorder = Arc::new(Recorder {
+            received: Mutex::new(vec![]),
+        });
+        assert!(contexts.l4_protocols.register(recorder.clone()).is_none());
+
+        let src = ip_addr_to_bytes("192.0.2.1").unwrap();
+        let dst = ip_addr_to_bytes("192.0.2.2").unwrap();
+        let data = b"custom".to_vec();
+        let header = create_ip_header(253, src, dst, &data, 1, 64);
+        let mut datagram = unsafe { to_u8_slice(&header) }.to_vec();
+        datagram.extend_from_slice(&data);
+        let len = datagram.len();
+        ip::input(&datagram, len, None, &mut device, &mut contexts, &mut pcbs).unwrap();
+        assert_eq!(vec![data], *recorder.received.lock().unwrap());
+
+        let numbers: Vec<u8> = contexts
+            .l4_protocols
+            .all()
+            .iter()
+            .map(|protocol| protocol.number())
+            .collect();
+        assert_eq!(vec![1, 2, 6, 17, 253], numbers);
+        contexts.l4_protocols.unregister(253);
+        ip::input(&datagram, len, None, &mut device, &mut contexts, &mut pcbs).unwrap();
+        assert_eq!(1, recorder.received.lock().unwrap().len());
+    }
+}
@@ -1,23 +1,51 @@
+pub mod cidr;
+pub mod congestion;
+pub mod dhcp;
 pub mod icmp;
+pub mod igmp;
+pub mod l4;
+pub mod nat;
 pub mod tcp;
+pub mod tcp_options;
+pub mod trie;
 pub mod udp;
 
-use log::{error, info, trace, warn};
+use log::{debug, error, info, trace, warn};
 
 use super::arp::arp_resolve;
 use super::{ControlBlocks, ProtocolContexts};
+use crate::devices::{NetDeviceType, NetDevices};
+#[cfg(feature = "fault-injection")]
+use crate::fault::{self, Fault};
 use crate::net::{NetInterface, NetInterfaceFamily};
 use crate::{
-    devices::{ethernet::ETH_ADDR_LEN, NetDevice, DEVICE_FLAG_NEED_ARP},
-    utils::byte::{be_to_le_u16, be_to_le_u32, le_to_be_u16},
-    utils::list::List,
+    devices::{
+        ethernet::{VlanTag, ETH_ADDR_LEN},
+        NetDevice,
+    },
+    utils::byte::{be_to_le_u16, le_to_be_u16},
     utils::{bytes_to_struct, cksum16, to_u8_slice},
 };
+use cidr::IpCidr;
+use dhcp::DHCP_SERVER_PORT;
+use icmp::{
+    ICMP_CODE_EXCEEDED_TTL, ICMP_CODE_FRAGMENT_NEEDED, ICMP_CODE_NET_UNREACH,
+    ICMP_CODE_PORT_UNREACH, ICMP_CODE_PROTO_UNREACH, ICMP_TYPE_DEST_UNREACH,
+    ICMP_TYPE_PARAM_PROBLEM, ICMP_TYPE_TIME_EXCEEDED,
+};
 use std::{
-    convert::TryInto,
+    cmp,
+    collections::hash_map::DefaultHasher,
+    fmt,
+    hash::{Hash, Hasher},
     mem::size_of,
-    sync::{Arc, Mutex},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Arc, Mutex,
+    },
 };
+use trie::PrefixTrie;
 
 pub type IPAdress = u32;
 
@@ -27,9 +55,11 @@ const IP_HEADER_MIN_SIZE: usize = 20;
 const IP_PAYLOAD_MAX_SIZE: usize = IP_MAX_SIZE - IP_HEADER_MIN_SIZE;
 
 const IP_VERSION_4: u8 = 4;
+pub const IP_TTL_DEFAULT: u8 = 0xff;
+const IP_FLAG_DONT_FRAGMENT: u16 = 0x4000;
 
-const IP_ADDR_ANY: IPAdress = 0x00000000; // 0.0.0.0
-const IP_ADDR_BROADCAST: IPAdress = 0xffffffff; // 255.255.255.255
+pub const IP_ADDR_ANY: IPAdress = 0x00000000; // 0.0.0.0
+pub const IP_ADDR_BROADCAST: IPAdress = 0xffffffff; // 255.255.255.255
 
 pub struct IPEndpoint {
     pub address: IPAdress,
@@ -52,6 +82,57 @@ impl IPEndpoint {
     }
 }
 
+/// `address:port` with `*` for an unspecified port as in netstat.
+impl fmt::Display for IPEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match be_to_le_u16(self.port) {
+            0 => write!(f, "{}:*", ip_addr_to_str(self.address)),
+            port => write!(f, "{}:{port}", ip_addr_to_str(self.address)),
+        }
+    }
+}
+
+/// Handling of options of datagrams forwarded out of an interface.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IPOptionsPolicy {
+    Pass,  // forwarded as is
+    Strip, // forwarded without options
+    Drop,  // dropped with ICMP parameter problem pointing at the options
+}
+
+impl IPOptionsPolicy {
+    fn from_u8(value: u8) -> IPOptionsPolicy {
+        match value {
+            1 => IPOptionsPolicy::Strip,
+            2 => IPOptionsPolicy::Drop,
+            _ => IPOptionsPolicy::Pass,
+        }
+    }
+}
+
+impl FromStr for IPOptionsPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pass" => Ok(IPOptionsPolicy::Pass),
+            "strip" => Ok(IPOptionsPolicy::Strip),
+            "drop" => Ok(IPOptionsPolicy::Drop),
+            _ => Err(format!("unknown IP options policy: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for IPOptionsPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IPOptionsPolicy::Pass => write!(f, "pass"),
+            IPOptionsPolicy::Strip => write!(f, "strip"),
+            IPOptionsPolicy::Drop => write!(f, "drop"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct IPInterface {
     pub interface: NetInterface,
@@ -59,16 +140,26 @@ pub struct IPInterface {
     pub unicast: IPAdress,
     pub netmask: IPAdress,
     pub broadcast: IPAdress,
+    pub proxy_arp: AtomicBool, // answers ARP requests for addresses routed via other interfaces
+    options_policy: AtomicU8,  // IPOptionsPolicy of forwarded datagrams
+    pub vlan: Option<VlanTag>, // tagged frames of the VLAN only, untagged ones without
 }
 
 impl IPInterface {
-    pub fn new(unicast: &str, netmask: &str) -> IPInterface {
+    /// Interface of an address and its subnet in CIDR notation, e.g. "192.0.2.2/24".
+    pub fn new(cidr: &str) -> IPInterface {
+        IPInterface::from_cidr(cidr.parse().unwrap())
+    }
+
+    pub fn from_cidr(cidr: IpCidr) -> IPInterface {
+        IPInterface::new_from_addr(cidr.address, cidr.netmask())
+    }
+
+    pub fn new_from_addr(unicast: IPAdress, netmask: IPAdress) -> IPInterface {
         let interface = NetInterface {
             family: NetInterfaceFamily::IP,
             next: None,
         };
-        let unicast = ip_addr_to_bytes(unicast).unwrap();
-        let netmask = ip_addr_to_bytes(netmask).unwrap();
         // unicast & netmask = nw address => nw address | !nestmask (all hosts) = broadcast
         let broadcast = (unicast & netmask) | !netmask;
 
@@ -78,66 +169,289 @@ impl IPInterface {
             unicast,
             netmask,
             broadcast,
+            proxy_arp: AtomicBool::new(false),
+            options_policy: AtomicU8::new(IPOptionsPolicy::Pass as u8),
+            vlan: None,
         }
     }
+
+    /// Binds the interface to a VLAN: it receives frames tagged with the VLAN id, and frames
+    /// sent from it are tagged with the id and the priority (PCP) of the tag.
+    pub fn with_vlan(mut self, vlan: VlanTag) -> IPInterface {
+        self.vlan = Some(vlan);
+        self
+    }
+
+    pub fn vlan_id(&self) -> Option<u16> {
+        self.vlan.map(|tag| tag.id)
+    }
+
+    pub fn cidr(&self) -> IpCidr {
+        IpCidr {
+            address: self.unicast,
+            prefix_len: self.netmask.count_ones() as u8,
+        }
+    }
+
+    pub fn proxy_arp(&self) -> bool {
+        self.proxy_arp.load(Ordering::Relaxed)
+    }
+
+    pub fn set_proxy_arp(&self, enabled: bool) {
+        self.proxy_arp.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn options_policy(&self) -> IPOptionsPolicy {
+        IPOptionsPolicy::from_u8(self.options_policy.load(Ordering::Relaxed))
+    }
+
+    pub fn set_options_policy(&self, policy: IPOptionsPolicy) {
+        self.options_policy.store(policy as u8, Ordering::Relaxed);
+    }
 }
 
 pub struct IPRoute {
-    network: IPAdress,
-    netmask: IPAdress,
+    destination: IpCidr, // network with host bits cleared
     next_hop: IPAdress,
     pub interface: Arc<IPInterface>,
+    metric: u32, // lower wins among routes of the same prefix length
+    down: bool,  // gateway not answering ARP probes, skipped by lookup
 }
 
 impl IPRoute {
     pub fn interface_route(interface: Arc<IPInterface>) -> IPRoute {
         IPRoute {
-            network: interface.unicast & interface.netmask,
-            netmask: interface.netmask,
+            destination: interface.cidr().trunc(),
             next_hop: IP_ADDR_ANY,
             interface,
+            metric: 0,
+            down: false,
         }
     }
 
-    pub fn gateway_route(gateway_ip: &str, interface: Arc<IPInterface>) -> IPRoute {
+    /// Route to a network via a gateway on the subnet of an interface.
+    pub fn network_route(
+        destination: IpCidr,
+        gateway: IPAdress,
+        metric: u32,
+        interface: Arc<IPInterface>,
+    ) -> IPRoute {
         IPRoute {
-            network: IP_ADDR_ANY,
-            netmask: IP_ADDR_ANY,
-            next_hop: ip_addr_to_bytes(gateway_ip).unwrap(),
+            destination: destination.trunc(),
+            next_hop: gateway,
             interface,
+            metric,
+            down: false,
+        }
+    }
+
+    pub fn gateway_route(gateway_ip: &str, interface: Arc<IPInterface>) -> IPRoute {
+        IPRoute::gateway_route_from_addr(ip_addr_to_bytes(gateway_ip).unwrap(), interface)
+    }
+
+    pub fn gateway_route_from_addr(gateway: IPAdress, interface: Arc<IPInterface>) -> IPRoute {
+        let default = IpCidr {
+            address: IP_ADDR_ANY,
+            prefix_len: 0,
+        };
+        IPRoute::network_route(default, gateway, 0, interface)
+    }
+}
+
+/// `network/prefix via gateway` (or `dev` for interface routes) as in `ip route`.
+impl fmt::Display for IPRoute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.destination)?;
+        match self.next_hop {
+            IP_ADDR_ANY => write!(f, " dev")?,
+            gateway => write!(f, " via {}", ip_addr_to_str(gateway))?,
+        }
+        write!(
+            f,
+            " src {} metric {}",
+            ip_addr_to_str(self.interface.unicast),
+            self.metric
+        )?;
+        if self.down {
+            write!(f, " down")?;
+        }
+        Ok(())
+    }
+}
+
+/// Reason of refusing to change routes at runtime.
+#[derive(Debug, PartialEq)]
+pub enum RouteError {
+    HostBitsSet(IpCidr),
+    GatewayNotConnected(IPAdress),
+    Exists,
+    NotFound,
+}
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteError::HostBitsSet(destination) => write!(
+                f,
+                "network {destination} has bits set outside the prefix: clear its host part, e.g. {}",
+                destination.trunc()
+            ),
+            RouteError::GatewayNotConnected(gateway) => write!(
+                f,
+                "gateway {} is not on the subnet of any interface: use a directly connected one",
+                ip_addr_to_str(*gateway)
+            ),
+            RouteError::Exists => write!(f, "route exists already: delete it first"),
+            RouteError::NotFound => write!(f, "no such route"),
         }
     }
 }
+
 pub struct IPRoutes {
-    entries: List<IPRoute>,
+    entries: Vec<IPRoute>,
+    trie: PrefixTrie<usize>, // indexes of entries by destination for longest prefix match
 }
 
 impl IPRoutes {
     pub fn new() -> IPRoutes {
         IPRoutes {
-            entries: List::<IPRoute>::new(),
+            entries: vec![],
+            trie: PrefixTrie::new(),
         }
     }
 
     pub fn register(&mut self, route: IPRoute) {
+        self.trie.insert(route.destination, self.entries.len());
         self.entries.push(route);
     }
 
-    pub fn lookup_ip_route(&self, dst: IPAdress) -> Option<&IPRoute> {
-        let mut candidate = None;
-        for route in self.entries.iter() {
-            if (dst & route.netmask) == route.network {
-                if candidate.is_none() {
-                    candidate = Some(route);
-                } else {
-                    let candidate_route = candidate.unwrap();
-                    if be_to_le_u32(candidate_route.netmask) < be_to_le_u32(route.netmask) {
-                        candidate = Some(route);
-                    }
+    /// Rebuilds the trie after entries are removed or their destinations change.
+    fn reindex(&mut self) {
+        self.trie.clear();
+        for (i, route) in self.entries.iter().enumerate() {
+            self.trie.insert(route.destination, i);
+        }
+    }
+
+    /// Adds a route to a network via a gateway on the subnet of an interface of devices.
+    pub fn add(
+        &mut self,
+        destination: IpCidr,
+        gateway: IPAdress,
+        metric: u32,
+        devices: &NetDevices,
+    ) -> Result<(), RouteError> {
+        if !destination.is_network() {
+            return Err(RouteError::HostBitsSet(destination));
+        }
+        let interface = devices
+            .entries
+            .iter()
+            .flat_map(|device| device.interfaces.iter())
+            .find(|interface| {
+                interface.unicast != IP_ADDR_ANY && interface.cidr().contains(gateway)
+            })
+            .ok_or(RouteError::GatewayNotConnected(gateway))?;
+        let exists = self
+            .entries
+            .iter()
+            .any(|route| route.destination == destination && route.next_hop == gateway);
+        if exists {
+            return Err(RouteError::Exists);
+        }
+        let route = IPRoute::network_route(destination, gateway, metric, interface.clone());
+        info!("IP: route added: {route}");
+        self.register(route);
+        Ok(())
+    }
+
+    /// Deletes routes to a network, only the one via a gateway if given.
+    pub fn delete(
+        &mut self,
+        destination: IpCidr,
+        gateway: Option<IPAdress>,
+    ) -> Result<(), RouteError> {
+        let before = self.entries.len();
+        self.entries.retain(|route| {
+            !(route.destination == destination
+                && gateway.is_none_or(|gateway| route.next_hop == gateway))
+        });
+        if self.entries.len() == before {
+            return Err(RouteError::NotFound);
+        }
+        self.reindex();
+        Ok(())
+    }
+
+    /// Points routes of an interface to its replacement, updating networks of interface routes.
+    pub fn replace_interface(&mut self, old: &Arc<IPInterface>, new: Arc<IPInterface>) {
+        for route in self.entries.iter_mut() {
+            if Arc::ptr_eq(&route.interface, old) {
+                if route.next_hop == IP_ADDR_ANY {
+                    route.destination = new.cidr().trunc();
                 }
+                route.interface = new.clone();
             }
         }
-        candidate
+        self.reindex();
+    }
+
+    /// Replaces default gateway route. Removes it when gateway is not given.
+    pub fn set_default_gateway(&mut self, gateway: Option<IPAdress>, interface: Arc<IPInterface>) {
+        self.entries
+            .retain(|route| !(route.destination.prefix_len == 0 && route.next_hop != IP_ADDR_ANY));
+        self.reindex();
+        if let Some(gateway) = gateway {
+            self.register(IPRoute::gateway_route_from_addr(gateway, interface));
+        }
+    }
+
+    /// Next hops of gateway routes with their interfaces.
+    pub fn gateways(&self) -> Vec<(IPAdress, Arc<IPInterface>)> {
+        let mut gateways: Vec<(IPAdress, Arc<IPInterface>)> = vec![];
+        for route in self.entries.iter() {
+            if route.next_hop != IP_ADDR_ANY
+                && !gateways
+                    .iter()
+                    .any(|(gateway, _)| *gateway == route.next_hop)
+            {
+                gateways.push((route.next_hop, route.interface.clone()));
+            }
+        }
+        gateways
+    }
+
+    /// Marks routes via a gateway down, so that lookup falls back to other routes, or up.
+    /// Returns the number of routes changed.
+    pub fn set_gateway_down(&mut self, gateway: IPAdress, down: bool) -> usize {
+        let mut changed = 0;
+        for route in self.entries.iter_mut() {
+            if route.next_hop == gateway && route.down != down {
+                route.down = down;
+                changed += 1;
+            }
+        }
+        changed
+    }
+
+    /// Route of the longest prefix containing an address, of the lowest metric among routes of
+    /// the prefix (the one registered first of the same metric). Routes down are skipped.
+    pub fn lookup_ip_route(&self, dst: IPAdress) -> Option<&IPRoute> {
+        self.trie.matches(dst).into_iter().find_map(|indexes| {
+            indexes
+                .iter()
+                .map(|i| &self.entries[*i])
+                .filter(|route| !route.down)
+                .min_by_key(|route| route.metric)
+        })
+    }
+
+    /// Routes in the order registered, one per line.
+    pub fn show(&self) -> String {
+        self.entries
+            .iter()
+            .map(|route| format!("{route}\n"))
+            .collect()
     }
 
     pub fn get_interface(&self, dst: IPAdress) -> Option<Arc<IPInterface>> {
@@ -150,6 +464,7 @@ impl IPRoutes {
 // see https://www.iana.org/assignments/protocol-numbers/protocol-numbers.txt
 pub enum IPProtocolType {
     Icmp = 0x01,
+    Igmp = 0x02,
     Tcp = 0x06,
     Udp = 0x11,
     Unknown,
@@ -159,6 +474,7 @@ impl IPProtocolType {
     pub fn from_u8(value: u8) -> IPProtocolType {
         match value {
             0x01 => IPProtocolType::Icmp,
+            0x02 => IPProtocolType::Igmp,
             0x06 => IPProtocolType::Tcp,
             0x11 => IPProtocolType::Udp,
             _ => IPProtocolType::Unknown,
@@ -181,6 +497,87 @@ pub struct IPHeader {
     opts: [u8; 0],
 }
 
+/// Reason of dropping a received datagram with a source address which can not be valid.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MartianReason {
+    UnspecifiedSource, // 0.0.0.0 other than DHCP client messages
+    LoopbackSource,    // 127/8 arriving on a non-loopback device
+    OwnSource,         // our own address looped back from the link
+    MulticastSource,   // 224/4
+}
+
+const MARTIAN_REASONS: [MartianReason; 4] = [
+    MartianReason::UnspecifiedSource,
+    MartianReason::LoopbackSource,
+    MartianReason::OwnSource,
+    MartianReason::MulticastSource,
+];
+
+/// Counters of received datagrams dropped per martian reason.
+#[derive(Default)]
+pub struct MartianCounters {
+    counts: [u64; MARTIAN_REASONS.len()],
+}
+
+impl MartianCounters {
+    pub fn record(&mut self, reason: MartianReason) {
+        let index = MARTIAN_REASONS.iter().position(|&r| r == reason).unwrap();
+        self.counts[index] += 1;
+    }
+
+    pub fn count(&self, reason: MartianReason) -> u64 {
+        let index = MARTIAN_REASONS.iter().position(|&r| r == reason).unwrap();
+        self.counts[index]
+    }
+}
+
+impl fmt::Display for MartianCounters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "martians:")?;
+        for reason in MARTIAN_REASONS {
+            writeln!(
+                f,
+                "  {:>18} {:>10}",
+                format!("{reason:?}"),
+                self.count(reason)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks a source address which no valid datagram arriving on a device can have.
+fn check_martian(
+    header: &IPHeader,
+    data: &[u8],
+    header_len: usize,
+    device: &NetDevice,
+    interface: &IPInterface,
+) -> Result<(), MartianReason> {
+    let src = header.src;
+    if src == IP_ADDR_ANY {
+        // DHCP clients without an address yet send from 0.0.0.0 to the server port.
+        let dst_port = data
+            .get(header_len + 2..header_len + 4)
+            .map(|port| u16::from_be_bytes([port[0], port[1]]));
+        if header.protocol != IPProtocolType::Udp as u8 || dst_port != Some(DHCP_SERVER_PORT) {
+            return Err(MartianReason::UnspecifiedSource);
+        }
+    }
+    if device.device_type != NetDeviceType::Loopback {
+        if src.to_ne_bytes()[0] == 127 {
+            return Err(MartianReason::LoopbackSource);
+        }
+        if src == interface.unicast && src != IP_ADDR_ANY {
+            return Err(MartianReason::OwnSource);
+        }
+    }
+    if src.to_ne_bytes()[0] & 0xf0 == 0xe0 {
+        return Err(MartianReason::MulticastSource);
+    }
+    Ok(())
+}
+
 pub struct IPHeaderIdManager {
     id_mtx: Mutex<u16>,
 }
@@ -200,11 +597,12 @@ impl IPHeaderIdManager {
 }
 
 fn create_ip_header(
-    ip_proto: IPProtocolType,
+    protocol: u8,
     src: IPAdress,
     dst: IPAdress,
     data: &Vec<u8>,
     id: u16,
+    ttl: u8,
 ) -> IPHeader {
     let hlen = size_of::<IPHeader>();
     let len = data.len();
@@ -218,8 +616,8 @@ fn create_ip_header(
         total_len: le_to_be_u16(total),
         id: le_to_be_u16(id),
         offset: 0,
-        ttl: 0xff,
-        protocol: ip_proto as u8,
+        ttl,
+        protocol,
         check_sum: 0,
         src,
         dst,
@@ -227,43 +625,71 @@ fn create_ip_header(
     };
     let header_bytes = unsafe { to_u8_slice(&header) };
     header.check_sum = le_to_be_u16(cksum16(header_bytes, hlen, 0));
+    #[cfg(feature = "fault-injection")]
+    if fault::hit(Fault::TxChecksum) {
+        header.check_sum = !header.check_sum;
+    }
     header
 }
 
 pub fn output(
     ip_proto: IPProtocolType,
+    data: Vec<u8>,
+    src: IPAdress,
+    dst: IPAdress,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+) -> Result<(), ()> {
+    output_with_ttl(ip_proto, data, src, dst, IP_TTL_DEFAULT, device, contexts)
+}
+
+/// Same as output with the TTL of the datagram specified (e.g. traceroute probes).
+pub fn output_with_ttl(
+    ip_proto: IPProtocolType,
+    data: Vec<u8>,
+    src: IPAdress,
+    dst: IPAdress,
+    ttl: u8,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+) -> Result<(), ()> {
+    output_protocol(ip_proto as u8, data, src, dst, ttl, device, contexts)
+}
+
+/// Same as output_with_ttl with an IP protocol number, e.g. of protocols registered as
+/// `l4::L4Protocol`.
+pub fn output_protocol(
+    protocol: u8,
     mut data: Vec<u8>,
     src: IPAdress,
     dst: IPAdress,
+    ttl: u8,
     device: &mut NetDevice,
     contexts: &mut ProtocolContexts,
 ) -> Result<(), ()> {
-    let route_opt = contexts.ip_routes.lookup_ip_route(dst);
-    if route_opt.is_none() {
+    let Some(interface) = route_interface(dst, device, contexts) else {
         return Err(());
-    }
-    let route = route_opt.unwrap();
-
-    if src != IP_ADDR_ANY && src != route.interface.unicast {
+    };
+    if src != IP_ADDR_ANY && src != interface.unicast {
         warn!(
             "IP: source address: {:?} not matching with interface unicast: {:?}",
             ip_addr_to_str(src),
-            ip_addr_to_str(route.interface.unicast)
+            ip_addr_to_str(interface.unicast)
         );
         return Err(());
     }
-    let next_hop = if route.next_hop != IP_ADDR_ANY {
-        route.next_hop
-    } else {
-        dst
+    let next_hop = match contexts.ip_routes.lookup_ip_route(dst) {
+        Some(route) if route.next_hop != IP_ADDR_ANY && dst != IP_ADDR_BROADCAST => route.next_hop,
+        _ => dst,
     };
 
     let header = create_ip_header(
-        ip_proto,
-        route.interface.unicast,
+        protocol,
+        interface.unicast,
         dst,
         &data,
         contexts.ip_id_manager.generate_id(),
+        ttl,
     );
 
     let header_dst = header.dst;
@@ -277,48 +703,293 @@ pub fn output(
     let header_bytes = unsafe { to_u8_slice::<IPHeader>(&header) }; // add icmp data here
     let mut ip_data = header_bytes.to_vec();
     ip_data.append(&mut data);
-    let ip_data_len = ip_data.len();
+    transmit(ip_data, dst, next_hop, interface, device, contexts)
+}
 
+/// Transmits a datagram to the HW address of the next hop, or queues it for ARP resolution.
+fn transmit(
+    ip_data: Vec<u8>,
+    dst: IPAdress,
+    next_hop: IPAdress,
+    interface: Arc<IPInterface>,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+) -> Result<(), ()> {
+    let ip_data_len = ip_data.len();
+    // Datagrams are not fragmented: those over the MTU of the device are refused.
+    if ip_data_len > device.mtu {
+        error!(
+            "IP: datagram to {} refused: {ip_data_len} bytes is over MTU {} of device: {}, fragmenting is not supported.",
+            ip_addr_to_str(dst),
+            device.mtu,
+            device.name
+        );
+        return Err(());
+    }
     let mut hw_addr: [u8; ETH_ADDR_LEN] = [0; ETH_ADDR_LEN];
-    if device.flags & DEVICE_FLAG_NEED_ARP > 0 {
-        if dst == route.interface.broadcast || dst == IP_ADDR_BROADCAST {
-            hw_addr = device.broadcast[..ETH_ADDR_LEN + 1].try_into().unwrap();
+    if device.capabilities.needs_arp {
+        if dst == interface.broadcast || dst == IP_ADDR_BROADCAST {
+            hw_addr = device.broadcast.ethernet().unwrap();
+        } else if igmp::is_multicast(dst) {
+            hw_addr = igmp::multicast_hw_addr(dst);
         } else {
-            let arp = arp_resolve(
-                device,
-                route.interface.clone(),
-                &mut contexts.arp_table,
-                next_hop,
-            );
-            if let Ok(result) = arp {
-                if result.is_none() {
+            let arp = arp_resolve(device, interface.clone(), &mut contexts.arp_table, next_hop);
+            match arp {
+                Ok(Some(resolved)) => hw_addr = resolved,
+                Ok(None) => {
                     info!("IP: waiting for ARP reply...");
+                    contexts.arp_table.enqueue(next_hop, ip_data);
                     return Ok(());
                 }
-                hw_addr = result.unwrap();
+                Err(_) => {
+                    error!(
+                        "IP: ARP resolution failed for next hop: {}",
+                        ip_addr_to_str(next_hop)
+                    );
+                    return Err(());
+                }
             }
         }
     }
 
-    device.transmit(super::ProtocolType::IP, ip_data, ip_data_len, hw_addr)
+    device.transmit_on_vlan(
+        super::ProtocolType::IP,
+        ip_data,
+        ip_data_len,
+        hw_addr,
+        interface.vlan,
+    )
 }
 
-fn check_ip_header(header: &IPHeader, data_len: usize, header_len: usize) -> Result<(), ()> {
+/// Interface datagrams to an address go out of: the one of the route, or the one of the device
+/// for the limited broadcast (RFC 1122 3.3.6) and multicast groups, which are not routed.
+pub fn route_interface(
+    dst: IPAdress,
+    device: &NetDevice,
+    contexts: &ProtocolContexts,
+) -> Option<Arc<IPInterface>> {
+    if dst == IP_ADDR_BROADCAST || igmp::is_multicast(dst) {
+        return device.get_interface(NetInterfaceFamily::IP);
+    }
+    contexts.ip_routes.get_interface(dst)
+}
+
+/// Whether a datagram is addressed to another host: to none of the unicast and broadcast
+/// addresses of interfaces of devices, nor the limited broadcast or a multicast group.
+pub fn is_transit(data: &[u8], devices: &NetDevices) -> bool {
+    if data.len() < IP_HEADER_MIN_SIZE || data[0] >> 4 != IP_VERSION_4 {
+        return false;
+    }
+    let dst = IPAdress::from_ne_bytes(data[16..20].try_into().unwrap());
+    if dst == IP_ADDR_BROADCAST || igmp::is_multicast(dst) {
+        return false;
+    }
+    !devices.entries.iter().any(|device| {
+        device
+            .interfaces
+            .iter()
+            .any(|interface| dst == interface.unicast || dst == interface.broadcast)
+    })
+}
+
+/// Forwards a datagram in transit (`is_transit`) arrived on a device of an index out of the
+/// device of the interface routed to, with TTL decremented and the checksum recomputed (RFC
+/// 1812 5.2, 5.3.1). Options go by the policy of the interface. The source is told with ICMP
+/// errors when TTL runs out, no route is found, options are dropped or the datagram does not
+/// fit the MTU, as datagrams are not fragmented. Datagrams out of the outside device of NAT get
+/// their source translated (see `nat::outbound`).
+pub fn forward(
+    data: &[u8],
+    len: usize,
+    index: usize,
+    devices: &mut NetDevices,
+    contexts: &mut ProtocolContexts,
+    pcbs: &mut ControlBlocks,
+) -> Result<(), ()> {
+    if cmp::min(len, data.len()) < IP_HEADER_MIN_SIZE {
+        error!("IP: data is too short.");
+        return Err(());
+    }
+    let header = unsafe { bytes_to_struct::<IPHeader>(data) };
+    let header_len = ((header.ver_len & 0x0f) << 2) as usize;
+    check_ip_header(&header, data, len, header_len)?;
+    let datagram = &data[..be_to_le_u16(header.total_len) as usize];
+    let (src, dst) = (header.src, header.dst);
+    let in_device = device_at(devices, index);
+    let in_interface = in_device.get_interface(NetInterfaceFamily::IP).ok_or(())?;
+    if let Err(reason) = check_martian(&header, data, header_len, in_device, &in_interface) {
+        contexts.martians.record(reason);
+        return Err(());
+    }
+    if src == IP_ADDR_ANY || src == IP_ADDR_BROADCAST {
+        return Err(());
+    }
+    let (out_index, out_interface, next_hop, mut forwarded) =
+        match forwarding_hop(datagram, devices, contexts) {
+            Ok(hop) => hop,
+            Err(Some((icmp_type, code, values))) => {
+                let in_device = device_at(devices, index);
+                icmp::error(icmp_type, code, values, datagram, in_device, contexts, pcbs);
+                return Err(());
+            }
+            Err(None) => return Err(()),
+        };
+    let in_name = device_at(devices, index).name.clone();
+    let out_device = device_at(devices, out_index);
+    nat::outbound(&mut forwarded, &in_name, out_device, contexts)?;
+    trace!(
+        "IP: forwarding datagram from {} to {} out of device: {}",
+        ip_addr_to_str(src),
+        ip_addr_to_str(dst),
+        out_device.name
+    );
+    transmit(
+        forwarded,
+        dst,
+        next_hop,
+        out_interface,
+        out_device,
+        contexts,
+    )
+}
+
+/// Device index, interface and next hop to forward a datagram to, and the datagram to forward
+/// with TTL decremented, or the type, code and values of the ICMP error to send back, if any.
+#[allow(clippy::type_complexity)]
+fn forwarding_hop(
+    datagram: &[u8],
+    devices: &NetDevices,
+    contexts: &ProtocolContexts,
+) -> Result<(usize, Arc<IPInterface>, IPAdress, Vec<u8>), Option<(u8, u8, u32)>> {
+    let header = unsafe { bytes_to_struct::<IPHeader>(datagram) };
+    let dst = header.dst;
+    if header.ttl <= 1 {
+        debug!("IP: TTL of datagram to {} exceeded.", ip_addr_to_str(dst));
+        return Err(Some((ICMP_TYPE_TIME_EXCEEDED, ICMP_CODE_EXCEEDED_TTL, 0)));
+    }
+    let route = contexts.ip_routes.lookup_ip_route(dst);
+    let out = route.and_then(|route| {
+        let index = devices.entries.iter().position(|device| {
+            device
+                .interfaces
+                .iter()
+                .any(|interface| Arc::ptr_eq(interface, &route.interface))
+        })?;
+        Some((index, route))
+    });
+    let Some((index, route)) = out else {
+        debug!("IP: no route to forward to {}.", ip_addr_to_str(dst));
+        return Err(Some((ICMP_TYPE_DEST_UNREACH, ICMP_CODE_NET_UNREACH, 0)));
+    };
+    let policy = route.interface.options_policy();
+    let mut forwarded = apply_options_policy(datagram, policy).map_err(|pointer| {
+        Some((
+            ICMP_TYPE_PARAM_PROBLEM,
+            0,
+            u32::from_ne_bytes([pointer, 0, 0, 0]),
+        ))
+    })?;
+    let mtu = devices.entries.iter().nth(index).unwrap().mtu;
+    if forwarded.len() > mtu {
+        // Fragmenting is not supported: only a datagram with DF set is told about it.
+        if be_to_le_u16(header.offset) & IP_FLAG_DONT_FRAGMENT == 0 {
+            warn!(
+                "IP: dropped datagram to {} of {} bytes over MTU {mtu}, fragmenting is not supported.",
+                ip_addr_to_str(dst),
+                forwarded.len()
+            );
+            return Err(None);
+        }
+        // Next-hop MTU in the low-order 16 bits (RFC 1191 4)
+        let [_, _, high, low] = (mtu as u32).to_be_bytes();
+        let values = u32::from_ne_bytes([0, 0, high, low]);
+        return Err(Some((
+            ICMP_TYPE_DEST_UNREACH,
+            ICMP_CODE_FRAGMENT_NEEDED,
+            values,
+        )));
+    }
+    forwarded[8] -= 1;
+    forwarded[10..12].copy_from_slice(&[0, 0]);
+    let header_len = ((forwarded[0] & 0x0f) << 2) as usize;
+    let check_sum = cksum16(&forwarded, header_len, 0);
+    forwarded[10..12].copy_from_slice(&check_sum.to_be_bytes());
+    let next_hop = match route.next_hop {
+        IP_ADDR_ANY => dst,
+        next_hop => next_hop,
+    };
+    Ok((index, route.interface.clone(), next_hop, forwarded))
+}
+
+fn device_at(devices: &mut NetDevices, index: usize) -> &mut NetDevice {
+    devices.get_mut_by_index(index).unwrap()
+}
+
+/// Applies an options policy to a datagram to forward, of lengths checked by `check_ip_header`.
+/// Returns the datagram to forward, or the pointer of ICMP parameter problem (offset of the
+/// options) when it is to be dropped.
+pub fn apply_options_policy(data: &[u8], policy: IPOptionsPolicy) -> Result<Vec<u8>, u8> {
+    let header_len = ((data[0] & 0x0f) << 2) as usize;
+    if header_len == IP_HEADER_MIN_SIZE || policy == IPOptionsPolicy::Pass {
+        return Ok(data.to_vec());
+    }
+    if policy == IPOptionsPolicy::Drop {
+        return Err(IP_HEADER_MIN_SIZE as u8);
+    }
+    let total_len = u16::from_be_bytes([data[2], data[3]]) as usize;
+    let mut stripped = data[..IP_HEADER_MIN_SIZE].to_vec();
+    stripped.extend_from_slice(&data[header_len..total_len]);
+    stripped[0] = (IP_VERSION_4 << 4) | (IP_HEADER_MIN_SIZE >> 2) as u8;
+    let len = (total_len - (header_len - IP_HEADER_MIN_SIZE)) as u16;
+    stripped[2..4].copy_from_slice(&len.to_be_bytes());
+    stripped[10..12].copy_from_slice(&[0, 0]);
+    let check_sum = cksum16(&stripped, IP_HEADER_MIN_SIZE, 0);
+    stripped[10..12].copy_from_slice(&check_sum.to_be_bytes());
+    Ok(stripped)
+}
+
+/// Hash of source/destination addresses, protocol and TCP/UDP ports of a datagram, the same for
+/// all datagrams of a flow. None for data too short for an IPv4 header.
+pub fn flow_hash(data: &[u8]) -> Option<u32> {
+    if data.len() < IP_HEADER_MIN_SIZE || data[0] >> 4 != IP_VERSION_4 {
+        return None;
+    }
+    let header_len = ((data[0] & 0x0f) << 2) as usize;
+    let protocol = data[9];
+    let mut hasher = DefaultHasher::new();
+    data[12..20].hash(&mut hasher); // source and destination addresses
+    protocol.hash(&mut hasher);
+    let has_ports = protocol == IPProtocolType::Tcp as u8 || protocol == IPProtocolType::Udp as u8;
+    if let Some(ports) = data.get(header_len..header_len + 4).filter(|_| has_ports) {
+        ports.hash(&mut hasher);
+    }
+    Some(hasher.finish() as u32)
+}
+
+/// Checks lengths of the header and the datagram against each other and the data received
+/// before any of them is used to slice the data.
+fn check_ip_header(
+    header: &IPHeader,
+    data: &[u8],
+    data_len: usize,
+    header_len: usize,
+) -> Result<(), ()> {
     let ip_version = header.ver_len >> 4;
     if ip_version != IP_VERSION_4 {
         error!("IP: version error with value: {ip_version}");
         return Err(());
     }
-    if data_len < header_len {
-        error!("IP: header length error.");
+    let data_len = cmp::min(data_len, data.len());
+    if header_len < IP_HEADER_MIN_SIZE || data_len < header_len {
+        error!("IP: header length error: {header_len}");
         return Err(());
     }
-    if data_len < be_to_le_u16(header.total_len) as usize {
-        error!("IP: total length error.");
+    let total_len = be_to_le_u16(header.total_len) as usize;
+    if total_len < header_len || data_len < total_len {
+        error!("IP: total length error: {total_len}");
         return Err(());
     }
-    let header_bytes = unsafe { to_u8_slice(header) };
-    if cksum16(header_bytes, header_len, 0) != 0 {
+    if cksum16(data, header_len, 0) != 0 {
         error!("IP: checksum error.");
         return Err(());
     }
@@ -330,73 +1001,103 @@ fn check_ip_header(header: &IPHeader, data_len: usize, header_len: usize) -> Res
     Ok(())
 }
 
+/// Handles a datagram received on the interface of the VLAN id of the frame tag, if any.
 pub fn input(
     data: &[u8],
     len: usize,
+    vlan: Option<u16>,
     device: &mut NetDevice,
     contexts: &mut ProtocolContexts,
     pcbs: &mut ControlBlocks,
 ) -> Result<(), ()> {
-    if len < IP_HEADER_MIN_SIZE {
-        panic!("IP: data is too short.")
+    if cmp::min(len, data.len()) < IP_HEADER_MIN_SIZE {
+        error!("IP: data is too short.");
+        return Err(());
     }
     let header = unsafe { bytes_to_struct::<IPHeader>(data) };
     let header_len = ((header.ver_len & 0x0f) << 2) as usize;
-    if let Err(_e) = check_ip_header(&header, len, header_len) {
+    if let Err(_e) = check_ip_header(&header, data, len, header_len) {
         return Err(());
     }
+    let len = be_to_le_u16(header.total_len) as usize; // excludes link layer padding
     trace!(
         "IP: input src: {:?} dst: {:?}",
         ip_addr_to_str(header.src),
         ip_addr_to_str(header.dst)
     );
-    let interface_lookup = device.get_interface(NetInterfaceFamily::IP);
+    let interface_lookup = device.get_vlan_interface(vlan);
     if let Some(interface) = interface_lookup {
-        if interface.unicast != header.dst {
+        if let Err(reason) = check_martian(&header, data, header_len, device, &interface) {
+            debug!(
+                "IP: dropped martian from {} on device: {} ({reason:?})",
+                ip_addr_to_str(header.src),
+                device.name
+            );
+            contexts.martians.record(reason);
             return Err(());
         }
-        let sub_data = &data[header_len..];
-        match IPProtocolType::from_u8(header.protocol) {
-            IPProtocolType::Icmp => {
-                return icmp::input(
-                    sub_data,
-                    len - header_len,
-                    header.src,
-                    header.dst,
-                    device,
-                    &interface,
-                    contexts,
-                    pcbs,
-                );
-            }
-            IPProtocolType::Tcp => {
-                return tcp::input(
-                    sub_data,
-                    len - header_len,
-                    header.src,
-                    header.dst,
-                    device,
-                    &interface,
-                    contexts,
-                    pcbs,
-                );
-            }
-            IPProtocolType::Udp => {
-                return udp::input(
-                    sub_data,
-                    len - header_len,
-                    header.src,
-                    header.dst,
+        // Limited and subnet-directed broadcasts are accepted for UDP (e.g. DHCP / SSDP), and
+        // datagrams to groups joined for UDP and IGMP.
+        let is_broadcast = header.dst == IP_ADDR_BROADCAST || header.dst == interface.broadcast;
+        let is_multicast = igmp::is_multicast(header.dst);
+        if is_multicast && !contexts.igmp_groups.contains(header.dst) {
+            return Ok(());
+        }
+        if interface.unicast != header.dst && !is_broadcast && !is_multicast {
+            return Err(());
+        }
+        if is_broadcast && header.protocol != IPProtocolType::Udp as u8 {
+            return Ok(());
+        }
+        if is_multicast
+            && header.protocol != IPProtocolType::Udp as u8
+            && header.protocol != IPProtocolType::Igmp as u8
+        {
+            return Ok(());
+        }
+        // Protocols are dispatched by number through the registry (l4::L4Protocols).
+        let Some(protocol) = contexts.l4_protocols.get(header.protocol) else {
+            trace!("IP: no protocol registered for number: {}", header.protocol);
+            if !is_broadcast && !is_multicast {
+                icmp::error(
+                    ICMP_TYPE_DEST_UNREACH,
+                    ICMP_CODE_PROTO_UNREACH,
+                    0,
+                    &data[..len],
                     device,
-                    &interface,
                     contexts,
                     pcbs,
                 );
             }
-            IPProtocolType::Unknown => {
-                return Ok(());
-            }
+            return Ok(());
         };
+        let result = protocol.input(
+            &data[header_len..],
+            len - header_len,
+            header.src,
+            header.dst,
+            device,
+            &interface,
+            contexts,
+            pcbs,
+        );
+        // No socket on the UDP port: the sender is told unless it broadcast (RFC 1122 4.1.3.1).
+        if result.is_err()
+            && header.protocol == IPProtocolType::Udp as u8
+            && !is_broadcast
+            && !is_multicast
+        {
+            icmp::error(
+                ICMP_TYPE_DEST_UNREACH,
+                ICMP_CODE_PORT_UNREACH,
+                0,
+                &data[..len],
+                device,
+                contexts,
+                pcbs,
+            );
+        }
+        return result;
     }
     Ok(())
 }
@@ -404,40 +1105,442 @@ pub fn input(
 /// Converts string IP to bytes in big endian.
 pub fn ip_addr_to_bytes(addr: &str) -> Option<IPAdress> {
     let mut parts = addr.split('.');
-    let mut part;
-    let mut res: u32 = 0;
-    for i in 0..4 {
-        part = parts.next();
-        part?;
-        let b = part.unwrap().parse::<u8>().unwrap();
-        res |= (b as u32) << (8 * i);
+    let mut octets = [0u8; 4];
+    for octet in octets.iter_mut() {
+        *octet = parts.next()?.parse::<u8>().ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
     }
-    Some(res)
+    // Octets in network order in memory regardless of host byte order
+    Some(IPAdress::from_ne_bytes(octets))
+}
+
+/// Parses an endpoint of `<IP>:<port>`.
+pub fn endpoint_from_str(endpoint: &str) -> Option<IPEndpoint> {
+    let (ip, port) = endpoint.trim().rsplit_once(':')?;
+    Some(IPEndpoint::new(ip_addr_to_bytes(ip)?, port.parse().ok()?))
 }
 
 /// Converts IP bytes in big endian to string.
 pub fn ip_addr_to_str(addr: IPAdress) -> String {
-    let mut parts = Vec::new();
-    for i in 0..4 {
-        let d = (addr >> (8 * i)) & 255;
-        parts.push(d.to_string());
-    }
-    parts.join(".")
+    addr.to_ne_bytes()
+        .iter()
+        .map(|octet| octet.to_string())
+        .collect::<Vec<String>>()
+        .join(".")
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{ip_addr_to_bytes, ip_addr_to_str};
+    use super::{
+        apply_options_policy, check_ip_header,
+        cidr::IpCidr,
+        create_ip_header, endpoint_from_str, flow_hash, forward,
+        icmp::{
+            self, ICMP_CODE_FRAGMENT_NEEDED, ICMP_CODE_NET_UNREACH, ICMP_CODE_PROTO_UNREACH,
+            ICMP_TYPE_DEST_UNREACH, ICMP_TYPE_TIME_EXCEEDED,
+        },
+        input, ip_addr_to_bytes, ip_addr_to_str, is_transit, output, IPHeader, IPOptionsPolicy,
+        IPProtocolType, IPRoute, IPRoutes, MartianReason, RouteError, IP_HEADER_MIN_SIZE,
+    };
+    use crate::{
+        devices::{ethernet::ETH_HDR_SIZE, test_device, NetDevice, NetDevices},
+        net::NetInterfaceFamily,
+        protocols::{ControlBlocks, ProtocolContexts},
+        utils::{bytes_to_struct, cksum16, to_u8_slice},
+    };
+
+    #[test]
+    fn test_flow_hash() {
+        let mut datagram = vec![
+            0x45, 0, 0, 28, 0, 1, 0, 0, 64, 17, 0, 0, 192, 0, 2, 1, 192, 0,
+        ];
+        datagram.extend_from_slice(&[2, 2, 0x9c, 0x40, 0, 7, 0, 8, 0, 0]);
+        let hash = flow_hash(&datagram).unwrap();
+
+        // Same flow regardless of the other fields and payload
+        let mut same_flow = datagram.clone();
+        same_flow[4..6].copy_from_slice(&[0, 2]);
+        same_flow.extend_from_slice(b"hello");
+        assert_eq!(Some(hash), flow_hash(&same_flow));
+
+        let mut other_port = datagram.clone();
+        other_port[21] = 8;
+        assert_ne!(Some(hash), flow_hash(&other_port));
+        assert_eq!(None, flow_hash(&datagram[..19]));
+    }
+
+    #[test]
+    fn test_apply_options_policy() {
+        // Record route option of 3 slots (12 bytes with NOP padding) and 4 bytes of payload
+        let mut datagram = vec![
+            0x48, 0, 0, 36, 0, 1, 0, 0, 64, 17, 0, 0, 192, 0, 2, 1, 198, 51,
+        ];
+        datagram.extend_from_slice(&[100, 1, 7, 11, 4, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        datagram.extend_from_slice(&[1, 2, 3, 4]);
+        let sum = cksum16(&datagram, 32, 0);
+        datagram[10..12].copy_from_slice(&sum.to_be_bytes());
+
+        assert_eq!(
+            Ok(datagram.clone()),
+            apply_options_policy(&datagram, IPOptionsPolicy::Pass)
+        );
+        assert_eq!(
+            Err(20),
+            apply_options_policy(&datagram, IPOptionsPolicy::Drop)
+        );
+        let stripped = apply_options_policy(&datagram, IPOptionsPolicy::Strip).unwrap();
+        assert_eq!(24, stripped.len());
+        assert_eq!([0x45, 0, 0, 24], stripped[..4]);
+        assert_eq!(0, cksum16(&stripped, 20, 0));
+        assert_eq!(datagram[12..20], stripped[12..20]);
+        assert_eq!([1, 2, 3, 4], stripped[20..]);
+
+        assert_eq!(Ok(IPOptionsPolicy::Strip), "strip".parse());
+        assert!("keep".parse::<IPOptionsPolicy>().is_err());
+    }
+
+    /// UDP datagram from 192.0.2.1 to 192.0.2.2 with the version / header length byte and the
+    /// total length given, checksummed over the header length.
+    fn crafted_datagram(ver_len: u8, total_len: u16) -> Vec<u8> {
+        let src = ip_addr_to_bytes("192.0.2.1").unwrap();
+        let dst = ip_addr_to_bytes("192.0.2.2").unwrap();
+        let data = vec![0, 68, 0, 67, 0, 8, 0, 0];
+        let header = create_ip_header(IPProtocolType::Udp as u8, src, dst, &data, 1, 64);
+        let mut datagram = unsafe { to_u8_slice(&header) }.to_vec();
+        datagram.extend_from_slice(&data);
+        datagram[0] = ver_len;
+        datagram[2..4].copy_from_slice(&total_len.to_be_bytes());
+        datagram[10..12].copy_from_slice(&[0, 0]);
+        let header_len = ((ver_len & 0x0f) << 2) as usize;
+        let sum = cksum16(&datagram, header_len, 0);
+        datagram[10..12].copy_from_slice(&sum.to_be_bytes());
+        datagram
+    }
+
+    fn check_input(datagram: &[u8]) -> Result<(), ()> {
+        let mut device = test_device("192.0.2.2/24");
+        let mut contexts = ProtocolContexts::new(IPRoutes::new());
+        let mut pcbs = ControlBlocks::new();
+        let len = datagram.len();
+        input(datagram, len, None, &mut device, &mut contexts, &mut pcbs)
+    }
+
+    #[test]
+    fn test_header_len_below_minimum() {
+        let datagram = crafted_datagram(0x45, 28);
+        let header = unsafe { bytes_to_struct::<IPHeader>(&datagram) };
+        assert!(check_ip_header(&header, &datagram, 28, 20).is_ok());
+
+        // IHL of 4 (16 bytes)
+        let datagram = crafted_datagram(0x44, 28);
+        let header = unsafe { bytes_to_struct::<IPHeader>(&datagram) };
+        assert!(check_ip_header(&header, &datagram, 28, 16).is_err());
+        assert!(check_input(&datagram).is_err());
+        assert!(check_input(&datagram[..12]).is_err());
+    }
+
+    #[test]
+    fn test_total_len_below_header_len() {
+        let datagram = crafted_datagram(0x46, 20);
+        let header = unsafe { bytes_to_struct::<IPHeader>(&datagram) };
+        assert!(check_ip_header(&header, &datagram, 28, 24).is_err());
+        assert!(check_input(&datagram).is_err());
+        assert!(check_input(&crafted_datagram(0x45, 8)).is_err());
+    }
+
+    #[test]
+    fn test_total_len_beyond_data() {
+        let datagram = crafted_datagram(0x45, 100);
+        let header = unsafe { bytes_to_struct::<IPHeader>(&datagram) };
+        assert!(check_ip_header(&header, &datagram, 28, 20).is_err());
+        // Length given beyond the data received
+        assert!(check_ip_header(&header, &datagram[..24], 100, 20).is_err());
+        assert!(check_input(&datagram).is_err());
+    }
+
+    #[test]
+    fn test_martian_sources() {
+        let mut device = test_device("192.0.2.2/24");
+        let mut contexts = ProtocolContexts::new(IPRoutes::new());
+        let mut pcbs = ControlBlocks::new();
+        let dst = ip_addr_to_bytes("192.0.2.2").unwrap();
+        // UDP header of a DHCP client message (68 -> 67)
+        let udp = vec![0, 68, 0, 67, 0, 8, 0, 0];
+        let mut receive = |src: &str, data: &Vec<u8>| {
+            let src = ip_addr_to_bytes(src).unwrap();
+            let header = create_ip_header(IPProtocolType::Udp as u8, src, dst, data, 1, 64);
+            let mut datagram = unsafe { to_u8_slice(&header) }.to_vec();
+            datagram.extend_from_slice(data);
+            let len = datagram.len();
+            input(&datagram, len, None, &mut device, &mut contexts, &mut pcbs)
+        };
+
+        assert!(receive("127.0.0.1", &udp).is_err());
+        assert!(receive("192.0.2.2", &udp).is_err());
+        assert!(receive("224.0.0.1", &udp).is_err());
+        assert!(receive("0.0.0.0", &vec![0, 68, 0, 53, 0, 8, 0, 0]).is_err());
+        receive("0.0.0.0", &udp).ok();
+        receive("192.0.2.1", &udp).ok();
+
+        let martians = &contexts.martians;
+        assert_eq!(1, martians.count(MartianReason::LoopbackSource));
+        assert_eq!(1, martians.count(MartianReason::OwnSource));
+        assert_eq!(1, martians.count(MartianReason::MulticastSource));
+        assert_eq!(1, martians.count(MartianReason::UnspecifiedSource));
+    }
+
+    #[test]
+    fn test_protocol_unreachable() {
+        let mut device = test_device("192.0.2.2/24");
+        let interface = device.get_interface(NetInterfaceFamily::IP).unwrap();
+        let mut routes = IPRoutes::new();
+        routes.register(IPRoute::interface_route(interface));
+        let mut contexts = ProtocolContexts::new(routes);
+        contexts.arp_table.update(
+            ip_addr_to_bytes("192.0.2.1").unwrap(),
+            [0x02, 0, 0, 0, 0, 0x01],
+        );
+        let mut pcbs = ControlBlocks::new();
+        let datagram = |protocol: u8, data: &[u8]| {
+            let src = ip_addr_to_bytes("192.0.2.1").unwrap();
+            let dst = ip_addr_to_bytes("192.0.2.2").unwrap();
+            let header = create_ip_header(protocol, src, dst, &data.to_vec(), 1, 64);
+            let mut datagram = unsafe { to_u8_slice(&header) }.to_vec();
+            datagram.extend_from_slice(data);
+            datagram
+        };
+
+        // Protocol not registered
+        let unknown = datagram(99, &[0xaa; 16]);
+        input(
+            &unknown,
+            unknown.len(),
+            None,
+            &mut device,
+            &mut contexts,
+            &mut pcbs,
+        )
+        .unwrap();
+        let tx_frames = &mut device.driver_data.as_mut().unwrap().tx_frames;
+        let frame = tx_frames.pop_front().unwrap();
+        let icmp_data = &frame[ETH_HDR_SIZE + 20..];
+        assert_eq!(
+            &[ICMP_TYPE_DEST_UNREACH, ICMP_CODE_PROTO_UNREACH],
+            &icmp_data[..2]
+        );
+        // IP header and the first 8 bytes of data
+        assert_eq!(&unknown[..28], &icmp_data[8..]);
+
+        // No error about an error
+        let error = datagram(IPProtocolType::Icmp as u8, icmp_data);
+        icmp::error(
+            ICMP_TYPE_DEST_UNREACH,
+            ICMP_CODE_PROTO_UNREACH,
+            0,
+            &error,
+            &mut device,
+            &mut contexts,
+            &mut pcbs,
+        );
+        assert!(device.driver_data.as_ref().unwrap().tx_frames.is_empty());
+    }
+
+    #[test]
+    fn test_output_over_mtu() {
+        let mut device = test_device("192.0.2.2/24");
+        device.mtu = 576;
+        let interface = device.get_interface(NetInterfaceFamily::IP).unwrap();
+        let mut routes = IPRoutes::new();
+        routes.register(IPRoute::interface_route(interface));
+        let mut contexts = ProtocolContexts::new(routes);
+        let dst = ip_addr_to_bytes("192.0.2.1").unwrap();
+        contexts.arp_table.update(dst, [0x02, 0, 0, 0, 0, 0x01]);
+        let src = ip_addr_to_bytes("192.0.2.2").unwrap();
+        let mut send = |len: usize, device: &mut NetDevice| {
+            let data = vec![0xaa; len];
+            output(IPProtocolType::Udp, data, src, dst, device, &mut contexts)
+        };
+
+        // Up to the MTU sent, and refused over it as datagrams are not fragmented
+        send(576 - IP_HEADER_MIN_SIZE, &mut device).unwrap();
+        let tx_frames = &mut device.driver_data.as_mut().unwrap().tx_frames;
+        assert_eq!(ETH_HDR_SIZE + 576, tx_frames.pop_front().unwrap().len());
+        assert!(send(577 - IP_HEADER_MIN_SIZE, &mut device).is_err());
+        assert!(device.driver_data.as_ref().unwrap().tx_frames.is_empty());
+    }
+
+    #[test]
+    fn test_forward() {
+        let mut devices = NetDevices::new();
+        let mut routes = IPRoutes::new();
+        for cidr in ["192.0.2.254/24", "198.51.100.254/24"] {
+            let device = test_device(cidr);
+            let interface = device.get_interface(NetInterfaceFamily::IP).unwrap();
+            routes.register(IPRoute::interface_route(interface));
+            devices.register(device);
+        }
+        let mut contexts = ProtocolContexts::new(routes);
+        for host in ["192.0.2.1", "198.51.100.1"] {
+            let hw_addr = [0x02, 0, 0, 0, 0, 0x01];
+            contexts
+                .arp_table
+                .update(ip_addr_to_bytes(host).unwrap(), hw_addr);
+        }
+        let mut pcbs = ControlBlocks::new();
+        let datagram = |dst: &str, ttl: u8| {
+            let src = ip_addr_to_bytes("192.0.2.1").unwrap();
+            let dst = ip_addr_to_bytes(dst).unwrap();
+            let data = vec![0xaa; 16];
+            let header = create_ip_header(IPProtocolType::Udp as u8, src, dst, &data, 1, ttl);
+            let mut datagram = unsafe { to_u8_slice(&header) }.to_vec();
+            datagram.extend_from_slice(&data);
+            datagram
+        };
+        let tx_frame = |devices: &mut NetDevices, index: usize| {
+            let device = devices.entries.iter_mut().nth(index).unwrap();
+            device.driver_data.as_mut().unwrap().tx_frames.pop_front()
+        };
+
+        // Out of the other device with TTL decremented and the checksum recomputed
+        let transit = datagram("198.51.100.1", 64);
+        assert!(!is_transit(&datagram("198.51.100.254", 64), &devices));
+        assert!(is_transit(&transit, &devices));
+        forward(
+            &transit,
+            transit.len(),
+            0,
+            &mut devices,
+            &mut contexts,
+            &mut pcbs,
+        )
+        .unwrap();
+        let frame = tx_frame(&mut devices, 1).unwrap();
+        let forwarded = &frame[ETH_HDR_SIZE..ETH_HDR_SIZE + transit.len()];
+        assert_eq!(63, forwarded[8]);
+        assert_eq!(0, cksum16(forwarded, 20, 0));
+        assert_eq!(&transit[12..], &forwarded[12..]);
+        assert!(tx_frame(&mut devices, 0).is_none());
+
+        // TTL running out and no route, told to the source
+        let expiring = datagram("198.51.100.1", 1);
+        let unroutable = datagram("203.0.113.1", 64);
+        for (datagram, icmp_type, code) in [
+            (expiring, ICMP_TYPE_TIME_EXCEEDED, 0),
+            (unroutable, ICMP_TYPE_DEST_UNREACH, ICMP_CODE_NET_UNREACH),
+        ] {
+            let len = datagram.len();
+            assert!(forward(&datagram, len, 0, &mut devices, &mut contexts, &mut pcbs).is_err());
+            let frame = tx_frame(&mut devices, 0).unwrap();
+            assert_eq!(
+                &[icmp_type, code],
+                &frame[ETH_HDR_SIZE + 20..ETH_HDR_SIZE + 22]
+            );
+            assert!(tx_frame(&mut devices, 1).is_none());
+        }
+
+        // Over the MTU out: told to the source with DF set, dropped silently otherwise
+        devices.entries.iter_mut().nth(1).unwrap().mtu = 30;
+        let len = transit.len();
+        assert!(forward(&transit, len, 0, &mut devices, &mut contexts, &mut pcbs).is_err());
+        assert!(tx_frame(&mut devices, 0).is_none());
+        let mut dont_fragment = transit.clone();
+        dont_fragment[6] |= 0x40;
+        dont_fragment[10..12].copy_from_slice(&[0, 0]);
+        let check_sum = cksum16(&dont_fragment, 20, 0);
+        dont_fragment[10..12].copy_from_slice(&check_sum.to_be_bytes());
+        assert!(forward(
+            &dont_fragment,
+            len,
+            0,
+            &mut devices,
+            &mut contexts,
+            &mut pcbs
+        )
+        .is_err());
+        let frame = tx_frame(&mut devices, 0).unwrap();
+        let icmp_data = &frame[ETH_HDR_SIZE + 20..];
+        assert_eq!(
+            &[ICMP_TYPE_DEST_UNREACH, ICMP_CODE_FRAGMENT_NEEDED],
+            &icmp_data[..2]
+        );
+        assert_eq!(&[0, 30], &icmp_data[6..8]);
+        assert!(tx_frame(&mut devices, 1).is_none());
+    }
+
+    #[test]
+    fn test_routes() {
+        let device = test_device("192.0.2.254/24");
+        let interface = device.get_interface(NetInterfaceFamily::IP).unwrap();
+        let mut devices = NetDevices::new();
+        devices.register(device);
+        let mut routes = IPRoutes::new();
+        routes.register(IPRoute::interface_route(interface));
+        let addr = |addr: &str| ip_addr_to_bytes(addr).unwrap();
+        let network: IpCidr = "198.51.100.0/24".parse().unwrap();
+
+        let host: IpCidr = "198.51.100.1/24".parse().unwrap();
+        assert_eq!(
+            Err(RouteError::HostBitsSet(host)),
+            routes.add(host, addr("192.0.2.1"), 0, &devices)
+        );
+        assert_eq!(
+            Err(RouteError::GatewayNotConnected(addr("203.0.113.1"))),
+            routes.add(network, addr("203.0.113.1"), 0, &devices)
+        );
+
+        // The lower metric wins among routes of the same prefix length.
+        routes
+            .add(network, addr("192.0.2.1"), 10, &devices)
+            .unwrap();
+        routes.add(network, addr("192.0.2.2"), 5, &devices).unwrap();
+        assert_eq!(
+            Err(RouteError::Exists),
+            routes.add(network, addr("192.0.2.1"), 0, &devices)
+        );
+        let dst = addr("198.51.100.1");
+        assert_eq!(
+            addr("192.0.2.2"),
+            routes.lookup_ip_route(dst).unwrap().next_hop
+        );
+        assert!(routes.show().contains("198.51.100.0/24 via 192.0.2.2"));
+
+        routes.delete(network, Some(addr("192.0.2.2"))).unwrap();
+        assert_eq!(
+            addr("192.0.2.1"),
+            routes.lookup_ip_route(dst).unwrap().next_hop
+        );
+        routes.delete(network, None).unwrap();
+        assert!(routes.lookup_ip_route(dst).is_none());
+        assert_eq!(Err(RouteError::NotFound), routes.delete(network, None));
+    }
 
     #[test]
     fn test_ip_addr_to_bytes() {
         let b = ip_addr_to_bytes("127.0.0.1");
-        assert_eq!(0x0100007F, b.unwrap());
+        assert_eq!([127, 0, 0, 1], b.unwrap().to_ne_bytes());
+    }
+
+    #[test]
+    fn test_ip_addr_to_bytes_invalid() {
+        assert!(ip_addr_to_bytes("127.0.0").is_none());
+        assert!(ip_addr_to_bytes("127.0.0.256").is_none());
+        assert!(ip_addr_to_bytes("127.0.0.1.1").is_none());
+    }
+
+    #[test]
+    fn test_endpoint_from_str() {
+        let endpoint = endpoint_from_str("192.0.2.2:8080").unwrap();
+        assert_eq!("192.0.2.2:8080", endpoint.to_string());
+        assert!(endpoint_from_str("192.0.2.2").is_none());
+        assert!(endpoint_from_str("192.0.2.2:http").is_none());
+        assert!(endpoint_from_str("192.0.2.2:65536").is_none());
     }
 
     #[test]
     fn test_ip_addr_to_str() {
-        let s = ip_addr_to_str(0x0100007F);
+        let s = ip_addr_to_str(u32::from_ne_bytes([127, 0, 0, 1]));
         assert_eq!("127.0.0.1", s);
     }
 }
@@ -479,5 +1582,9 @@ mod test {
         let header_bytes = unsafe { to_u8_slice(&hdr) };
         let res = cksum16(header_bytes, hlen, 0);
         assert_eq!(0xC2E9, res);
+
+        // Wire bytes do not depend on host byte order.
+        assert_eq!([0x45, 0x00, 0x00, 0x18], header_bytes[..4]);
+        assert_eq!([192, 0, 0, 1, 54, 0, 2, 121], header_bytes[12..20]);
     }
 }
@@ -0,0 +1,388 @@
+use super::{ip_addr_to_str, IPAdress, IPProtocolType, IP_HEADER_MIN_SIZE, IP_VERSION_4};
+use crate::{devices::NetDevice, net::NetInterfaceFamily, protocols::ProtocolContexts, timer};
+use log::{debug, trace};
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, SystemTime},
+};
+
+// Ports of the outside address mapped to flows
+const NAT_PORT_MIN: u16 = 49152;
+const NAT_PORT_MAX: u16 = 65535;
+
+const NAT_UDP_TIMEOUT: Duration = Duration::from_secs(300); // RFC 4787 REQ-5
+const NAT_TCP_ESTABLISHED_TIMEOUT: Duration = Duration::from_secs(7440); // RFC 5382 REQ-5
+const NAT_TCP_TRANSITORY_TIMEOUT: Duration = Duration::from_secs(240); // RFC 5382 REQ-5
+
+const TCP_FLAG_FIN: u8 = 0x01;
+const TCP_FLAG_RST: u8 = 0x04;
+
+/// Protocol and inside and remote endpoints (address and port) of a flow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct FlowKey {
+    protocol: u8,
+    inside: (IPAdress, u16),
+    remote: (IPAdress, u16),
+}
+
+struct NatMapping {
+    flow: FlowKey,
+    used_at: SystemTime,
+    transitory: bool, // TCP flow which sent FIN or RST
+}
+
+impl NatMapping {
+    fn expired(&self) -> bool {
+        let timeout = if self.flow.protocol == IPProtocolType::Udp as u8 {
+            NAT_UDP_TIMEOUT
+        } else if self.transitory {
+            NAT_TCP_TRANSITORY_TIMEOUT
+        } else {
+            NAT_TCP_ESTABLISHED_TIMEOUT
+        };
+        timer::since(self.used_at) >= timeout
+    }
+}
+
+/// Source NAT (masquerade) of TCP and UDP flows forwarded from other devices out of an outside
+/// device: the source becomes the address of the outside device with a port of its own per
+/// flow, and datagrams of the remote endpoint to that port are translated back and forwarded to
+/// the inside endpoint. Mappings of flows idle for their timeout are removed. Datagrams of other
+/// protocols are not translated and dropped.
+pub struct Nat {
+    pub outside: String, // name of the outside device
+    flows: HashMap<FlowKey, u16>,
+    mappings: HashMap<(u8, u16), NatMapping>, // by protocol and outside port
+    next_port: u16,
+    translated_out: u64,
+    translated_in: u64,
+    dropped: u64, // datagrams not translatable or without a port left
+}
+
+impl Nat {
+    pub fn new(outside: &str) -> Nat {
+        Nat {
+            outside: outside.to_string(),
+            flows: HashMap::new(),
+            mappings: HashMap::new(),
+            next_port: NAT_PORT_MIN,
+            translated_out: 0,
+            translated_in: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.mappings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mappings.is_empty()
+    }
+
+    /// Removes mappings of flows idle for their timeout.
+    pub fn expire(&mut self) {
+        let flows = &mut self.flows;
+        self.mappings.retain(|_, mapping| {
+            let expired = mapping.expired();
+            if expired {
+                flows.remove(&mapping.flow);
+            }
+            !expired
+        });
+    }
+
+    /// Rewrites the source of a datagram forwarded out of the outside device to an address of
+    /// it and the port mapped to the flow, mapping one for a new flow.
+    pub fn translate_outbound(&mut self, datagram: &mut [u8], address: IPAdress) -> Result<(), ()> {
+        let Some((flow, header_len)) = flow_of(datagram) else {
+            self.dropped += 1;
+            return Err(());
+        };
+        let port = match self.flows.get(&flow) {
+            Some(&port) if !self.mappings[&(flow.protocol, port)].expired() => port,
+            _ => match self.map(flow) {
+                Some(port) => port,
+                None => {
+                    debug!("NAT: no port left for a flow of {}.", endpoint(flow.inside));
+                    self.dropped += 1;
+                    return Err(());
+                }
+            },
+        };
+        let mapping = self.mappings.get_mut(&(flow.protocol, port)).unwrap();
+        mapping.used_at = timer::now();
+        mapping.transitory |= closes(datagram, header_len);
+        rewrite(datagram, header_len, 12, address, port);
+        self.translated_out += 1;
+        Ok(())
+    }
+
+    /// Rewrites the destination of a datagram of the remote endpoint of a flow to the inside
+    /// endpoint. None for datagrams to ports not mapped or from other endpoints.
+    pub fn translate_inbound(&mut self, datagram: &[u8]) -> Option<Vec<u8>> {
+        let (flow, header_len) = flow_of(datagram)?;
+        // Source of a datagram received is the remote endpoint, and the destination is the port.
+        let (remote, (_, port)) = (flow.inside, flow.remote);
+        let mapping = self.mappings.get_mut(&(flow.protocol, port))?;
+        if mapping.flow.remote != remote || mapping.expired() {
+            return None;
+        }
+        mapping.used_at = timer::now();
+        mapping.transitory |= closes(datagram, header_len);
+        let (address, port) = mapping.flow.inside;
+        let mut translated = datagram.to_vec();
+        rewrite(&mut translated, header_len, 16, address, port);
+        self.translated_in += 1;
+        Some(translated)
+    }
+
+    fn map(&mut self, flow: FlowKey) -> Option<u16> {
+        self.expire();
+        let ports = (NAT_PORT_MAX - NAT_PORT_MIN) as usize + 1;
+        let port = (0..ports)
+            .map(|i| {
+                let offset = (self.next_port - NAT_PORT_MIN) as usize + i;
+                NAT_PORT_MIN + (offset % ports) as u16
+            })
+            .find(|port| !self.mappings.contains_key(&(flow.protocol, *port)))?;
+        self.next_port = if port == NAT_PORT_MAX {
+            NAT_PORT_MIN
+        } else {
+            port + 1
+        };
+        if let Some(stale) = self.flows.insert(flow, port) {
+            self.mappings.remove(&(flow.protocol, stale));
+        }
+        self.mappings.insert(
+            (flow.protocol, port),
+            NatMapping {
+                flow,
+                used_at: timer::now(),
+                transitory: false,
+            },
+        );
+        trace!(
+            "NAT: mapped {} to {} as port {port}.",
+            endpoint(flow.inside),
+            endpoint(flow.remote)
+        );
+        Some(port)
+    }
+}
+
+impl fmt::Display for Nat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "nat (outside: {}):", self.outside)?;
+        writeln!(f, "  mappings: {}", self.mappings.len())?;
+        writeln!(f, "  translated out: {}", self.translated_out)?;
+        writeln!(f, "  translated in: {}", self.translated_in)?;
+        writeln!(f, "  dropped: {}", self.dropped)
+    }
+}
+
+/// Translates the source of a datagram forwarded from another device out of the outside device
+/// of NAT, if any is configured. Fails for datagrams to drop.
+pub fn outbound(
+    datagram: &mut [u8],
+    in_device: &str,
+    out_device: &NetDevice,
+    contexts: &mut ProtocolContexts,
+) -> Result<(), ()> {
+    let Some(nat) = contexts.nat.as_mut() else {
+        return Ok(());
+    };
+    if out_device.name != nat.outside || in_device == nat.outside {
+        return Ok(());
+    }
+    let interface = out_device.get_interface(NetInterfaceFamily::IP).ok_or(())?;
+    nat.translate_outbound(datagram, interface.unicast)
+}
+
+/// Datagram received on the outside device of NAT with the destination translated to the
+/// inside endpoint of a flow, to be forwarded. None for other datagrams, which are input as
+/// usual.
+pub fn inbound(
+    datagram: &[u8],
+    device: &NetDevice,
+    contexts: &mut ProtocolContexts,
+) -> Option<Vec<u8>> {
+    let nat = contexts.nat.as_mut()?;
+    if device.name != nat.outside || datagram.len() < IP_HEADER_MIN_SIZE {
+        return None;
+    }
+    let interface = device.get_interface(NetInterfaceFamily::IP)?;
+    let dst = IPAdress::from_ne_bytes(datagram[16..20].try_into().unwrap());
+    if dst != interface.unicast {
+        return None;
+    }
+    nat.translate_inbound(datagram)
+}
+
+/// Flow of a TCP or UDP datagram, not a fragment, with its source as the inside endpoint, and
+/// the header length.
+fn flow_of(datagram: &[u8]) -> Option<(FlowKey, usize)> {
+    if datagram.len() < IP_HEADER_MIN_SIZE || datagram[0] >> 4 != IP_VERSION_4 {
+        return None;
+    }
+    let header_len = ((datagram[0] & 0x0f) << 2) as usize;
+    let protocol = datagram[9];
+    let l4_len = if protocol == IPProtocolType::Tcp as u8 {
+        20
+    } else if protocol == IPProtocolType::Udp as u8 {
+        8
+    } else {
+        return None;
+    };
+    let offset = u16::from_be_bytes([datagram[6], datagram[7]]);
+    if offset & 0x3fff != 0 || datagram.len() < header_len + l4_len {
+        return None;
+    }
+    let address = |at: usize| IPAdress::from_ne_bytes(datagram[at..at + 4].try_into().unwrap());
+    let port = |at: usize| u16::from_be_bytes([datagram[at], datagram[at + 1]]);
+    let flow = FlowKey {
+        protocol,
+        inside: (address(12), port(header_len)),
+        remote: (address(16), port(header_len + 2)),
+    };
+    Some((flow, header_len))
+}
+
+fn closes(datagram: &[u8], header_len: usize) -> bool {
+    datagram[9] == IPProtocolType::Tcp as u8
+        && datagram[header_len + 13] & (TCP_FLAG_FIN | TCP_FLAG_RST) != 0
+}
+
+/// Rewrites the source (address at 12) or the destination (at 16) of a datagram with the
+/// checksums of the IP header and the TCP or UDP header updated.
+fn rewrite(datagram: &mut [u8], header_len: usize, at: usize, address: IPAdress, port: u16) {
+    let port_at = header_len + if at == 12 { 0 } else { 2 };
+    let check_at = header_len
+        + if datagram[9] == IPProtocolType::Tcp as u8 {
+            16
+        } else {
+            6
+        };
+    let mut old = datagram[at..at + 4].to_vec();
+    old.extend_from_slice(&datagram[port_at..port_at + 2]);
+    let mut new = address.to_ne_bytes().to_vec();
+    new.extend_from_slice(&port.to_be_bytes());
+
+    datagram[at..at + 4].copy_from_slice(&new[..4]);
+    datagram[port_at..port_at + 2].copy_from_slice(&new[4..]);
+    let check_sum = u16::from_be_bytes([datagram[10], datagram[11]]);
+    let check_sum = adjust_check_sum(check_sum, &old[..4], &new[..4]);
+    datagram[10..12].copy_from_slice(&check_sum.to_be_bytes());
+    // Pseudo header covers the addresses. No checksum for UDP with zero.
+    let check_sum = u16::from_be_bytes([datagram[check_at], datagram[check_at + 1]]);
+    if datagram[9] == IPProtocolType::Udp as u8 && check_sum == 0 {
+        return;
+    }
+    let check_sum = match adjust_check_sum(check_sum, &old, &new) {
+        0 if datagram[9] == IPProtocolType::Udp as u8 => 0xffff,
+        check_sum => check_sum,
+    };
+    datagram[check_at..check_at + 2].copy_from_slice(&check_sum.to_be_bytes());
+}
+
+/// Checksum updated for 16-bit words replaced: HC' = ~(~HC + ~m + m') (RFC 1624 3).
+fn adjust_check_sum(check_sum: u16, old: &[u8], new: &[u8]) -> u16 {
+    let words = |data: &[u8]| -> Vec<u32> {
+        data.chunks(2)
+            .map(|w| u16::from_be_bytes([w[0], w[1]]) as u32)
+            .collect()
+    };
+    let mut sum = !check_sum as u32;
+    sum += words(old).iter().map(|w| !*w & 0xffff).sum::<u32>();
+    sum += words(new).iter().sum::<u32>();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn endpoint((address, port): (IPAdress, u16)) -> String {
+    format!("{}:{port}", ip_addr_to_str(address))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Nat, NAT_PORT_MIN, NAT_UDP_TIMEOUT};
+    use crate::{
+        protocols::ip::{ip_addr_to_bytes, IPProtocolType},
+        timer,
+        utils::cksum16,
+    };
+    use std::time::{Duration, SystemTime};
+
+    /// UDP datagram with checksums of the IP header and the UDP header with the pseudo header.
+    fn udp_datagram(src: &str, src_port: u16, dst: &str, dst_port: u16) -> Vec<u8> {
+        let mut datagram = vec![0x45, 0, 0, 33, 0, 0, 0, 0, 64, IPProtocolType::Udp as u8];
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(&ip_addr_to_bytes(src).unwrap().to_ne_bytes());
+        datagram.extend_from_slice(&ip_addr_to_bytes(dst).unwrap().to_ne_bytes());
+        datagram.extend_from_slice(&src_port.to_be_bytes());
+        datagram.extend_from_slice(&dst_port.to_be_bytes());
+        datagram.extend_from_slice(&[0, 13, 0, 0]);
+        datagram.extend_from_slice(b"hello");
+        let check_sum = cksum16(&datagram, 20, 0);
+        datagram[10..12].copy_from_slice(&check_sum.to_be_bytes());
+        let check_sum = udp_check_sum(&datagram);
+        datagram[26..28].copy_from_slice(&check_sum.to_be_bytes());
+        datagram
+    }
+
+    fn udp_check_sum(datagram: &[u8]) -> u16 {
+        let mut pseudo = datagram[12..20].to_vec();
+        pseudo.extend_from_slice(&[0, IPProtocolType::Udp as u8, 0, 13]);
+        let mut segment = datagram[20..].to_vec();
+        segment[6..8].copy_from_slice(&[0, 0]);
+        pseudo.extend_from_slice(&segment);
+        cksum16(&pseudo, pseudo.len(), 0)
+    }
+
+    #[test]
+    fn test_translate() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        timer::set_simulated_now(Some(start));
+        let outside = ip_addr_to_bytes("198.51.100.2").unwrap();
+        let mut nat = Nat::new("veth1");
+
+        let mut datagram = udp_datagram("192.0.2.1", 5000, "198.51.100.1", 53);
+        nat.translate_outbound(&mut datagram, outside).unwrap();
+        assert_eq!(outside.to_ne_bytes(), datagram[12..16]);
+        assert_eq!(NAT_PORT_MIN.to_be_bytes(), datagram[20..22]);
+        assert_eq!(0, cksum16(&datagram, 20, 0));
+        assert_eq!(udp_check_sum(&datagram).to_be_bytes(), datagram[26..28]);
+
+        // Same flow, same port. Another flow, the next port.
+        let mut datagram = udp_datagram("192.0.2.1", 5000, "198.51.100.1", 53);
+        nat.translate_outbound(&mut datagram, outside).unwrap();
+        assert_eq!(NAT_PORT_MIN.to_be_bytes(), datagram[20..22]);
+        let mut datagram = udp_datagram("192.0.2.3", 5000, "198.51.100.1", 53);
+        nat.translate_outbound(&mut datagram, outside).unwrap();
+        assert_eq!((NAT_PORT_MIN + 1).to_be_bytes(), datagram[20..22]);
+        assert_eq!(2, nat.len());
+
+        // Replies to the mapped port from the remote endpoint only
+        let reply = udp_datagram("198.51.100.1", 53, "198.51.100.2", NAT_PORT_MIN);
+        let translated = nat.translate_inbound(&reply).unwrap();
+        assert_eq!(
+            ip_addr_to_bytes("192.0.2.1").unwrap().to_ne_bytes(),
+            translated[16..20]
+        );
+        assert_eq!(5000u16.to_be_bytes(), translated[22..24]);
+        assert_eq!(0, cksum16(&translated, 20, 0));
+        assert_eq!(udp_check_sum(&translated).to_be_bytes(), translated[26..28]);
+        let other = udp_datagram("198.51.100.9", 53, "198.51.100.2", NAT_PORT_MIN);
+        assert!(nat.translate_inbound(&other).is_none());
+
+        // Idle flows expire.
+        timer::set_simulated_now(Some(start + NAT_UDP_TIMEOUT));
+        assert!(nat.translate_inbound(&reply).is_none());
+        nat.expire();
+        assert!(nat.is_empty());
+        timer::set_simulated_now(None);
+    }
+}
@@ -1,9 +1,17 @@
+use super::congestion::{CongestionAlgorithm, CongestionControl};
+use super::icmp::{quoted_tcp_segment, ICMP_TYPE_DEST_UNREACH};
+use super::l4::L4Protocol;
+use super::tcp_options;
 use super::{ControlBlocks, ProtocolContexts};
 use super::{IPAdress, IPEndpoint, IPInterface, IPProtocolType, IP_ADDR_ANY, IP_HEADER_MIN_SIZE};
 use crate::devices::NetDevices;
+#[cfg(feature = "fault-injection")]
+use crate::fault::{self, Fault};
+use crate::timer::{self, TimerQueue};
 use crate::{
     devices::NetDevice,
     protocols::ip::ip_addr_to_str,
+    protocols::SocketError,
     utils::byte::{be_to_le_u16, be_to_le_u32, le_to_be_u16, le_to_be_u32},
     utils::{bytes_to_struct, cksum16, to_u8_slice},
 };
@@ -11,23 +19,53 @@ use log::{debug, error, info, warn};
 use rand::Rng;
 use std::{
     cmp,
-    collections::VecDeque,
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    fmt,
+    hash::{Hash, Hasher},
     mem::size_of,
+    net::Shutdown,
+    str::FromStr,
     sync::{
         mpsc::{self, Sender},
         Arc, Mutex,
     },
-    time::{Duration, SystemTime},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
     vec,
 };
 
 const TCP_PCB_COUNT: usize = 16;
-const TCP_DEFAULT_ITVL_MICROS: u64 = 200000;
+const TCP_DEFAULT_ITVL_MICROS: u64 = 200000; // RTO before the first RTT sample
+const TCP_RTO_MIN_MILLIS: u64 = 200;
+const TCP_RTO_MAX_SECS: u64 = 60;
+const TCP_CLOCK_GRANULARITY_MILLIS: u64 = 100; // RTO floor term G (RFC 6298 2.4)
 const TCP_RETRANSMIT_TIMOUT_SEC: u64 = 12;
+const TCP_SYN_RETRIES: u8 = 5;
+const TCP_SYN_RTO_MILLIS: u64 = 1000; // initial SYN timeout (RFC 6298)
 const TCP_TIMEWAIT_SEC: u64 = 30; // substitute for 2MSL
 const TCP_SRC_PORT_MIN: u16 = 49152;
 const TCP_SRC_PORT_MAX: u16 = 65535;
-const PCB_BUF_LEN: usize = 65535;
+const PCB_BUF_LEN: usize = 65535; // receive buffer size at most as window is not scaled
+pub const TCP_SEND_BUF_LEN: usize = 256 * 1024;
+const TCP_HELD_LEN_MAX: usize = 32 * 1024; // bytes held out of order per PCB
+const TCP_DELAYED_ACK_MILLIS: u64 = 100;
+const TCP_DUP_ACK_THRESHOLD: u8 = 3; // fast retransmit (RFC 5681)
+const TCP_COALESCE_MILLIS: u64 = 200; // small writes held at most while coalescing (as TCP_CORK)
+const TCP_SEND_RETRY_MILLIS: u64 = 10; // interval of send_all waiting for room in buffers
+const TCP_BACKLOG: usize = 8; // connections pending accept per listener, half of the PCBs
+const TCP_SYN_COOKIE_PERIOD_SECS: u64 = 64; // cookies are valid for one to two periods
+                                            // Peer MSS encoded in cookies as the index of the largest entry not above it (as Linux does).
+                                            // SYNs without the option get the default of 536 (RFC 1122 4.2.2.6).
+const TCP_SYN_COOKIE_MSS: [u16; 4] = [536, 1300, 1440, 1460];
+const TCP_CHALLENGE_ACK_LIMIT: u32 = 1000; // per second of all connections (RFC 5961 7)
+
+/// Logs a message of a connection tagged with its ID, e.g. `TCP[conn=3]: SYN found.`, so that
+/// interleaved logs of concurrent connections can be filtered per connection.
+macro_rules! conn_log {
+    ($level:ident, $conn_id:expr, $($arg:tt)+) => {
+        $level!("TCP[conn={}]: {}", $conn_id, format_args!($($arg)+))
+    };
+}
 
 #[derive(Debug)]
 struct PseudoHeader {
@@ -55,6 +93,22 @@ fn tcp_flag_exists(flags: u8, flag: TcpFlag) -> bool {
     (flags & 0x3f) & (flag as u8) != 0
 }
 
+/// a < b in sequence space, where numbers wrap around modulo 2^32 (RFC 793 3.3). Valid for
+/// numbers less than 2^31 apart.
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// a <= b in sequence space.
+fn seq_leq(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+
+/// low <= seq < high in sequence space, e.g. a sequence number in the receive window.
+fn seq_between(low: u32, seq: u32, high: u32) -> bool {
+    seq.wrapping_sub(low) < high.wrapping_sub(low)
+}
+
 #[repr(packed)]
 struct TcpHeader {
     src_port: u16,
@@ -68,13 +122,16 @@ struct TcpHeader {
     urg_ptr: u16,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 struct TcpSegmentInfo {
     seq_num: u32,
     ack_num: u32,
     len: u16,
     window: u16,
     urg_ptr: u16,
+    mss: Option<u16>,
+    sack_permitted: bool,
+    sack_blocks: Vec<(u32, u32)>,
 }
 
 struct TcpPcbSendContext {
@@ -89,7 +146,7 @@ struct TcpPcbSendContext {
 struct TcpPcbRecvContext {
     next: u32,
     window: u16,
-    urg_ptr: u16,
+    urg_ptr: Option<u32>, // sequence number following urgent data not received yet (RCV.UP)
 }
 
 #[derive(PartialEq, Clone, Copy, Debug)]
@@ -108,6 +165,55 @@ enum TcpPcbState {
     LastAck,
 }
 
+/// When data received in order is acknowledged. Segments out of order or filling a gap are
+/// acknowledged right away regardless.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum AckPolicy {
+    EverySegment,
+    EveryOther, // or on the delayed ACK timer (RFC 1122 4.2.3.2)
+    Delayed,    // on the delayed ACK timer only
+}
+
+impl FromStr for AckPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "every" => Ok(AckPolicy::EverySegment),
+            "every-other" => Ok(AckPolicy::EveryOther),
+            "delayed" => Ok(AckPolicy::Delayed),
+            _ => Err(format!("unknown ACK policy: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for AckPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AckPolicy::EverySegment => write!(f, "every"),
+            AckPolicy::EveryOther => write!(f, "every-other"),
+            AckPolicy::Delayed => write!(f, "delayed"),
+        }
+    }
+}
+
+/// What becomes of the last byte of urgent data (URG flag) received. The urgent pointer is
+/// taken to point to the byte following urgent data (RFC 6093).
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum UrgentPolicy {
+    Inline,    // left in the stream like other data (SO_OOBINLINE)
+    OutOfBand, // taken out of the stream to read with `receive_oob`
+    Discard,   // taken out of the stream and dropped
+}
+
+/// Key of a PCB in the lookup tables of `TcpPcbs`: local address and port (network order) of a
+/// listening PCB, followed by remote ones for others.
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+enum TcpPcbKey {
+    Listener(IPAdress, u16),
+    Connection(IPAdress, u16, IPAdress, u16),
+}
+
 #[derive(PartialEq, Clone, Copy)]
 enum TcpPcbMode {
     NotSet,
@@ -121,7 +227,8 @@ struct TcpDataQueueEntry {
     retry_interval: Duration,
     seq_num: u32,
     flags: u8,
-    data: Vec<u8>,
+    len: usize,   // of data in the send buffer from seq_num
+    sacked: bool, // reported received by the peer's SACK blocks
 }
 
 pub struct TcpDataQueue {
@@ -136,19 +243,116 @@ impl TcpDataQueue {
     }
 }
 
+/// Smoothed RTT and RTT variance of a connection giving the retransmission timeout (RFC 6298).
+struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+}
+
+impl RttEstimator {
+    fn new() -> RttEstimator {
+        RttEstimator {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: Duration::from_micros(TCP_DEFAULT_ITVL_MICROS),
+        }
+    }
+
+    fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                // RTTVAR = 3/4 * RTTVAR + 1/4 * |SRTT - R|, SRTT = 7/8 * SRTT + 1/8 * R
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        let granularity = Duration::from_millis(TCP_CLOCK_GRANULARITY_MILLIS);
+        self.rto = (self.srtt.unwrap() + cmp::max(granularity, self.rttvar * 4)).clamp(
+            Duration::from_millis(TCP_RTO_MIN_MILLIS),
+            Duration::from_secs(TCP_RTO_MAX_SECS),
+        );
+    }
+}
+
+/// Receive-side statistics of segments arriving ahead of recv.next, i.e. leaving a gap in the
+/// sequence space.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TcpReassemblyStats {
+    pub out_of_order: u64, // segments arrived ahead of recv.next
+    pub gap: u32,          // current gap in bytes between recv.next and the furthest segment
+    pub max_gap: u32,
+    pub held: usize,  // segments held for later processing
+    pub dropped: u64, // segments dropped for the budget of held bytes
+    pub trimmed: u64, // bytes received again, e.g. retransmitted, and trimmed off segments
+}
+
+/// Counters of a connection, with the RTT estimate and RTO filled in by `connection_stats`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TcpConnStats {
+    pub segments_sent: u64, // retransmissions included
+    pub bytes_sent: u64,
+    pub segments_received: u64,
+    pub bytes_received: u64,
+    pub retransmits: u64,
+    pub dup_acks: u64,
+    pub challenge_acks: u64, // sent for RSTs and SYNs that may be spoofed (RFC 5961)
+    pub transitions: u64,    // state changes since the PCB was allocated
+    pub srtt: Option<Duration>,
+    pub rto: Duration,
+}
+
 pub struct TcpBacklog {
     pcb_ids: VecDeque<usize>,
+    limit: usize,      // connections half-open or waiting for accept
+    dropped: u64,      // SYNs dropped for the limit
+    no_pcb_drops: u64, // SYNs dropped for lack of free PCBs
+    cookies_sent: u64,
+    cookies_accepted: u64,
 }
 
 impl TcpBacklog {
     pub fn new() -> TcpBacklog {
         TcpBacklog {
             pcb_ids: VecDeque::<usize>::new(),
+            limit: TCP_BACKLOG,
+            dropped: 0,
+            no_pcb_drops: 0,
+            cookies_sent: 0,
+            cookies_accepted: 0,
         }
     }
 }
 
+/// Connections of a listener and SYNs it dropped.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TcpBacklogStats {
+    pub half_open: usize, // in SYN-RECEIVED
+    pub queued: usize,    // established and waiting for accept
+    pub limit: usize,
+    pub dropped: u64,
+    pub no_pcb_drops: u64,
+    pub cookies_sent: u64,     // SYN-ACKs answered without a PCB
+    pub cookies_accepted: u64, // ACKs of valid cookies establishing connections
+}
+
+/// Event of a connection sent to its subscriber (see `subscribe`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TcpEvent {
+    Connected,
+    DataReady(usize), // bytes buffered to read
+    PeerClosed,       // FIN received, reads return data buffered and then none
+    Urgent,           // urgent data is coming, see `UrgentPolicy`
+    SoftError(SocketError),
+    Closed(Option<SocketError>), // PCB released, with the reason when not closed normally
+}
+
 pub struct TcpPcb {
+    conn_id: u64, // stable across the connection unlike PCB id reused after release
     state: TcpPcbState,
     mode: TcpPcbMode,
     local: IPEndpoint,
@@ -156,12 +360,40 @@ pub struct TcpPcb {
     send_context: TcpPcbSendContext,
     iss: u32, // Initial send sequence number
     recv_context: TcpPcbRecvContext,
-    irs: u32, // Initial receive sequence number
-    mtu: u16,
-    mss: u16,
-    buf: Vec<u8>, // [u8; 65535],
+    irs: u32,          // Initial receive sequence number
+    mss: u16,          // of segments sent, set with the congestion control
+    peer_mss: u16,     // MSS option of the peer's SYN, 0 without one
+    buf: Vec<u8>,      // received data not read yet, recv_buf_size at most
+    send_buf: Vec<u8>, // data from send.una: in flight, then waiting for send/congestion window
+    recv_buf_size: usize,
+    send_buf_size: usize,
+    adv_window: u16, // receive window last advertised to the peer
+    congestion_algorithm: CongestionAlgorithm,
+    congestion: Option<Box<dyn CongestionControl>>, // created on first send with MSS
+    dup_acks: u8,
+    rtt: RttEstimator,
+    counters: TcpConnStats,
+    reassembly: TcpReassemblyStats,
+    sack_permitted: bool, // both ends sent SACK-permitted option on SYN
+    no_delay: bool,       // sends small segments without waiting for ACKs (TCP_NODELAY)
+    coalesce: bool,       // holds writes smaller than MSS for more of them (TCP_CORK)
+    coalesce_at: Option<SystemTime>, // data held for coalescing is sent anyway
+    push_pending: bool,   // data buffered is sent without coalescing (PSH)
+    syn_cookies: bool,    // listener answers SYNs with cookies instead of PCBs
+    ack_policy: AckPolicy,
+    urgent_policy: UrgentPolicy,
+    oob: Option<u8>,     // urgent byte taken out of the stream, not read yet
+    fin_pending: bool,   // FIN of close to send after data buffered
+    recv_shutdown: bool, // data received is discarded (SHUT_RD)
+    held: VecDeque<(u32, Vec<u8>)>, // segments arrived out of order by sequence number
+    last_held: u32,      // sequence number of the latest segment held
     wait_time: Option<SystemTime>,
+    delayed_ack: Option<SystemTime>, // arrival of data not acknowledged yet
+    persist_at: Option<SystemTime>,  // zero window probe due
     sender: Option<Sender<bool>>,
+    events: Option<Sender<TcpEvent>>,
+    error: Option<SocketError>, // reason of release reported to woken up users
+    soft_error: Option<SocketError>, // ICMP error not fatal to the connection, reported once
     data_queue: TcpDataQueue,
     parent_id: Option<usize>,
     backlog: TcpBacklog,
@@ -170,6 +402,7 @@ pub struct TcpPcb {
 impl TcpPcb {
     pub fn new() -> TcpPcb {
         TcpPcb {
+            conn_id: 0,
             state: TcpPcbState::Free,
             mode: TcpPcbMode::NotSet,
             local: IPEndpoint {
@@ -192,56 +425,393 @@ impl TcpPcb {
             recv_context: TcpPcbRecvContext {
                 next: 0,
                 window: 0,
-                urg_ptr: 0,
+                urg_ptr: None,
             },
             irs: 0,
-            mtu: 0,
             mss: 0,
-            buf: Vec::with_capacity(PCB_BUF_LEN),
+            peer_mss: 0,
+            buf: Vec::new(),
+            send_buf: Vec::new(),
+            recv_buf_size: PCB_BUF_LEN,
+            send_buf_size: TCP_SEND_BUF_LEN,
+            adv_window: 0,
+            congestion_algorithm: CongestionAlgorithm::Reno,
+            congestion: None,
+            dup_acks: 0,
+            rtt: RttEstimator::new(),
+            counters: TcpConnStats::default(),
+            reassembly: TcpReassemblyStats::default(),
+            sack_permitted: false,
+            no_delay: false,
+            coalesce: false,
+            coalesce_at: None,
+            push_pending: false,
+            ack_policy: AckPolicy::EveryOther,
+            syn_cookies: false,
+            urgent_policy: UrgentPolicy::Inline,
+            oob: None,
+            fin_pending: false,
+            recv_shutdown: false,
+            held: VecDeque::new(),
+            last_held: 0,
             wait_time: None,
+            delayed_ack: None,
+            persist_at: None,
             sender: None,
+            events: None,
+            error: None,
+            soft_error: None,
             data_queue: TcpDataQueue::new(),
             parent_id: None,
             backlog: TcpBacklog::new(),
         }
     }
 
-    pub fn add_data_queue(&mut self, seq_num: u32, flags: u8, data: Vec<u8>) {
-        let now = SystemTime::now();
+    pub fn add_data_queue(&mut self, seq_num: u32, flags: u8, len: usize) {
+        let now = timer::now();
         let entry = TcpDataQueueEntry {
             first_sent_at: now,
             last_sent_at: now,
-            retry_interval: Duration::from_micros(TCP_DEFAULT_ITVL_MICROS),
+            retry_interval: self.rtt.rto,
             seq_num,
             flags,
-            data,
+            len,
+            sacked: false,
         };
         self.data_queue.entries.push_back(entry);
     }
 
+    /// Bytes held in receive and send buffers, and out of order.
+    fn memory_used(&self) -> usize {
+        self.buf.len()
+            + self.send_buf.len()
+            + self.held.iter().map(|(_, data)| data.len()).sum::<usize>()
+    }
+
+    /// Sizes the receive buffer to `recv_buf_size` for a connection being opened and advertises
+    /// all of it as the window.
+    fn open_recv_window(&mut self) {
+        self.buf.clear();
+        self.buf.reserve_exact(self.recv_buf_size);
+        self.update_recv_window();
+    }
+
+    /// Sets the receive window to the free space of the receive buffer.
+    fn update_recv_window(&mut self) {
+        self.recv_context.window = self.recv_buf_size.saturating_sub(self.buf.len()) as u16;
+    }
+
+    /// Bytes of the send buffer sent and not acknowledged yet. The buffer starts at send.una, so
+    /// the offset of a sequence number is its distance from send.una.
+    fn in_flight_len(&self) -> usize {
+        cmp::min(
+            self.send_context.next.wrapping_sub(self.send_context.una) as usize,
+            self.send_buf.len(),
+        )
+    }
+
+    /// The earliest time `retransmit` has something to do for the PCB: TIME-WAIT expiry, a
+    /// delayed ACK, a retransmission or its timeout, or a zero window probe.
+    fn next_deadline(&self) -> Option<SystemTime> {
+        let now = timer::now();
+        let mut deadlines = vec![];
+        match self.state {
+            TcpPcbState::Free => return None,
+            TcpPcbState::TimeWait => deadlines.push(self.wait_time.unwrap_or(now)),
+            _ => {}
+        }
+        if let Some(arrived_at) = self.delayed_ack {
+            deadlines.push(arrived_at + Duration::from_millis(TCP_DELAYED_ACK_MILLIS));
+        }
+        let persisting = self.send_context.window == 0;
+        if let Some(oldest) = self.data_queue.entries.front() {
+            if self.state != TcpPcbState::SynSent && !persisting {
+                deadlines
+                    .push(oldest.first_sent_at + Duration::from_secs(TCP_RETRANSMIT_TIMOUT_SEC));
+            }
+        }
+        deadlines.extend(
+            self.data_queue
+                .entries
+                .iter()
+                .filter(|entry| !entry.sacked)
+                .map(|entry| entry.last_sent_at + entry.retry_interval),
+        );
+        if persisting && self.data_queue.entries.is_empty() && self.unsent_len() > 0 {
+            deadlines.push(self.persist_at.unwrap_or(now));
+        }
+        deadlines.extend(self.coalesce_at);
+        deadlines.into_iter().min()
+    }
+
+    /// Bytes of the send buffer not sent yet.
+    fn unsent_len(&self) -> usize {
+        self.send_buf.len() - self.in_flight_len()
+    }
+
+    /// Data of a segment queued for retransmission with its sequence number, which moves to
+    /// send.una when the segment is partially acknowledged.
+    fn queued_data(&self, seq_num: u32, len: usize) -> (u32, Vec<u8>) {
+        let (seq_num, len) = if seq_lt(seq_num, self.send_context.una) {
+            let acked = self.send_context.una.wrapping_sub(seq_num) as usize;
+            (self.send_context.una, len.saturating_sub(acked))
+        } else {
+            (seq_num, len)
+        };
+        let offset = cmp::min(
+            seq_num.wrapping_sub(self.send_context.una) as usize,
+            self.send_buf.len(),
+        );
+        let end = cmp::min(offset + len, self.send_buf.len());
+        (seq_num, self.send_buf[offset..end].to_vec())
+    }
+
+    /// Advances send.una to an acknowledgment number, removing data acknowledged from the send
+    /// buffer and segments from the retransmission queue.
+    fn acknowledge(&mut self, ack_num: u32) {
+        let acked = ack_num.wrapping_sub(self.send_context.una) as usize;
+        self.send_buf.drain(..cmp::min(acked, self.send_buf.len()));
+        self.send_context.una = ack_num;
+        self.clean_data_queue();
+    }
+
+    /// Marks entries covered by SACK blocks of the peer not to be retransmitted.
+    fn mark_sacked(&mut self, blocks: &[(u32, u32)]) {
+        for entry in self.data_queue.entries.iter_mut() {
+            let end = entry.seq_num.wrapping_add(entry.len as u32);
+            if entry.len > 0
+                && blocks
+                    .iter()
+                    .any(|(left, right)| seq_leq(*left, entry.seq_num) && seq_leq(end, *right))
+            {
+                entry.sacked = true;
+            }
+        }
+    }
+
+    /// Holds a segment arrived ahead of recv.next till the gap gets filled. Beyond
+    /// TCP_HELD_LEN_MAX, segments furthest ahead are dropped first as the ones nearer to
+    /// recv.next are delivered sooner. Returns false when the segment itself is dropped.
+    fn hold(&mut self, seq_num: u32, data: &[u8]) -> bool {
+        let (seq_num, data) = self.trim_held(seq_num, data);
+        if data.is_empty() {
+            return true;
+        }
+        let mut held_len: usize = self.held.iter().map(|(_, held)| held.len()).sum();
+        while held_len + data.len() > TCP_HELD_LEN_MAX {
+            match self.held.back() {
+                Some((seq, _)) if seq_lt(seq_num, *seq) => {
+                    held_len -= self.held.pop_back().unwrap().1.len();
+                    self.reassembly.dropped += 1;
+                }
+                _ => break,
+            }
+        }
+        if held_len + data.len() > TCP_HELD_LEN_MAX {
+            self.reassembly.dropped += 1;
+            self.update_reassembly_stats();
+            return false;
+        }
+        let pos = self.held.partition_point(|(seq, _)| seq_lt(*seq, seq_num));
+        match self.held.get(pos) {
+            Some((seq, held)) if *seq == seq_num && held.len() >= data.len() => {}
+            Some((seq, _)) if *seq == seq_num => self.held[pos].1 = data.to_vec(),
+            _ => self.held.insert(pos, (seq_num, data.to_vec())),
+        }
+        self.last_held = seq_num;
+        self.update_reassembly_stats();
+        true
+    }
+
+    /// Trims bytes of a segment received already, i.e. before recv.next, counting them.
+    /// Returns the sequence number and the data left, empty when all of it was received.
+    fn trim_received<'a>(&mut self, seq_num: u32, data: &'a [u8]) -> (u32, &'a [u8]) {
+        if seq_leq(self.recv_context.next, seq_num) {
+            return (seq_num, data);
+        }
+        let offset = cmp::min(
+            self.recv_context.next.wrapping_sub(seq_num) as usize,
+            data.len(),
+        );
+        self.reassembly.trimmed += offset as u64;
+        (seq_num.wrapping_add(offset as u32), &data[offset..])
+    }
+
+    /// Trims the head of a segment ahead of recv.next covered by segments held already,
+    /// counting the bytes trimmed, so that retransmissions of held data are not held twice.
+    fn trim_held<'a>(&mut self, seq_num: u32, data: &'a [u8]) -> (u32, &'a [u8]) {
+        let end = seq_num.wrapping_add(data.len() as u32);
+        let mut covered = seq_num;
+        for (seq, held) in self.held.iter() {
+            let held_end = seq.wrapping_add(held.len() as u32);
+            if seq_leq(*seq, covered) && seq_lt(covered, held_end) {
+                covered = if seq_lt(held_end, end) { held_end } else { end };
+            }
+        }
+        let offset = covered.wrapping_sub(seq_num) as usize;
+        self.reassembly.trimmed += offset as u64;
+        (covered, &data[offset..])
+    }
+
+    /// Appends data starting at `seq` to the receive buffer. The last byte of urgent data is
+    /// taken out of the stream unless the policy leaves it inline.
+    fn append_data(&mut self, seq: u32, data: &[u8]) {
+        if let Some(urg_ptr) = self.recv_context.urg_ptr {
+            let offset = urg_ptr.wrapping_sub(1).wrapping_sub(seq) as usize;
+            if offset < data.len() {
+                self.recv_context.urg_ptr = None;
+                if self.urgent_policy != UrgentPolicy::Inline {
+                    conn_log!(
+                        debug,
+                        self.conn_id,
+                        "urgent byte taken out of the stream ({:?}).",
+                        self.urgent_policy
+                    );
+                    if self.urgent_policy == UrgentPolicy::OutOfBand {
+                        self.oob = Some(data[offset]);
+                    }
+                    self.buf.extend_from_slice(&data[..offset]);
+                    self.buf.extend_from_slice(&data[offset + 1..]);
+                    return;
+                }
+            }
+        }
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Moves held segments made contiguous by recv.next to the receive buffer. Returns the
+    /// number of bytes moved.
+    fn drain_held(&mut self) -> usize {
+        let mut drained = 0;
+        while let Some((seq, _)) = self.held.front() {
+            if seq_lt(self.recv_context.next, *seq) {
+                break;
+            }
+            let (seq, data) = self.held.pop_front().unwrap();
+            let end = seq.wrapping_add(data.len() as u32);
+            if seq_lt(self.recv_context.next, end) {
+                let new = &data[self.recv_context.next.wrapping_sub(seq) as usize..];
+                self.append_data(self.recv_context.next, new);
+                self.recv_context.next = end;
+                self.update_recv_window();
+                drained += new.len();
+            }
+        }
+        self.update_reassembly_stats();
+        drained
+    }
+
+    fn update_reassembly_stats(&mut self) {
+        let stats = &mut self.reassembly;
+        stats.held = self.held.len();
+        stats.gap = self
+            .held
+            .iter()
+            .map(|(seq, data)| {
+                seq.wrapping_add(data.len() as u32)
+                    .wrapping_sub(self.recv_context.next)
+            })
+            .max()
+            .unwrap_or(0);
+        stats.max_gap = cmp::max(stats.max_gap, stats.gap);
+    }
+
+    /// Ranges of held data, the one with the latest segment held first (RFC 2018).
+    fn sack_blocks(&self) -> Vec<(u32, u32)> {
+        let mut blocks: Vec<(u32, u32)> = vec![];
+        for (seq, data) in self.held.iter() {
+            let end = seq.wrapping_add(data.len() as u32);
+            match blocks.last_mut() {
+                Some((_, right)) if seq_leq(*seq, *right) => {
+                    if seq_lt(*right, end) {
+                        *right = end;
+                    }
+                }
+                _ => blocks.push((*seq, end)),
+            }
+        }
+        if let Some(i) = blocks
+            .iter()
+            .position(|(left, right)| seq_between(*left, self.last_held, *right))
+        {
+            let recent = blocks.remove(i);
+            blocks.insert(0, recent);
+        }
+        blocks
+    }
+
+    /// Removes entries fully acknowledged by send.una from retransmission queue. The newest of
+    /// them never retransmitted gives an RTT sample (Karn's algorithm).
     pub fn clean_data_queue(&mut self) {
-        let mut found = false;
-        let mut index_to_delete = 0;
-        for (i, entry) in self.data_queue.entries.iter().enumerate() {
-            if entry.seq_num >= self.send_context.una {
+        let mut rtt = None;
+        while let Some(entry) = self.data_queue.entries.front() {
+            let mut seq_len = entry.len as u32;
+            if tcp_flag_exists(entry.flags, TcpFlag::SYN)
+                || tcp_flag_exists(entry.flags, TcpFlag::FIN)
+            {
+                seq_len += 1;
+            }
+            if seq_lt(self.send_context.una, entry.seq_num.wrapping_add(seq_len)) {
                 break;
             }
-            found = true;
-            index_to_delete = i;
+            if entry.first_sent_at == entry.last_sent_at {
+                rtt = timer::now().duration_since(entry.first_sent_at).ok();
+            }
+            self.data_queue.entries.pop_front();
+        }
+        if let Some(rtt) = rtt {
+            self.rtt.sample(rtt);
+            conn_log!(
+                debug,
+                self.conn_id,
+                "RTT sample: {:?} SRTT: {:?} RTTVAR: {:?} RTO: {:?}",
+                rtt,
+                self.rtt.srtt,
+                self.rtt.rttvar,
+                self.rtt.rto
+            );
+        }
+    }
+
+    /// Changes the state counting transitions for `connection_stats`.
+    fn set_state(&mut self, state: TcpPcbState) {
+        if self.state != state {
+            self.state = state;
+            self.counters.transitions += 1;
+            if state == TcpPcbState::Established {
+                self.notify(TcpEvent::Connected);
+            }
         }
-        if found {
-            self.data_queue.entries.remove(index_to_delete);
+    }
+
+    /// Sends an event to the subscriber, which is dropped once it stops listening.
+    fn notify(&mut self, event: TcpEvent) {
+        if let Some(events) = self.events.as_ref() {
+            if events.send(event).is_err() {
+                conn_log!(debug, self.conn_id, "event subscriber gone.");
+                self.events = None;
+            }
         }
     }
 
     pub fn release(&mut self) {
         self.state = TcpPcbState::Free;
+        self.notify(TcpEvent::Closed(self.error));
+        self.events = None;
         if self.sender.is_some() {
             if self.sender.as_ref().unwrap().send(false).is_err() {
-                warn!("TCP: attempting PRB release, however channel not listening.");
+                conn_log!(
+                    warn,
+                    self.conn_id,
+                    "attempting PRB release, however channel not listening."
+                );
             }
         }
         self.data_queue.entries.clear();
+        self.buf.clear();
+        self.send_buf.clear();
+        self.held.clear();
+        self.congestion = None;
 
         // TODO: close all backlog pcbs also
         // for pcb in self.backlog.pcb_ids.iter_mut() {}
@@ -255,6 +825,25 @@ impl TcpPcb {
 
 pub struct TcpPcbs {
     pub entries: Vec<TcpPcb>,
+    pub congestion_algorithm: CongestionAlgorithm, // default of new PCBs
+    pub ack_policy: AckPolicy,                     // default of new PCBs
+    pub recv_buf_size: usize,                      // default of new PCBs
+    pub send_buf_size: usize,                      // default of new PCBs
+    pub rto_max: Duration,                         // limit of retransmission interval backed off
+    pub syn_retries: u8,   // SYN retransmissions before active open times out
+    pub syn_rto: Duration, // initial SYN retransmission interval
+    pub backlog: usize,    // default backlog limit of listeners
+    pub syn_cookies: bool, // default of listeners
+    pub challenge_ack_limit: u32,
+    pub challenge_acks_limited: u64,         // not sent for the limit
+    challenge_acks_since: (SystemTime, u32), // start of the current second and ACKs sent in it
+    cookie_secret: u64,
+    next_conn_id: u64,
+    listeners: HashMap<(IPAdress, u16), usize>,
+    connections: HashMap<(IPAdress, u16, IPAdress, u16), usize>,
+    keys: Vec<Option<TcpPcbKey>>,     // of each PCB in the tables
+    timers: TimerQueue<usize>,        // next deadline of each PCB with timers running
+    timer_wakeup: Option<Sender<()>>, // thread sleeping till the earliest deadline
 }
 
 impl TcpPcbs {
@@ -263,51 +852,427 @@ impl TcpPcbs {
         for _ in 0..TCP_PCB_COUNT {
             entries.push(TcpPcb::new());
         }
-        TcpPcbs { entries }
+        TcpPcbs {
+            entries,
+            congestion_algorithm: CongestionAlgorithm::Reno,
+            ack_policy: AckPolicy::EveryOther,
+            recv_buf_size: PCB_BUF_LEN,
+            send_buf_size: TCP_SEND_BUF_LEN,
+            rto_max: Duration::from_secs(TCP_RTO_MAX_SECS),
+            syn_retries: TCP_SYN_RETRIES,
+            syn_rto: Duration::from_millis(TCP_SYN_RTO_MILLIS),
+            backlog: TCP_BACKLOG,
+            syn_cookies: false,
+            challenge_ack_limit: TCP_CHALLENGE_ACK_LIMIT,
+            challenge_acks_limited: 0,
+            challenge_acks_since: (UNIX_EPOCH, 0),
+            cookie_secret: rand::thread_rng().gen(),
+            next_conn_id: 1,
+            listeners: HashMap::new(),
+            connections: HashMap::new(),
+            keys: vec![None; TCP_PCB_COUNT],
+            timers: TimerQueue::new(),
+            timer_wakeup: None,
+        }
     }
 
-    pub fn new_entry(&mut self) -> Option<(usize, &mut TcpPcb)> {
-        for (i, pcb) in self.entries.iter_mut().enumerate() {
+    /// Lets a thread of its own drive timers instead of `L4Protocol::timer_tick`: it sleeps till
+    /// `next_deadline` and gets woken up through the sender when an earlier one is scheduled.
+    /// None gives timers back to ticks, disconnecting the thread.
+    pub fn set_timer_wakeup(&mut self, wakeup: Option<Sender<()>>) {
+        self.timer_wakeup = wakeup;
+    }
+
+    /// The earliest deadline of timers of all PCBs.
+    pub fn next_deadline(&mut self) -> Option<SystemTime> {
+        self.timers.next_deadline()
+    }
+
+    /// Schedules the next deadline of a PCB in the timer queue, or cancels it when the PCB has
+    /// no timer running. Called after the PCB is handled by input, timers or users.
+    fn arm(&mut self, pcb_id: usize) {
+        let Some(at) = self.entries[pcb_id].next_deadline() else {
+            self.timers.cancel(pcb_id);
+            return;
+        };
+        let earliest = self.timers.next_deadline();
+        self.timers.schedule(pcb_id, at);
+        if let Some(wakeup) = self.timer_wakeup.as_ref() {
+            if earliest.is_none_or(|earliest| at < earliest) {
+                let _ = wakeup.send(());
+            }
+        }
+    }
+
+    /// Takes one of the challenge ACKs allowed in the current second.
+    fn allow_challenge_ack(&mut self) -> bool {
+        let now = timer::now();
+        let (since, sent) = &mut self.challenge_acks_since;
+        if now.duration_since(*since).unwrap_or_default() >= Duration::from_secs(1) {
+            *since = now;
+            *sent = 0;
+        }
+        if *sent >= self.challenge_ack_limit {
+            self.challenge_acks_limited += 1;
+            return false;
+        }
+        *sent += 1;
+        true
+    }
+
+    /// Connections with their state, RTO and retransmission count.
+    pub fn stats(&self) -> String {
+        let mut text = String::from("tcp:\n");
+        if self.challenge_acks_limited > 0 {
+            text += &format!(
+                "  challenge ACKs rate limited: {}\n",
+                self.challenge_acks_limited
+            );
+        }
+        for (id, pcb) in self.entries.iter().enumerate() {
             if pcb.state == TcpPcbState::Free {
-                pcb.state = TcpPcbState::Closed;
-                return Some((i, pcb));
+                continue;
+            }
+            text += &format!(
+                "  {id:>3} conn={} {}:{} -> {}:{} {:?} rto: {:?} retransmits: {}\n",
+                pcb.conn_id,
+                ip_addr_to_str(pcb.local.address),
+                be_to_le_u16(pcb.local.port),
+                ip_addr_to_str(pcb.remote.address),
+                be_to_le_u16(pcb.remote.port),
+                pcb.state,
+                pcb.rtt.rto,
+                pcb.counters.retransmits
+            );
+            if let Some(backlog) = self.backlog_stats(id) {
+                text += &format!(
+                    "      backlog: {} half-open {} queued / {} dropped: {} no PCB: {}\n",
+                    backlog.half_open,
+                    backlog.queued,
+                    backlog.limit,
+                    backlog.dropped,
+                    backlog.no_pcb_drops
+                );
+                if self.entries[id].syn_cookies {
+                    text += &format!(
+                        "      syn cookies: {} sent {} accepted\n",
+                        backlog.cookies_sent, backlog.cookies_accepted
+                    );
+                }
             }
         }
-        None
+        text
+    }
+
+    /// Backlog of a listener. None for PCBs not in LISTEN state.
+    pub fn backlog_stats(&self, pcb_id: usize) -> Option<TcpBacklogStats> {
+        let pcb = self.entries.get(pcb_id)?;
+        if pcb.state != TcpPcbState::Listen {
+            return None;
+        }
+        Some(TcpBacklogStats {
+            half_open: self.half_open(pcb_id),
+            queued: pcb.backlog.pcb_ids.len(),
+            limit: pcb.backlog.limit,
+            dropped: pcb.backlog.dropped,
+            no_pcb_drops: pcb.backlog.no_pcb_drops,
+            cookies_sent: pcb.backlog.cookies_sent,
+            cookies_accepted: pcb.backlog.cookies_accepted,
+        })
+    }
+
+    fn half_open(&self, pcb_id: usize) -> usize {
+        self.entries
+            .iter()
+            .filter(|pcb| pcb.parent_id == Some(pcb_id) && pcb.state == TcpPcbState::SynReceived)
+            .count()
+    }
+
+    /// Counters of a connection with its current RTT estimate and RTO (`tcp stats`).
+    pub fn connection_stats(&self, pcb_id: usize) -> Option<TcpConnStats> {
+        let pcb = self.entries.get(pcb_id)?;
+        if pcb.state == TcpPcbState::Free {
+            return None;
+        }
+        Some(TcpConnStats {
+            srtt: pcb.rtt.srtt,
+            rto: pcb.rtt.rto,
+            ..pcb.counters
+        })
+    }
+
+    /// Connections with their counters (`conn stats`), or a connection of a PCB id.
+    pub fn connection_stats_table(&self, pcb_id: Option<usize>) -> String {
+        let mut text = format!(
+            "{:>3} {:>5} {:<11} {:>8} {:>10} {:>8} {:>10} {:>7} {:>6} {:>6} {:>6} {:>9} {:>9}\n",
+            "ID",
+            "Conn",
+            "State",
+            "Seg Out",
+            "Bytes Out",
+            "Seg In",
+            "Bytes In",
+            "Retrans",
+            "DupAck",
+            "ChAck",
+            "Trans",
+            "SRTT",
+            "RTO"
+        );
+        for id in 0..self.entries.len() {
+            if pcb_id.is_some_and(|pcb_id| pcb_id != id) {
+                continue;
+            }
+            let Some(stats) = self.connection_stats(id) else {
+                continue;
+            };
+            let pcb = &self.entries[id];
+            text += &format!(
+                "{id:>3} {:>5} {:<11} {:>8} {:>10} {:>8} {:>10} {:>7} {:>6} {:>6} {:>6} {:>9} {:>9}\n",
+                pcb.conn_id,
+                format!("{:?}", pcb.state),
+                stats.segments_sent,
+                stats.bytes_sent,
+                stats.segments_received,
+                stats.bytes_received,
+                stats.retransmits,
+                stats.dup_acks,
+                stats.challenge_acks,
+                stats.transitions,
+                stats
+                    .srtt
+                    .map_or(String::from("-"), |srtt| format!("{:?}", srtt)),
+                format!("{:?}", stats.rto)
+            );
+        }
+        text
+    }
+
+    /// Rows of `proto local foreign state` of connections (`conn show --netstat`).
+    pub fn netstat(&self) -> String {
+        let mut text = String::new();
+        for pcb in self.entries.iter() {
+            let state = match pcb.state {
+                TcpPcbState::Free => continue,
+                TcpPcbState::Closed => "CLOSE",
+                TcpPcbState::Listen => "LISTEN",
+                TcpPcbState::SynSent => "SYN_SENT",
+                TcpPcbState::SynReceived => "SYN_RECV",
+                TcpPcbState::Established => "ESTABLISHED",
+                TcpPcbState::FinWait1 => "FIN_WAIT1",
+                TcpPcbState::FinWait2 => "FIN_WAIT2",
+                TcpPcbState::Closing => "CLOSING",
+                TcpPcbState::TimeWait => "TIME_WAIT",
+                TcpPcbState::CloseWait => "CLOSE_WAIT",
+                TcpPcbState::LastAck => "LAST_ACK",
+            };
+            text += &format!(
+                "{:<5} {:<23} {:<23} {state}\n",
+                "tcp",
+                pcb.local.to_string(),
+                pcb.remote.to_string()
+            );
+        }
+        text
+    }
+
+    /// Connections with receive-side reassembly statistics (`conn show`).
+    pub fn show(&self) -> String {
+        let mut text = format!(
+            "{:>3} {:>5} {:<21} {:<21} {:<11} {:>6} {:>6} {:>8} {:>5} {:>7} {:>8}\n",
+            "ID",
+            "Conn",
+            "Local",
+            "Remote",
+            "State",
+            "OOO",
+            "Gap",
+            "Max Gap",
+            "Held",
+            "Dropped",
+            "Trimmed"
+        );
+        for (id, pcb) in self.entries.iter().enumerate() {
+            if pcb.state == TcpPcbState::Free {
+                continue;
+            }
+            let stats = &pcb.reassembly;
+            text += &format!(
+                "{id:>3} {:>5} {:<21} {:<21} {:<11} {:>6} {:>6} {:>8} {:>5} {:>7} {:>8}\n",
+                pcb.conn_id,
+                format!(
+                    "{}:{}",
+                    ip_addr_to_str(pcb.local.address),
+                    be_to_le_u16(pcb.local.port)
+                ),
+                format!(
+                    "{}:{}",
+                    ip_addr_to_str(pcb.remote.address),
+                    be_to_le_u16(pcb.remote.port)
+                ),
+                format!("{:?}", pcb.state),
+                stats.out_of_order,
+                stats.gap,
+                stats.max_gap,
+                stats.held,
+                stats.dropped,
+                stats.trimmed
+            );
+        }
+        text
+    }
+
+    /// Bytes held in receive and send buffers, and out of order, of all PCBs.
+    pub fn memory_used(&self) -> usize {
+        self.entries.iter().map(TcpPcb::memory_used).sum()
+    }
+
+    /// PCB IDs in use with bytes held by each.
+    pub fn memory_usage(&self) -> Vec<(usize, usize)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|(_, pcb)| pcb.state != TcpPcbState::Free)
+            .map(|(id, pcb)| (id, pcb.memory_used()))
+            .collect()
+    }
+
+    pub fn new_entry(&mut self) -> Option<(usize, &mut TcpPcb)> {
+        #[cfg(feature = "fault-injection")]
+        if fault::hit(Fault::PcbAlloc) {
+            return None;
+        }
+        let i = self
+            .entries
+            .iter()
+            .position(|pcb| pcb.state == TcpPcbState::Free)?;
+        self.unindex(i);
+        let pcb = &mut self.entries[i];
+        pcb.set_state(TcpPcbState::Closed);
+        pcb.conn_id = self.next_conn_id;
+        self.next_conn_id += 1;
+        pcb.error = None;
+        pcb.soft_error = None;
+        pcb.events = None;
+        pcb.congestion_algorithm = self.congestion_algorithm;
+        pcb.ack_policy = self.ack_policy;
+        pcb.syn_cookies = self.syn_cookies;
+        pcb.recv_buf_size = self.recv_buf_size;
+        pcb.send_buf_size = self.send_buf_size;
+        pcb.dup_acks = 0;
+        pcb.rtt = RttEstimator::new();
+        pcb.counters = TcpConnStats::default();
+        pcb.reassembly = TcpReassemblyStats::default();
+        pcb.sack_permitted = false;
+        pcb.peer_mss = 0;
+        pcb.delayed_ack = None;
+        pcb.persist_at = None;
+        pcb.no_delay = false;
+        pcb.coalesce = false;
+        pcb.coalesce_at = None;
+        pcb.push_pending = false;
+        pcb.urgent_policy = UrgentPolicy::Inline;
+        pcb.oob = None;
+        pcb.recv_context.urg_ptr = None;
+        pcb.fin_pending = false;
+        pcb.recv_shutdown = false;
+        pcb.wait_time = None;
+        pcb.parent_id = None;
+        pcb.backlog = TcpBacklog {
+            limit: self.backlog,
+            ..TcpBacklog::new()
+        };
+        pcb.local = IPEndpoint::new(IP_ADDR_ANY, 0);
+        pcb.remote = IPEndpoint::new(IP_ADDR_ANY, 0);
+        Some((i, pcb))
+    }
+
+    /// Adds a PCB to the lookup tables of `select` after its endpoints or state change:
+    /// listening PCBs by local endpoint and others by both endpoints.
+    fn index(&mut self, pcb_id: usize) {
+        self.unindex(pcb_id);
+        let pcb = &self.entries[pcb_id];
+        let (local, remote) = (&pcb.local, &pcb.remote);
+        let key = match pcb.state {
+            TcpPcbState::Free | TcpPcbState::Closed => return,
+            TcpPcbState::Listen => {
+                let key = (local.address, local.port);
+                self.listeners.insert(key, pcb_id);
+                TcpPcbKey::Listener(key.0, key.1)
+            }
+            _ => {
+                let key = (local.address, local.port, remote.address, remote.port);
+                self.connections.insert(key, pcb_id);
+                TcpPcbKey::Connection(key.0, key.1, key.2, key.3)
+            }
+        };
+        self.keys[pcb_id] = Some(key);
+    }
+
+    /// Removes the lookup entry of a PCB. Entries of released PCBs are kept till reuse, so one
+    /// using the same key since is left alone.
+    fn unindex(&mut self, pcb_id: usize) {
+        match self.keys[pcb_id].take() {
+            Some(TcpPcbKey::Listener(address, port)) => {
+                let key = (address, port);
+                if self.listeners.get(&key) == Some(&pcb_id) {
+                    self.listeners.remove(&key);
+                }
+            }
+            Some(TcpPcbKey::Connection(local, local_port, remote, remote_port)) => {
+                let key = (local, local_port, remote, remote_port);
+                if self.connections.get(&key) == Some(&pcb_id) {
+                    self.connections.remove(&key);
+                }
+            }
+            None => {}
+        }
     }
 
     pub fn get_mut_by_id(&mut self, pcb_id: usize) -> Option<&mut TcpPcb> {
         self.entries.get_mut(pcb_id)
     }
 
+    /// Looks up the PCB of a segment: the connection of both endpoints, or else a PCB listening
+    /// on the local one, bound to the address or ANY. Without remote endpoint, any PCB using the
+    /// local one is looked for, which scans all PCBs.
     pub fn select(
         &mut self,
         local: &IPEndpoint,
         remote_opt: Option<&IPEndpoint>,
     ) -> Option<(usize, &mut TcpPcb)> {
-        let mut listen_pcb = None;
-        for (i, pcb) in self.entries.iter_mut().enumerate() {
-            if (pcb.local.address == IP_ADDR_ANY || pcb.local.address == local.address)
-                && pcb.local.port == local.port
-            {
-                // Bindable check for local address only
-                if remote_opt.is_none() {
-                    return Some((i, pcb));
-                }
-                let remote = remote_opt.unwrap();
-                // Both remote address and port match
-                if pcb.remote.address == remote.address {
-                    return Some((i, pcb));
-                }
-                // Listen without specifying remote address
-                if pcb.state == TcpPcbState::Listen {
-                    if pcb.remote.address == IP_ADDR_ANY && pcb.remote.port == 0 {
-                        listen_pcb = Some((i, pcb));
-                    }
-                }
+        let remote = match remote_opt {
+            Some(remote) => remote,
+            None => {
+                return self.entries.iter_mut().enumerate().find(|(_, pcb)| {
+                    pcb.state != TcpPcbState::Free
+                        && (pcb.local.address == IP_ADDR_ANY || pcb.local.address == local.address)
+                        && pcb.local.port == local.port
+                })
             }
-        }
-        listen_pcb
+        };
+        // Entries of released PCBs are left till reuse.
+        let live = |pcb_id: &&usize| self.entries[**pcb_id].state != TcpPcbState::Free;
+        let pcb_id = [local.address, IP_ADDR_ANY]
+            .iter()
+            .filter_map(|&address| {
+                self.connections
+                    .get(&(address, local.port, remote.address, remote.port))
+            })
+            .find(live)
+            .or_else(|| {
+                [local.address, IP_ADDR_ANY]
+                    .iter()
+                    .filter_map(|&address| self.listeners.get(&(address, local.port)))
+                    .filter(live)
+                    .find(|&&pcb_id| {
+                        // Listening for a specific remote address
+                        let listener = &self.entries[pcb_id].remote;
+                        listener.address == IP_ADDR_ANY || listener.address == remote.address
+                    })
+            })
+            .copied()?;
+        Some((pcb_id, &mut self.entries[pcb_id]))
     }
 
     pub fn close_sockets(&mut self) {
@@ -322,71 +1287,232 @@ fn pcb_by_id(pcbs: &mut TcpPcbs, pcb_id: usize) -> &mut TcpPcb {
         .expect("TCP: PCB with specified id was not found.")
 }
 
+/// Starts or restarts the TIME-WAIT timeout.
 fn set_wait_time(pcb: &mut TcpPcb) {
-    let addition = Duration::from_secs(TCP_TIMEWAIT_SEC);
-    if pcb.wait_time.is_none() {
-        pcb.wait_time = SystemTime::now().checked_add(addition);
-    } else {
-        pcb.wait_time.unwrap().checked_add(addition);
-    }
+    pcb.wait_time = timer::now().checked_add(Duration::from_secs(TCP_TIMEWAIT_SEC));
 }
 
+/// Retransmits segments on timeout doubling the interval of each up to `rto_max`. Active
+/// open times out when `syn_retries` SYN retransmissions go unanswered. Only PCBs with a
+/// deadline due in the timer queue are visited, and their next deadline is scheduled after.
 pub fn retransmit(pcbs: &mut TcpPcbs, device: &mut NetDevice, contexts: &mut ProtocolContexts) {
-    for pcb in pcbs.entries.iter_mut() {
-        if pcb.state == TcpPcbState::Free {
-            continue;
-        }
-        if pcb.state == TcpPcbState::TimeWait {
-            if pcb.wait_time.unwrap().elapsed().unwrap().as_micros() > 0 {
-                info!(
-                    "TCP: timewait has elapsed for local = {:?} remote = {:?}",
-                    ip_addr_to_str(pcb.local.address),
-                    ip_addr_to_str(pcb.remote.address)
-                );
-                pcb.release();
-                continue;
-            }
+    let rto_max = pcbs.rto_max;
+    let syn_retries = pcbs.syn_retries;
+    for pcb_id in pcbs.timers.expired(timer::now()) {
+        let pcb = &mut pcbs.entries[pcb_id];
+        if pcb.state != TcpPcbState::Free {
+            on_timer(pcb, rto_max, syn_retries, device, contexts);
         }
-        while let Some(queue) = pcb.data_queue.entries.pop_front() {
-            if queue.first_sent_at.elapsed().unwrap().as_secs() >= TCP_RETRANSMIT_TIMOUT_SEC {
-                pcb.release();
-                continue;
-            }
-            let timeout = queue
-                .last_sent_at
-                .checked_add(queue.retry_interval)
-                .unwrap();
-            if timeout.elapsed().is_err() {
-                // elapsed errors when time is before now
-                info!("TCP: retransmitting a segment...");
-                output_segment(
-                    queue.seq_num,
-                    pcb.recv_context.next,
-                    queue.flags,
-                    pcb.recv_context.window,
-                    queue.data.clone(), // TODO: fix clone
-                    &pcb.local,
-                    &pcb.remote,
-                    device,
-                    contexts,
-                );
+        pcbs.arm(pcb_id);
+    }
+}
+
+/// Same as retransmit on a stack of several devices, sending segments of each connection out
+/// of the device routed to for its remote address.
+pub fn retransmit_on_devices(
+    pcbs: &mut TcpPcbs,
+    devices: &mut NetDevices,
+    contexts: &mut ProtocolContexts,
+) {
+    let rto_max = pcbs.rto_max;
+    let syn_retries = pcbs.syn_retries;
+    for pcb_id in pcbs.timers.expired(timer::now()) {
+        let pcb = &mut pcbs.entries[pcb_id];
+        if pcb.state != TcpPcbState::Free {
+            if let Some(device) = devices.get_mut_by_dst(pcb.remote.address, &contexts.ip_routes) {
+                on_timer(pcb, rto_max, syn_retries, device, contexts);
             }
         }
+        pcbs.arm(pcb_id);
     }
 }
 
-pub fn output_segment(
-    seq_num: u32,
-    ack_num: u32,
-    flags: u8,
-    window: u16,
-    mut tcp_data: Vec<u8>,
-    local: &IPEndpoint,
-    remote: &IPEndpoint,
-    device: &mut NetDevice,
-    contexts: &mut ProtocolContexts,
-) -> usize {
-    let tcp_hdr_size = size_of::<TcpHeader>();
+/// Device segments of a connection go out of: the one routed to for its remote address.
+pub fn pcb_device<'a>(
+    pcb_id: usize,
+    devices: &'a mut NetDevices,
+    contexts: &ProtocolContexts,
+    pcbs: &ControlBlocks,
+) -> Option<&'a mut NetDevice> {
+    let remote = remote_endpoint(pcb_id, pcbs).map_or(IP_ADDR_ANY, |remote| remote.address);
+    devices.get_mut_by_dst(remote, &contexts.ip_routes)
+}
+
+/// Handles timers of a PCB due: TIME-WAIT expiry, delayed ACK, retransmissions and their
+/// timeout, and zero window probes.
+fn on_timer(
+    pcb: &mut TcpPcb,
+    rto_max: Duration,
+    syn_retries: u8,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+) {
+    if pcb.state == TcpPcbState::TimeWait {
+        if pcb.wait_time.is_none_or(timer::is_due) {
+            conn_log!(
+                info,
+                pcb.conn_id,
+                "timewait has elapsed for local = {:?} remote = {:?}",
+                ip_addr_to_str(pcb.local.address),
+                ip_addr_to_str(pcb.remote.address)
+            );
+            pcb.release();
+            return;
+        }
+    }
+    if let Some(arrived_at) = pcb.delayed_ack {
+        if timer::since(arrived_at) >= Duration::from_millis(TCP_DELAYED_ACK_MILLIS) {
+            conn_log!(
+                debug,
+                pcb.conn_id,
+                "delayed ACK timer expired. Sending ACK..."
+            );
+            output(pcb, TcpFlag::ACK as u8, vec![], device, contexts);
+        }
+    }
+    if pcb.coalesce_at.is_some_and(timer::is_due) {
+        conn_log!(
+            debug,
+            pcb.conn_id,
+            "coalescing timed out. Sending data held..."
+        );
+        flush(pcb, device, contexts);
+    }
+    if pcb.state == TcpPcbState::SynSent && pcb.counters.retransmits >= syn_retries as u64 {
+        let timed_out = pcb.data_queue.entries.front().is_none_or(|syn| {
+            timer::is_due(syn.last_sent_at.checked_add(syn.retry_interval).unwrap())
+        });
+        if timed_out {
+            conn_log!(
+                info,
+                pcb.conn_id,
+                "no reply to {syn_retries} SYN retransmissions. Timing out..."
+            );
+            pcb.error = pcb.soft_error.take().or(Some(SocketError::TimedOut));
+            pcb.release();
+        }
+        return;
+    }
+    // Probes of zero window are retransmitted as long as the peer is there (RFC 1122 4.2.2.17).
+    let persisting = pcb.send_context.window == 0;
+    if let Some(oldest) = pcb.data_queue.entries.front() {
+        if pcb.state != TcpPcbState::SynSent
+            && !persisting
+            && timer::since(oldest.first_sent_at).as_secs() >= TCP_RETRANSMIT_TIMOUT_SEC
+        {
+            // A soft error received meanwhile is the likely cause of the timeout.
+            pcb.error = pcb.soft_error.take();
+            pcb.release();
+            return;
+        }
+    }
+    let mut timed_out = false;
+    let sack_blocks = pcb.sack_blocks();
+    for i in 0..pcb.data_queue.entries.len() {
+        let queue = &pcb.data_queue.entries[i];
+        if queue.sacked {
+            continue;
+        }
+        let timeout = queue
+            .last_sent_at
+            .checked_add(queue.retry_interval)
+            .unwrap();
+        if timer::is_due(timeout) {
+            conn_log!(info, pcb.conn_id, "retransmitting a segment...");
+            pcb.adv_window = pcb.recv_context.window;
+            let (seq_num, data) = pcb.queued_data(queue.seq_num, queue.len);
+            pcb.counters.retransmits += 1;
+            pcb.counters.segments_sent += 1;
+            pcb.counters.bytes_sent += data.len() as u64;
+            output_segment_with_options(
+                seq_num,
+                pcb.recv_context.next,
+                queue.flags,
+                pcb.recv_context.window,
+                &segment_options(queue.flags, pcb.sack_permitted, &sack_blocks, device),
+                data,
+                &pcb.local,
+                &pcb.remote,
+                device,
+                contexts,
+            );
+            let queue = &mut pcb.data_queue.entries[i];
+            queue.last_sent_at = timer::now();
+            queue.retry_interval = cmp::min(queue.retry_interval * 2, rto_max);
+            timed_out = true;
+        }
+    }
+    if timed_out && !persisting {
+        let in_flight = pcb.send_context.next.wrapping_sub(pcb.send_context.una);
+        if let Some(congestion) = pcb.congestion.as_mut() {
+            congestion.on_rto(in_flight);
+        }
+    }
+    if persisting && pcb.data_queue.entries.is_empty() && pcb.unsent_len() > 0 {
+        match pcb.persist_at {
+            None => pcb.persist_at = timer::now().checked_add(pcb.rtt.rto),
+            Some(at) if timer::is_due(at) => {
+                // A byte of data queued for retransmission probes the window with backoff.
+                conn_log!(info, pcb.conn_id, "zero window. Probing with a byte...");
+                let offset = pcb.in_flight_len();
+                let data = pcb.send_buf[offset..offset + 1].to_vec();
+                output(
+                    pcb,
+                    TcpFlag::ACK as u8 | TcpFlag::PSH as u8,
+                    data,
+                    device,
+                    contexts,
+                );
+                pcb.send_context.next = pcb.send_context.next.wrapping_add(1);
+                pcb.persist_at = None;
+            }
+            Some(_) => {}
+        }
+    } else {
+        pcb.persist_at = None;
+    }
+}
+
+pub fn output_segment(
+    seq_num: u32,
+    ack_num: u32,
+    flags: u8,
+    window: u16,
+    tcp_data: Vec<u8>,
+    local: &IPEndpoint,
+    remote: &IPEndpoint,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+) -> usize {
+    output_segment_with_options(
+        seq_num,
+        ack_num,
+        flags,
+        window,
+        &[],
+        tcp_data,
+        local,
+        remote,
+        device,
+        contexts,
+    )
+}
+
+/// Same as output_segment with options (padded to 4 bytes) following the header.
+#[allow(clippy::too_many_arguments)]
+pub fn output_segment_with_options(
+    seq_num: u32,
+    ack_num: u32,
+    flags: u8,
+    window: u16,
+    options: &[u8],
+    mut tcp_data: Vec<u8>,
+    local: &IPEndpoint,
+    remote: &IPEndpoint,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+) -> usize {
+    let tcp_hdr_size = size_of::<TcpHeader>() + options.len();
     let tcp_data_len = tcp_data.len();
     let total_len = tcp_data_len + tcp_hdr_size;
     let tcp_header = TcpHeader {
@@ -412,6 +1538,7 @@ pub fn output_segment(
 
     let tcp_hdr_bytes = unsafe { to_u8_slice::<TcpHeader>(&tcp_header) };
     let mut data = tcp_hdr_bytes.to_vec();
+    data.extend_from_slice(options);
     data.append(&mut tcp_data);
     // Update checksum
     let sum = cksum16(&data, total_len, !pseudo_sum as u32);
@@ -444,13 +1571,20 @@ pub fn output(
     if (tcp_flag_exists(flags, TcpFlag::SYN) || tcp_flag_exists(flags, TcpFlag::FIN))
         || data.len() > 0
     {
-        pcb.add_data_queue(seq_num, flags, data.clone()); // TODO: fix clone
+        pcb.add_data_queue(seq_num, flags, data.len());
     }
-    output_segment(
+    // Any segment acknowledges data received so far.
+    pcb.delayed_ack = None;
+    pcb.adv_window = pcb.recv_context.window;
+    pcb.counters.segments_sent += 1;
+    pcb.counters.bytes_sent += data.len() as u64;
+    let options = segment_options(flags, pcb.sack_permitted, &pcb.sack_blocks(), device);
+    output_segment_with_options(
         seq_num,
         pcb.recv_context.next,
         flags,
         pcb.recv_context.window,
+        &options,
         data,
         &pcb.local,
         &pcb.remote,
@@ -459,6 +1593,231 @@ pub fn output(
     )
 }
 
+/// Sends SYN of active open, retransmitted first after `syn_rto` instead of the RTO.
+fn output_syn(
+    pcb: &mut TcpPcb,
+    syn_rto: Duration,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+) -> usize {
+    let len = output(pcb, TcpFlag::SYN as u8, vec![], device, contexts);
+    if let Some(syn) = pcb.data_queue.entries.back_mut() {
+        syn.retry_interval = syn_rto;
+    }
+    len
+}
+
+/// Options of an outgoing segment: MSS of the device MTU and SACK-permitted on SYN, the latter
+/// offered on active open and answered when the peer offered it, or SACK blocks of data held out
+/// of order.
+fn segment_options(
+    flags: u8,
+    sack_permitted: bool,
+    sack_blocks: &[(u32, u32)],
+    device: &NetDevice,
+) -> Vec<u8> {
+    if tcp_flag_exists(flags, TcpFlag::SYN) {
+        let mss = device_mss(device).min(u16::MAX as usize) as u16;
+        let mut options = tcp_options::mss(mss);
+        if !tcp_flag_exists(flags, TcpFlag::ACK) || sack_permitted {
+            options.extend(tcp_options::sack_permitted());
+        }
+        return options;
+    }
+    if sack_permitted {
+        return tcp_options::sack(sack_blocks);
+    }
+    vec![]
+}
+
+/// Largest segment fitting in the MTU of a device with minimum IP and TCP headers.
+fn device_mss(device: &NetDevice) -> usize {
+    device.mtu - (IP_HEADER_MIN_SIZE + size_of::<TcpHeader>())
+}
+
+fn syn_cookie_period(now: SystemTime) -> u64 {
+    now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / TCP_SYN_COOKIE_PERIOD_SECS
+}
+
+/// Index of the MSS table entry a cookie carries for the MSS option of a SYN, if any.
+fn syn_cookie_mss_index(mss: Option<u16>) -> usize {
+    let mss = mss.unwrap_or(TCP_SYN_COOKIE_MSS[0]);
+    TCP_SYN_COOKIE_MSS
+        .iter()
+        .rposition(|&entry| entry <= mss)
+        .unwrap_or(0)
+}
+
+/// ISS of a SYN-ACK sent without a PCB: 5 bits of the period, the SACK-permitted option of the
+/// SYN, 2 bits of the MSS table index of its MSS option and 24 bits of a keyed hash of the
+/// endpoints, the peer's ISS and the others.
+fn syn_cookie(
+    secret: u64,
+    local: &IPEndpoint,
+    remote: &IPEndpoint,
+    irs: u32,
+    period: u64,
+    sack_permitted: bool,
+    mss_index: usize,
+) -> u32 {
+    let mut hasher = DefaultHasher::new();
+    (
+        secret,
+        local.address,
+        local.port,
+        remote.address,
+        remote.port,
+        irs,
+        period,
+        sack_permitted,
+        mss_index,
+    )
+        .hash(&mut hasher);
+    ((period as u32 & 0x1f) << 27)
+        | ((sack_permitted as u32) << 26)
+        | ((mss_index as u32 & 0x3) << 24)
+        | (hasher.finish() as u32 & 0xff_ffff)
+}
+
+/// Checks a cookie acknowledged by the ACK of a handshake, made in this period or the previous
+/// one. Returns the SACK-permitted option and the MSS of the SYN.
+fn check_syn_cookie(
+    secret: u64,
+    local: &IPEndpoint,
+    remote: &IPEndpoint,
+    irs: u32,
+    cookie: u32,
+    now: SystemTime,
+) -> Option<(bool, u16)> {
+    let period = syn_cookie_period(now);
+    let sack_permitted = cookie & (1 << 26) != 0;
+    let mss_index = ((cookie >> 24) & 0x3) as usize;
+    [period, period.saturating_sub(1)]
+        .into_iter()
+        .find(|&period| {
+            syn_cookie(
+                secret,
+                local,
+                remote,
+                irs,
+                period,
+                sack_permitted,
+                mss_index,
+            ) == cookie
+        })
+        .map(|_| (sack_permitted, TCP_SYN_COOKIE_MSS[mss_index]))
+}
+
+/// Answers SYN to a listener with a SYN-ACK of a cookie as ISS, leaving no state behind.
+fn output_syn_cookie(
+    seg: &TcpSegmentInfo,
+    local: &IPEndpoint,
+    remote: &IPEndpoint,
+    pcb_id: usize,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+    pcbs: &mut ControlBlocks,
+) {
+    let secret = pcbs.tcp_pcbs.cookie_secret;
+    let period = syn_cookie_period(timer::now());
+    let cookie = syn_cookie(
+        secret,
+        local,
+        remote,
+        seg.seq_num,
+        period,
+        seg.sack_permitted,
+        syn_cookie_mss_index(seg.mss),
+    );
+    let listener = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+    listener.backlog.cookies_sent += 1;
+    let flags = TcpFlag::SYN as u8 | TcpFlag::ACK as u8;
+    output_segment_with_options(
+        cookie,
+        seg.seq_num.wrapping_add(1),
+        flags,
+        listener.recv_buf_size as u16,
+        &segment_options(flags, seg.sack_permitted, &[], device),
+        vec![],
+        local,
+        remote,
+        device,
+        contexts,
+    );
+}
+
+/// Creates the connection of an ACK acknowledging a valid cookie in SYN-RECEIVED state, which
+/// the ACK then establishes. The ACK is dropped without room in the backlog or a free PCB.
+#[allow(clippy::too_many_arguments)]
+fn accept_syn_cookie(
+    seg: TcpSegmentInfo,
+    sack_permitted: bool,
+    peer_mss: u16,
+    flags: u8,
+    data: &[u8],
+    len: usize,
+    local: IPEndpoint,
+    remote: IPEndpoint,
+    pcb_id: usize,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+    pcbs: &mut ControlBlocks,
+) {
+    let (irs, iss) = (seg.seq_num.wrapping_sub(1), seg.ack_num.wrapping_sub(1));
+    let conn_id = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id).conn_id;
+    if !pcbs.memory_admits(1) {
+        conn_log!(
+            warn,
+            conn_id,
+            "memory limit reached. Dropping ACK of SYN cookie..."
+        );
+        return;
+    }
+    let listener = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+    // Half-open connections take no room with cookies.
+    if listener.backlog.pcb_ids.len() >= listener.backlog.limit {
+        listener.backlog.dropped += 1;
+        conn_log!(info, conn_id, "backlog full. Dropping ACK of SYN cookie...");
+        return;
+    }
+    let (recv_buf_size, send_buf_size) = (listener.recv_buf_size, listener.send_buf_size);
+    let Some((new_pcb_id, pcb)) = pcbs.tcp_pcbs.new_entry() else {
+        pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id).backlog.no_pcb_drops += 1;
+        conn_log!(warn, conn_id, "no free PCB. Dropping ACK of SYN cookie...");
+        return;
+    };
+    pcb.mode = TcpPcbMode::Socket;
+    pcb.parent_id = Some(pcb_id);
+    pcb.recv_buf_size = recv_buf_size;
+    pcb.send_buf_size = send_buf_size;
+    pcb.local = IPEndpoint {
+        address: local.address,
+        port: local.port,
+    };
+    pcb.remote = IPEndpoint {
+        address: remote.address,
+        port: remote.port,
+    };
+    pcb.sack_permitted = sack_permitted;
+    pcb.peer_mss = peer_mss;
+    pcb.irs = irs;
+    pcb.recv_context.next = seg.seq_num;
+    pcb.open_recv_window();
+    pcb.iss = iss;
+    pcb.send_context.una = iss;
+    pcb.send_context.next = seg.ack_num;
+    pcb.send_context.window = seg.window;
+    pcb.send_context.wl1 = seg.seq_num;
+    pcb.send_context.wl2 = seg.ack_num;
+    pcb.set_state(TcpPcbState::SynReceived);
+    conn_log!(info, pcb.conn_id, "SYN cookie accepted.");
+    pcbs.tcp_pcbs.index(new_pcb_id);
+    pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id)
+        .backlog
+        .cookies_accepted += 1;
+    segment_arrives(seg, flags, data, len, local, remote, device, contexts, pcbs);
+}
+
 // rfc793 section 3.9
 fn segment_arrives(
     seg: TcpSegmentInfo,
@@ -474,6 +1833,7 @@ fn segment_arrives(
     let pcb_state;
     let pcb_id;
     let pcb_mode;
+    let conn_id;
 
     debug!("TCP: segment flag byte = {:#010b}", flags);
 
@@ -504,7 +1864,7 @@ fn segment_arrives(
                 info!("TCP: non-ACK received. Replying RST-ACK...");
                 output_segment(
                     0,
-                    seg.seq_num + (seg.len as u32),
+                    seg.seq_num.wrapping_add(seg.len as u32),
                     TcpFlag::RST as u8 | TcpFlag::ACK as u8,
                     0,
                     vec![],
@@ -517,23 +1877,52 @@ fn segment_arrives(
             return;
         }
         let (id, pcb) = pcb_opt.unwrap();
+        pcb.counters.segments_received += 1;
+        pcb.counters.bytes_received += len as u64;
         pcb_state = pcb.state;
         pcb_id = id;
         pcb_mode = pcb.mode;
+        conn_id = pcb.conn_id;
     }
 
     let mut acceptable = false;
 
     // Listen state
     if pcb_state == TcpPcbState::Listen {
-        info!("TCP: connection in LISTEN state.");
+        conn_log!(info, conn_id, "connection in LISTEN state.");
         // Check for reset first.
         if tcp_flag_exists(flags, TcpFlag::RST) {
             return;
         }
+        let syn_cookies =
+            pcb_mode == TcpPcbMode::Socket && pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id).syn_cookies;
         // Secondly check for ack.
         if tcp_flag_exists(flags, TcpFlag::ACK) {
-            info!("TCP: ACK found. Replying with RST...");
+            if syn_cookies && !tcp_flag_exists(flags, TcpFlag::SYN) {
+                let secret = pcbs.tcp_pcbs.cookie_secret;
+                let (irs, cookie) = (seg.seq_num.wrapping_sub(1), seg.ack_num.wrapping_sub(1));
+                let now = timer::now();
+                if let Some((sack_permitted, peer_mss)) =
+                    check_syn_cookie(secret, &local, &remote, irs, cookie, now)
+                {
+                    accept_syn_cookie(
+                        seg,
+                        sack_permitted,
+                        peer_mss,
+                        flags,
+                        data,
+                        len,
+                        local,
+                        remote,
+                        pcb_id,
+                        device,
+                        contexts,
+                        pcbs,
+                    );
+                    return;
+                }
+            }
+            conn_log!(info, conn_id, "ACK found. Replying with RST...");
             output_segment(
                 seg.ack_num,
                 0,
@@ -549,28 +1938,52 @@ fn segment_arrives(
         }
         // Third check on SYN
         if tcp_flag_exists(flags, TcpFlag::SYN) {
-            info!("TCP: SYN found.");
+            conn_log!(info, conn_id, "SYN found.");
             // Ignore: security / compartment / precedence checks
-            let pcb = {
+            if syn_cookies {
+                conn_log!(info, conn_id, "replying with SYN-ACK of SYN cookie...");
+                output_syn_cookie(&seg, &local, &remote, pcb_id, device, contexts, pcbs);
+                return;
+            }
+            let (new_pcb_id, pcb) = {
                 if pcb_mode == TcpPcbMode::Socket {
-                    let new_pcb = pcbs
-                        .tcp_pcbs
-                        .new_entry()
-                        .expect("TCP: failed to allocate new pcb.")
-                        .1;
+                    if !pcbs.memory_admits(1) {
+                        conn_log!(warn, conn_id, "memory limit reached. Dropping SYN...");
+                        return;
+                    }
+                    let half_open = pcbs.tcp_pcbs.half_open(pcb_id);
+                    let parent = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+                    // Dropped rather than reset so that the peer retries after accept makes room.
+                    if half_open + parent.backlog.pcb_ids.len() >= parent.backlog.limit {
+                        parent.backlog.dropped += 1;
+                        conn_log!(info, conn_id, "backlog full. Dropping SYN...");
+                        return;
+                    }
+                    let (recv_buf_size, send_buf_size) =
+                        (parent.recv_buf_size, parent.send_buf_size);
+                    let Some((new_pcb_id, new_pcb)) = pcbs.tcp_pcbs.new_entry() else {
+                        let parent = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+                        parent.backlog.no_pcb_drops += 1;
+                        conn_log!(warn, conn_id, "no free PCB. Dropping SYN...");
+                        return;
+                    };
                     new_pcb.mode = TcpPcbMode::Socket;
                     new_pcb.parent_id = Some(pcb_id);
-                    new_pcb
+                    new_pcb.recv_buf_size = recv_buf_size;
+                    new_pcb.send_buf_size = send_buf_size;
+                    (new_pcb_id, new_pcb)
                 } else {
-                    pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id)
+                    (pcb_id, pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id))
                 }
             };
             pcb.local = local;
             pcb.remote = remote;
-            pcb.recv_context.window = PCB_BUF_LEN as u16;
-            pcb.recv_context.next = seg.seq_num + 1;
+            pcb.sack_permitted = seg.sack_permitted;
+            pcb.peer_mss = seg.mss.unwrap_or(0);
+            pcb.open_recv_window();
+            pcb.recv_context.next = seg.seq_num.wrapping_add(1);
             pcb.iss = rand::thread_rng().gen_range(0..u32::MAX);
-            info!("TCP: replying with SYN-ACK...");
+            conn_log!(info, pcb.conn_id, "replying with SYN-ACK...");
             output(
                 pcb,
                 TcpFlag::SYN as u8 | TcpFlag::ACK as u8,
@@ -578,9 +1991,10 @@ fn segment_arrives(
                 device,
                 contexts,
             );
-            pcb.send_context.next = pcb.iss + 1;
+            pcb.send_context.next = pcb.iss.wrapping_add(1);
             pcb.send_context.una = pcb.iss;
-            pcb.state = TcpPcbState::SynReceived;
+            pcb.set_state(TcpPcbState::SynReceived);
+            pcbs.tcp_pcbs.index(new_pcb_id);
             // Any other incoming control or data with SYN will be processed in SYN-RECEIVED state.
             // But processing SYN or ACK should not be repeated.
             return;
@@ -588,12 +2002,16 @@ fn segment_arrives(
         // Fourth: other text or control
         return; // drop segment
     } else if pcb_state == TcpPcbState::SynSent {
-        info!("TCP: connection in SYN-SENT state.");
+        conn_log!(info, conn_id, "connection in SYN-SENT state.");
         let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
         // First: check ACK
         if tcp_flag_exists(flags, TcpFlag::ACK) {
-            if seg.ack_num <= pcb.iss || seg.ack_num > pcb.send_context.next {
-                info!("TCP: ACK found with glitches. Replying with RST...");
+            if seq_leq(seg.ack_num, pcb.iss) || seq_lt(pcb.send_context.next, seg.ack_num) {
+                conn_log!(
+                    info,
+                    conn_id,
+                    "ACK found with glitches. Replying with RST..."
+                );
                 output_segment(
                     seg.ack_num,
                     0,
@@ -607,14 +2025,16 @@ fn segment_arrives(
                 );
                 return;
             }
-            if pcb.send_context.una <= seg.ack_num && seg.ack_num <= pcb.send_context.next {
+            if seq_leq(pcb.send_context.una, seg.ack_num)
+                && seq_leq(seg.ack_num, pcb.send_context.next)
+            {
                 acceptable = true;
             }
         }
         // Second: check RST
         if tcp_flag_exists(flags, TcpFlag::RST) {
             if acceptable {
-                info!("TCP: RST found. Closing connection.");
+                conn_log!(info, conn_id, "RST found. Closing connection.");
                 pcb.release();
             }
             return;
@@ -622,31 +2042,40 @@ fn segment_arrives(
         // Third: check security and precedence (ignored)
         // Fourth: check SYN
         if tcp_flag_exists(flags, TcpFlag::SYN) {
-            info!("TCP: SYN found.");
-            pcb.recv_context.next = seg.seq_num + 1;
+            conn_log!(info, conn_id, "SYN found.");
+            pcb.recv_context.next = seg.seq_num.wrapping_add(1);
             pcb.irs = seg.seq_num;
+            pcb.sack_permitted = seg.sack_permitted;
+            pcb.peer_mss = seg.mss.unwrap_or(0);
             if acceptable {
-                pcb.send_context.una = seg.ack_num;
-                pcb.clean_data_queue();
+                pcb.acknowledge(seg.ack_num);
             }
-            if pcb.send_context.una > pcb.iss {
-                pcb.state = TcpPcbState::Established;
-                info!("TCP: send.una > iss = Established. Replying with ACK...");
+            if seq_lt(pcb.iss, pcb.send_context.una) {
+                pcb.set_state(TcpPcbState::Established);
+                conn_log!(
+                    info,
+                    conn_id,
+                    "send.una > iss = Established. Replying with ACK..."
+                );
                 output(pcb, TcpFlag::ACK as u8, vec![], device, contexts);
                 // RFC793 does not specify, but send window initialization reqiured
                 pcb.send_context.window = seg.window;
                 pcb.send_context.wl1 = seg.seq_num;
                 pcb.send_context.wl2 = seg.ack_num;
                 if pcb.sender.is_some() {
-                    info!("TCP: waking up sleeping PCB of open command...");
+                    conn_log!(info, conn_id, "waking up sleeping PCB of open command...");
                     if pcb.sender.as_ref().unwrap().send(true).is_err() {
-                        info!("TCP: PCB channel not listening.");
+                        conn_log!(info, conn_id, "PCB channel not listening.");
                     };
                 }
                 // Ignore: continue to sixth check on URG
             } else {
-                info!("TCP: send.una <= iss = Syn-Received. Replying with SYN-ACK...");
-                pcb.state = TcpPcbState::SynReceived;
+                conn_log!(
+                    info,
+                    conn_id,
+                    "send.una <= iss = Syn-Received. Replying with SYN-ACK..."
+                );
+                pcb.set_state(TcpPcbState::SynReceived);
                 output(
                     pcb,
                     TcpFlag::SYN as u8 | TcpFlag::ACK as u8,
@@ -662,8 +2091,10 @@ fn segment_arrives(
         return;
     }
 
-    info!(
-        "TCP: connection checked for LISTEN or SYN-SENT state. It is in {:?}",
+    conn_log!(
+        info,
+        conn_id,
+        "connection checked for LISTEN or SYN-SENT state. It is in {:?}",
         pcb_state
     );
 
@@ -678,9 +2109,12 @@ fn segment_arrives(
         || pcb_state == TcpPcbState::TimeWait
     {
         let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
-        info!(
-            "TCP: PCB recv.window = {:x} recv.next = {:x}",
-            pcb.recv_context.window, pcb.recv_context.next
+        conn_log!(
+            info,
+            conn_id,
+            "PCB recv.window = {:x} recv.next = {:x}",
+            pcb.recv_context.window,
+            pcb.recv_context.next
         );
         if seg.len < 1 {
             if pcb.recv_context.window < 1 {
@@ -688,9 +2122,11 @@ fn segment_arrives(
                     acceptable = true;
                 }
             } else {
-                if pcb.recv_context.next <= seg.seq_num
-                    && seg.seq_num < pcb.recv_context.next + pcb.recv_context.window as u32
-                {
+                let end = pcb
+                    .recv_context
+                    .next
+                    .wrapping_add(pcb.recv_context.window as u32);
+                if seq_between(pcb.recv_context.next, seg.seq_num, end) {
                     acceptable = true;
                 }
             }
@@ -698,20 +2134,27 @@ fn segment_arrives(
             if pcb.recv_context.window < 1 {
                 // not acceptable
             } else {
-                if (pcb.recv_context.next <= seg.seq_num
-                    && seg.seq_num < pcb.recv_context.next + pcb.recv_context.window as u32)
-                    || (pcb.recv_context.next <= seg.seq_num + seg.len as u32 - 1
-                        && seg.seq_num + seg.len as u32 - 1
-                            < pcb.recv_context.next + pcb.recv_context.window as u32)
+                let end = pcb
+                    .recv_context
+                    .next
+                    .wrapping_add(pcb.recv_context.window as u32);
+                let last = seg.seq_num.wrapping_add(seg.len as u32 - 1);
+                if seq_between(pcb.recv_context.next, seg.seq_num, end)
+                    || seq_between(pcb.recv_context.next, last, end)
                 {
                     acceptable = true;
                 }
             }
         }
         if !acceptable {
-            info!("TCP: seq not acceptable.");
             if tcp_flag_exists(flags, TcpFlag::RST) {
-                info!("TCP: RST found and sequence/window not acceptable. Replying with ACK...");
+                conn_log!(info, conn_id, "RST out of window. Dropping...");
+            } else {
+                conn_log!(info, conn_id, "seq not acceptable. Replying with ACK...");
+                // Retransmission of data received already
+                if len > 0 && seq_leq(seg.seq_num.wrapping_add(len as u32), pcb.recv_context.next) {
+                    pcb.reassembly.trimmed += len as u64;
+                }
                 output(pcb, TcpFlag::ACK as u8, vec![], device, contexts);
             }
             return;
@@ -724,10 +2167,27 @@ fn segment_arrives(
         // begins at RCV.NXT.  Segments with higher begining sequence
         // numbers may be held for later processing.
     }
-    // Second: check RST bit
+    // Second: check RST bit. Only RST exactly at recv.next resets the connection, others in
+    // the window get a challenge ACK so that blind guesses of the window fail (RFC 5961 3.2).
+    if tcp_flag_exists(flags, TcpFlag::RST) && acceptable {
+        let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+        if seg.seq_num != pcb.recv_context.next {
+            conn_log!(
+                info,
+                conn_id,
+                "RST in window but not at recv.next. Sending challenge ACK..."
+            );
+            challenge_ack(pcb_id, device, contexts, pcbs);
+            return;
+        }
+    }
     if pcb_state == TcpPcbState::SynReceived {
         if tcp_flag_exists(flags, TcpFlag::RST) {
-            info!("TCP: RST found for connection in SYN-RECEIVED state. Closing...");
+            conn_log!(
+                info,
+                conn_id,
+                "RST found for connection in SYN-RECEIVED state. Closing..."
+            );
             let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
             pcb.release();
             return;
@@ -738,7 +2198,7 @@ fn segment_arrives(
         || pcb_state == TcpPcbState::CloseWait
     {
         if tcp_flag_exists(flags, TcpFlag::RST) {
-            info!("TCP: connection reset.");
+            conn_log!(info, conn_id, "connection reset.");
             let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
             pcb.release();
             return;
@@ -747,55 +2207,75 @@ fn segment_arrives(
         || pcb_state == TcpPcbState::LastAck
         || pcb_state == TcpPcbState::TimeWait
     {
-        info!("TCP: connection in final state. Closing...");
-        let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
-        pcb.release();
-        return;
+        if tcp_flag_exists(flags, TcpFlag::RST) {
+            conn_log!(
+                info,
+                conn_id,
+                "RST found for connection in final state. Closing..."
+            );
+            let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+            pcb.release();
+            return;
+        }
     }
 
     // Third: security and precedence check (ignored)
 
-    // Fourth: check SYN bit
-    if pcb_state == TcpPcbState::SynReceived
-        || pcb_state == TcpPcbState::Established
+    // Fourth: check SYN bit. SYN of synchronized connections gets a challenge ACK instead of
+    // resetting them, whatever its sequence number (RFC 5961 4.2).
+    if pcb_state == TcpPcbState::SynReceived && tcp_flag_exists(flags, TcpFlag::SYN) {
+        conn_log!(info, conn_id, "SYN found. Connection reset.");
+        let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+        pcb.release();
+        return;
+    }
+    if (pcb_state == TcpPcbState::Established
         || pcb_state == TcpPcbState::FinWait1
         || pcb_state == TcpPcbState::FinWait2
         || pcb_state == TcpPcbState::CloseWait
         || pcb_state == TcpPcbState::Closing
         || pcb_state == TcpPcbState::LastAck
-        || pcb_state == TcpPcbState::TimeWait
+        || pcb_state == TcpPcbState::TimeWait)
+        && tcp_flag_exists(flags, TcpFlag::SYN)
     {
-        if tcp_flag_exists(flags, TcpFlag::SYN) {
-            info!("TCP: SYN found. Connection reset.");
-            let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
-            pcb.release();
-            return;
-        }
+        conn_log!(info, conn_id, "SYN found. Sending challenge ACK...");
+        challenge_ack(pcb_id, device, contexts, pcbs);
+        return;
     }
 
     // Fifth: check ACK
     if !tcp_flag_exists(flags, TcpFlag::ACK) {
         return; // drop segment
     }
-    info!("TCP: ACK found.");
+    conn_log!(info, conn_id, "ACK found.");
     if pcb_state == TcpPcbState::SynReceived {
-        info!("TCP: connection in SYN-RECEIVED state.");
+        conn_log!(info, conn_id, "connection in SYN-RECEIVED state.");
         let mut parent_id = None;
         {
             let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
-            if pcb.send_context.una <= seg.ack_num && seg.ack_num <= pcb.send_context.next {
-                info!("TCP: send.una <= seg.ack = ESTABLISHED. Waking up sleeping PCB...");
-                pcb.state = TcpPcbState::Established;
+            if seq_leq(pcb.send_context.una, seg.ack_num)
+                && seq_leq(seg.ack_num, pcb.send_context.next)
+            {
+                conn_log!(
+                    info,
+                    conn_id,
+                    "send.una <= seg.ack = ESTABLISHED. Waking up sleeping PCB..."
+                );
+                pcb.set_state(TcpPcbState::Established);
                 if pcb.sender.is_some() {
                     if pcb.sender.as_ref().unwrap().send(true).is_err() {
-                        warn!("TCP: PCB channel not listening.");
+                        conn_log!(warn, conn_id, "PCB channel not listening.");
                     }
                 }
                 if pcb.parent_id.is_some() {
                     parent_id = pcb.parent_id;
                 }
             } else {
-                info!("TCP: send.una > seg.ack = not ESTABLISHED. Replying with RST...");
+                conn_log!(
+                    info,
+                    conn_id,
+                    "send.una > seg.ack = not ESTABLISHED. Replying with RST..."
+                );
                 output_segment(
                     seg.ack_num,
                     0,
@@ -811,12 +2291,16 @@ fn segment_arrives(
             }
         }
         if parent_id.is_some() {
-            info!("TCP: parent PCB found. Waking up sleeping parent PCB...");
+            conn_log!(
+                info,
+                conn_id,
+                "parent PCB found. Waking up sleeping parent PCB..."
+            );
             let parent_pcb = pcb_by_id(&mut pcbs.tcp_pcbs, parent_id.unwrap());
             parent_pcb.add_backlog(pcb_id);
             if parent_pcb.sender.is_some() {
                 if parent_pcb.sender.as_ref().unwrap().send(true).is_err() {
-                    warn!("TCP: parent PCB channel not listening.");
+                    conn_log!(warn, conn_id, "parent PCB channel not listening.");
                 }
             }
         }
@@ -825,79 +2309,273 @@ fn segment_arrives(
         || pcb_state == TcpPcbState::FinWait2
         || pcb_state == TcpPcbState::CloseWait
         || pcb_state == TcpPcbState::Closing
+        || pcb_state == TcpPcbState::LastAck
     {
         let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+        if pcb.sack_permitted && !seg.sack_blocks.is_empty() {
+            pcb.mark_sacked(&seg.sack_blocks);
+        }
         // Received ack including unacked sequence number
-        if pcb.send_context.una < seg.ack_num && seg.ack_num <= pcb.send_context.next {
-            info!(
-                "TCP: received ack including unacked seq number. Updating send.una with seg.ack."
+        if seq_lt(pcb.send_context.una, seg.ack_num) && seq_leq(seg.ack_num, pcb.send_context.next)
+        {
+            conn_log!(
+                info,
+                conn_id,
+                "received ack including unacked seq number. Updating send.una with seg.ack."
             );
-            pcb.send_context.una = seg.ack_num;
-            pcb.clean_data_queue();
+            let acked = seg.ack_num.wrapping_sub(pcb.send_context.una);
+            pcb.acknowledge(seg.ack_num);
+            pcb.dup_acks = 0;
+            if let Some(congestion) = pcb.congestion.as_mut() {
+                congestion.on_ack(acked, timer::now());
+            }
 
             // Ignore: users should receive positive acknowledgments for buffers which have been SENT
             // and fully acknowledged (i.e., SEND buffer should be returned with "ok" response)
-            if pcb.send_context.wl1 < seg.seq_num
-                || (pcb.send_context.wl1 == seg.seq_num && pcb.send_context.wl2 <= seg.ack_num)
+            if seq_lt(pcb.send_context.wl1, seg.seq_num)
+                || (pcb.send_context.wl1 == seg.seq_num
+                    && seq_leq(pcb.send_context.wl2, seg.ack_num))
             {
                 pcb.send_context.window = seg.window;
                 pcb.send_context.wl1 = seg.seq_num;
                 pcb.send_context.wl2 = seg.ack_num;
             }
-        } else if seg.ack_num < pcb.send_context.una {
+            flush(pcb, device, contexts);
+        } else if seg.ack_num == pcb.send_context.una
+            && seg.window != pcb.send_context.window
+            && (seq_lt(pcb.send_context.wl1, seg.seq_num)
+                || (pcb.send_context.wl1 == seg.seq_num
+                    && seq_leq(pcb.send_context.wl2, seg.ack_num)))
+        {
+            // Window update, e.g. reopening zero window
+            conn_log!(
+                debug,
+                conn_id,
+                "window updated: {} -> {}",
+                pcb.send_context.window,
+                seg.window
+            );
+            pcb.send_context.window = seg.window;
+            pcb.send_context.wl1 = seg.seq_num;
+            pcb.send_context.wl2 = seg.ack_num;
+            flush(pcb, device, contexts);
+        } else if seg.ack_num == pcb.send_context.una
+            && len == 0
+            && seg.window == pcb.send_context.window
+            && pcb.send_context.una != pcb.send_context.next
+        {
+            pcb.dup_acks = pcb.dup_acks.saturating_add(1);
+            pcb.counters.dup_acks += 1;
+            if pcb.dup_acks == TCP_DUP_ACK_THRESHOLD {
+                conn_log!(
+                    info,
+                    conn_id,
+                    "duplicate ACKs received. Fast retransmitting..."
+                );
+                let in_flight = pcb.send_context.next.wrapping_sub(pcb.send_context.una);
+                if let Some(congestion) = pcb.congestion.as_mut() {
+                    congestion.on_loss(in_flight, timer::now());
+                }
+                pcb.adv_window = pcb.recv_context.window;
+                let unsacked = pcb
+                    .data_queue
+                    .entries
+                    .iter()
+                    .position(|entry| !entry.sacked);
+                if let Some(i) = unsacked {
+                    let queue = &pcb.data_queue.entries[i];
+                    let (seq_num, data) = pcb.queued_data(queue.seq_num, queue.len);
+                    pcb.counters.retransmits += 1;
+                    pcb.counters.segments_sent += 1;
+                    pcb.counters.bytes_sent += data.len() as u64;
+                    output_segment(
+                        seq_num,
+                        pcb.recv_context.next,
+                        queue.flags,
+                        pcb.recv_context.window,
+                        data,
+                        &pcb.local,
+                        &pcb.remote,
+                        device,
+                        contexts,
+                    );
+                    pcb.data_queue.entries[i].last_sent_at = timer::now();
+                }
+            } else if pcb.dup_acks > TCP_DUP_ACK_THRESHOLD {
+                if let Some(congestion) = pcb.congestion.as_mut() {
+                    congestion.on_dup_ack();
+                }
+                flush(pcb, device, contexts);
+            }
+        } else if seq_lt(seg.ack_num, pcb.send_context.una) {
             // Ignore: already checked ack
-        } else if seg.ack_num > pcb.send_context.next {
-            info!("TCP: seg.ack > send.next. Replying with ACK...");
+        } else if seq_lt(pcb.send_context.next, seg.ack_num) {
+            conn_log!(info, conn_id, "seg.ack > send.next. Replying with ACK...");
             output(pcb, TcpFlag::ACK as u8, vec![], device, contexts);
             return;
         }
+        if pcb_state == TcpPcbState::FinWait1
+            && !pcb.fin_pending
+            && seg.ack_num == pcb.send_context.next
+        {
+            conn_log!(
+                info,
+                conn_id,
+                "FIN acknowledged in FIN-WAIT1 state. Moving to FIN-WAIT2..."
+            );
+            pcb.set_state(TcpPcbState::FinWait2);
+        }
         if pcb_state == TcpPcbState::Closing {
             if seg.ack_num == pcb.send_context.next {
-                info!("TCP: connection in CLOSING state and seg.ack == send.next. Waking up PCB with wait time...");
-                pcb.state = TcpPcbState::TimeWait;
+                conn_log!(info, conn_id, "connection in CLOSING state and seg.ack == send.next. Waking up PCB with wait time...");
+                pcb.set_state(TcpPcbState::TimeWait);
                 set_wait_time(pcb);
                 if pcb.sender.is_some() {
                     if pcb.sender.as_ref().unwrap().send(true).is_err() {
-                        warn!("TCP: PCB channel not listening.");
+                        conn_log!(warn, conn_id, "PCB channel not listening.");
                     };
                 }
             }
         }
-    } else if pcb_state == TcpPcbState::LastAck {
-        info!("TCP: connection in LAST-ACK state.");
-        let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
-        if seg.ack_num == pcb.send_context.next {
-            pcb.release();
+        if pcb_state == TcpPcbState::LastAck {
+            // Data buffered before close is still acknowledged till FIN is.
+            if !pcb.fin_pending && seg.ack_num == pcb.send_context.next {
+                conn_log!(
+                    info,
+                    conn_id,
+                    "FIN acknowledged in LAST-ACK state. Releasing PCB..."
+                );
+                pcb.release();
+            }
+            return;
         }
-        return;
     } else if pcb_state == TcpPcbState::TimeWait {
         if tcp_flag_exists(flags, TcpFlag::FIN) {
-            info!("TCP: FIN found for connection in TIME-WAIT state. Extending wait time...");
+            conn_log!(
+                info,
+                conn_id,
+                "FIN found for connection in TIME-WAIT state. Extending wait time..."
+            );
             let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
             set_wait_time(pcb);
         }
     }
 
-    // Sixth: check URG (ignored)
+    // Sixth: check URG
+    if tcp_flag_exists(flags, TcpFlag::URG)
+        && (pcb_state == TcpPcbState::Established
+            || pcb_state == TcpPcbState::FinWait1
+            || pcb_state == TcpPcbState::FinWait2)
+    {
+        // RCV.UP <- max(RCV.UP, SEG.UP), signaling the user of urgent data ahead.
+        let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+        let urg_ptr = seg.seq_num.wrapping_add(seg.urg_ptr as u32);
+        let current = pcb.recv_context.urg_ptr.unwrap_or(pcb.recv_context.next);
+        if seq_lt(current, urg_ptr) {
+            conn_log!(info, conn_id, "urgent data ahead till {urg_ptr}.");
+            pcb.recv_context.urg_ptr = Some(urg_ptr);
+            pcb.notify(TcpEvent::Urgent);
+        }
+    }
 
     // Seventh: process segment text
+    let mut beyond_window = false;
     if pcb_state == TcpPcbState::Established
         || pcb_state == TcpPcbState::FinWait1
         || pcb_state == TcpPcbState::FinWait2
     {
+        if len > 0 && !pcbs.memory_admits(len) {
+            conn_log!(warn, conn_id, "memory limit exceeded. Dropping segment...");
+            return;
+        }
         let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
-        if len > 0 {
-            info!("TCP: received data. Updating window, replying with ACK and waking up PCB...");
-            // memcpy(pcb->buf + (sizeof(pcb->buf) - pcb->rcv.wnd), data, len);
-            pcb.buf.append(&mut data.to_vec());
-            pcb.recv_context.next = seg.seq_num + seg.len as u32;
-            pcb.recv_context.window -= len as u16;
+        if seg.len > 0 && seq_lt(pcb.recv_context.next, seg.seq_num) {
+            // Held till the gap gets filled. The duplicate ACK makes the peer retransmit the gap.
+            // FIN is left to be retransmitted, as it closes the stream after the gap only.
+            let gap = seg.seq_num.wrapping_sub(pcb.recv_context.next);
+            pcb.reassembly.out_of_order += 1;
+            if pcb.hold(seg.seq_num, &data[..len]) {
+                conn_log!(
+                    info,
+                    conn_id,
+                    "segment ahead of recv.next by {gap} bytes. Holding and replying with ACK..."
+                );
+            } else {
+                conn_log!(
+                    warn,
+                    conn_id,
+                    "segment ahead of recv.next by {gap} bytes exceeds held bytes limit. Dropping and replying with ACK..."
+                );
+            }
+            output(pcb, TcpFlag::ACK as u8, vec![], device, contexts);
+            return;
+        }
+        if len > 0 && pcb.recv_shutdown {
+            conn_log!(
+                debug,
+                conn_id,
+                "receiving shut down. Acknowledging and discarding {len} bytes..."
+            );
+            pcb.recv_context.next = seg.seq_num.wrapping_add(len as u32);
             output(pcb, TcpFlag::ACK as u8, vec![], device, contexts);
+        } else if len > 0 {
+            conn_log!(
+                info,
+                conn_id,
+                "received data. Updating window, acknowledging and waking up PCB..."
+            );
+            // Only data from recv.next fitting in the window is taken. Bytes before it were
+            // received already, e.g. of a retransmission overlapping the last segment. The rest
+            // is left to be retransmitted once the window opens, and so is FIN following it.
+            let (start, new) = pcb.trim_received(seg.seq_num, &data[..len]);
+            if new.len() < len {
+                conn_log!(
+                    debug,
+                    conn_id,
+                    "{} bytes received already trimmed.",
+                    len - new.len()
+                );
+            }
+            let accepted = cmp::min(new.len(), pcb.recv_context.window as usize);
+            beyond_window = accepted < new.len();
+            if beyond_window {
+                conn_log!(
+                    debug,
+                    conn_id,
+                    "{} bytes beyond recv.window dropped.",
+                    new.len() - accepted
+                );
+            }
+            pcb.append_data(start, &new[..accepted]);
+            pcb.recv_context.next = start.wrapping_add(accepted as u32);
+            pcb.update_recv_window();
+            let filled_gap = !pcb.held.is_empty();
+            if filled_gap {
+                let drained = pcb.drain_held();
+                conn_log!(
+                    debug,
+                    conn_id,
+                    "{drained} bytes held out of order delivered."
+                );
+            }
+            // ACK by the policy, but right away when a gap is filled (RFC 5681 4.2)
+            let ack_now = match pcb.ack_policy {
+                AckPolicy::EverySegment => true,
+                AckPolicy::EveryOther => pcb.delayed_ack.is_some(),
+                AckPolicy::Delayed => false,
+            };
+            if filled_gap || ack_now || beyond_window {
+                output(pcb, TcpFlag::ACK as u8, vec![], device, contexts);
+            } else if pcb.delayed_ack.is_none() {
+                pcb.delayed_ack = Some(timer::now());
+            }
             if pcb.sender.is_some() {
                 if pcb.sender.as_ref().unwrap().send(true).is_err() {
-                    warn!("TCP: PCB channel in receive not listening.");
+                    conn_log!(warn, conn_id, "PCB channel in receive not listening.");
                 };
             }
+            let buffered = pcb.buf.len();
+            pcb.notify(TcpEvent::DataReady(buffered));
         }
     } else if pcb_state == TcpPcbState::CloseWait
         || pcb_state == TcpPcbState::Closing
@@ -908,8 +2586,8 @@ fn segment_arrives(
     }
 
     // Eighth: check FIN
-    if tcp_flag_exists(flags, TcpFlag::FIN) {
-        info!("TCP: FIN flag found.");
+    if tcp_flag_exists(flags, TcpFlag::FIN) && !beyond_window {
+        conn_log!(info, conn_id, "FIN flag found.");
         let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
         if pcb_state == TcpPcbState::Closed
             || pcb_state == TcpPcbState::Listen
@@ -918,30 +2596,56 @@ fn segment_arrives(
             return; // drop segment
         }
 
-        info!("TCP: sending ACK...");
-        pcb.recv_context.next = seg.seq_num + 1;
+        conn_log!(info, conn_id, "sending ACK...");
+        pcb.recv_context.next = seg.seq_num.wrapping_add(seg.len as u32);
         output(pcb, TcpFlag::ACK as u8, vec![], device, contexts);
+        if pcb_state == TcpPcbState::SynReceived
+            || pcb_state == TcpPcbState::Established
+            || pcb_state == TcpPcbState::FinWait1
+            || pcb_state == TcpPcbState::FinWait2
+        {
+            pcb.notify(TcpEvent::PeerClosed);
+        }
 
         if pcb_state == TcpPcbState::SynReceived || pcb_state == TcpPcbState::Established {
-            info!("TCP: connection in SYN-RECEIVED / ESTABLISHED state. Moving to CLOSE-WAIT and waking up PCB...");
-            pcb.state = TcpPcbState::CloseWait;
+            conn_log!(info, conn_id, "connection in SYN-RECEIVED / ESTABLISHED state. Moving to CLOSE-WAIT and waking up PCB...");
+            pcb.set_state(TcpPcbState::CloseWait);
             if pcb.sender.is_some() {
                 if pcb.sender.as_ref().unwrap().send(true).is_err() {
-                    warn!("TCP: PCB channel not listening.");
+                    conn_log!(warn, conn_id, "PCB channel not listening.");
                 }
             }
         } else if pcb_state == TcpPcbState::FinWait1 {
-            if seg.ack_num == pcb.send_context.next {
-                info!("TCP: connection in FIN-WAIT1 state and seg.ack == send.next. Moving to TIME-WAIT and waking up PCB...");
-                pcb.state = TcpPcbState::TimeWait;
+            if !pcb.fin_pending && seg.ack_num == pcb.send_context.next {
+                conn_log!(info, conn_id, "connection in FIN-WAIT1 state and seg.ack == send.next. Moving to TIME-WAIT and waking up PCB...");
+                pcb.set_state(TcpPcbState::TimeWait);
                 set_wait_time(pcb);
+                if let Some(sender) = pcb.sender.as_ref() {
+                    if sender.send(true).is_err() {
+                        conn_log!(warn, conn_id, "PCB channel not listening.");
+                    }
+                }
             } else {
-                info!("TCP: connection in FIN-WAIT1 state and seg.ack != send.next. Moving to CLOSING...");
-                pcb.state = TcpPcbState::Closing;
+                conn_log!(
+                    info,
+                    conn_id,
+                    "connection in FIN-WAIT1 state and seg.ack != send.next. Moving to CLOSING..."
+                );
+                pcb.set_state(TcpPcbState::Closing);
             }
         } else if pcb_state == TcpPcbState::FinWait2 {
-            info!("TCP: connection in FIN-WAIT2 state. Moving to TIME-WAIT...");
-            pcb.state = TcpPcbState::TimeWait;
+            conn_log!(
+                info,
+                conn_id,
+                "connection in FIN-WAIT2 state. Moving to TIME-WAIT and waking up PCB..."
+            );
+            pcb.set_state(TcpPcbState::TimeWait);
+            set_wait_time(pcb);
+            if let Some(sender) = pcb.sender.as_ref() {
+                if sender.send(true).is_err() {
+                    conn_log!(warn, conn_id, "PCB channel not listening.");
+                }
+            }
         } else if pcb_state == TcpPcbState::CloseWait {
             // Remain in CLOSE-WAIT state.
         } else if pcb_state == TcpPcbState::Closing {
@@ -955,6 +2659,73 @@ fn segment_arrives(
     }
 }
 
+/// Sends a challenge ACK for a RST or SYN that may be spoofed. A peer that did lose the
+/// connection answers it with RST at the exact sequence number (RFC 5961). Limited per second
+/// for all connections so that segments forged in bulk do not turn into as many ACKs.
+fn challenge_ack(
+    pcb_id: usize,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+    pcbs: &mut ControlBlocks,
+) {
+    if !pcbs.tcp_pcbs.allow_challenge_ack() {
+        debug!("TCP: challenge ACK limit reached. Dropping segment...");
+        return;
+    }
+    let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+    pcb.counters.challenge_acks += 1;
+    output(pcb, TcpFlag::ACK as u8, vec![], device, contexts);
+}
+
+/// TCP registered in `l4::L4Protocols`, handling ICMP errors quoting segments of connections
+/// and retransmissions on timer ticks.
+pub struct TcpProtocol;
+
+impl L4Protocol for TcpProtocol {
+    fn number(&self) -> u8 {
+        IPProtocolType::Tcp as u8
+    }
+
+    fn name(&self) -> &str {
+        "tcp"
+    }
+
+    fn input(
+        &self,
+        data: &[u8],
+        len: usize,
+        src: IPAdress,
+        dst: IPAdress,
+        device: &mut NetDevice,
+        iface: &IPInterface,
+        contexts: &mut ProtocolContexts,
+        pcbs: &mut ControlBlocks,
+    ) -> Result<(), ()> {
+        input(data, len, src, dst, device, iface, contexts, pcbs)
+    }
+
+    fn icmp_error(&self, icmp_type: u8, code: u8, quoted: &[u8], pcbs: &mut ControlBlocks) -> bool {
+        match quoted_tcp_segment(quoted) {
+            Some((local, remote, seq)) => {
+                icmp_error(&local, &remote, seq, icmp_type, code, pcbs);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn timer_tick(
+        &self,
+        device: &mut NetDevice,
+        contexts: &mut ProtocolContexts,
+        pcbs: &mut ControlBlocks,
+    ) {
+        if pcbs.tcp_pcbs.timer_wakeup.is_none() {
+            retransmit(&mut pcbs.tcp_pcbs, device, contexts);
+        }
+    }
+}
+
 pub fn input(
     data: &[u8],
     len: usize,
@@ -1014,12 +2785,20 @@ pub fn input(
     if tcp_flag_exists(header.flags, TcpFlag::FIN) {
         seg_len += 1;
     }
+    if header_len < tcp_hdr_size || header_len > len {
+        error!("TCP input: invalid data offset: {header_len}");
+        return Err(());
+    }
+    let options = tcp_options::parse(&data[tcp_hdr_size..header_len]);
     let seg = TcpSegmentInfo {
         seq_num: be_to_le_u32(header.seq_num),
         ack_num: be_to_le_u32(header.ack_num),
         len: seg_len as u16,
         window: be_to_le_u16(header.window),
         urg_ptr: be_to_le_u16(header.urg_ptr),
+        mss: options.mss,
+        sack_permitted: options.sack_permitted,
+        sack_blocks: options.sack_blocks,
     };
 
     info!("TCP: received segment = {:?}", seg);
@@ -1027,7 +2806,7 @@ pub fn input(
     segment_arrives(
         seg,
         header.flags,
-        &data[tcp_hdr_size..],
+        &data[header_len..len],
         len - header_len,
         local,
         remote,
@@ -1036,6 +2815,18 @@ pub fn input(
         pcbs,
     );
 
+    // The segment may have started or stopped timers of the connection, or opened one.
+    let local = IPEndpoint {
+        address: dst,
+        port: header.dst_port,
+    };
+    let remote = IPEndpoint {
+        address: src,
+        port: header.src_port,
+    };
+    if let Some((pcb_id, _)) = pcbs.tcp_pcbs.select(&local, Some(&remote)) {
+        pcbs.tcp_pcbs.arm(pcb_id);
+    }
     Ok(())
 }
 
@@ -1048,19 +2839,22 @@ pub fn rfc793_open(
     pcbs_arc: Arc<Mutex<ControlBlocks>>,
     devices_arc: Arc<Mutex<NetDevices>>,
     contexts_arc: Arc<Mutex<ProtocolContexts>>,
-) -> Option<usize> {
+) -> Result<usize, SocketError> {
     let pcb_id;
+    let conn_id;
     let pcb_state;
     let initial_pcb_state;
     let (sender, receiver) = mpsc::channel();
     {
-        let pcbs = &mut pcbs_arc.lock().unwrap();
         let devices = &mut devices_arc.lock().unwrap();
         let contexts = &mut contexts_arc.lock().unwrap();
-        let eth_device = devices
-            .get_mut_by_type(crate::devices::NetDeviceType::Ethernet)
-            .unwrap();
+        let pcbs = &mut pcbs_arc.lock().unwrap();
+        let remote = remote_opt
+            .as_ref()
+            .map_or(IP_ADDR_ANY, |remote| remote.address);
+        let eth_device = devices.get_mut_by_dst(remote, &contexts.ip_routes).unwrap();
 
+        let syn_rto = pcbs.tcp_pcbs.syn_rto;
         let (new_pcb_id, pcb) = pcbs
             .tcp_pcbs
             .new_entry()
@@ -1074,31 +2868,38 @@ pub fn rfc793_open(
         }
 
         if !active {
-            info!(
-                "TCP: passive open with local IP = {:?} port = {:?}",
+            conn_log!(
+                info,
+                pcb.conn_id,
+                "passive open with local IP = {:?} port = {:?}",
                 ip_addr_to_str(pcb.local.address),
                 be_to_le_u16(pcb.local.port)
             );
-            pcb.state = TcpPcbState::Listen;
+            pcb.set_state(TcpPcbState::Listen);
         } else {
-            info!(
-                "TCP: active open with local = {:?} and remote = {:?}",
+            conn_log!(
+                info,
+                pcb.conn_id,
+                "active open with local = {:?} and remote = {:?}",
                 ip_addr_to_str(pcb.local.address),
                 ip_addr_to_str(pcb.remote.address)
             );
-            pcb.recv_context.window = PCB_BUF_LEN as u16;
+            pcb.open_recv_window();
             pcb.iss = rand::thread_rng().gen_range(0..u32::MAX);
 
-            output(pcb, TcpFlag::SYN as u8, vec![], eth_device, contexts);
+            output_syn(pcb, syn_rto, eth_device, contexts);
             // if res.is_err() {
-            //     pcb.state = TcpPcbState::Closed;
+            //     pcb.set_state(TcpPcbState::Closed);
             // }
             pcb.send_context.una = pcb.iss;
-            pcb.send_context.next = pcb.iss + 1;
-            pcb.state = TcpPcbState::SynSent;
+            pcb.send_context.next = pcb.iss.wrapping_add(1);
+            pcb.set_state(TcpPcbState::SynSent);
         }
+        conn_id = pcb.conn_id;
         pcb_state = pcb.state;
         initial_pcb_state = pcb.state;
+        pcbs.tcp_pcbs.arm(pcb_id);
+        pcbs.tcp_pcbs.index(pcb_id);
     }
     while pcb_state == initial_pcb_state {
         let proceed = receiver.recv().unwrap();
@@ -1110,12 +2911,13 @@ pub fn rfc793_open(
             }
             if !proceed || pcb.state != TcpPcbState::SynReceived {
                 pcb.release();
-                return None;
+                // RST in reply to SYN unless an error is recorded
+                return Err(pcb.error.unwrap_or(SocketError::ConnectionRefused));
             }
         }
     }
-    info!("TCP rfc793_open: connection established.");
-    Some(pcb_id)
+    conn_log!(info, conn_id, "rfc793_open: connection established.");
+    Ok(pcb_id)
 }
 
 // User commands (Socket)
@@ -1129,21 +2931,20 @@ pub fn open(pcbs: &mut ControlBlocks) -> usize {
     pcb_id
 }
 
-pub fn connect(
+/// Sends SYN of an active open without waiting for the handshake, whose progress can be seen
+/// by `subscribe`. For callers driving the stack by themselves like `sim::SimStack`.
+pub fn start_connect(
     pcb_id: usize,
     remote: &IPEndpoint,
     device: &mut NetDevice,
     contexts: &mut ProtocolContexts,
-    pcbs_arc: &mut Arc<Mutex<ControlBlocks>>,
-) -> Option<usize> {
-    let mut local = {
-        let pcbs = &mut pcbs_arc.lock().unwrap();
-        let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
-        if pcb.mode != TcpPcbMode::Socket {
-            panic!("TCP: pcb is not opened as socket mode.");
-        }
-        IPEndpoint::new(pcb.local.address, pcb.local.port)
-    };
+    pcbs: &mut ControlBlocks,
+) {
+    let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+    if pcb.mode != TcpPcbMode::Socket {
+        panic!("TCP: pcb is not opened as socket mode.");
+    }
+    let mut local = IPEndpoint::new(pcb.local.address, pcb.local.port);
     if local.address == IP_ADDR_ANY {
         let interface = contexts
             .ip_routes
@@ -1152,7 +2953,6 @@ pub fn connect(
         local.address = interface.unicast;
     }
     if local.port == 0 {
-        let pcbs = &mut pcbs_arc.lock().unwrap();
         for port in TCP_SRC_PORT_MIN..TCP_SRC_PORT_MAX {
             local.port = port;
             if pcbs.tcp_pcbs.select(&local, Some(remote)).is_none() {
@@ -1163,22 +2963,35 @@ pub fn connect(
             panic!("TCP: dynamic port assignment failed.");
         }
     }
+    let syn_rto = pcbs.tcp_pcbs.syn_rto;
+    let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+    pcb.local.address = local.address;
+    pcb.local.port = local.port;
+    pcb.remote.address = remote.address;
+    pcb.remote.port = remote.port;
+    pcb.open_recv_window();
+    pcb.iss = rand::thread_rng().gen_range(0..u32::MAX);
+    output_syn(pcb, syn_rto, device, contexts);
+    // close & release if fails
+    pcb.send_context.una = pcb.iss;
+    pcb.send_context.next = pcb.iss.wrapping_add(1);
+    pcb.set_state(TcpPcbState::SynSent);
+    pcbs.tcp_pcbs.index(pcb_id);
+    pcbs.tcp_pcbs.arm(pcb_id);
+}
+
+pub fn connect(
+    pcb_id: usize,
+    remote: &IPEndpoint,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+    pcbs_arc: &mut Arc<Mutex<ControlBlocks>>,
+) -> Option<usize> {
     let (sender, receiver) = mpsc::channel();
     {
         let pcbs = &mut pcbs_arc.lock().unwrap();
-        let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
-        pcb.local.address = local.address;
-        pcb.local.port = local.port;
-        pcb.remote.address = remote.address;
-        pcb.remote.port = remote.port;
-        pcb.recv_context.window = PCB_BUF_LEN as u16;
-        pcb.iss = rand::thread_rng().gen_range(0..u32::MAX);
-        output(pcb, TcpFlag::SYN as u8, vec![], device, contexts);
-        // close & release if fails
-        pcb.send_context.una = pcb.iss;
-        pcb.send_context.next = pcb.iss + 1;
-        pcb.state = TcpPcbState::SynSent;
-        pcb.sender = Some(sender);
+        start_connect(pcb_id, remote, device, contexts, pcbs);
+        pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id).sender = Some(sender);
     }
     loop {
         let wakeup = receiver.recv().unwrap();
@@ -1187,14 +3000,14 @@ pub fn connect(
             let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
 
             if !wakeup {
-                pcb.state = TcpPcbState::Closed;
+                pcb.set_state(TcpPcbState::Closed);
                 return None;
             }
             if pcb.state == TcpPcbState::Established {
                 break;
             }
             if pcb.state != TcpPcbState::SynReceived {
-                pcb.state = TcpPcbState::Closed;
+                pcb.set_state(TcpPcbState::Closed);
                 return None;
             }
         }
@@ -1217,63 +3030,79 @@ pub fn bind(pcb_id: usize, local: IPEndpoint, pcbs: &mut ControlBlocks) {
         panic!("TCP: PCB was not open in socket mode.");
     }
     pcb.local = local;
-    info!(
-        "TCP: bound local address = {:?} port = {:?}",
+    conn_log!(
+        info,
+        pcb.conn_id,
+        "bound local address = {:?} port = {:?}",
         ip_addr_to_str(pcb.local.address),
         pcb.local.port
     );
 }
 
+/// Sets the limit of connections half-open or waiting for accept of a listener, beyond which
+/// SYNs are dropped.
+pub fn set_backlog(pcb_id: usize, limit: usize, pcbs: &mut ControlBlocks) {
+    let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+    pcb.backlog.limit = limit;
+}
+
+/// Makes a listener answer SYNs with SYN cookies, allocating PCBs only for handshakes completed
+/// by ACKs, so that SYN floods cannot use up the PCBs. Connections of cookies have no SYN-ACK
+/// retransmitted.
+pub fn set_syn_cookies(pcb_id: usize, enabled: bool, pcbs: &mut ControlBlocks) {
+    let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+    pcb.syn_cookies = enabled;
+}
+
 pub fn listen(pcb_id: usize, pcbs: &mut ControlBlocks) {
     let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
     if pcb.mode != TcpPcbMode::Socket {
         panic!("TCP: PCB was not open in socket mode.");
     }
-    pcb.state = TcpPcbState::Listen;
+    pcb.set_state(TcpPcbState::Listen);
+    pcbs.tcp_pcbs.index(pcb_id);
 }
 
+/// Blocks till a connection of a listener is established and returns its PCB id, or the
+/// `Closed` error when the listener is closed meanwhile.
 pub fn accept(
     pcb_id: usize,
-    remote: &IPEndpoint,
     pcbs_arc: &mut Arc<Mutex<ControlBlocks>>,
-) -> Option<usize> {
+) -> Result<usize, SocketError> {
     let (sender, receiver) = mpsc::channel();
-    let mut next_backlog;
-    {
-        let pcbs = &mut pcbs_arc.lock().unwrap();
-        let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
-        if pcb.mode != TcpPcbMode::Socket {
-            panic!("TCP: PCB was not open in socket mode.");
-        }
-        if pcb.state != TcpPcbState::Listen {
-            panic!("TCP: PCB is not in LISTEN state.");
-        }
-        pcb.sender = Some(sender);
-        next_backlog = pcb.backlog.pcb_ids.pop_front();
-    }
-    let mut backlog_id = None;
     loop {
-        if next_backlog.is_some() {
-            if !receiver.recv().unwrap() {
-                return None;
-            }
-            {
-                let pcbs = &mut pcbs_arc.lock().unwrap();
-                let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
-                if pcb.state == TcpPcbState::Closed {
-                    warn!("TCP accept: PCB is in closed state.");
-                    return None;
-                }
-                backlog_id = next_backlog;
-                next_backlog = pcb.backlog.pcb_ids.pop_front();
+        {
+            let pcbs = &mut pcbs_arc.lock().unwrap();
+            if let Some(child_id) = try_accept(pcb_id, pcbs)? {
+                return Ok(child_id);
             }
-        } else {
-            break;
+            pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id).sender = Some(sender.clone());
         }
+        let _ = receiver.recv();
+    }
+}
+
+/// Takes a connection established on a listener if any, without blocking.
+pub fn try_accept(pcb_id: usize, pcbs: &mut ControlBlocks) -> Result<Option<usize>, SocketError> {
+    let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+    if pcb.mode != TcpPcbMode::Socket {
+        panic!("TCP: PCB was not open in socket mode.");
     }
-    backlog_id
+    if pcb.state != TcpPcbState::Listen {
+        conn_log!(warn, pcb.conn_id, "accept: PCB is not in LISTEN state.");
+        return Err(pcb.error.unwrap_or(SocketError::Closed));
+    }
+    let Some(child_id) = pcb.backlog.pcb_ids.pop_front() else {
+        return Ok(None);
+    };
+    // No longer pending on the listener
+    pcb_by_id(&mut pcbs.tcp_pcbs, child_id).parent_id = None;
+    Ok(Some(child_id))
 }
 
+/// Buffers data and sends it as far as send window and congestion window allow. The rest
+/// is sent on ACK arrivals. Returns the length buffered, which is limited by the send buffer
+/// of the PCB, holding data till it is acknowledged, and the memory limit of all PCBs.
 pub fn send(
     pcb_id: usize,
     data: Vec<u8>,
@@ -1281,167 +3110,2874 @@ pub fn send(
     contexts: &mut ProtocolContexts,
     pcbs_arc: &mut Arc<Mutex<ControlBlocks>>,
 ) -> Option<usize> {
-    let (sender, receiver) = mpsc::channel();
-    let mut sent = 0;
-    let mut retry = false;
-    let mut pcb_state;
-    let mut pcb_send_window;
-    let mut pcb_send_next;
-    let mut pcb_send_una;
-    {
-        let pcbs = &mut pcbs_arc.lock().unwrap();
-        let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
-        pcb.sender = Some(sender);
+    let pcbs = &mut pcbs_arc.lock().unwrap();
+    let memory_free = pcbs.memory_free();
+    let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+    let pcb_state = pcb.state;
+    if pcb_state == TcpPcbState::Closed || pcb_state == TcpPcbState::Free {
+        conn_log!(error, pcb.conn_id, "connection does not exist.");
+        None
+    } else if pcb_state == TcpPcbState::Listen {
+        conn_log!(error, pcb.conn_id, "this connection is passive.");
+        None
+    } else if pcb_state == TcpPcbState::SynSent || pcb_state == TcpPcbState::SynReceived {
+        conn_log!(error, pcb.conn_id, "insufficient resources.");
+        None
+    } else if pcb_state == TcpPcbState::Established || pcb_state == TcpPcbState::CloseWait {
+        if pcb.congestion.is_none() {
+            let mss = match pcb.peer_mss {
+                0 => device_mss(device),
+                peer_mss => device_mss(device).min(peer_mss as usize),
+            };
+            pcb.mss = mss as u16;
+            pcb.congestion = Some(pcb.congestion_algorithm.create(mss as u32));
+        }
+        let free = cmp::min(
+            pcb.send_buf_size.saturating_sub(pcb.send_buf.len()),
+            memory_free,
+        );
+        let len = cmp::min(free, data.len());
+        if len < data.len() {
+            conn_log!(
+                debug,
+                pcb.conn_id,
+                "send buffer is full. buffered {len} of {}",
+                data.len()
+            );
+        }
+        pcb.send_buf.extend_from_slice(&data[..len]);
+        flush(pcb, device, contexts);
+        pcbs.tcp_pcbs.arm(pcb_id);
+        Some(len)
+    } else {
+        conn_log!(warn, pcb.conn_id, "connection is closing.");
+        None
     }
+}
 
-    loop {
-        {
-            let pcbs = &mut pcbs_arc.lock().unwrap();
-            let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
-            pcb_state = pcb.state;
-            pcb_send_window = pcb.send_context.window as u32;
-            pcb_send_next = pcb.send_context.next;
-            pcb_send_una = pcb.send_context.una;
+/// Sends buffered data limited by MSS, send window and congestion window, followed by FIN of
+/// close once all of it is sent.
+fn flush(pcb: &mut TcpPcb, device: &mut NetDevice, contexts: &mut ProtocolContexts) {
+    let cwnd = match pcb.congestion.as_ref() {
+        Some(congestion) => congestion.cwnd(),
+        None => return output_fin(pcb, device, contexts),
+    };
+    let window = cmp::min(pcb.send_context.window as u32, cwnd);
+    while pcb.unsent_len() > 0 {
+        let in_flight = pcb.send_context.next.wrapping_sub(pcb.send_context.una);
+        if in_flight >= window {
+            conn_log!(
+                debug,
+                pcb.conn_id,
+                "window is full. in flight = {in_flight} window = {window}"
+            );
+            break;
         }
-        if pcb_state == TcpPcbState::Closed {
-            error!("TCP: connection does not exist.");
-            return None;
-        } else if pcb_state == TcpPcbState::Listen {
-            error!("TCP: this connection is passive.");
-            return None;
-        } else if pcb_state == TcpPcbState::SynSent || pcb_state == TcpPcbState::SynReceived {
-            error!("TCP: insufficient resources.");
-            return None;
-        } else if pcb_state == TcpPcbState::Established || pcb_state == TcpPcbState::CloseWait {
-            let mss = device.mtu - (IP_HEADER_MIN_SIZE + size_of::<TcpHeader>());
-            let len = data.len();
-            while sent < len {
-                let capacity = (pcb_send_window - (pcb_send_next - pcb_send_una)) as usize;
-                if capacity < 1 {
-                    if !receiver.recv().unwrap() {
-                        return None;
-                    }
-                    retry = true;
-                    break;
-                } else {
-                    let pcbs = &mut pcbs_arc.lock().unwrap();
-                    let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
-                    let send_len = cmp::min(cmp::min(mss, len - sent), capacity);
-                    output(
-                        pcb,
-                        TcpFlag::ACK as u8 | TcpFlag::PSH as u8,
-                        data[sent..].to_vec(),
-                        device,
-                        contexts,
-                    );
-                    pcb.send_context.next += send_len as u32;
-                    sent += send_len;
-                    retry = false;
-                }
-            }
-            if !retry {
-                break;
+        let capacity = (window - in_flight) as usize;
+        let send_len = cmp::min(cmp::min(pcb.mss as usize, pcb.unsent_len()), capacity);
+        // Nagle: a segment smaller than MSS waits till data in flight is acknowledged (RFC 896).
+        if send_len < pcb.mss as usize && in_flight > 0 && !pcb.no_delay {
+            conn_log!(
+                debug,
+                pcb.conn_id,
+                "holding {send_len} bytes till data in flight is acknowledged."
+            );
+            break;
+        }
+        // Coalescing: the rest smaller than MSS waits for more writes till pushed or timed out.
+        let pushed =
+            pcb.push_pending || pcb.fin_pending || pcb.coalesce_at.is_some_and(timer::is_due);
+        if pcb.coalesce && pcb.unsent_len() < pcb.mss as usize && !pushed {
+            if pcb.coalesce_at.is_none() {
+                conn_log!(
+                    debug,
+                    pcb.conn_id,
+                    "holding {send_len} bytes for more writes."
+                );
+                pcb.coalesce_at =
+                    timer::now().checked_add(Duration::from_millis(TCP_COALESCE_MILLIS));
             }
-        } else if pcb_state == TcpPcbState::FinWait1
-            || pcb_state == TcpPcbState::FinWait2
-            || pcb_state == TcpPcbState::Closing
-            || pcb_state == TcpPcbState::LastAck
-            || pcb_state == TcpPcbState::TimeWait
+            break;
+        }
+        let offset = pcb.in_flight_len();
+        let data = pcb.send_buf[offset..offset + send_len].to_vec();
+        output(
+            pcb,
+            TcpFlag::ACK as u8 | TcpFlag::PSH as u8,
+            data,
+            device,
+            contexts,
+        );
+        pcb.send_context.next = pcb.send_context.next.wrapping_add(send_len as u32);
+        pcb.coalesce_at = None;
+    }
+    if pcb.unsent_len() == 0 {
+        pcb.push_pending = false;
+    }
+    output_fin(pcb, device, contexts);
+}
+
+fn output_fin(pcb: &mut TcpPcb, device: &mut NetDevice, contexts: &mut ProtocolContexts) {
+    if !pcb.fin_pending || pcb.unsent_len() > 0 {
+        return;
+    }
+    conn_log!(info, pcb.conn_id, "all data sent. Sending FIN...");
+    pcb.fin_pending = false;
+    output(
+        pcb,
+        TcpFlag::FIN as u8 | TcpFlag::ACK as u8,
+        vec![],
+        device,
+        contexts,
+    );
+    pcb.send_context.next = pcb.send_context.next.wrapping_add(1);
+}
+
+/// Sends all data waiting for room in buffers. Returns None when the connection is gone.
+pub fn send_all(
+    pcb_id: usize,
+    data: Vec<u8>,
+    pcbs_arc: Arc<Mutex<ControlBlocks>>,
+    devices_arc: Arc<Mutex<NetDevices>>,
+    contexts_arc: Arc<Mutex<ProtocolContexts>>,
+) -> Option<usize> {
+    let mut sent = 0;
+    while sent < data.len() {
         {
-            warn!("TCP: connection is closing.");
-            return None;
-        } else {
-            warn!("TCP: unknown state.");
-            return None;
+            let devices = &mut devices_arc.lock().unwrap();
+            let contexts = &mut contexts_arc.lock().unwrap();
+            let pcbs = pcbs_arc.lock().unwrap();
+            let device = pcb_device(pcb_id, devices, contexts, &pcbs).unwrap();
+            drop(pcbs); // locked again by send
+            sent += send(
+                pcb_id,
+                data[sent..].to_vec(),
+                device,
+                contexts,
+                &mut pcbs_arc.clone(),
+            )?;
+        }
+        if sent < data.len() {
+            thread::sleep(Duration::from_millis(TCP_SEND_RETRY_MILLIS));
         }
     }
     Some(sent)
 }
 
-pub fn receive(pcb_id: usize, size: usize, pcbs_arc: Arc<Mutex<ControlBlocks>>) -> Option<Vec<u8>> {
+/// Sets the send buffer size of a PCB (SO_SNDBUF). Buffered data beyond the size is kept.
+pub fn set_send_buffer_size(pcb_id: usize, size: usize, pcbs: &mut ControlBlocks) {
+    let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+    pcb.send_buf_size = size;
+}
+
+/// Disables coalescing small writes into full segments while data is in flight
+/// (TCP_NODELAY). Data held meanwhile is sent on the next ACK or send.
+pub fn set_no_delay(pcb_id: usize, no_delay: bool, pcbs: &mut ControlBlocks) {
+    let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+    pcb.no_delay = no_delay;
+}
+
+/// Enables holding writes smaller than MSS till more data makes a full segment, `push` or
+/// TCP_COALESCE_MILLIS later (TCP_CORK). Disabling it sends data held right away.
+pub fn set_coalesce(
+    pcb_id: usize,
+    coalesce: bool,
+    pcbs: &mut ControlBlocks,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+) {
+    let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+    pcb.coalesce = coalesce;
+    if !coalesce && pcb.coalesce_at.take().is_some() {
+        flush(pcb, device, contexts);
+    }
+    pcbs.tcp_pcbs.arm(pcb_id);
+}
+
+/// Sends data buffered without waiting for more writes to coalesce (PSH). Data held for
+/// windows or Nagle is still sent as they allow.
+pub fn push(
+    pcb_id: usize,
+    pcbs: &mut ControlBlocks,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+) {
+    let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+    if pcb.unsent_len() == 0 {
+        return;
+    }
+    pcb.push_pending = true;
+    if pcb.congestion.is_some() {
+        flush(pcb, device, contexts);
+    }
+    pcbs.tcp_pcbs.arm(pcb_id);
+}
+
+/// Selects what becomes of urgent data received on a PCB.
+pub fn set_urgent_policy(pcb_id: usize, policy: UrgentPolicy, pcbs: &mut ControlBlocks) {
+    let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+    pcb.urgent_policy = policy;
+}
+
+/// Takes the urgent byte received out of band, if any (MSG_OOB).
+pub fn receive_oob(pcb_id: usize, pcbs: &mut ControlBlocks) -> Option<u8> {
+    let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+    pcb.oob.take()
+}
+
+/// Sets the receive buffer size of a PCB (SO_RCVBUF) capped at the max window, and the
+/// window of a connection accordingly.
+pub fn set_recv_buffer_size(pcb_id: usize, size: usize, pcbs: &mut ControlBlocks) {
+    let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+    if size > PCB_BUF_LEN {
+        warn!("TCP: receive buffer size is capped at {PCB_BUF_LEN}.");
+    }
+    pcb.recv_buf_size = cmp::min(size, PCB_BUF_LEN);
+    if pcb.state != TcpPcbState::Closed && pcb.state != TcpPcbState::Listen {
+        pcb.update_recv_window();
+    }
+}
+
+/// Selects congestion control algorithm of a PCB. Congestion window restarts from the next send.
+pub fn set_congestion_control(
+    pcb_id: usize,
+    algorithm: CongestionAlgorithm,
+    pcbs: &mut ControlBlocks,
+) {
+    let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+    pcb.congestion_algorithm = algorithm;
+    pcb.congestion = None;
+}
+
+/// Sends an ACK advertising the receive window when it has opened by an MSS or half the
+/// buffer since the last advertisement (RFC 1122 4.2.3.3), so that a sender waiting for the
+/// window resumes.
+fn window_update(pcb: &mut TcpPcb, device: &mut NetDevice, contexts: &mut ProtocolContexts) {
+    if pcb.state != TcpPcbState::Established
+        && pcb.state != TcpPcbState::FinWait1
+        && pcb.state != TcpPcbState::FinWait2
+    {
+        return;
+    }
+    let mss = if pcb.mss > 0 {
+        pcb.mss as usize
+    } else {
+        device_mss(device)
+    };
+    let opened = pcb.recv_context.window.saturating_sub(pcb.adv_window) as usize;
+    if opened >= cmp::min(mss, pcb.recv_buf_size / 2) {
+        conn_log!(
+            debug,
+            pcb.conn_id,
+            "window update: {} -> {}",
+            pcb.adv_window,
+            pcb.recv_context.window
+        );
+        output(pcb, TcpFlag::ACK as u8, vec![], device, contexts);
+    }
+}
+
+/// Blocks till data of `size` bytes at most is received. The peer is notified of the window
+/// opened by reading.
+pub fn receive(
+    pcb_id: usize,
+    size: usize,
+    pcbs_arc: Arc<Mutex<ControlBlocks>>,
+    devices_arc: Arc<Mutex<NetDevices>>,
+    contexts_arc: Arc<Mutex<ProtocolContexts>>,
+) -> Option<Vec<u8>> {
     let (sender, receiver) = mpsc::channel();
-    let mut remain = None;
     let mut pcb_state;
-    let pcb_buf_len = PCB_BUF_LEN;
-    let mut pcb_recv_window;
+    let mut buffered;
+    let conn_id;
     {
         let pcbs = &mut pcbs_arc.lock().unwrap();
         let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+        if pcb.recv_shutdown {
+            return Some(vec![]);
+        }
         pcb.sender = Some(sender);
+        conn_id = pcb.conn_id;
         pcb_state = pcb.state;
-        pcb_recv_window = pcb.recv_context.window as usize;
+        buffered = pcb.buf.len();
     }
 
     loop {
-        if pcb_state == TcpPcbState::Closed {
-            error!("TCP: connection does not exist.");
+        if pcb_state == TcpPcbState::Closed || pcb_state == TcpPcbState::Free {
+            conn_log!(error, conn_id, "connection does not exist.");
             return None;
         } else if pcb_state == TcpPcbState::Listen
             || pcb_state == TcpPcbState::SynSent
             || pcb_state == TcpPcbState::SynReceived
         {
-            error!("TCP: insufficient resources.");
+            conn_log!(error, conn_id, "insufficient resources.");
             return None;
         } else if pcb_state == TcpPcbState::Established
             || pcb_state == TcpPcbState::FinWait1
             || pcb_state == TcpPcbState::FinWait2
         {
-            if pcb_recv_window >= pcb_buf_len {
-                info!("TCP: sleeping for incoming data...");
+            if buffered == 0 {
+                conn_log!(info, conn_id, "sleeping for incoming data...");
                 if !receiver.recv().unwrap() {
                     return None;
                 }
                 let pcbs = &mut pcbs_arc.lock().unwrap();
                 let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+                if pcb.recv_shutdown {
+                    return Some(vec![]);
+                }
                 pcb_state = pcb.state;
-                pcb_recv_window = pcb.recv_context.window as usize;
-                remain = Some(pcb_buf_len - pcb_recv_window);
+                buffered = pcb.buf.len();
             } else {
-                info!("TCP: buffer size > recv.window...");
-                break;
-            }
-        } else if pcb_state == TcpPcbState::CloseWait {
-            if pcb_buf_len > pcb_recv_window {
-                remain = Some(pcb_buf_len - pcb_recv_window);
+                conn_log!(info, conn_id, "{buffered} bytes buffered...");
                 break;
             }
-            break; // fall through
-        } else if pcb_state == TcpPcbState::Closing
+        } else if pcb_state == TcpPcbState::CloseWait
+            || pcb_state == TcpPcbState::Closing
             || pcb_state == TcpPcbState::LastAck
             || pcb_state == TcpPcbState::TimeWait
         {
-            info!("TCP: connection closing.");
+            break; // data buffered before FIN, if any, then end of stream
         } else {
-            warn!("TCP: unknown state.");
+            conn_log!(warn, conn_id, "unknown state.");
         }
-        debug!("TCP receive: retrying...");
+        conn_log!(debug, conn_id, "receive: retrying...");
     }
+    let devices = &mut devices_arc.lock().unwrap();
+    let contexts = &mut contexts_arc.lock().unwrap();
     let pcbs = &mut pcbs_arc.lock().unwrap();
     let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
-    let buf_len = pcb.buf.len();
-    let len = {
-        if remain.is_none() {
-            cmp::min(buf_len, size)
-        } else {
-            cmp::min(buf_len, cmp::min(size, remain.unwrap()))
+    let len = cmp::min(pcb.buf.len(), size);
+    let data: Vec<u8> = pcb.buf.drain(..len).collect();
+    pcb.update_recv_window();
+    if len > 0 {
+        let remote = pcb.remote.address;
+        let eth_device = devices.get_mut_by_dst(remote, &contexts.ip_routes).unwrap();
+        window_update(pcb, eth_device, contexts);
+    }
+    Some(data)
+}
+
+/// Shuts down the send side, the receive side or both of a connection (BSD shutdown). Shutting
+/// down sending sends FIN after data buffered, moving to FIN-WAIT-1, or to LAST-ACK when the
+/// peer has closed already, while data keeps being received. After shutting down receiving,
+/// `receive` returns no data and data arriving is acknowledged and discarded. Fails for
+/// connections not synchronized.
+pub fn shutdown(
+    pcb_id: usize,
+    how: Shutdown,
+    pcbs: &mut ControlBlocks,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+) -> Result<(), ()> {
+    let pcb = match pcbs.tcp_pcbs.get_mut_by_id(pcb_id) {
+        Some(pcb)
+            if !matches!(
+                pcb.state,
+                TcpPcbState::Free
+                    | TcpPcbState::Closed
+                    | TcpPcbState::Listen
+                    | TcpPcbState::SynSent
+            ) =>
+        {
+            pcb
+        }
+        _ => {
+            error!("TCP: no connection with PCB id: {pcb_id} to shut down.");
+            return Err(());
         }
     };
-    let data = pcb.buf[..len].to_vec();
-    pcb.buf = pcb.buf[len..].to_vec();
-    pcb.recv_context.window += len as u16;
-    Some(data)
+    if how != Shutdown::Write && !pcb.recv_shutdown {
+        conn_log!(info, pcb.conn_id, "shutting down receiving.");
+        pcb.recv_shutdown = true;
+        if let Some(sender) = pcb.sender.as_ref() {
+            if sender.send(true).is_err() {
+                conn_log!(warn, pcb.conn_id, "PCB channel not listening.");
+            }
+        }
+    }
+    if how != Shutdown::Read {
+        match pcb.state {
+            TcpPcbState::SynReceived | TcpPcbState::Established => {
+                pcb.set_state(TcpPcbState::FinWait1);
+                pcb.fin_pending = true;
+                flush(pcb, device, contexts);
+            }
+            TcpPcbState::CloseWait => {
+                pcb.set_state(TcpPcbState::LastAck);
+                pcb.fin_pending = true;
+                flush(pcb, device, contexts);
+            }
+            _ => {} // sending shut down already
+        }
+        pcbs.tcp_pcbs.arm(pcb_id);
+    }
+    Ok(())
 }
 
+/// Closes a connection gracefully shutting down both sides. The PCB is released once the peer
+/// acknowledges FIN, after TIME-WAIT for active close. Connections not synchronized are
+/// released right away.
 pub fn close(
     pcb_id: usize,
     pcbs: &mut ControlBlocks,
     device: &mut NetDevice,
     contexts: &mut ProtocolContexts,
 ) {
-    let pcb_opt = pcbs.tcp_pcbs.get_mut_by_id(pcb_id);
-    if pcb_opt.is_some() {
-        let pcb = pcb_opt.unwrap();
-        output(pcb, TcpFlag::RST as u8, vec![], device, contexts);
+    let pcb = match pcbs.tcp_pcbs.get_mut_by_id(pcb_id) {
+        Some(pcb) => pcb,
+        None => return,
+    };
+    match pcb.state {
+        TcpPcbState::Free => {}
+        TcpPcbState::Listen => close_listener(pcb_id, pcbs, device, contexts),
+        TcpPcbState::Closed | TcpPcbState::SynSent => {
+            conn_log!(info, pcb.conn_id, "closing connection not synchronized.");
+            pcb.release();
+        }
+        _ => {
+            let _ = shutdown(pcb_id, Shutdown::Both, pcbs, device, contexts);
+        }
+    }
+}
+
+/// Closes a connection gracefully and blocks till the peer acknowledges FIN and closes its side
+/// too. Returns the error when the connection gets released otherwise.
+pub fn close_blocking(
+    pcb_id: usize,
+    pcbs_arc: Arc<Mutex<ControlBlocks>>,
+    devices_arc: Arc<Mutex<NetDevices>>,
+    contexts_arc: Arc<Mutex<ProtocolContexts>>,
+) -> Result<(), SocketError> {
+    let (sender, receiver) = mpsc::channel();
+    let conn_id = {
+        let devices = &mut devices_arc.lock().unwrap();
+        let contexts = &mut contexts_arc.lock().unwrap();
+        let pcbs = &mut pcbs_arc.lock().unwrap();
+        let device = pcb_device(pcb_id, devices, contexts, pcbs).unwrap();
+        close(pcb_id, pcbs, device, contexts);
+        let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+        pcb.sender = Some(sender);
+        pcb.conn_id
+    };
+    loop {
+        {
+            let pcbs = &mut pcbs_arc.lock().unwrap();
+            let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+            if pcb.conn_id != conn_id {
+                return Ok(()); // released and reused
+            }
+            match pcb.state {
+                TcpPcbState::TimeWait => return Ok(()),
+                TcpPcbState::Free => return pcb.error.map_or(Ok(()), Err),
+                _ => {}
+            }
+        }
+        conn_log!(info, conn_id, "sleeping for close to complete...");
+        if receiver.recv().is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Handles an ICMP Destination Unreachable or Time Exceeded quoting a segment of a connection
+/// (RFC 1122 4.2.3.9). Protocol / port unreachable are hard errors aborting the connection.
+/// Others are soft errors recorded for the user. Errors quoting a sequence number not in flight
+/// are ignored (RFC 5927).
+pub fn icmp_error(
+    local: &IPEndpoint,
+    remote: &IPEndpoint,
+    seq: u32,
+    icmp_type: u8,
+    code: u8,
+    pcbs: &mut ControlBlocks,
+) {
+    let pcb = match pcbs.tcp_pcbs.select(local, Some(remote)) {
+        // Not a listening PCB matched for no connection
+        Some((_, pcb))
+            if pcb.remote.address == remote.address && pcb.remote.port == remote.port =>
+        {
+            pcb
+        }
+        _ => {
+            debug!("TCP: ICMP error for no connection. Ignoring...");
+            return;
+        }
+    };
+    if seq_lt(seq, pcb.send_context.una) || seq_lt(pcb.send_context.next, seq) {
+        conn_log!(
+            warn,
+            pcb.conn_id,
+            "ICMP error quoting seq {seq:x} not in flight. Ignoring..."
+        );
+        return;
+    }
+    let (error, hard) = match (icmp_type, code) {
+        (ICMP_TYPE_DEST_UNREACH, 0) => (SocketError::NetworkUnreachable, false),
+        (ICMP_TYPE_DEST_UNREACH, 2 | 3) => (SocketError::ConnectionRefused, true),
+        (ICMP_TYPE_DEST_UNREACH, 4) => return, // fragmentation needed: no path MTU discovery
+        (ICMP_TYPE_DEST_UNREACH, _) => (SocketError::HostUnreachable, false),
+        _ => (SocketError::TimeExceeded, false),
+    };
+    if hard {
+        conn_log!(info, pcb.conn_id, "ICMP hard error: {error:?}. Aborting...");
+        pcb.error = Some(error);
         pcb.release();
+    } else {
+        conn_log!(info, pcb.conn_id, "ICMP soft error: {error:?}.");
+        pcb.soft_error = Some(error);
+        pcb.notify(TcpEvent::SoftError(error));
+    }
+}
+
+/// Aborts a connection by an operator: sends RST when synchronized and releases the PCB
+/// waking up blocked users with AdministrativelyClosed error.
+/// Stops accepting SYNs resetting connections not accepted yet, wakes up `accept` with the
+/// `Closed` error and frees the local port.
+fn close_listener(
+    pcb_id: usize,
+    pcbs: &mut ControlBlocks,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+) {
+    let children: Vec<usize> = (0..pcbs.tcp_pcbs.entries.len())
+        .filter(|id| {
+            let child = &pcbs.tcp_pcbs.entries[*id];
+            child.parent_id == Some(pcb_id) && child.state != TcpPcbState::Free
+        })
+        .collect();
+    for child_id in children {
+        let _ = abort(child_id, pcbs, device, contexts);
+    }
+    pcbs.tcp_pcbs.unindex(pcb_id);
+    let pcb = pcb_by_id(&mut pcbs.tcp_pcbs, pcb_id);
+    conn_log!(info, pcb.conn_id, "closing listener.");
+    pcb.error = Some(SocketError::Closed);
+    pcb.release();
+}
+
+pub fn abort(
+    pcb_id: usize,
+    pcbs: &mut ControlBlocks,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+) -> Result<(), ()> {
+    let pcb = match pcbs.tcp_pcbs.get_mut_by_id(pcb_id) {
+        Some(pcb) if pcb.state != TcpPcbState::Free => pcb,
+        _ => {
+            error!("TCP: no connection with PCB id: {pcb_id} to abort.");
+            return Err(());
+        }
+    };
+    match pcb.state {
+        TcpPcbState::SynReceived
+        | TcpPcbState::Established
+        | TcpPcbState::FinWait1
+        | TcpPcbState::FinWait2
+        | TcpPcbState::CloseWait => {
+            output(pcb, TcpFlag::RST as u8, vec![], device, contexts);
+        }
+        _ => {}
+    }
+    conn_log!(
+        info,
+        pcb.conn_id,
+        "aborting connection of PCB id: {pcb_id} in state: {:?}",
+        pcb.state
+    );
+    pcb.error = Some(SocketError::AdministrativelyClosed);
+    pcb.release();
+    Ok(())
+}
+
+/// Returns the reason a connection got released under its users, if any.
+pub fn socket_error(pcb_id: usize, pcbs: &mut ControlBlocks) -> Option<SocketError> {
+    pcbs.tcp_pcbs.get_mut_by_id(pcb_id)?.error
+}
+
+/// Returns the reason of release if any, or else the pending soft error clearing it (SO_ERROR).
+/// Counters of a connection of a PCB. None once the PCB is released.
+pub fn stats(pcb_id: usize, pcbs: &ControlBlocks) -> Option<TcpConnStats> {
+    pcbs.tcp_pcbs.connection_stats(pcb_id)
+}
+
+/// Remote endpoint of a connection of a PCB. None once the PCB is released.
+pub fn remote_endpoint(pcb_id: usize, pcbs: &ControlBlocks) -> Option<IPEndpoint> {
+    let pcb = pcbs.tcp_pcbs.entries.get(pcb_id)?;
+    if pcb.state == TcpPcbState::Free {
+        return None;
+    }
+    Some(IPEndpoint {
+        address: pcb.remote.address,
+        port: pcb.remote.port,
+    })
+}
+
+pub fn take_error(pcb_id: usize, pcbs: &mut ControlBlocks) -> Option<SocketError> {
+    let pcb = pcbs.tcp_pcbs.get_mut_by_id(pcb_id)?;
+    pcb.error.or_else(|| pcb.soft_error.take())
+}
+
+/// Subscribes to events of a connection, replacing the previous subscriber. What happened
+/// already is sent first: connected, data buffered and FIN received. The channel gets closed
+/// after `TcpEvent::Closed`.
+pub fn subscribe(pcb_id: usize, pcbs: &mut ControlBlocks) -> mpsc::Receiver<TcpEvent> {
+    let (sender, receiver) = mpsc::channel();
+    let Some(pcb) = pcbs.tcp_pcbs.get_mut_by_id(pcb_id) else {
+        sender.send(TcpEvent::Closed(None)).unwrap();
+        return receiver;
+    };
+    pcb.events = Some(sender);
+    if pcb.state == TcpPcbState::Free {
+        pcb.notify(TcpEvent::Closed(pcb.error));
+        pcb.events = None;
+        return receiver;
+    }
+    let synchronized = pcb.state != TcpPcbState::Closed
+        && pcb.state != TcpPcbState::Listen
+        && pcb.state != TcpPcbState::SynSent
+        && pcb.state != TcpPcbState::SynReceived;
+    if synchronized {
+        pcb.notify(TcpEvent::Connected);
+    }
+    if !pcb.buf.is_empty() {
+        let buffered = pcb.buf.len();
+        pcb.notify(TcpEvent::DataReady(buffered));
+    }
+    let fin_received = pcb.state == TcpPcbState::CloseWait
+        || pcb.state == TcpPcbState::Closing
+        || pcb.state == TcpPcbState::LastAck
+        || pcb.state == TcpPcbState::TimeWait;
+    if fin_received {
+        pcb.notify(TcpEvent::PeerClosed);
+    }
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        abort, accept, bind, close, input, listen, open, output, output_segment, output_syn, push,
+        receive, receive_oob, retransmit, segment_arrives, send, seq_between, seq_leq, seq_lt,
+        set_backlog, set_coalesce, set_no_delay, set_recv_buffer_size, set_send_buffer_size,
+        set_syn_cookies, set_urgent_policy, shutdown, socket_error, stats, subscribe, take_error,
+        tcp_flag_exists,
+        tcp_options::{self, TcpOptions},
+        AckPolicy, RttEstimator, TcpEvent, TcpFlag, TcpHeader, TcpPcbState, TcpPcbs, TcpProtocol,
+        TcpSegmentInfo, UrgentPolicy, PCB_BUF_LEN, TCP_COALESCE_MILLIS, TCP_DEFAULT_ITVL_MICROS,
+        TCP_DELAYED_ACK_MILLIS, TCP_HELD_LEN_MAX, TCP_PCB_COUNT, TCP_RETRANSMIT_TIMOUT_SEC,
+        TCP_RTO_MAX_SECS, TCP_RTO_MIN_MILLIS, TCP_TIMEWAIT_SEC,
+    };
+    use crate::{
+        devices::{ethernet, test_device, NetDevice, NetDeviceType, NetDevices},
+        net::NetInterfaceFamily,
+        protocols::ip::{
+            self,
+            icmp::{self, ICMP_TYPE_DEST_UNREACH},
+            l4::L4Protocol,
+            IPEndpoint, IPHeader, IPRoute, IPRoutes,
+        },
+        protocols::{arp, ControlBlocks, ProtocolContexts, SocketError},
+        utils::{bytes_to_struct, cksum16},
+    };
+    use std::{
+        mem::size_of,
+        net::Shutdown,
+        sync::{mpsc, Arc, Mutex},
+        thread,
+        time::{Duration, SystemTime},
+    };
+
+    /// Test device and contexts with a resolved neighbor so that segments go out right away.
+    fn setup() -> (NetDevice, ProtocolContexts) {
+        let device = test_device("192.0.2.2/24");
+        let mut routes = IPRoutes::new();
+        let interface = device.get_interface(NetInterfaceFamily::IP).unwrap();
+        routes.register(IPRoute::interface_route(interface));
+        let mut contexts = ProtocolContexts::new(routes);
+        contexts.arp_table.update(
+            ip::ip_addr_to_bytes("192.0.2.1").unwrap(),
+            [0x02, 0, 0, 0, 0, 0x01],
+        );
+        (device, contexts)
+    }
+
+    fn established_pcb(pcbs: &mut ControlBlocks) -> usize {
+        let (pcb_id, pcb) = pcbs.tcp_pcbs.new_entry().unwrap();
+        pcb.state = TcpPcbState::Established;
+        pcb.local = IPEndpoint::new_from_str("192.0.2.2", 7);
+        pcb.remote = IPEndpoint::new_from_str("192.0.2.1", 50000);
+        pcb.send_context.window = PCB_BUF_LEN as u16;
+        pcbs.tcp_pcbs.index(pcb_id);
+        pcb_id
+    }
+
+    /// Handles timers due after tests move times of PCBs back, rescheduling their deadlines.
+    fn expire_timers(pcbs: &mut TcpPcbs, device: &mut NetDevice, contexts: &mut ProtocolContexts) {
+        for pcb_id in 0..pcbs.entries.len() {
+            pcbs.arm(pcb_id);
+        }
+        retransmit(pcbs, device, contexts);
+    }
+
+    #[test]
+    fn test_timer_queue() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs = ControlBlocks::new();
+        let pcb_id = established_pcb(&mut pcbs);
+        shutdown(
+            pcb_id,
+            Shutdown::Write,
+            &mut pcbs,
+            &mut device,
+            &mut contexts,
+        )
+        .unwrap();
+        let fin = &pcbs.tcp_pcbs.entries[pcb_id].data_queue.entries[0];
+        let due = fin.last_sent_at + fin.retry_interval;
+        assert_eq!(Some(due), pcbs.tcp_pcbs.timers.deadline(pcb_id));
+
+        // PCBs without deadlines due are not visited.
+        let idle_id = established_pcb(&mut pcbs);
+        pcbs.tcp_pcbs.entries[idle_id].delayed_ack =
+            Some(SystemTime::now() - Duration::from_secs(1));
+        retransmit(&mut pcbs.tcp_pcbs, &mut device, &mut contexts);
+        assert!(pcbs.tcp_pcbs.entries[idle_id].delayed_ack.is_some());
+        assert_eq!(1, device.driver_data.as_ref().unwrap().tx_frames.len());
+
+        // FIN due is retransmitted and its next deadline scheduled.
+        let fin = &mut pcbs.tcp_pcbs.entries[pcb_id].data_queue.entries[0];
+        fin.last_sent_at -= fin.retry_interval;
+        pcbs.tcp_pcbs.timers.schedule(pcb_id, SystemTime::now());
+        retransmit(&mut pcbs.tcp_pcbs, &mut device, &mut contexts);
+        assert_eq!(2, device.driver_data.as_ref().unwrap().tx_frames.len());
+        let fin = &pcbs.tcp_pcbs.entries[pcb_id].data_queue.entries[0];
+        let due = fin.last_sent_at + fin.retry_interval;
+        assert_eq!(Some(due), pcbs.tcp_pcbs.timers.deadline(pcb_id));
+        assert_eq!(None, pcbs.tcp_pcbs.timers.deadline(idle_id));
+    }
+
+    #[test]
+    fn test_timer_wakeup() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs = ControlBlocks::new();
+        let (wakeup, wakeups) = mpsc::channel();
+        pcbs.tcp_pcbs.set_timer_wakeup(Some(wakeup));
+        let first_id = established_pcb(&mut pcbs);
+        shutdown(
+            first_id,
+            Shutdown::Write,
+            &mut pcbs,
+            &mut device,
+            &mut contexts,
+        )
+        .unwrap();
+        assert!(wakeups.try_recv().is_ok());
+
+        // A later deadline lets the thread sleep on, an earlier one wakes it up.
+        let second_id = established_pcb(&mut pcbs);
+        shutdown(
+            second_id,
+            Shutdown::Write,
+            &mut pcbs,
+            &mut device,
+            &mut contexts,
+        )
+        .unwrap();
+        assert!(wakeups.try_recv().is_err());
+        pcbs.tcp_pcbs.entries[second_id].delayed_ack = Some(SystemTime::now());
+        pcbs.tcp_pcbs.arm(second_id);
+        assert!(wakeups.try_recv().is_ok());
+        let delayed_ack = SystemTime::now() + Duration::from_millis(TCP_DELAYED_ACK_MILLIS);
+        assert!(pcbs.tcp_pcbs.next_deadline().unwrap() <= delayed_ack);
+
+        // Ticks leave timers to the thread.
+        let fin = &mut pcbs.tcp_pcbs.entries[first_id].data_queue.entries[0];
+        fin.last_sent_at -= fin.retry_interval;
+        pcbs.tcp_pcbs.timers.schedule(first_id, SystemTime::now());
+        TcpProtocol.timer_tick(&mut device, &mut contexts, &mut pcbs);
+        assert_eq!(2, device.driver_data.as_ref().unwrap().tx_frames.len());
+        pcbs.tcp_pcbs.set_timer_wakeup(None);
+        TcpProtocol.timer_tick(&mut device, &mut contexts, &mut pcbs);
+        assert_eq!(3, device.driver_data.as_ref().unwrap().tx_frames.len());
+    }
+
+    #[test]
+    fn test_abort_established() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs = ControlBlocks::new();
+        let (sender, receiver) = mpsc::channel();
+        let pcb_id = established_pcb(&mut pcbs);
+        pcbs.tcp_pcbs.entries[pcb_id].sender = Some(sender);
+
+        abort(pcb_id, &mut pcbs, &mut device, &mut contexts).unwrap();
+        assert!(!receiver.recv().unwrap());
+        assert_eq!(
+            Some(SocketError::AdministrativelyClosed),
+            socket_error(pcb_id, &mut pcbs)
+        );
+        assert_eq!(TcpPcbState::Free, pcbs.tcp_pcbs.entries[pcb_id].state);
+
+        let driver_data = device.driver_data.as_mut().unwrap();
+        let frame = driver_data.tx_frames.pop_front().unwrap();
+        let offset = ethernet::ETH_HDR_SIZE + size_of::<IPHeader>();
+        let header = unsafe { bytes_to_struct::<TcpHeader>(&frame[offset..]) };
+        assert_eq!(TcpFlag::RST as u8, header.flags);
+
+        // Aborting a released PCB fails.
+        assert!(abort(pcb_id, &mut pcbs, &mut device, &mut contexts).is_err());
+    }
+
+    #[test]
+    fn test_rtt_estimation() {
+        let mut rtt = RttEstimator::new();
+        assert_eq!(Duration::from_micros(TCP_DEFAULT_ITVL_MICROS), rtt.rto);
+
+        // First sample: SRTT = R, RTTVAR = R/2, RTO = SRTT + 4 * RTTVAR
+        rtt.sample(Duration::from_millis(100));
+        assert_eq!(Duration::from_millis(300), rtt.rto);
+        rtt.sample(Duration::from_millis(100));
+        assert_eq!(Duration::from_millis(100), rtt.srtt.unwrap());
+        assert_eq!(Duration::from_micros(37500), rtt.rttvar);
+        assert_eq!(Duration::from_millis(250), rtt.rto);
+
+        // Clamped by minimum and maximum
+        let mut rtt = RttEstimator::new();
+        rtt.sample(Duration::from_millis(1));
+        assert_eq!(Duration::from_millis(TCP_RTO_MIN_MILLIS), rtt.rto);
+        rtt.sample(Duration::from_secs(100));
+        assert_eq!(Duration::from_secs(TCP_RTO_MAX_SECS), rtt.rto);
+    }
+
+    #[test]
+    fn test_retransmit_backoff() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs = ControlBlocks::new();
+        pcbs.tcp_pcbs.rto_max = Duration::from_millis(500);
+        let pcb_id = established_pcb(&mut pcbs);
+        let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+        pcb.send_buf = b"hello".to_vec();
+        pcb.send_context.una = 1;
+        pcb.send_context.next = 6;
+        pcb.add_data_queue(1, TcpFlag::ACK as u8, 5);
+
+        // 200ms -> 400ms -> 500ms (max) -> 500ms
+        for expected in [400, 500, 500] {
+            let queue = &mut pcbs.tcp_pcbs.entries[pcb_id].data_queue.entries[0];
+            queue.last_sent_at -= queue.retry_interval;
+            expire_timers(&mut pcbs.tcp_pcbs, &mut device, &mut contexts);
+            let queue = &pcbs.tcp_pcbs.entries[pcb_id].data_queue.entries[0];
+            assert_eq!(Duration::from_millis(expected), queue.retry_interval);
+        }
+        assert_eq!(3, pcbs.tcp_pcbs.entries[pcb_id].counters.retransmits);
+        assert_eq!(3, device.driver_data.as_ref().unwrap().tx_frames.len());
+        assert!(pcbs.tcp_pcbs.stats().contains("retransmits: 3"));
+    }
+
+    #[test]
+    fn test_syn_timeout() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs = ControlBlocks::new();
+        pcbs.tcp_pcbs.syn_retries = 2;
+        pcbs.tcp_pcbs.syn_rto = Duration::from_millis(300);
+        let pcb_id = established_pcb(&mut pcbs);
+        let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+        pcb.state = TcpPcbState::SynSent;
+        output_syn(pcb, Duration::from_millis(300), &mut device, &mut contexts);
+
+        // 300ms -> 600ms -> 1200ms, then times out after the last interval
+        for expected in [600, 1200] {
+            let queue = &mut pcbs.tcp_pcbs.entries[pcb_id].data_queue.entries[0];
+            assert!(tcp_flag_exists(queue.flags, TcpFlag::SYN));
+            queue.last_sent_at -= queue.retry_interval;
+            expire_timers(&mut pcbs.tcp_pcbs, &mut device, &mut contexts);
+            let queue = &pcbs.tcp_pcbs.entries[pcb_id].data_queue.entries[0];
+            assert_eq!(Duration::from_millis(expected), queue.retry_interval);
+        }
+        expire_timers(&mut pcbs.tcp_pcbs, &mut device, &mut contexts);
+        assert_eq!(TcpPcbState::SynSent, pcbs.tcp_pcbs.entries[pcb_id].state);
+
+        let queue = &mut pcbs.tcp_pcbs.entries[pcb_id].data_queue.entries[0];
+        queue.last_sent_at -= queue.retry_interval;
+        expire_timers(&mut pcbs.tcp_pcbs, &mut device, &mut contexts);
+        assert_eq!(TcpPcbState::Free, pcbs.tcp_pcbs.entries[pcb_id].state);
+        assert_eq!(Some(SocketError::TimedOut), socket_error(pcb_id, &mut pcbs));
+        assert_eq!(3, device.driver_data.as_ref().unwrap().tx_frames.len());
+    }
+
+    /// ICMP error of a type and code quoting the segment 192.0.2.2:7 -> 192.0.2.1:50000 of seq.
+    fn icmp_error_message(icmp_type: u8, code: u8, seq: u32) -> Vec<u8> {
+        let mut data = vec![icmp_type, code, 0, 0, 0, 0, 0, 0];
+        data.extend_from_slice(&[
+            0x45, 0, 0, 40, 0, 0, 0, 0, 64, 6, 0, 0, 192, 0, 2, 2, 192, 0, 2, 1,
+        ]);
+        data.extend_from_slice(&[0, 7, 0xc3, 0x50]);
+        data.extend_from_slice(&seq.to_be_bytes());
+        let sum = cksum16(&data, data.len(), 0);
+        data[2] = (sum >> 8) as u8;
+        data[3] = (sum & 0xff) as u8;
+        data
+    }
+
+    #[test]
+    fn test_icmp_errors() {
+        let (mut device, mut contexts) = setup();
+        let iface = device.get_interface(NetInterfaceFamily::IP).unwrap();
+        let mut pcbs = ControlBlocks::new();
+        let pcb_id = established_pcb(&mut pcbs);
+        let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+        pcb.send_context.una = 100;
+        pcb.send_context.next = 200;
+        let src = ip::ip_addr_to_bytes("192.0.2.254").unwrap();
+        let mut deliver = |data: Vec<u8>, pcbs: &mut ControlBlocks| {
+            icmp::input(
+                &data,
+                data.len(),
+                src,
+                iface.unicast,
+                &mut device,
+                &iface,
+                &mut contexts,
+                pcbs,
+            )
+            .unwrap();
+        };
+
+        // Soft error is reported once.
+        deliver(
+            icmp_error_message(ICMP_TYPE_DEST_UNREACH, 1, 150),
+            &mut pcbs,
+        );
+        assert_eq!(
+            TcpPcbState::Established,
+            pcbs.tcp_pcbs.entries[pcb_id].state
+        );
+        assert_eq!(
+            Some(SocketError::HostUnreachable),
+            take_error(pcb_id, &mut pcbs)
+        );
+        assert_eq!(None, take_error(pcb_id, &mut pcbs));
+
+        // Errors quoting a sequence number not in flight are ignored.
+        deliver(
+            icmp_error_message(ICMP_TYPE_DEST_UNREACH, 3, 300),
+            &mut pcbs,
+        );
+        assert_eq!(
+            TcpPcbState::Established,
+            pcbs.tcp_pcbs.entries[pcb_id].state
+        );
+
+        // Port unreachable aborts the connection.
+        deliver(
+            icmp_error_message(ICMP_TYPE_DEST_UNREACH, 3, 100),
+            &mut pcbs,
+        );
+        assert_eq!(TcpPcbState::Free, pcbs.tcp_pcbs.entries[pcb_id].state);
+        assert_eq!(
+            Some(SocketError::ConnectionRefused),
+            socket_error(pcb_id, &mut pcbs)
+        );
+    }
+
+    #[test]
+    fn test_segment_wire_format() {
+        let (mut device, mut contexts) = setup();
+        output_segment(
+            0x01020304,
+            0x05060708,
+            TcpFlag::ACK as u8,
+            0x1234,
+            b"hi".to_vec(),
+            &IPEndpoint::new_from_str("192.0.2.2", 7),
+            &IPEndpoint::new_from_str("192.0.2.1", 50000),
+            &mut device,
+            &mut contexts,
+        );
+        let frame = device
+            .driver_data
+            .as_mut()
+            .unwrap()
+            .tx_frames
+            .pop_front()
+            .unwrap();
+        let ip = &frame[ethernet::ETH_HDR_SIZE..];
+        assert_eq!([192, 0, 2, 2, 192, 0, 2, 1], ip[12..20]);
+        let tcp = &ip[size_of::<IPHeader>()..];
+        let expected = [
+            0x00, 0x07, 0xc3, 0x50, // ports 7 -> 50000
+            0x01, 0x02, 0x03, 0x04, // seq
+            0x05, 0x06, 0x07, 0x08, // ack
+            0x50, 0x10, 0x12, 0x34, // offset, flags, window
+        ];
+        assert_eq!(expected, tcp[..16]);
+        assert_eq!(b"hi", &tcp[20..22]);
+    }
+
+    /// TCP header and options of the last frame transmitted.
+    fn last_segment(device: &NetDevice) -> (TcpHeader, TcpOptions) {
+        let frame = device
+            .driver_data
+            .as_ref()
+            .unwrap()
+            .tx_frames
+            .back()
+            .unwrap();
+        let tcp = &frame[ethernet::ETH_HDR_SIZE + size_of::<IPHeader>()..];
+        let header = unsafe { bytes_to_struct::<TcpHeader>(tcp) };
+        let header_len = ((header.offset >> 4) << 2) as usize;
+        let options = tcp_options::parse(&tcp[size_of::<TcpHeader>()..header_len]);
+        (header, options)
+    }
+
+    #[test]
+    fn test_out_of_order_reassembly() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs = ControlBlocks::new();
+        let pcb_id = established_pcb(&mut pcbs);
+        let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+        pcb.recv_context.next = 1000;
+        pcb.recv_context.window = PCB_BUF_LEN as u16;
+        pcb.send_context.una = 1;
+        pcb.send_context.next = 1;
+        pcb.sack_permitted = true;
+        let mut arrive = |seq_num, data: &[u8], pcbs: &mut ControlBlocks| {
+            let seg = TcpSegmentInfo {
+                seq_num,
+                ack_num: 1,
+                len: data.len() as u16,
+                window: PCB_BUF_LEN as u16,
+                ..Default::default()
+            };
+            let local = IPEndpoint::new_from_str("192.0.2.2", 7);
+            let remote = IPEndpoint::new_from_str("192.0.2.1", 50000);
+            segment_arrives(
+                seg,
+                TcpFlag::ACK as u8,
+                data,
+                data.len(),
+                local,
+                remote,
+                &mut device,
+                &mut contexts,
+                pcbs,
+            );
+            last_segment(&device)
+        };
+
+        arrive(1200, &[2; 100], &mut pcbs);
+        let (header, options) = arrive(1100, &[1; 100], &mut pcbs);
+        assert_eq!(1000, u32::from_be(header.ack_num));
+        assert_eq!(vec![(1100, 1300)], options.sack_blocks);
+        let pcb = &pcbs.tcp_pcbs.entries[pcb_id];
+        assert!(pcb.buf.is_empty());
+        assert_eq!(2, pcb.reassembly.out_of_order);
+        assert_eq!(2, pcb.reassembly.held);
+        assert_eq!(300, pcb.reassembly.gap);
+
+        // In-order segment fills the gap and the held segments follow it.
+        let (header, options) = arrive(1000, &[0; 100], &mut pcbs);
+        assert_eq!(1300, u32::from_be(header.ack_num));
+        assert!(options.sack_blocks.is_empty());
+        let pcb = &pcbs.tcp_pcbs.entries[pcb_id];
+        let expected: Vec<u8> = [[0; 100], [1; 100], [2; 100]].concat();
+        assert_eq!(expected, pcb.buf);
+        assert_eq!(0, pcb.reassembly.held);
+        assert_eq!(0, pcb.reassembly.gap);
+        assert_eq!(300, pcb.reassembly.max_gap);
+        assert!(pcbs.tcp_pcbs.show().contains("Established"));
+
+        // Beyond the budget, the segment furthest ahead gives way to the one nearer.
+        let chunk = TCP_HELD_LEN_MAX / 2;
+        arrive(3000 + chunk as u32, &vec![4; chunk], &mut pcbs);
+        arrive(2000 + chunk as u32, &vec![3; chunk], &mut pcbs);
+        arrive(1400, &[5; 100], &mut pcbs);
+        let pcb = &pcbs.tcp_pcbs.entries[pcb_id];
+        assert_eq!(2, pcb.reassembly.held);
+        assert_eq!(1, pcb.reassembly.dropped);
+        arrive(5000 + 2 * chunk as u32, &vec![6; chunk], &mut pcbs);
+        let pcb = &pcbs.tcp_pcbs.entries[pcb_id];
+        assert_eq!(2, pcb.reassembly.held);
+        assert_eq!(2, pcb.reassembly.dropped);
+        assert_eq!(Some(&1400), pcb.held.front().map(|(seq, _)| seq));
+    }
+
+    #[test]
+    fn test_fin_ahead_of_gap() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs = ControlBlocks::new();
+        let pcb_id = established_pcb(&mut pcbs);
+        let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+        pcb.recv_context.next = 1000;
+        pcb.recv_context.window = PCB_BUF_LEN as u16;
+        pcb.send_context.una = 1;
+        pcb.send_context.next = 1;
+        let mut arrive = |seq_num, flags, data: &[u8], pcbs: &mut ControlBlocks| {
+            let seg = TcpSegmentInfo {
+                seq_num,
+                ack_num: 1,
+                len: data.len() as u16 + tcp_flag_exists(flags, TcpFlag::FIN) as u16,
+                window: PCB_BUF_LEN as u16,
+                ..Default::default()
+            };
+            let local = IPEndpoint::new_from_str("192.0.2.2", 7);
+            let remote = IPEndpoint::new_from_str("192.0.2.1", 50000);
+            segment_arrives(
+                seg,
+                flags,
+                data,
+                data.len(),
+                local,
+                remote,
+                &mut device,
+                &mut contexts,
+                pcbs,
+            );
+            last_segment(&device)
+        };
+        let fin = TcpFlag::ACK as u8 | TcpFlag::FIN as u8;
+
+        // FIN past the gap is acknowledged up to recv.next only, leaving the connection open.
+        arrive(1100, TcpFlag::ACK as u8, &[1; 100], &mut pcbs);
+        let (header, _) = arrive(1200, fin, &[], &mut pcbs);
+        assert_eq!(1000, u32::from_be(header.ack_num));
+        let pcb = &pcbs.tcp_pcbs.entries[pcb_id];
+        assert_eq!(TcpPcbState::Established, pcb.state);
+        assert_eq!(1000, pcb.recv_context.next);
+
+        // Once the gap is filled, the retransmitted FIN ends the whole stream.
+        arrive(1000, TcpFlag::ACK as u8, &[0; 100], &mut pcbs);
+        let (header, _) = arrive(1200, fin, &[], &mut pcbs);
+        assert_eq!(1201, u32::from_be(header.ack_num));
+        let pcb = &pcbs.tcp_pcbs.entries[pcb_id];
+        assert_eq!(TcpPcbState::CloseWait, pcb.state);
+        assert_eq!([[0; 100], [1; 100]].concat(), pcb.buf);
+    }
+
+    #[test]
+    fn test_retransmission_trimming() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs = ControlBlocks::new();
+        let pcb_id = established_pcb(&mut pcbs);
+        let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+        pcb.recv_context.next = 1000;
+        pcb.recv_context.window = PCB_BUF_LEN as u16;
+        pcb.send_context.una = 1;
+        pcb.send_context.next = 1;
+        let mut arrive = |seq_num: u32, data: &[u8], pcbs: &mut ControlBlocks| {
+            let seg = TcpSegmentInfo {
+                seq_num,
+                ack_num: 1,
+                len: data.len() as u16,
+                window: PCB_BUF_LEN as u16,
+                ..Default::default()
+            };
+            segment_arrives(
+                seg,
+                TcpFlag::ACK as u8,
+                data,
+                data.len(),
+                IPEndpoint::new_from_str("192.0.2.2", 7),
+                IPEndpoint::new_from_str("192.0.2.1", 50000),
+                &mut device,
+                &mut contexts,
+                pcbs,
+            );
+        };
+        let stream =
+            |range: std::ops::Range<u32>| -> Vec<u8> { range.map(|seq| seq as u8).collect() };
+
+        // Whole and partial retransmissions of data buffered already
+        arrive(1000, &stream(1000..1100), &mut pcbs);
+        arrive(1000, &stream(1000..1100), &mut pcbs);
+        arrive(1050, &stream(1050..1150), &mut pcbs);
+        let pcb = &pcbs.tcp_pcbs.entries[pcb_id];
+        assert_eq!(stream(1000..1150), pcb.buf);
+        assert_eq!(1150, pcb.recv_context.next);
+        assert_eq!(150, pcb.reassembly.trimmed);
+
+        // Retransmissions overlapping held data are held once.
+        arrive(1200, &stream(1200..1300), &mut pcbs);
+        arrive(1250, &stream(1250..1350), &mut pcbs);
+        arrive(1200, &stream(1200..1300), &mut pcbs);
+        let pcb = &pcbs.tcp_pcbs.entries[pcb_id];
+        let held: usize = pcb.held.iter().map(|(_, data)| data.len()).sum();
+        assert_eq!(150, held);
+        assert_eq!(300, pcb.reassembly.trimmed);
+
+        arrive(1100, &stream(1100..1200), &mut pcbs);
+        let pcb = &pcbs.tcp_pcbs.entries[pcb_id];
+        assert_eq!(stream(1000..1350), pcb.buf);
+        assert_eq!(1350, pcb.recv_context.next);
+        assert_eq!(350, pcb.reassembly.trimmed);
+    }
+
+    #[test]
+    fn test_seq_arithmetic() {
+        assert!(seq_lt(1, 2));
+        assert!(!seq_lt(2, 2));
+        assert!(seq_leq(2, 2));
+        assert!(seq_lt(u32::MAX, 0));
+        assert!(seq_lt(u32::MAX - 10, 10));
+        assert!(!seq_lt(10, u32::MAX - 10));
+        assert!(seq_leq(u32::MAX, 0));
+        assert!(seq_between(u32::MAX - 10, u32::MAX, 10));
+        assert!(seq_between(u32::MAX - 10, 0, 10));
+        assert!(seq_between(u32::MAX - 10, u32::MAX - 10, 10));
+        assert!(!seq_between(u32::MAX - 10, 10, 10));
+        assert!(!seq_between(u32::MAX - 10, u32::MAX - 11, 10));
+    }
+
+    #[test]
+    fn test_seq_wrap_around() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs = ControlBlocks::new();
+        let pcb_id = established_pcb(&mut pcbs);
+        let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+        pcb.recv_context.next = u32::MAX - 99;
+        pcb.recv_context.window = PCB_BUF_LEN as u16;
+        pcb.send_context.una = u32::MAX - 4;
+        pcb.send_context.next = 5;
+        pcb.send_context.wl1 = u32::MAX - 99;
+        pcb.send_context.wl2 = u32::MAX - 4;
+        pcb.send_buf = b"0123456789".to_vec();
+        pcb.add_data_queue(u32::MAX - 4, TcpFlag::ACK as u8, 10);
+        pcb.sack_permitted = true;
+        let mut arrive = |seq_num, ack_num, data: &[u8], pcbs: &mut ControlBlocks| {
+            let seg = TcpSegmentInfo {
+                seq_num,
+                ack_num,
+                len: data.len() as u16,
+                window: PCB_BUF_LEN as u16,
+                ..Default::default()
+            };
+            segment_arrives(
+                seg,
+                TcpFlag::ACK as u8,
+                data,
+                data.len(),
+                IPEndpoint::new_from_str("192.0.2.2", 7),
+                IPEndpoint::new_from_str("192.0.2.1", 50000),
+                &mut device,
+                &mut contexts,
+                pcbs,
+            );
+            last_segment(&device)
+        };
+
+        // Held beyond the wrap, then delivered by the segment before it.
+        let (header, options) = arrive(50, u32::MAX - 4, &[2; 50], &mut pcbs);
+        assert_eq!(u32::MAX - 99, u32::from_be(header.ack_num));
+        assert_eq!(vec![(50, 100)], options.sack_blocks);
+        assert_eq!(200, pcbs.tcp_pcbs.entries[pcb_id].reassembly.gap);
+        let (header, _) = arrive(u32::MAX - 99, u32::MAX - 4, &[1; 150], &mut pcbs);
+        assert_eq!(100, u32::from_be(header.ack_num));
+        let pcb = &pcbs.tcp_pcbs.entries[pcb_id];
+        let expected: Vec<u8> = [vec![1; 150], vec![2; 50]].concat();
+        assert_eq!(expected, pcb.buf);
+        assert_eq!(0, pcb.reassembly.held);
+
+        // ACK beyond the wrap acknowledges data sent before it.
+        arrive(100, 5, &[], &mut pcbs);
+        let pcb = &pcbs.tcp_pcbs.entries[pcb_id];
+        assert_eq!(5, pcb.send_context.una);
+        assert!(pcb.send_buf.is_empty());
+        assert!(pcb.data_queue.entries.is_empty());
+        assert_eq!((100, 5), (pcb.send_context.wl1, pcb.send_context.wl2));
+    }
+
+    #[test]
+    fn test_delayed_ack() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs = ControlBlocks::new();
+        let pcb_id = established_pcb(&mut pcbs);
+        let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+        pcb.recv_context.next = 1000;
+        pcb.recv_context.window = PCB_BUF_LEN as u16;
+        pcb.send_context.una = 1;
+        pcb.send_context.next = 1;
+        let mut arrive = |seq_num, pcbs: &mut ControlBlocks| {
+            let seg = TcpSegmentInfo {
+                seq_num,
+                ack_num: 1,
+                len: 100,
+                window: PCB_BUF_LEN as u16,
+                ..Default::default()
+            };
+            let local = IPEndpoint::new_from_str("192.0.2.2", 7);
+            let remote = IPEndpoint::new_from_str("192.0.2.1", 50000);
+            segment_arrives(
+                seg,
+                TcpFlag::ACK as u8,
+                &[0; 100],
+                100,
+                local,
+                remote,
+                &mut device,
+                &mut contexts,
+                pcbs,
+            );
+            device.driver_data.as_mut().unwrap().tx_frames.len()
+        };
+
+        // ACK of every second segment
+        assert_eq!(0, arrive(1000, &mut pcbs));
+        assert_eq!(1, arrive(1100, &mut pcbs));
+        assert_eq!(1, arrive(1200, &mut pcbs));
+
+        // ACK on the timer
+        expire_timers(&mut pcbs.tcp_pcbs, &mut device, &mut contexts);
+        assert_eq!(1, device.driver_data.as_ref().unwrap().tx_frames.len());
+        let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+        *pcb.delayed_ack.as_mut().unwrap() -= Duration::from_millis(TCP_DELAYED_ACK_MILLIS);
+        expire_timers(&mut pcbs.tcp_pcbs, &mut device, &mut contexts);
+        assert_eq!(2, device.driver_data.as_ref().unwrap().tx_frames.len());
+        let (header, _) = last_segment(&device);
+        assert_eq!(1300, u32::from_be(header.ack_num));
+        assert!(pcbs.tcp_pcbs.entries[pcb_id].delayed_ack.is_none());
+    }
+
+    #[test]
+    fn test_sacked_not_retransmitted() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs = ControlBlocks::new();
+        let pcb_id = established_pcb(&mut pcbs);
+        let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+        pcb.send_buf = b"helloworld".to_vec();
+        pcb.send_context.una = 1;
+        pcb.send_context.next = 11;
+        pcb.add_data_queue(1, TcpFlag::ACK as u8, 5);
+        pcb.add_data_queue(6, TcpFlag::ACK as u8, 5);
+        pcb.mark_sacked(&[(6, 11)]);
+        for queue in pcb.data_queue.entries.iter_mut() {
+            queue.last_sent_at -= queue.retry_interval;
+        }
+
+        expire_timers(&mut pcbs.tcp_pcbs, &mut device, &mut contexts);
+        let (header, _) = last_segment(&device);
+        assert_eq!(1, device.driver_data.as_ref().unwrap().tx_frames.len());
+        assert_eq!(1, u32::from_be(header.seq_num));
+    }
+
+    #[test]
+    fn test_holes_around_sacked_retransmitted() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs = ControlBlocks::new();
+        let pcb_id = established_pcb(&mut pcbs);
+        let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+        pcb.send_buf = b"helloworld".to_vec();
+        pcb.send_context.una = 1;
+        pcb.send_context.next = 11;
+        pcb.add_data_queue(1, TcpFlag::ACK as u8, 3);
+        pcb.add_data_queue(4, TcpFlag::ACK as u8, 3);
+        pcb.add_data_queue(7, TcpFlag::ACK as u8, 4);
+        pcb.mark_sacked(&[(4, 7)]);
+        for queue in pcb.data_queue.entries.iter_mut() {
+            queue.last_sent_at -= queue.retry_interval;
+        }
+
+        // Both holes are retransmitted and the deadline moves past now.
+        expire_timers(&mut pcbs.tcp_pcbs, &mut device, &mut contexts);
+        let tx_frames = &device.driver_data.as_ref().unwrap().tx_frames;
+        let offset = ethernet::ETH_HDR_SIZE + size_of::<IPHeader>();
+        let seq_nums: Vec<u32> = tx_frames
+            .iter()
+            .map(|frame| {
+                let header = unsafe { bytes_to_struct::<TcpHeader>(&frame[offset..]) };
+                u32::from_be(header.seq_num)
+            })
+            .collect();
+        assert_eq!(vec![1, 7], seq_nums);
+        let deadline = pcbs.tcp_pcbs.timers.deadline(pcb_id).unwrap();
+        assert!(deadline > SystemTime::now());
+    }
+
+    #[test]
+    fn test_netstat() {
+        let mut pcbs = ControlBlocks::new();
+        established_pcb(&mut pcbs);
+        let (_, pcb) = pcbs.tcp_pcbs.new_entry().unwrap();
+        pcb.state = TcpPcbState::Listen;
+        pcb.local = IPEndpoint::new_from_str("0.0.0.0", 80);
+        let netstat = pcbs.netstat();
+        let rows: Vec<Vec<&str>> = netstat
+            .lines()
+            .map(|row| row.split_whitespace().collect())
+            .collect();
+        assert_eq!(3, rows.len());
+        assert_eq!(
+            vec!["tcp", "192.0.2.2:7", "192.0.2.1:50000", "ESTABLISHED"],
+            rows[1]
+        );
+        assert_eq!(vec!["tcp", "0.0.0.0:80", "0.0.0.0:*", "LISTEN"], rows[2]);
+    }
+
+    #[test]
+    fn test_conn_id_not_reused() {
+        let mut pcbs = ControlBlocks::new();
+        let first = established_pcb(&mut pcbs);
+        let first_conn = pcbs.tcp_pcbs.entries[first].conn_id;
+        pcbs.tcp_pcbs.entries[first].release();
+        // PCB slot gets reused, connection ID does not.
+        let second = established_pcb(&mut pcbs);
+        assert_eq!(first, second);
+        let second_conn = pcbs.tcp_pcbs.entries[second].conn_id;
+        assert!(second_conn > first_conn);
+        assert!(pcbs
+            .tcp_pcbs
+            .stats()
+            .contains(&format!("conn={second_conn} ")));
+    }
+
+    #[test]
+    fn test_first_syn_sent_on_arp_reply() {
+        let mut device = test_device("192.0.2.2/24");
+        let interface = device.get_interface(NetInterfaceFamily::IP).unwrap();
+        let mut routes = IPRoutes::new();
+        routes.register(IPRoute::interface_route(interface));
+        let mut contexts = ProtocolContexts::new(routes);
+        let mut pcbs = ControlBlocks::new();
+        let pcb_id = established_pcb(&mut pcbs);
+        let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+        pcb.state = TcpPcbState::SynSent;
+
+        // Cold cache: only an ARP request goes out and SYN waits for the reply.
+        output(pcb, TcpFlag::SYN as u8, vec![], &mut device, &mut contexts);
+        let tx_frames = &mut device.driver_data.as_mut().unwrap().tx_frames;
+        assert_eq!(1, tx_frames.len());
+        assert_eq!([0x08, 0x06], tx_frames.pop_front().unwrap()[12..14]);
+
+        let mut reply = [0; 28];
+        reply[..8].copy_from_slice(&[0x00, 0x01, 0x08, 0x00, 6, 4, 0x00, 0x02]);
+        reply[8..14].copy_from_slice(&[0x02, 0, 0, 0, 0, 0x01]);
+        reply[14..18].copy_from_slice(&[192, 0, 2, 1]);
+        reply[24..28].copy_from_slice(&[192, 0, 2, 2]);
+        arp::input(&reply, reply.len(), None, &mut device, &mut contexts).unwrap();
+
+        // SYN goes out on the reply without waiting for retransmission.
+        let frame = device
+            .driver_data
+            .as_mut()
+            .unwrap()
+            .tx_frames
+            .pop_front()
+            .unwrap();
+        let offset = ethernet::ETH_HDR_SIZE + size_of::<IPHeader>();
+        let header = unsafe { bytes_to_struct::<TcpHeader>(&frame[offset..]) };
+        assert_eq!(TcpFlag::SYN as u8, header.flags);
+        expire_timers(&mut pcbs.tcp_pcbs, &mut device, &mut contexts);
+        assert!(device.driver_data.as_ref().unwrap().tx_frames.is_empty());
+    }
+
+    #[test]
+    fn test_window_update_on_receive() {
+        let (device, contexts) = setup();
+        let mut devices = NetDevices::new();
+        devices.register(device);
+        let devices_arc = Arc::new(Mutex::new(devices));
+        let contexts_arc = Arc::new(Mutex::new(contexts));
+        let pcbs_arc = Arc::new(Mutex::new(ControlBlocks::new()));
+        let pcb_id = {
+            let pcbs = &mut pcbs_arc.lock().unwrap();
+            let pcb_id = established_pcb(pcbs);
+            let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+            pcb.buf = vec![0; PCB_BUF_LEN];
+            pcb.recv_context.window = 0;
+            pcb.adv_window = 0;
+            pcb_id
+        };
+        let mut read = |size| {
+            receive(
+                pcb_id,
+                size,
+                pcbs_arc.clone(),
+                devices_arc.clone(),
+                contexts_arc.clone(),
+            )
+            .unwrap();
+            let devices = &mut devices_arc.lock().unwrap();
+            let device = devices.get_mut_by_type(NetDeviceType::Ethernet).unwrap();
+            device.driver_data.as_mut().unwrap().tx_frames.pop_front()
+        };
+
+        // Window opened less than an MSS is not advertised.
+        assert!(read(100).is_none());
+        let frame = read(2000).unwrap();
+        let offset = ethernet::ETH_HDR_SIZE + size_of::<IPHeader>();
+        let header = unsafe { bytes_to_struct::<TcpHeader>(&frame[offset..]) };
+        assert_eq!(TcpFlag::ACK as u8, header.flags);
+        assert_eq!(2100, u16::from_be(header.window));
+    }
+
+    #[test]
+    fn test_events() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs = ControlBlocks::new();
+        let pcb_id = established_pcb(&mut pcbs);
+        let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+        pcb.recv_context.next = 1000;
+        pcb.recv_context.window = PCB_BUF_LEN as u16;
+        pcb.send_context.una = 1;
+        pcb.send_context.next = 1;
+        pcb.buf = vec![0; 10];
+        let events = subscribe(pcb_id, &mut pcbs);
+        assert_eq!(
+            vec![TcpEvent::Connected, TcpEvent::DataReady(10)],
+            events.try_iter().collect::<Vec<_>>()
+        );
+
+        let mut arrive = |flags, data: &[u8], pcbs: &mut ControlBlocks| {
+            let seq_num = pcbs.tcp_pcbs.entries[pcb_id].recv_context.next;
+            let seg = TcpSegmentInfo {
+                seq_num,
+                ack_num: 1,
+                len: data.len() as u16 + tcp_flag_exists(flags, TcpFlag::FIN) as u16,
+                window: PCB_BUF_LEN as u16,
+                ..Default::default()
+            };
+            let local = IPEndpoint::new_from_str("192.0.2.2", 7);
+            let remote = IPEndpoint::new_from_str("192.0.2.1", 50000);
+            segment_arrives(
+                seg,
+                flags,
+                data,
+                data.len(),
+                local,
+                remote,
+                &mut device,
+                &mut contexts,
+                pcbs,
+            );
+        };
+        arrive(TcpFlag::ACK as u8, &[1; 100], &mut pcbs);
+        arrive(TcpFlag::ACK as u8 | TcpFlag::FIN as u8, &[], &mut pcbs);
+        assert_eq!(
+            vec![TcpEvent::DataReady(110), TcpEvent::PeerClosed],
+            events.try_iter().collect::<Vec<_>>()
+        );
+
+        // The subscription ends with the connection.
+        let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+        pcb.error = Some(SocketError::TimedOut);
+        pcb.release();
+        assert_eq!(
+            vec![TcpEvent::Closed(Some(SocketError::TimedOut))],
+            events.iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_urgent_data() {
+        let (mut device, mut contexts) = setup();
+        let mut arrive = |urg_ptr, data: &[u8], pcb_id: usize, pcbs: &mut ControlBlocks| {
+            let pcb = &pcbs.tcp_pcbs.entries[pcb_id];
+            let seg = TcpSegmentInfo {
+                seq_num: pcb.recv_context.next,
+                ack_num: 1,
+                len: data.len() as u16,
+                window: PCB_BUF_LEN as u16,
+                urg_ptr,
+                ..Default::default()
+            };
+            let flags = match urg_ptr {
+                0 => TcpFlag::ACK as u8,
+                _ => TcpFlag::ACK as u8 | TcpFlag::URG as u8,
+            };
+            let local = IPEndpoint::new_from_str("192.0.2.2", 7);
+            let remote = IPEndpoint::new_from_str("192.0.2.1", 50000);
+            segment_arrives(
+                seg,
+                flags,
+                data,
+                data.len(),
+                local,
+                remote,
+                &mut device,
+                &mut contexts,
+                pcbs,
+            );
+        };
+        let mut received = |policy| {
+            let mut pcbs = ControlBlocks::new();
+            let pcb_id = established_pcb(&mut pcbs);
+            set_urgent_policy(pcb_id, policy, &mut pcbs);
+            let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+            pcb.recv_context.next = 1000;
+            pcb.recv_context.window = PCB_BUF_LEN as u16;
+            pcb.send_context.una = 1;
+            pcb.send_context.next = 1;
+            let events = subscribe(pcb_id, &mut pcbs);
+            // Urgent data ending with "!" at the start of the next segment
+            arrive(5, b"abcd", pcb_id, &mut pcbs);
+            arrive(0, b"!efg", pcb_id, &mut pcbs);
+            assert!(events.try_iter().any(|event| event == TcpEvent::Urgent));
+            let pcb = &pcbs.tcp_pcbs.entries[pcb_id];
+            assert_eq!(1008, pcb.recv_context.next);
+            (pcb.buf.clone(), receive_oob(pcb_id, &mut pcbs))
+        };
+
+        assert_eq!((b"abcd!efg".to_vec(), None), received(UrgentPolicy::Inline));
+        assert_eq!(
+            (b"abcdefg".to_vec(), Some(b'!')),
+            received(UrgentPolicy::OutOfBand)
+        );
+        assert_eq!((b"abcdefg".to_vec(), None), received(UrgentPolicy::Discard));
+    }
+
+    #[test]
+    fn test_receive_window() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs = ControlBlocks::new();
+        let pcb_id = established_pcb(&mut pcbs);
+        set_recv_buffer_size(pcb_id, 1000, &mut pcbs);
+        let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+        assert_eq!(1000, pcb.recv_context.window);
+        pcb.recv_context.next = 1000;
+        pcb.send_context.una = 1;
+        pcb.send_context.next = 1;
+        pcb.ack_policy = AckPolicy::EverySegment;
+        let mut arrive = |seq_num, flags, data: &[u8], pcbs: &mut ControlBlocks| {
+            let seg = TcpSegmentInfo {
+                seq_num,
+                ack_num: 1,
+                len: data.len() as u16 + tcp_flag_exists(flags, TcpFlag::FIN) as u16,
+                window: PCB_BUF_LEN as u16,
+                ..Default::default()
+            };
+            let local = IPEndpoint::new_from_str("192.0.2.2", 7);
+            let remote = IPEndpoint::new_from_str("192.0.2.1", 50000);
+            segment_arrives(
+                seg,
+                flags,
+                data,
+                data.len(),
+                local,
+                remote,
+                &mut device,
+                &mut contexts,
+                pcbs,
+            );
+            last_segment(&device).0
+        };
+
+        let header = arrive(1000, TcpFlag::ACK as u8, &[1; 600], &mut pcbs);
+        assert_eq!(1600, u32::from_be(header.ack_num));
+        assert_eq!(400, u16::from_be(header.window));
+
+        // Exceeding the window, with FIN left for later.
+        let flags = TcpFlag::ACK as u8 | TcpFlag::FIN as u8;
+        let header = arrive(1600, flags, &[2; 600], &mut pcbs);
+        assert_eq!(2000, u32::from_be(header.ack_num));
+        assert_eq!(0, u16::from_be(header.window));
+        let pcb = &pcbs.tcp_pcbs.entries[pcb_id];
+        assert_eq!(TcpPcbState::Established, pcb.state);
+        assert_eq!([vec![1; 600], vec![2; 400]].concat(), pcb.buf);
+
+        // A connection reusing the PCB starts with the whole buffer free.
+        let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+        pcb.release();
+        let (_, pcb) = pcbs.tcp_pcbs.new_entry().unwrap();
+        pcb.open_recv_window();
+        assert!(pcb.buf.is_empty());
+        assert_eq!(PCB_BUF_LEN as u16, pcb.recv_context.window);
+    }
+
+    #[test]
+    fn test_send_limited_by_buffers() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs_arc = Arc::new(Mutex::new(ControlBlocks::new()));
+        let (pcb_id, other_id) = {
+            let pcbs = &mut pcbs_arc.lock().unwrap();
+            pcbs.memory_limit = Some(15000);
+            let pcb_id = established_pcb(pcbs);
+            set_send_buffer_size(pcb_id, 10000, pcbs);
+            (pcb_id, established_pcb(pcbs))
+        };
+        let mut send_data = |pcb_id, len| {
+            send(
+                pcb_id,
+                vec![0; len],
+                &mut device,
+                &mut contexts,
+                &mut pcbs_arc,
+            )
+        };
+
+        // Sent segments stay in the send buffer till acknowledged.
+        assert_eq!(Some(10000), send_data(pcb_id, 20000));
+        assert_eq!(Some(0), send_data(pcb_id, 20000));
+
+        // Memory of all PCBs is limited.
+        assert_eq!(Some(5000), send_data(other_id, 20000));
+        assert_eq!(Some(0), send_data(other_id, 20000));
+    }
+
+    #[test]
+    fn test_memory_limit_of_stack() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs_arc = Arc::new(Mutex::new(ControlBlocks::new()));
+        let pcb_id = {
+            let pcbs = &mut pcbs_arc.lock().unwrap();
+            pcbs.memory_limit = Some(1000);
+            established_pcb(pcbs)
+        };
+        let sent = send(
+            pcb_id,
+            vec![0; 2000],
+            &mut device,
+            &mut contexts,
+            &mut pcbs_arc,
+        );
+        assert_eq!(Some(1000), sent);
+
+        // New connections are refused at the limit.
+        let pcbs = &mut pcbs_arc.lock().unwrap();
+        let listener = open(pcbs);
+        bind(listener, IPEndpoint::new_from_str("192.0.2.2", 8), pcbs);
+        listen(listener, pcbs);
+        let seg = TcpSegmentInfo {
+            seq_num: 1000,
+            window: PCB_BUF_LEN as u16,
+            ..Default::default()
+        };
+        segment_arrives(
+            seg,
+            TcpFlag::SYN as u8,
+            &[],
+            0,
+            IPEndpoint::new_from_str("192.0.2.2", 8),
+            IPEndpoint::new_from_str("192.0.2.1", 50000),
+            &mut device,
+            &mut contexts,
+            pcbs,
+        );
+        assert_eq!(0, pcbs.tcp_pcbs.half_open(listener));
+        let stats = pcbs.memory_stats();
+        assert!(stats.contains("used: 1000 limit: 1000 refused: 1"));
+        assert!(stats.contains(&format!("tcp  {pcb_id:>3}: 1000")));
+    }
+
+    /// Moves segments transmitted by one end of a connection to TCP input of the other, dropping
+    /// the ones `lost` picks by their order. Returns the count transmitted.
+    fn deliver(
+        from: &mut NetDevice,
+        (src, dst): (&str, &str),
+        to: &mut NetDevice,
+        contexts: &mut ProtocolContexts,
+        pcbs: &mut ControlBlocks,
+        lost: &mut dyn FnMut(usize) -> bool,
+    ) -> usize {
+        let frames: Vec<Vec<u8>> = from
+            .driver_data
+            .as_mut()
+            .unwrap()
+            .tx_frames
+            .drain(..)
+            .collect();
+        let iface = to.get_interface(NetInterfaceFamily::IP).unwrap();
+        let (src, dst) = (
+            ip::ip_addr_to_bytes(src).unwrap(),
+            ip::ip_addr_to_bytes(dst).unwrap(),
+        );
+        for (i, frame) in frames.iter().enumerate() {
+            if lost(i) {
+                continue;
+            }
+            // Frames of short segments are padded.
+            let ip = &frame[ethernet::ETH_HDR_SIZE..];
+            let len = u16::from_be_bytes([ip[2], ip[3]]) as usize - size_of::<IPHeader>();
+            let tcp = &ip[size_of::<IPHeader>()..];
+            input(tcp, len, src, dst, to, &iface, contexts, pcbs).unwrap();
+        }
+        frames.len()
+    }
+
+    #[test]
+    fn test_send_larger_than_mss() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs_arc = Arc::new(Mutex::new(ControlBlocks::new()));
+        let pcb_id = {
+            let pcbs = &mut pcbs_arc.lock().unwrap();
+            let pcb_id = established_pcb(pcbs);
+            let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+            pcb.recv_context.next = 1000;
+            pcb.recv_context.window = PCB_BUF_LEN as u16;
+            pcb.send_context.una = 1;
+            pcb.send_context.next = 1;
+            pcb_id
+        };
+        // The peer acknowledging every segment
+        let mut peer_device = test_device("192.0.2.1/24");
+        let mut routes = IPRoutes::new();
+        let interface = peer_device.get_interface(NetInterfaceFamily::IP).unwrap();
+        routes.register(IPRoute::interface_route(interface));
+        let mut peer_contexts = ProtocolContexts::new(routes);
+        peer_contexts.arp_table.update(
+            ip::ip_addr_to_bytes("192.0.2.2").unwrap(),
+            [0x02, 0, 0, 0, 0, 0x02],
+        );
+        let mut peer_pcbs = ControlBlocks::new();
+        let (peer_id, peer) = peer_pcbs.tcp_pcbs.new_entry().unwrap();
+        peer.state = TcpPcbState::Established;
+        peer.local = IPEndpoint::new_from_str("192.0.2.1", 50000);
+        peer.remote = IPEndpoint::new_from_str("192.0.2.2", 7);
+        peer.recv_context.next = 1;
+        peer.recv_context.window = PCB_BUF_LEN as u16;
+        peer.send_context.una = 1000;
+        peer.send_context.next = 1000;
+        peer.send_context.window = PCB_BUF_LEN as u16;
+        peer.ack_policy = AckPolicy::EverySegment;
+        peer_pcbs.tcp_pcbs.index(peer_id);
+
+        let data: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        let sent = send(
+            pcb_id,
+            data.clone(),
+            &mut device,
+            &mut contexts,
+            &mut pcbs_arc,
+        );
+        assert_eq!(Some(5000), sent);
+        let pcbs = &mut pcbs_arc.lock().unwrap();
+        let mut exchange = |lost: &mut dyn FnMut(usize) -> bool,
+                            device: &mut NetDevice,
+                            contexts: &mut ProtocolContexts,
+                            pcbs: &mut ControlBlocks| {
+            let mut count = deliver(
+                device,
+                ("192.0.2.2", "192.0.2.1"),
+                &mut peer_device,
+                &mut peer_contexts,
+                &mut peer_pcbs,
+                lost,
+            );
+            count += deliver(
+                &mut peer_device,
+                ("192.0.2.1", "192.0.2.2"),
+                device,
+                contexts,
+                pcbs,
+                &mut |_| false,
+            );
+            (count, peer_pcbs.tcp_pcbs.entries[peer_id].buf.clone())
+        };
+
+        // The second segment is lost, and the others are held out of order.
+        let (mut count, _) = exchange(&mut |i| i == 1, &mut device, &mut contexts, pcbs);
+        while count > 0 {
+            count = exchange(&mut |_| false, &mut device, &mut contexts, pcbs).0;
+        }
+        let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+        assert_eq!(1461, pcb.send_context.una);
+        assert_eq!(5000 - 1460, pcb.send_buf.len());
+
+        // Retransmitted from the send buffer at the offset of the sequence number
+        for queue in pcb.data_queue.entries.iter_mut() {
+            queue.last_sent_at -= queue.retry_interval;
+        }
+        expire_timers(&mut pcbs.tcp_pcbs, &mut device, &mut contexts);
+        let mut received = vec![];
+        let mut count = 1;
+        while count > 0 {
+            (count, received) = exchange(&mut |_| false, &mut device, &mut contexts, pcbs);
+        }
+        assert_eq!(data, received);
+        let pcb = &pcbs.tcp_pcbs.entries[pcb_id];
+        assert_eq!(5001, pcb.send_context.una);
+        assert!(pcb.send_buf.is_empty() && pcb.data_queue.entries.is_empty());
+    }
+
+    #[test]
+    fn test_send_limited_by_cwnd() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs_arc = Arc::new(Mutex::new(ControlBlocks::new()));
+        let pcb_id = established_pcb(&mut pcbs_arc.lock().unwrap());
+
+        // Initial window of 1460 bytes MSS is 3 segments (RFC 5681).
+        let sent = send(
+            pcb_id,
+            vec![0; 10000],
+            &mut device,
+            &mut contexts,
+            &mut pcbs_arc,
+        );
+        assert_eq!(Some(10000), sent);
+        assert_eq!(3, device.driver_data.as_ref().unwrap().tx_frames.len());
+        let pcbs = &mut pcbs_arc.lock().unwrap();
+        assert_eq!(10000 - 3 * 1460, pcbs.tcp_pcbs.entries[pcb_id].unsent_len());
+    }
+
+    #[test]
+    fn test_zero_window_probe() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs_arc = Arc::new(Mutex::new(ControlBlocks::new()));
+        let pcb_id = {
+            let pcbs = &mut pcbs_arc.lock().unwrap();
+            let pcb_id = established_pcb(pcbs);
+            let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+            pcb.recv_context.next = 1000;
+            pcb.send_context.una = 1;
+            pcb.send_context.next = 1;
+            pcb.send_context.window = 0;
+            pcb_id
+        };
+        let frames = |device: &NetDevice| device.driver_data.as_ref().unwrap().tx_frames.len();
+        send(
+            pcb_id,
+            vec![7; 100],
+            &mut device,
+            &mut contexts,
+            &mut pcbs_arc,
+        );
+        assert_eq!(0, frames(&device));
+
+        // The first probe goes after RTO.
+        let pcbs = &mut pcbs_arc.lock().unwrap();
+        expire_timers(&mut pcbs.tcp_pcbs, &mut device, &mut contexts);
+        assert_eq!(0, frames(&device));
+        let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+        *pcb.persist_at.as_mut().unwrap() -= pcb.rtt.rto;
+        expire_timers(&mut pcbs.tcp_pcbs, &mut device, &mut contexts);
+        assert_eq!(1, frames(&device));
+        let pcb = &pcbs.tcp_pcbs.entries[pcb_id];
+        assert_eq!(2, pcb.send_context.next);
+        assert_eq!(99, pcb.unsent_len());
+
+        // Probe is retransmitted with backoff without giving up.
+        let queue = &mut pcbs.tcp_pcbs.entries[pcb_id].data_queue.entries[0];
+        let interval = queue.retry_interval;
+        queue.last_sent_at -= interval;
+        queue.first_sent_at -= Duration::from_secs(TCP_RETRANSMIT_TIMOUT_SEC);
+        expire_timers(&mut pcbs.tcp_pcbs, &mut device, &mut contexts);
+        assert_eq!(2, frames(&device));
+        let pcb = &pcbs.tcp_pcbs.entries[pcb_id];
+        assert_eq!(TcpPcbState::Established, pcb.state);
+        assert_eq!(interval * 2, pcb.data_queue.entries[0].retry_interval);
+
+        // Window update ACK of the probe byte reopens the window.
+        let seg = TcpSegmentInfo {
+            seq_num: 1000,
+            ack_num: 2,
+            window: 1000,
+            ..Default::default()
+        };
+        let local = IPEndpoint::new_from_str("192.0.2.2", 7);
+        let remote = IPEndpoint::new_from_str("192.0.2.1", 50000);
+        segment_arrives(
+            seg,
+            TcpFlag::ACK as u8,
+            &[],
+            0,
+            local,
+            remote,
+            &mut device,
+            &mut contexts,
+            pcbs,
+        );
+        let pcb = &pcbs.tcp_pcbs.entries[pcb_id];
+        assert_eq!(0, pcb.unsent_len());
+        assert_eq!(99, pcb.send_buf.len());
+        assert_eq!(101, pcb.send_context.next);
+        assert_eq!(3, frames(&device));
+    }
+
+    #[test]
+    fn test_nagle() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs_arc = Arc::new(Mutex::new(ControlBlocks::new()));
+        let pcb_id = established_pcb(&mut pcbs_arc.lock().unwrap());
+        let mut write = |data: Vec<u8>, pcbs_arc: &mut Arc<Mutex<ControlBlocks>>| {
+            send(pcb_id, data, &mut device, &mut contexts, pcbs_arc);
+            device.driver_data.as_ref().unwrap().tx_frames.len()
+        };
+
+        // Small writes are coalesced while data is in flight.
+        assert_eq!(1, write(vec![0; 100], &mut pcbs_arc));
+        assert_eq!(1, write(vec![0; 100], &mut pcbs_arc));
+        assert_eq!(1, write(vec![0; 100], &mut pcbs_arc));
+        assert_eq!(
+            200,
+            pcbs_arc.lock().unwrap().tcp_pcbs.entries[pcb_id].unsent_len()
+        );
+        // A full segment is not held, but the rest smaller than MSS is.
+        assert_eq!(2, write(vec![0; 1460], &mut pcbs_arc));
+        assert_eq!(
+            200,
+            pcbs_arc.lock().unwrap().tcp_pcbs.entries[pcb_id].unsent_len()
+        );
+
+        set_no_delay(pcb_id, true, &mut pcbs_arc.lock().unwrap());
+        assert_eq!(3, write(vec![0; 10], &mut pcbs_arc));
+        assert_eq!(
+            0,
+            pcbs_arc.lock().unwrap().tcp_pcbs.entries[pcb_id].unsent_len()
+        );
+    }
+
+    #[test]
+    fn test_write_coalescing() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs_arc = Arc::new(Mutex::new(ControlBlocks::new()));
+        let pcb_id = established_pcb(&mut pcbs_arc.lock().unwrap());
+        {
+            let pcbs = &mut pcbs_arc.lock().unwrap();
+            set_no_delay(pcb_id, true, pcbs);
+            set_coalesce(pcb_id, true, pcbs, &mut device, &mut contexts);
+        }
+        let sent = |device: &NetDevice| device.driver_data.as_ref().unwrap().tx_frames.len();
+
+        // Small writes are held even without data in flight, till they make a full segment.
+        send(
+            pcb_id,
+            vec![1; 100],
+            &mut device,
+            &mut contexts,
+            &mut pcbs_arc,
+        );
+        send(
+            pcb_id,
+            vec![2; 100],
+            &mut device,
+            &mut contexts,
+            &mut pcbs_arc,
+        );
+        assert_eq!(0, sent(&device));
+        send(
+            pcb_id,
+            vec![3; 1400],
+            &mut device,
+            &mut contexts,
+            &mut pcbs_arc,
+        );
+        assert_eq!(1, sent(&device));
+
+        // Pushed, or sent anyway after a while.
+        {
+            let pcbs = &mut pcbs_arc.lock().unwrap();
+            assert_eq!(140, pcbs.tcp_pcbs.entries[pcb_id].unsent_len());
+            push(pcb_id, pcbs, &mut device, &mut contexts);
+            assert_eq!(2, sent(&device));
+            assert_eq!(0, pcbs.tcp_pcbs.entries[pcb_id].unsent_len());
+        }
+        send(
+            pcb_id,
+            vec![4; 10],
+            &mut device,
+            &mut contexts,
+            &mut pcbs_arc,
+        );
+        assert_eq!(2, sent(&device));
+        {
+            let pcbs = &mut pcbs_arc.lock().unwrap();
+            let due = pcbs.tcp_pcbs.entries[pcb_id].coalesce_at.unwrap();
+            assert!(pcbs.tcp_pcbs.timers.deadline(pcb_id).unwrap() <= due);
+            pcbs.tcp_pcbs.entries[pcb_id].coalesce_at =
+                Some(due - Duration::from_millis(TCP_COALESCE_MILLIS));
+            expire_timers(&mut pcbs.tcp_pcbs, &mut device, &mut contexts);
+            assert_eq!(3, sent(&device));
+        }
+
+        // Closing sends data held with FIN.
+        send(
+            pcb_id,
+            vec![5; 10],
+            &mut device,
+            &mut contexts,
+            &mut pcbs_arc,
+        );
+        assert_eq!(3, sent(&device));
+        let pcbs = &mut pcbs_arc.lock().unwrap();
+        shutdown(pcb_id, Shutdown::Write, pcbs, &mut device, &mut contexts).unwrap();
+        assert_eq!(5, sent(&device));
+    }
+
+    /// Delivers a segment from the peer of `established_pcb`.
+    fn peer_segment(
+        seq_num: u32,
+        ack_num: u32,
+        flags: u8,
+        window: u16,
+        device: &mut NetDevice,
+        contexts: &mut ProtocolContexts,
+        pcbs: &mut ControlBlocks,
+    ) {
+        let seg = TcpSegmentInfo {
+            seq_num,
+            ack_num,
+            len: tcp_flag_exists(flags, TcpFlag::FIN) as u16,
+            window,
+            ..Default::default()
+        };
+        segment_arrives(
+            seg,
+            flags,
+            &[],
+            0,
+            IPEndpoint::new_from_str("192.0.2.2", 7),
+            IPEndpoint::new_from_str("192.0.2.1", 50000),
+            device,
+            contexts,
+            pcbs,
+        );
+    }
+
+    #[test]
+    fn test_active_close() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs = ControlBlocks::new();
+        let (sender, receiver) = mpsc::channel();
+        let pcb_id = established_pcb(&mut pcbs);
+        let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+        pcb.recv_context.next = 1000;
+        pcb.recv_context.window = PCB_BUF_LEN as u16;
+        pcb.send_context.una = 1;
+        pcb.send_context.next = 1;
+        pcb.sender = Some(sender);
+
+        close(pcb_id, &mut pcbs, &mut device, &mut contexts);
+        let (header, _) = last_segment(&device);
+        assert_eq!(TcpFlag::FIN as u8 | TcpFlag::ACK as u8, header.flags);
+        assert_eq!(TcpPcbState::FinWait1, pcbs.tcp_pcbs.entries[pcb_id].state);
+
+        let ack = TcpFlag::ACK as u8;
+        peer_segment(1000, 2, ack, 1000, &mut device, &mut contexts, &mut pcbs);
+        assert_eq!(TcpPcbState::FinWait2, pcbs.tcp_pcbs.entries[pcb_id].state);
+
+        let fin = TcpFlag::FIN as u8 | ack;
+        peer_segment(1000, 2, fin, 1000, &mut device, &mut contexts, &mut pcbs);
+        let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+        assert_eq!(TcpPcbState::TimeWait, pcb.state);
+        assert_eq!(1001, pcb.recv_context.next);
+        assert!(receiver.recv().unwrap());
+
+        // Released when TIME-WAIT elapses.
+        *pcb.wait_time.as_mut().unwrap() -= Duration::from_secs(TCP_TIMEWAIT_SEC);
+        expire_timers(&mut pcbs.tcp_pcbs, &mut device, &mut contexts);
+        assert_eq!(TcpPcbState::Free, pcbs.tcp_pcbs.entries[pcb_id].state);
+    }
+
+    #[test]
+    fn test_passive_close_after_data() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs_arc = Arc::new(Mutex::new(ControlBlocks::new()));
+        let pcb_id = {
+            let pcbs = &mut pcbs_arc.lock().unwrap();
+            let pcb_id = established_pcb(pcbs);
+            let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+            pcb.recv_context.next = 1000;
+            pcb.recv_context.window = PCB_BUF_LEN as u16;
+            pcb.send_context.una = 1;
+            pcb.send_context.next = 1;
+            pcb.send_context.window = 0;
+            pcb_id
+        };
+        send(
+            pcb_id,
+            vec![7; 100],
+            &mut device,
+            &mut contexts,
+            &mut pcbs_arc,
+        );
+        let pcbs = &mut pcbs_arc.lock().unwrap();
+        let ack = TcpFlag::ACK as u8;
+        let fin = TcpFlag::FIN as u8 | ack;
+        peer_segment(1000, 1, fin, 0, &mut device, &mut contexts, pcbs);
+        assert_eq!(TcpPcbState::CloseWait, pcbs.tcp_pcbs.entries[pcb_id].state);
+
+        // FIN waits for data buffered.
+        let frames = |device: &NetDevice| device.driver_data.as_ref().unwrap().tx_frames.len();
+        let sent = frames(&device);
+        close(pcb_id, pcbs, &mut device, &mut contexts);
+        assert_eq!(sent, frames(&device));
+        assert_eq!(TcpPcbState::LastAck, pcbs.tcp_pcbs.entries[pcb_id].state);
+
+        peer_segment(1001, 1, ack, 1000, &mut device, &mut contexts, pcbs);
+        let (header, _) = last_segment(&device);
+        assert_eq!(TcpFlag::FIN as u8 | ack, header.flags);
+        assert_eq!(102, pcbs.tcp_pcbs.entries[pcb_id].send_context.next);
+
+        peer_segment(1001, 101, ack, 1000, &mut device, &mut contexts, pcbs);
+        assert_eq!(TcpPcbState::LastAck, pcbs.tcp_pcbs.entries[pcb_id].state);
+        peer_segment(1001, 102, ack, 1000, &mut device, &mut contexts, pcbs);
+        assert_eq!(TcpPcbState::Free, pcbs.tcp_pcbs.entries[pcb_id].state);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs = ControlBlocks::new();
+        let pcb_id = established_pcb(&mut pcbs);
+        let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+        pcb.recv_context.next = 1000;
+        pcb.recv_context.window = PCB_BUF_LEN as u16;
+        pcb.send_context.una = 1;
+        pcb.send_context.next = 1;
+        let data_arrives = |seq_num: u32,
+                            device: &mut NetDevice,
+                            contexts: &mut ProtocolContexts,
+                            pcbs: &mut ControlBlocks| {
+            let seg = TcpSegmentInfo {
+                seq_num,
+                ack_num: 2,
+                len: 100,
+                window: 1000,
+                ..Default::default()
+            };
+            segment_arrives(
+                seg,
+                TcpFlag::ACK as u8,
+                &[7; 100],
+                100,
+                IPEndpoint::new_from_str("192.0.2.2", 7),
+                IPEndpoint::new_from_str("192.0.2.1", 50000),
+                device,
+                contexts,
+                pcbs,
+            );
+        };
+
+        // Data keeps arriving after FIN.
+        shutdown(
+            pcb_id,
+            Shutdown::Write,
+            &mut pcbs,
+            &mut device,
+            &mut contexts,
+        )
+        .unwrap();
+        assert_eq!(TcpPcbState::FinWait1, pcbs.tcp_pcbs.entries[pcb_id].state);
+        data_arrives(1000, &mut device, &mut contexts, &mut pcbs);
+        let pcb = &pcbs.tcp_pcbs.entries[pcb_id];
+        assert_eq!(TcpPcbState::FinWait2, pcb.state);
+        assert_eq!(100, pcb.buf.len());
+
+        // Data is acknowledged but discarded after shutting down receiving.
+        shutdown(
+            pcb_id,
+            Shutdown::Read,
+            &mut pcbs,
+            &mut device,
+            &mut contexts,
+        )
+        .unwrap();
+        data_arrives(1100, &mut device, &mut contexts, &mut pcbs);
+        let pcb = &pcbs.tcp_pcbs.entries[pcb_id];
+        assert_eq!(100, pcb.buf.len());
+        assert_eq!(1200, pcb.recv_context.next);
+
+        // No connection to shut down once released.
+        pcbs.tcp_pcbs.entries[pcb_id].release();
+        assert!(shutdown(
+            pcb_id,
+            Shutdown::Both,
+            &mut pcbs,
+            &mut device,
+            &mut contexts
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_receive_after_shutdown_and_peer_fin() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs = ControlBlocks::new();
+        let pcb_id = established_pcb(&mut pcbs);
+        let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+        pcb.recv_context.next = 1000;
+        pcb.recv_context.window = PCB_BUF_LEN as u16;
+        pcb.send_context.una = 1;
+        pcb.send_context.next = 1;
+        shutdown(
+            pcb_id,
+            Shutdown::Write,
+            &mut pcbs,
+            &mut device,
+            &mut contexts,
+        )
+        .unwrap();
+
+        // The peer acknowledges our FIN, sends data and its own FIN.
+        for (seq_num, flags, data) in [
+            (1000, TcpFlag::ACK as u8, &[7; 100][..]),
+            (1100, TcpFlag::ACK as u8 | TcpFlag::FIN as u8, &[][..]),
+        ] {
+            let seg = TcpSegmentInfo {
+                seq_num,
+                ack_num: 2,
+                len: data.len() as u16 + tcp_flag_exists(flags, TcpFlag::FIN) as u16,
+                window: 1000,
+                ..Default::default()
+            };
+            segment_arrives(
+                seg,
+                flags,
+                data,
+                data.len(),
+                IPEndpoint::new_from_str("192.0.2.2", 7),
+                IPEndpoint::new_from_str("192.0.2.1", 50000),
+                &mut device,
+                &mut contexts,
+                &mut pcbs,
+            );
+        }
+        assert_eq!(TcpPcbState::TimeWait, pcbs.tcp_pcbs.entries[pcb_id].state);
+
+        // Data buffered before FIN is returned, then end of stream.
+        let mut devices = NetDevices::new();
+        devices.register(device);
+        let devices_arc = Arc::new(Mutex::new(devices));
+        let contexts_arc = Arc::new(Mutex::new(contexts));
+        let pcbs_arc = Arc::new(Mutex::new(pcbs));
+        let read = || {
+            receive(
+                pcb_id,
+                PCB_BUF_LEN,
+                pcbs_arc.clone(),
+                devices_arc.clone(),
+                contexts_arc.clone(),
+            )
+        };
+        assert_eq!(Some(vec![7; 100]), read());
+        assert_eq!(Some(vec![]), read());
+    }
+
+    #[test]
+    fn test_select() {
+        let mut pcbs = ControlBlocks::new();
+        let (listener_id, listener) = pcbs.tcp_pcbs.new_entry().unwrap();
+        listener.state = TcpPcbState::Listen;
+        listener.local = IPEndpoint::new_from_str("0.0.0.0", 7);
+        pcbs.tcp_pcbs.index(listener_id);
+        let pcb_id = established_pcb(&mut pcbs);
+
+        let tcp_pcbs = &mut pcbs.tcp_pcbs;
+        let local = IPEndpoint::new_from_str("192.0.2.2", 7);
+        let select = |tcp_pcbs: &mut TcpPcbs, remote: IPEndpoint| {
+            tcp_pcbs.select(&local, Some(&remote)).map(|(id, _)| id)
+        };
+        assert_eq!(
+            Some(pcb_id),
+            select(tcp_pcbs, IPEndpoint::new_from_str("192.0.2.1", 50000))
+        );
+        assert_eq!(
+            Some(listener_id),
+            select(tcp_pcbs, IPEndpoint::new_from_str("192.0.2.1", 50001))
+        );
+
+        // Released connection is not selected, nor its entry kept after reuse.
+        tcp_pcbs.entries[pcb_id].release();
+        let remote = IPEndpoint::new_from_str("192.0.2.1", 50000);
+        assert_eq!(Some(listener_id), select(tcp_pcbs, remote));
+        tcp_pcbs.new_entry().unwrap();
+        assert!(tcp_pcbs.connections.is_empty());
+    }
+
+    #[test]
+    fn test_select_after_key_reused() {
+        let mut pcbs = ControlBlocks::new();
+        let (other_id, _) = pcbs.tcp_pcbs.new_entry().unwrap();
+        let released_id = established_pcb(&mut pcbs);
+        pcbs.tcp_pcbs.entries[released_id].release();
+        pcbs.tcp_pcbs.entries[other_id].release();
+
+        // A connection of the same endpoints in another PCB stays selected when the released
+        // one is reused.
+        let pcb_id = established_pcb(&mut pcbs);
+        assert_eq!(other_id, pcb_id);
+        assert_eq!(released_id, pcbs.tcp_pcbs.new_entry().unwrap().0);
+        let local = IPEndpoint::new_from_str("192.0.2.2", 7);
+        let remote = IPEndpoint::new_from_str("192.0.2.1", 50000);
+        assert_eq!(
+            Some(pcb_id),
+            pcbs.tcp_pcbs
+                .select(&local, Some(&remote))
+                .map(|(id, _)| id)
+        );
+    }
+
+    #[test]
+    fn test_ack_policies() {
+        // ACKs sent for 9 segments in order, on the timer afterwards, and for 2 segments after a
+        // lost one and the retransmission filling the gap
+        let cases = [
+            (AckPolicy::EverySegment, 9, 0, 3),
+            (AckPolicy::EveryOther, 4, 1, 3),
+            (AckPolicy::Delayed, 0, 1, 3),
+        ];
+        fn arrive(
+            seq_num: u32,
+            device: &mut NetDevice,
+            contexts: &mut ProtocolContexts,
+            pcbs: &mut ControlBlocks,
+        ) -> usize {
+            let seg = TcpSegmentInfo {
+                seq_num,
+                ack_num: 1,
+                len: 100,
+                window: PCB_BUF_LEN as u16,
+                ..Default::default()
+            };
+            segment_arrives(
+                seg,
+                TcpFlag::ACK as u8,
+                &[0; 100],
+                100,
+                IPEndpoint::new_from_str("192.0.2.2", 7),
+                IPEndpoint::new_from_str("192.0.2.1", 50000),
+                device,
+                contexts,
+                pcbs,
+            );
+            device
+                .driver_data
+                .as_mut()
+                .unwrap()
+                .tx_frames
+                .drain(..)
+                .count()
+        }
+        for (policy, in_order, on_timer, lossy) in cases {
+            let (mut device, mut contexts) = setup();
+            let mut pcbs = ControlBlocks::new();
+            pcbs.tcp_pcbs.ack_policy = policy;
+            let pcb_id = established_pcb(&mut pcbs);
+            let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+            pcb.recv_context.next = 1000;
+            pcb.recv_context.window = PCB_BUF_LEN as u16;
+            pcb.send_context.una = 1;
+            pcb.send_context.next = 1;
+            let acks: usize = (0..9)
+                .map(|i| arrive(1000 + i * 100, &mut device, &mut contexts, &mut pcbs))
+                .sum();
+            assert_eq!(in_order, acks, "{policy}");
+
+            let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+            if let Some(arrived_at) = pcb.delayed_ack.as_mut() {
+                *arrived_at -= Duration::from_millis(TCP_DELAYED_ACK_MILLIS);
+            }
+            expire_timers(&mut pcbs.tcp_pcbs, &mut device, &mut contexts);
+            let acks = device
+                .driver_data
+                .as_mut()
+                .unwrap()
+                .tx_frames
+                .drain(..)
+                .count();
+            assert_eq!(on_timer, acks, "{policy}");
+
+            let acks: usize = [2000, 2100, 1900]
+                .iter()
+                .map(|&seq_num| arrive(seq_num, &mut device, &mut contexts, &mut pcbs))
+                .sum();
+            assert_eq!(lossy, acks, "{policy}");
+            assert_eq!(2200, pcbs.tcp_pcbs.entries[pcb_id].recv_context.next);
+        }
+    }
+
+    #[test]
+    fn test_connection_stats() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs = ControlBlocks::new();
+        let pcb_id = established_pcb(&mut pcbs);
+        let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+        pcb.recv_context.next = 1000;
+        pcb.recv_context.window = PCB_BUF_LEN as u16;
+        pcb.send_context.una = 1;
+        pcb.send_context.next = 1;
+        pcb.send_buf = b"hello".to_vec();
+        output(
+            pcb,
+            TcpFlag::ACK as u8,
+            b"hello".to_vec(),
+            &mut device,
+            &mut contexts,
+        );
+        pcb.send_context.next = 6;
+
+        // Fast retransmit on the third duplicate ACK
+        let (ack, window) = (TcpFlag::ACK as u8, PCB_BUF_LEN as u16);
+        for _ in 0..3 {
+            peer_segment(1000, 1, ack, window, &mut device, &mut contexts, &mut pcbs);
+        }
+        peer_segment(1000, 6, ack, window, &mut device, &mut contexts, &mut pcbs);
+        let fin = TcpFlag::FIN as u8 | ack;
+        peer_segment(1000, 6, fin, window, &mut device, &mut contexts, &mut pcbs);
+
+        let counters = stats(pcb_id, &pcbs).unwrap();
+        assert_eq!(5, counters.segments_received);
+        assert_eq!(1, counters.retransmits);
+        assert_eq!(3, counters.dup_acks);
+        assert_eq!(10, counters.bytes_sent);
+        assert_eq!(1, counters.transitions); // ESTABLISHED -> CLOSE-WAIT
+        assert_eq!(pcbs.tcp_pcbs.entries[pcb_id].rtt.rto, counters.rto);
+        assert!(pcbs
+            .tcp_pcbs
+            .connection_stats_table(Some(pcb_id))
+            .contains("CloseWait"));
+
+        pcbs.tcp_pcbs.entries[pcb_id].release();
+        assert_eq!(None, stats(pcb_id, &pcbs));
+    }
+
+    #[test]
+    fn test_challenge_ack() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs = ControlBlocks::new();
+        let pcb_id = established_pcb(&mut pcbs);
+        let pcb = &mut pcbs.tcp_pcbs.entries[pcb_id];
+        pcb.recv_context.next = 1000;
+        pcb.recv_context.window = PCB_BUF_LEN as u16;
+        pcb.send_context.una = 1;
+        pcb.send_context.next = 1;
+        let window = PCB_BUF_LEN as u16;
+        let rst = TcpFlag::RST as u8;
+        let frames = |device: &NetDevice| device.driver_data.as_ref().unwrap().tx_frames.len();
+
+        // RST in the window but not at recv.next, and SYN
+        peer_segment(1005, 1, rst, window, &mut device, &mut contexts, &mut pcbs);
+        let (header, _) = last_segment(&device);
+        assert_eq!(TcpFlag::ACK as u8, header.flags);
+        assert_eq!(1000, u32::from_be(header.ack_num));
+        let syn = TcpFlag::SYN as u8;
+        peer_segment(1003, 0, syn, window, &mut device, &mut contexts, &mut pcbs);
+        assert_eq!(2, frames(&device));
+        let pcb = &pcbs.tcp_pcbs.entries[pcb_id];
+        assert_eq!(TcpPcbState::Established, pcb.state);
+        assert_eq!(2, pcb.counters.challenge_acks);
+
+        // RST out of the window is dropped silently.
+        peer_segment(
+            1000 + 70000,
+            1,
+            rst,
+            window,
+            &mut device,
+            &mut contexts,
+            &mut pcbs,
+        );
+        assert_eq!(2, frames(&device));
+
+        pcbs.tcp_pcbs.challenge_ack_limit = 2;
+        peer_segment(1005, 1, rst, window, &mut device, &mut contexts, &mut pcbs);
+        assert_eq!(2, frames(&device));
+        assert_eq!(1, pcbs.tcp_pcbs.challenge_acks_limited);
+        assert!(pcbs
+            .tcp_pcbs
+            .stats()
+            .contains("challenge ACKs rate limited: 1"));
+
+        peer_segment(1000, 1, rst, window, &mut device, &mut contexts, &mut pcbs);
+        assert_eq!(TcpPcbState::Free, pcbs.tcp_pcbs.entries[pcb_id].state);
+    }
+
+    #[test]
+    fn test_backlog_limit() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs = ControlBlocks::new();
+        let listener = open(&mut pcbs);
+        bind(
+            listener,
+            IPEndpoint::new_from_str("192.0.2.2", 7),
+            &mut pcbs,
+        );
+        set_backlog(listener, 1, &mut pcbs);
+        listen(listener, &mut pcbs);
+
+        let mut syn = |port: u16, pcbs: &mut ControlBlocks| {
+            let seg = TcpSegmentInfo {
+                seq_num: 1000,
+                window: PCB_BUF_LEN as u16,
+                ..Default::default()
+            };
+            segment_arrives(
+                seg,
+                TcpFlag::SYN as u8,
+                &[],
+                0,
+                IPEndpoint::new_from_str("192.0.2.2", 7),
+                IPEndpoint::new_from_str("192.0.2.1", port),
+                &mut device,
+                &mut contexts,
+                pcbs,
+            );
+        };
+        syn(50000, &mut pcbs);
+        syn(50001, &mut pcbs); // beyond the limit
+        let stats = pcbs.tcp_pcbs.backlog_stats(listener).unwrap();
+        assert_eq!(
+            (1, 0, 1, 0),
+            (
+                stats.half_open,
+                stats.queued,
+                stats.dropped,
+                stats.no_pcb_drops
+            )
+        );
+
+        // Dropped without a panic when PCBs run out.
+        pcbs.tcp_pcbs.entries[listener].backlog.limit = TCP_PCB_COUNT;
+        for port in 50002..50002 + TCP_PCB_COUNT as u16 {
+            syn(port, &mut pcbs);
+        }
+        let stats = pcbs.tcp_pcbs.backlog_stats(listener).unwrap();
+        assert_eq!(TCP_PCB_COUNT - 1, stats.half_open);
+        assert_eq!(2, stats.no_pcb_drops);
+        assert!(pcbs.tcp_pcbs.stats().contains("no PCB: 2"));
+    }
+
+    #[test]
+    fn test_close_listener() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs_arc = Arc::new(Mutex::new(ControlBlocks::new()));
+        let local = IPEndpoint::new_from_str("192.0.2.2", 7);
+        fn arrive(
+            flags: u8,
+            port: u16,
+            ack_num: u32,
+            device: &mut NetDevice,
+            contexts: &mut ProtocolContexts,
+            pcbs: &mut ControlBlocks,
+        ) {
+            let seg = TcpSegmentInfo {
+                seq_num: if tcp_flag_exists(flags, TcpFlag::SYN) {
+                    1000
+                } else {
+                    1001
+                },
+                ack_num,
+                window: PCB_BUF_LEN as u16,
+                ..Default::default()
+            };
+            let (local, remote) = (
+                IPEndpoint::new_from_str("192.0.2.2", 7),
+                IPEndpoint::new_from_str("192.0.2.1", port),
+            );
+            segment_arrives(seg, flags, &[], 0, local, remote, device, contexts, pcbs);
+        }
+        let (listener, accepted) = {
+            let pcbs = &mut pcbs_arc.lock().unwrap();
+            let listener = open(pcbs);
+            bind(listener, IPEndpoint::new_from_str("192.0.2.2", 7), pcbs);
+            listen(listener, pcbs);
+            let syn = TcpFlag::SYN as u8;
+            arrive(syn, 50000, 0, &mut device, &mut contexts, pcbs);
+            arrive(syn, 50001, 0, &mut device, &mut contexts, pcbs);
+            let remote = IPEndpoint::new_from_str("192.0.2.1", 50001);
+            let (accepted, pcb) = pcbs.tcp_pcbs.select(&local, Some(&remote)).unwrap();
+            let ack_num = pcb.iss + 1;
+            let ack = TcpFlag::ACK as u8;
+            arrive(ack, 50001, ack_num, &mut device, &mut contexts, pcbs);
+            (listener, accepted)
+        };
+        assert_eq!(Ok(accepted), accept(listener, &mut pcbs_arc));
+
+        // Blocked in accept till the listener is closed.
+        let mut accept_arc = pcbs_arc.clone();
+        let handle = thread::spawn(move || accept(listener, &mut accept_arc));
+        thread::sleep(Duration::from_millis(50));
+        let mut pcbs = pcbs_arc.lock().unwrap();
+        close(listener, &mut pcbs, &mut device, &mut contexts);
+        let (header, _) = last_segment(&device);
+        assert_eq!(TcpFlag::RST as u8, header.flags);
+        drop(pcbs);
+        assert_eq!(Err(SocketError::Closed), handle.join().unwrap());
+
+        let pcbs = &mut pcbs_arc.lock().unwrap();
+        let states: Vec<TcpPcbState> = pcbs.tcp_pcbs.entries.iter().map(|pcb| pcb.state).collect();
+        assert_eq!(TcpPcbState::Established, states[accepted]);
+        assert_eq!(
+            1,
+            states
+                .iter()
+                .filter(|state| **state != TcpPcbState::Free)
+                .count()
+        );
+        let remote = IPEndpoint::new_from_str("192.0.2.1", 50002);
+        assert!(pcbs.tcp_pcbs.select(&local, Some(&remote)).is_none());
+    }
+
+    #[test]
+    fn test_mss_option() {
+        let (mut device, mut contexts) = setup();
+        device.set_mtu(9000).unwrap();
+        let mut pcbs = ControlBlocks::new();
+        let endpoint = |addr, port| IPEndpoint::new_from_str(addr, port);
+        let (local, remote) = (endpoint("192.0.2.2", 7), endpoint("192.0.2.1", 50000));
+        let listener = open(&mut pcbs);
+        bind(listener, endpoint("192.0.2.2", 7), &mut pcbs);
+        listen(listener, &mut pcbs);
+        let seg = TcpSegmentInfo {
+            seq_num: 1000,
+            window: PCB_BUF_LEN as u16,
+            mss: Some(1220),
+            ..Default::default()
+        };
+        segment_arrives(
+            seg,
+            TcpFlag::SYN as u8,
+            &[],
+            0,
+            endpoint("192.0.2.2", 7),
+            endpoint("192.0.2.1", 50000),
+            &mut device,
+            &mut contexts,
+            &mut pcbs,
+        );
+
+        // SYN-ACK offers the MSS of the jumbo MTU and the peer's smaller one is kept.
+        let (header, options) = last_segment(&device);
+        assert_eq!(TcpFlag::SYN as u8 | TcpFlag::ACK as u8, header.flags);
+        assert_eq!(Some(8960), options.mss);
+        let (_, pcb) = pcbs.tcp_pcbs.select(&local, Some(&remote)).unwrap();
+        assert_eq!(1220, pcb.peer_mss);
+    }
+
+    #[test]
+    fn test_syn_cookies() {
+        let (mut device, mut contexts) = setup();
+        let mut pcbs = ControlBlocks::new();
+        let local = IPEndpoint::new_from_str("192.0.2.2", 7);
+        let listener = open(&mut pcbs);
+        bind(
+            listener,
+            IPEndpoint::new_from_str("192.0.2.2", 7),
+            &mut pcbs,
+        );
+        set_syn_cookies(listener, true, &mut pcbs);
+        listen(listener, &mut pcbs);
+        let arrive = |flags: u8,
+                      port: u16,
+                      seq_num: u32,
+                      ack_num: u32,
+                      device: &mut NetDevice,
+                      contexts: &mut ProtocolContexts,
+                      pcbs: &mut ControlBlocks| {
+            let seg = TcpSegmentInfo {
+                seq_num,
+                ack_num,
+                window: PCB_BUF_LEN as u16,
+                sack_permitted: tcp_flag_exists(flags, TcpFlag::SYN),
+                ..Default::default()
+            };
+            let remote = IPEndpoint::new_from_str("192.0.2.1", port);
+            let local = IPEndpoint::new_from_str("192.0.2.2", 7);
+            segment_arrives(seg, flags, &[], 0, local, remote, device, contexts, pcbs);
+        };
+
+        // SYNs take no PCB.
+        for port in 50000..50000 + TCP_PCB_COUNT as u16 {
+            arrive(
+                TcpFlag::SYN as u8,
+                port,
+                1000,
+                0,
+                &mut device,
+                &mut contexts,
+                &mut pcbs,
+            );
+        }
+        let (header, options) = last_segment(&device);
+        assert_eq!(TcpFlag::SYN as u8 | TcpFlag::ACK as u8, header.flags);
+        assert_eq!(1001, u32::from_be(header.ack_num));
+        assert!(options.sack_permitted);
+        let cookie = u32::from_be(header.seq_num);
+        let stats = pcbs.tcp_pcbs.backlog_stats(listener).unwrap();
+        assert_eq!(
+            (0, TCP_PCB_COUNT as u64),
+            (stats.half_open, stats.cookies_sent)
+        );
+
+        // ACK of a wrong cookie is reset.
+        let last_port = 50000 + TCP_PCB_COUNT as u16 - 1;
+        let ack = TcpFlag::ACK as u8;
+        arrive(
+            ack,
+            50000,
+            1001,
+            cookie + 1,
+            &mut device,
+            &mut contexts,
+            &mut pcbs,
+        );
+        assert_eq!(TcpFlag::RST as u8, last_segment(&device).0.flags);
+
+        // ACK of the cookie establishes a connection waiting for accept.
+        arrive(
+            ack,
+            last_port,
+            1001,
+            cookie + 1,
+            &mut device,
+            &mut contexts,
+            &mut pcbs,
+        );
+        let remote = IPEndpoint::new_from_str("192.0.2.1", last_port);
+        let (child, pcb) = pcbs.tcp_pcbs.select(&local, Some(&remote)).unwrap();
+        assert_eq!(TcpPcbState::Established, pcb.state);
+        assert!(pcb.sack_permitted);
+        assert_eq!(
+            (cookie + 1, 1001),
+            (pcb.send_context.next, pcb.recv_context.next)
+        );
+        let stats = pcbs.tcp_pcbs.backlog_stats(listener).unwrap();
+        assert_eq!((1, 1), (stats.queued, stats.cookies_accepted));
+        assert_eq!(
+            Some(child),
+            pcbs.tcp_pcbs.entries[listener]
+                .backlog
+                .pcb_ids
+                .front()
+                .copied()
+        );
+    }
+
+    #[test]
+    fn test_syn_cookie_mss() {
+        let (mut device, mut contexts) = setup();
+        device.set_mtu(9000).unwrap();
+        let mut pcbs = ControlBlocks::new();
+        let local = IPEndpoint::new_from_str("192.0.2.2", 7);
+        let listener = open(&mut pcbs);
+        bind(
+            listener,
+            IPEndpoint::new_from_str("192.0.2.2", 7),
+            &mut pcbs,
+        );
+        set_syn_cookies(listener, true, &mut pcbs);
+        listen(listener, &mut pcbs);
+        let mut arrive = |flags: u8, port: u16, ack_num: u32, mss, pcbs: &mut ControlBlocks| {
+            let seg = TcpSegmentInfo {
+                seq_num: 1000 + tcp_flag_exists(flags, TcpFlag::ACK) as u32,
+                ack_num,
+                window: PCB_BUF_LEN as u16,
+                mss,
+                ..Default::default()
+            };
+            let remote = IPEndpoint::new_from_str("192.0.2.1", port);
+            let local = IPEndpoint::new_from_str("192.0.2.2", 7);
+            segment_arrives(
+                seg,
+                flags,
+                &[],
+                0,
+                local,
+                remote,
+                &mut device,
+                &mut contexts,
+                pcbs,
+            );
+            u32::from_be(last_segment(&device).0.seq_num)
+        };
+
+        // The peer's MSS comes back from the cookie rounded down to the table, and the default
+        // one without the option, instead of the MSS of the jumbo MTU.
+        for (port, mss, expected) in [(50000, Some(1400), 1300), (50001, None, 536)] {
+            let cookie = arrive(TcpFlag::SYN as u8, port, 0, mss, &mut pcbs);
+            arrive(TcpFlag::ACK as u8, port, cookie + 1, None, &mut pcbs);
+            let remote = IPEndpoint::new_from_str("192.0.2.1", port);
+            let (_, pcb) = pcbs.tcp_pcbs.select(&local, Some(&remote)).unwrap();
+            assert_eq!(TcpPcbState::Established, pcb.state);
+            assert_eq!(expected, pcb.peer_mss);
+        }
     }
 }
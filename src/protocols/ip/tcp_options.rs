@@ -0,0 +1,103 @@
+const TCP_OPT_END: u8 = 0;
+const TCP_OPT_NOP: u8 = 1;
+const TCP_OPT_MSS: u8 = 2;
+const TCP_OPT_SACK_PERMITTED: u8 = 4;
+const TCP_OPT_SACK: u8 = 5;
+pub const TCP_SACK_BLOCKS_MAX: usize = 3; // blocks fitting in 40 bytes with other options
+
+/// Options of a received segment the stack makes use of.
+#[derive(Debug, Default, PartialEq)]
+pub struct TcpOptions {
+    pub mss: Option<u16>,
+    pub sack_permitted: bool,
+    pub sack_blocks: Vec<(u32, u32)>, // left edge and right edge (exclusive) (RFC 2018)
+}
+
+/// Parses options between the fixed header and data. Malformed options end parsing.
+pub fn parse(options: &[u8]) -> TcpOptions {
+    let mut parsed = TcpOptions::default();
+    let mut i = 0;
+    while i < options.len() {
+        let kind = options[i];
+        if kind == TCP_OPT_END {
+            break;
+        }
+        if kind == TCP_OPT_NOP {
+            i += 1;
+            continue;
+        }
+        let len = match options.get(i + 1) {
+            Some(len) if *len >= 2 && i + (*len as usize) <= options.len() => *len as usize,
+            _ => break,
+        };
+        let value = &options[i + 2..i + len];
+        match kind {
+            TCP_OPT_MSS if value.len() == 2 => {
+                parsed.mss = Some(u16::from_be_bytes([value[0], value[1]]))
+            }
+            TCP_OPT_SACK_PERMITTED => parsed.sack_permitted = true,
+            TCP_OPT_SACK => {
+                for block in value.chunks_exact(8) {
+                    let left = u32::from_be_bytes([block[0], block[1], block[2], block[3]]);
+                    let right = u32::from_be_bytes([block[4], block[5], block[6], block[7]]);
+                    parsed.sack_blocks.push((left, right));
+                }
+            }
+            _ => {}
+        }
+        i += len;
+    }
+    parsed
+}
+
+/// MSS option of SYN segments: the largest segment the sender receives (RFC 879).
+pub fn mss(mss: u16) -> Vec<u8> {
+    let [high, low] = mss.to_be_bytes();
+    vec![TCP_OPT_MSS, 4, high, low]
+}
+
+/// SACK-permitted option of SYN segments padded to 4 bytes.
+pub fn sack_permitted() -> Vec<u8> {
+    vec![TCP_OPT_NOP, TCP_OPT_NOP, TCP_OPT_SACK_PERMITTED, 2]
+}
+
+/// SACK option with blocks up to TCP_SACK_BLOCKS_MAX padded to 4 bytes.
+pub fn sack(blocks: &[(u32, u32)]) -> Vec<u8> {
+    let blocks = &blocks[..blocks.len().min(TCP_SACK_BLOCKS_MAX)];
+    if blocks.is_empty() {
+        return vec![];
+    }
+    let mut option = vec![
+        TCP_OPT_NOP,
+        TCP_OPT_NOP,
+        TCP_OPT_SACK,
+        (2 + 8 * blocks.len()) as u8,
+    ];
+    for (left, right) in blocks {
+        option.extend_from_slice(&left.to_be_bytes());
+        option.extend_from_slice(&right.to_be_bytes());
+    }
+    option
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mss, parse, sack, sack_permitted, TcpOptions};
+
+    #[test]
+    fn test_parse() {
+        // MSS, SACK-permitted, timestamps and window scale of a Linux SYN
+        let syn = [
+            2, 4, 0x05, 0xb4, 4, 2, 8, 10, 0, 0, 0, 1, 0, 0, 0, 0, 1, 3, 3, 7,
+        ];
+        assert!(parse(&syn).sack_permitted);
+        assert_eq!(Some(1460), parse(&syn).mss);
+        assert_eq!(TcpOptions::default(), parse(&[2, 40, 0, 0]));
+        assert_eq!(Some(8960), parse(&mss(8960)).mss);
+
+        let options = sack(&[(100, 200), (300, 400)]);
+        assert_eq!(0, options.len() % 4);
+        assert_eq!(vec![(100, 200), (300, 400)], parse(&options).sack_blocks);
+        assert_eq!(0, sack_permitted().len() % 4);
+    }
+}
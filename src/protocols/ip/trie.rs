@@ -0,0 +1,110 @@
+use super::{cidr::IpCidr, IPAdress};
+use crate::utils::byte::be_to_le_u32;
+
+/// Binary trie of IP prefixes for longest prefix match. A prefix of length n is the node n bits
+/// down from the root, following the bits of its address from the most significant one, and
+/// holds the values of the prefix. Lookup walks the bits of an address, so that it takes at
+/// most 32 steps however many prefixes there are.
+pub struct PrefixTrie<T> {
+    nodes: Vec<TrieNode<T>>, // root first
+}
+
+struct TrieNode<T> {
+    children: [Option<usize>; 2],
+    values: Vec<T>,
+}
+
+impl<T> TrieNode<T> {
+    fn new() -> TrieNode<T> {
+        TrieNode {
+            children: [None, None],
+            values: vec![],
+        }
+    }
+}
+
+impl<T> PrefixTrie<T> {
+    pub fn new() -> PrefixTrie<T> {
+        PrefixTrie {
+            nodes: vec![TrieNode::new()],
+        }
+    }
+
+    /// Adds a value to a prefix. Bits of the address outside the prefix are ignored.
+    pub fn insert(&mut self, prefix: IpCidr, value: T) {
+        let mut node = 0;
+        for depth in 0..prefix.prefix_len {
+            let bit = bit_at(prefix.address, depth);
+            node = match self.nodes[node].children[bit] {
+                Some(child) => child,
+                None => {
+                    self.nodes.push(TrieNode::new());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children[bit] = Some(child);
+                    child
+                }
+            };
+        }
+        self.nodes[node].values.push(value);
+    }
+
+    /// Values of the prefixes containing an address, those of the longest prefix first.
+    pub fn matches(&self, address: IPAdress) -> Vec<&[T]> {
+        let mut matches = vec![];
+        let mut node = Some(0);
+        let mut depth = 0;
+        while let Some(index) = node {
+            let values = &self.nodes[index].values;
+            if !values.is_empty() {
+                matches.push(values.as_slice());
+            }
+            if depth == 32 {
+                break;
+            }
+            node = self.nodes[index].children[bit_at(address, depth)];
+            depth += 1;
+        }
+        matches.reverse();
+        matches
+    }
+
+    pub fn clear(&mut self) {
+        self.nodes.truncate(1);
+        self.nodes[0] = TrieNode::new();
+    }
+}
+
+/// Bit of an address in network byte order at a depth from the most significant bit.
+fn bit_at(address: IPAdress, depth: u8) -> usize {
+    ((be_to_le_u32(address) >> (31 - depth)) & 1) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PrefixTrie;
+    use crate::protocols::ip::ip_addr_to_bytes;
+
+    #[test]
+    fn test_matches() {
+        let mut trie = PrefixTrie::new();
+        for (prefix, value) in [
+            ("0.0.0.0/0", 0),
+            ("192.0.2.0/24", 24),
+            ("192.0.2.128/25", 25),
+            ("192.0.2.1/32", 32),
+            ("192.0.2.0/24", 124),
+        ] {
+            trie.insert(prefix.parse().unwrap(), value);
+        }
+        let addr = |addr: &str| ip_addr_to_bytes(addr).unwrap();
+
+        let expected: Vec<&[i32]> = vec![&[32], &[24, 124], &[0]];
+        assert_eq!(expected, trie.matches(addr("192.0.2.1")));
+        let expected: Vec<&[i32]> = vec![&[25], &[24, 124], &[0]];
+        assert_eq!(expected, trie.matches(addr("192.0.2.200")));
+        let expected: Vec<&[i32]> = vec![&[0]];
+        assert_eq!(expected, trie.matches(addr("198.51.100.1")));
+        trie.clear();
+        assert!(trie.matches(addr("192.0.2.1")).is_empty());
+    }
+}
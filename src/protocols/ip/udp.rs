@@ -1,7 +1,13 @@
+use super::igmp;
+use super::l4::L4Protocol;
+use super::{
+    route_interface, IPAdress, IPEndpoint, IPInterface, IPProtocolType, IP_ADDR_ANY,
+    IP_ADDR_BROADCAST, IP_PAYLOAD_MAX_SIZE, IP_TTL_DEFAULT,
+};
 use super::{ControlBlocks, ProtocolContexts};
-use super::{IPAdress, IPEndpoint, IPInterface, IPProtocolType, IP_ADDR_ANY, IP_PAYLOAD_MAX_SIZE};
 use crate::{
     devices::NetDevice,
+    protocols::SocketError,
     utils::byte::{be_to_le_u16, le_to_be_u16},
     utils::{bytes_to_struct, cksum16, to_u8_slice},
 };
@@ -9,15 +15,18 @@ use log::{debug, error, info, trace, warn};
 use std::{
     collections::VecDeque,
     mem::size_of,
+    ops::RangeInclusive,
     sync::{
         mpsc::{self, Sender},
         Arc, Mutex,
     },
+    time::{Duration, Instant},
 };
 
 const UDP_PCB_COUNT: usize = 16;
 const UDP_SRC_PORT_MIN: u16 = 49152;
 const UDP_SRC_PORT_MAX: u16 = 65535;
+const UDP_MULTICAST_TTL_DEFAULT: u8 = 1; // stays in the subnet (RFC 1112 6.1)
 
 struct PseudoHeader {
     src: IPAdress,
@@ -47,11 +56,24 @@ enum UdpPcbState {
 pub struct UdpPcb {
     state: UdpPcbState,
     local_endpoint: IPEndpoint,
+    remote_endpoint: Option<IPEndpoint>, // peer of connect, datagrams from others are dropped
+    broadcast: bool, // sends to broadcast addresses and receives broadcasts when bound to unicast
+    port_range: Option<RangeInclusive<u16>>, // receives datagrams to ports not bound otherwise
+    groups: Vec<IPAdress>, // multicast groups joined, left with `leave_groups` before close
+    multicast_ttl: u8,
+    error: Option<SocketError>, // reason of close reported to woken up users
     pub sender: Option<Sender<bool>>,
     data_entries: VecDeque<UdpDataEntry>,
 }
 
 impl UdpPcb {
+    /// Whether a datagram from an endpoint is received: from any unless connected.
+    fn accepts(&self, src: IPAdress, src_port: u16) -> bool {
+        self.remote_endpoint
+            .as_ref()
+            .is_none_or(|remote| remote.address == src && remote.port == src_port)
+    }
+
     pub fn new() -> UdpPcb {
         UdpPcb {
             state: UdpPcbState::Free,
@@ -59,6 +81,12 @@ impl UdpPcb {
                 address: IP_ADDR_ANY,
                 port: 0,
             },
+            remote_endpoint: None,
+            broadcast: false,
+            port_range: None,
+            groups: vec![],
+            multicast_ttl: UDP_MULTICAST_TTL_DEFAULT,
+            error: None,
             sender: None,
             data_entries: VecDeque::new(),
         }
@@ -67,12 +95,15 @@ impl UdpPcb {
 
 pub struct UdpDataEntry {
     pub remote_endpoint: IPEndpoint,
+    pub local_port: u16, // destination port, e.g. of datagrams received with a port range
     pub len: usize,
     pub data: Vec<u8>,
 }
 
 pub struct UdpPcbs {
     pub entries: Vec<UdpPcb>,
+    bad_length: u64,   // datagrams dropped for length not fitting IP payload
+    bad_checksum: u64, // datagrams dropped for checksum mismatch
 }
 
 impl UdpPcbs {
@@ -81,23 +112,71 @@ impl UdpPcbs {
         for _ in 0..UDP_PCB_COUNT {
             entries.push(UdpPcb::new());
         }
-        UdpPcbs { entries }
+        UdpPcbs {
+            entries,
+            bad_length: 0,
+            bad_checksum: 0,
+        }
+    }
+
+    /// PCB IDs in use with bytes of datagrams queued to read on each.
+    pub fn memory_usage(&self) -> Vec<(usize, usize)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|(_, pcb)| pcb.state != UdpPcbState::Free)
+            .map(|(id, pcb)| (id, pcb.data_entries.iter().map(|entry| entry.len).sum()))
+            .collect()
+    }
+
+    pub fn stats(&self) -> String {
+        format!(
+            "udp:\n  dropped for bad length: {} bad checksum: {}\n",
+            self.bad_length, self.bad_checksum
+        )
     }
 
     fn delete_entry(&mut self, pcb_id: usize) {
         let mut entry = &mut self.entries[pcb_id];
 
         entry.state = UdpPcbState::Closing;
-        if entry.sender.is_some() {
-            entry.sender.as_ref().unwrap().send(false).unwrap();
+        if let Some(sender) = entry.sender.take() {
+            if sender.send(false).is_err() {
+                warn!("UDP: closing PCB, however channel not listening.");
+            }
         }
 
         entry.state = UdpPcbState::Free;
         entry.local_endpoint.address = IP_ADDR_ANY;
         entry.local_endpoint.port = 0;
+        entry.remote_endpoint = None;
+        entry.broadcast = false;
+        entry.port_range = None;
+        entry.groups.clear();
+        entry.multicast_ttl = UDP_MULTICAST_TTL_DEFAULT;
         entry.data_entries.clear();
     }
 
+    /// Rows of `proto local foreign state` of open PCBs (`conn show --netstat`).
+    pub fn netstat(&self) -> String {
+        let mut text = String::new();
+        for pcb in self.entries.iter() {
+            if pcb.state == UdpPcbState::Open {
+                let foreign = match pcb.remote_endpoint.as_ref() {
+                    Some(remote) => remote.to_string(),
+                    None => "*:*".to_string(),
+                };
+                text += &format!(
+                    "{:<5} {:<23} {:<23}\n",
+                    "udp",
+                    pcb.local_endpoint.to_string(),
+                    foreign
+                );
+            }
+        }
+        text
+    }
+
     pub fn get_by_id(&self, pcb_id: usize) -> Option<&UdpPcb> {
         self.entries.get(pcb_id)
     }
@@ -121,6 +200,49 @@ impl UdpPcbs {
         None
     }
 
+    /// Id of a PCB bound to a port range including a port (in network byte order) on an address.
+    fn select_port_range(&self, host_addr: IPAdress, host_port: u16) -> Option<usize> {
+        self.entries.iter().position(|pcb| {
+            pcb.state == UdpPcbState::Open
+                && (pcb.local_endpoint.address == IP_ADDR_ANY
+                    || pcb.local_endpoint.address == host_addr)
+                && pcb
+                    .port_range
+                    .as_ref()
+                    .is_some_and(|ports| ports.contains(&be_to_le_u16(host_port)))
+        })
+    }
+
+    /// Ids of PCBs receiving broadcasts on a port: bound to ANY or with broadcast option set.
+    pub fn select_broadcast(&self, host_port: u16) -> Vec<usize> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|(_, pcb)| {
+                pcb.state == UdpPcbState::Open
+                    && pcb.local_endpoint.port == host_port
+                    && (pcb.local_endpoint.address == IP_ADDR_ANY || pcb.broadcast)
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Ids of PCBs receiving datagrams to a multicast group joined on a port: bound to ANY or
+    /// to the group.
+    pub fn select_multicast(&self, group: IPAdress, host_port: u16) -> Vec<usize> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|(_, pcb)| {
+                pcb.state == UdpPcbState::Open
+                    && pcb.local_endpoint.port == host_port
+                    && (pcb.local_endpoint.address == IP_ADDR_ANY
+                        || pcb.local_endpoint.address == group)
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+
     pub fn is_endpoint_used(&self, host_addr: IPAdress, host_port: u16) -> bool {
         for pcb in self.entries.iter() {
             if pcb.state == UdpPcbState::Open {
@@ -136,6 +258,17 @@ impl UdpPcbs {
         false
     }
 
+    /// A port (in network byte order) not used on an address, of the dynamic range.
+    fn dynamic_port(&self, address: IPAdress) -> u16 {
+        for p in UDP_SRC_PORT_MIN..UDP_SRC_PORT_MAX {
+            if !self.is_endpoint_used(address, le_to_be_u16(p)) {
+                info!("UDP: assigned a port number: {p}");
+                return le_to_be_u16(p);
+            }
+        }
+        panic!("UDP: failed to dynamically assign port.")
+    }
+
     pub fn close_sockets(&mut self) {
         for pcb in self.entries.iter() {
             if pcb.sender.is_some() {
@@ -145,6 +278,33 @@ impl UdpPcbs {
     }
 }
 
+/// UDP registered in `l4::L4Protocols`. ICMP errors quoting datagrams go to ICMP PCBs.
+pub struct UdpProtocol;
+
+impl L4Protocol for UdpProtocol {
+    fn number(&self) -> u8 {
+        IPProtocolType::Udp as u8
+    }
+
+    fn name(&self) -> &str {
+        "udp"
+    }
+
+    fn input(
+        &self,
+        data: &[u8],
+        len: usize,
+        src: IPAdress,
+        dst: IPAdress,
+        device: &mut NetDevice,
+        iface: &IPInterface,
+        contexts: &mut ProtocolContexts,
+        pcbs: &mut ControlBlocks,
+    ) -> Result<(), ()> {
+        input(data, len, src, dst, device, iface, contexts, pcbs)
+    }
+}
+
 pub fn input(
     data: &[u8],
     len: usize,
@@ -158,15 +318,30 @@ pub fn input(
     trace!("UDP: received data {:02x?}", data);
 
     let udp_hdr_size = size_of::<UdpHeader>();
+    if len < udp_hdr_size {
+        warn!("UDP: datagram of {len} bytes too short for header. Dropping...");
+        pcbs.udp_pcbs.bad_length += 1;
+        return Ok(());
+    }
     let header = unsafe { bytes_to_struct::<UdpHeader>(data) };
+    let summary = || {
+        format!(
+            "{} -> {} len = {} checksum = {:#06x} IP payload len = {len}",
+            IPEndpoint::new(src, be_to_le_u16(header.src_port)),
+            IPEndpoint::new(dst, be_to_le_u16(header.dst_port)),
+            be_to_le_u16(header.len),
+            be_to_le_u16(header.checksum)
+        )
+    };
 
-    let header_len = be_to_le_u16(header.len);
-    if header_len != len as u16 {
-        panic!(
-            "UDP: data length = {:?} and header length = {:?} do not match.",
-            len, header_len
-        );
+    // Bytes beyond the UDP length are padding of the IP payload and ignored.
+    let header_len = be_to_le_u16(header.len) as usize;
+    if header_len < udp_hdr_size || header_len > len {
+        warn!("UDP: length mismatch. Dropping {}", summary());
+        pcbs.udp_pcbs.bad_length += 1;
+        return Ok(());
     }
+    let len = header_len;
     let pseudo_header = PseudoHeader {
         src,
         dst,
@@ -176,15 +351,48 @@ pub fn input(
     };
     let pseudo_hdr_bytes = unsafe { to_u8_slice(&pseudo_header) };
     let pseudo_sum = !cksum16(pseudo_hdr_bytes, pseudo_hdr_bytes.len(), 0);
+    // Zero checksum means the sender did not compute one (RFC 768).
     let sum = cksum16(data, len, pseudo_sum as u32);
-    if sum != 0 {
-        error!("UDP: input checksum failure: value = {sum}");
-        return Err(());
+    if header.checksum != 0 && sum != 0 {
+        warn!("UDP: checksum mismatch. Dropping {}", summary());
+        pcbs.udp_pcbs.bad_checksum += 1;
+        return Ok(());
     }
 
-    let pcb_opt = pcbs.udp_pcbs.get_by_host(dst, header.dst_port);
     let dst_port = header.dst_port;
-    if pcb_opt.is_none() {
+    let pcb_ids = if dst == IP_ADDR_BROADCAST || dst == iface.broadcast {
+        pcbs.udp_pcbs.select_broadcast(dst_port)
+    } else if igmp::is_multicast(dst) {
+        pcbs.udp_pcbs.select_multicast(dst, dst_port)
+    } else {
+        pcbs.udp_pcbs
+            .entries
+            .iter()
+            .position(|pcb| {
+                pcb.state == UdpPcbState::Open
+                    && (pcb.local_endpoint.address == IP_ADDR_ANY
+                        || pcb.local_endpoint.address == dst)
+                    && pcb.local_endpoint.port == dst_port
+            })
+            .into_iter()
+            .collect()
+    };
+    // Datagrams not claimed by a specific socket go to a port range one if any.
+    let pcb_ids = match pcb_ids.is_empty() {
+        true => pcbs
+            .udp_pcbs
+            .select_port_range(dst, dst_port)
+            .into_iter()
+            .collect(),
+        false => pcb_ids,
+    };
+    // Connected PCBs only receive from their peer.
+    let src_port = header.src_port;
+    let pcb_ids: Vec<usize> = pcb_ids
+        .into_iter()
+        .filter(|pcb_id| pcbs.udp_pcbs.entries[*pcb_id].accepts(src, src_port))
+        .collect();
+    if pcb_ids.is_empty() {
         error!(
             "UDP: there is no connection for IP: {:?}:{:?}",
             dst, dst_port
@@ -198,21 +406,29 @@ pub fn input(
         be_to_le_u16(header.dst_port)
     );
 
-    let pcb = pcb_opt.unwrap();
-    let udp_data = data[udp_hdr_size..].to_vec();
-    let remote_endpoint = IPEndpoint {
-        address: src, // packet source is remote address
-        port: header.src_port,
-    };
-    let data_entry = UdpDataEntry {
-        remote_endpoint,
-        len: len - udp_hdr_size,
-        data: udp_data,
-    };
-    pcb.data_entries.push_back(data_entry);
+    for pcb_id in pcb_ids {
+        if !pcbs.memory_admits(len - udp_hdr_size) {
+            warn!("UDP: memory limit reached. Dropping {}", summary());
+            continue;
+        }
+        let pcb = &mut pcbs.udp_pcbs.entries[pcb_id];
+        let data_entry = UdpDataEntry {
+            remote_endpoint: IPEndpoint {
+                address: src, // packet source is remote address
+                port: header.src_port,
+            },
+            local_port: be_to_le_u16(dst_port),
+            len: len - udp_hdr_size,
+            data: data[udp_hdr_size..len].to_vec(),
+        };
+        pcb.data_entries.push_back(data_entry);
 
-    let sender = pcb.sender.as_ref().unwrap();
-    sender.send(true).unwrap();
+        if let Some(sender) = pcb.sender.as_ref() {
+            if sender.send(true).is_err() {
+                warn!("UDP: PCB channel not listening.");
+            }
+        }
+    }
 
     Ok(())
 }
@@ -220,10 +436,23 @@ pub fn input(
 pub fn output(
     src: IPEndpoint,
     dst: IPEndpoint,
-    mut udp_data: Vec<u8>,
+    udp_data: Vec<u8>,
     device: &mut NetDevice,
     contexts: &mut ProtocolContexts,
     pcbs: &mut ControlBlocks,
+) {
+    output_with_ttl(src, dst, udp_data, IP_TTL_DEFAULT, device, contexts, pcbs)
+}
+
+/// Same as output with the TTL of the datagram specified (e.g. traceroute probes).
+pub fn output_with_ttl(
+    src: IPEndpoint,
+    dst: IPEndpoint,
+    mut udp_data: Vec<u8>,
+    ttl: u8,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+    _pcbs: &mut ControlBlocks,
 ) {
     info!("UDP: output");
     let udp_hdr_size = size_of::<UdpHeader>();
@@ -257,11 +486,12 @@ pub fn output(
     data[6] = ((sum & 0xff00) >> 8) as u8;
     data[7] = (sum & 0xff) as u8;
 
-    super::output(
+    super::output_with_ttl(
         IPProtocolType::Udp,
         data,
         src.address,
         dst.address,
+        ttl,
         device,
         contexts,
     )
@@ -274,6 +504,7 @@ pub fn open(pcbs: &mut UdpPcbs) -> usize {
     for (i, entry) in pcbs.entries.iter_mut().enumerate() {
         if entry.state == UdpPcbState::Free {
             entry.state = UdpPcbState::Open;
+            entry.error = None;
             return i;
         }
     }
@@ -298,6 +529,75 @@ pub fn bind(pcbs: &mut UdpPcbs, pcb_id: usize, local_endpoint: IPEndpoint) {
     panic!("UDP: no PCB entry with specified id: {pcb_id}.");
 }
 
+/// Binds a PCB to a range of ports (in host byte order) on an address, e.g. `1..=65535` for
+/// all ports, receiving datagrams to any port of it not bound by other PCBs (diagnostic sink).
+pub fn bind_ports(
+    pcbs: &mut UdpPcbs,
+    pcb_id: usize,
+    address: IPAdress,
+    ports: RangeInclusive<u16>,
+) {
+    info!("UDP: binding ports {}-{}...", ports.start(), ports.end());
+    let pcb = pcbs
+        .get_mut_by_id(pcb_id)
+        .expect("UDP: no specified PCB entry to bind.");
+    pcb.local_endpoint = IPEndpoint { address, port: 0 };
+    pcb.port_range = Some(ports);
+}
+
+/// Connects a PCB to a remote endpoint (BSD connect on UDP): only datagrams from it are
+/// received and `send` goes to it. An unbound PCB gets a dynamic port so that replies reach it.
+pub fn connect(pcbs: &mut UdpPcbs, pcb_id: usize, remote: IPEndpoint) {
+    info!("UDP: connecting PCB id: {pcb_id} to {remote}");
+    let port = match pcbs.get_by_id(pcb_id) {
+        Some(pcb) if pcb.local_endpoint.port == 0 && pcb.port_range.is_none() => {
+            Some(pcbs.dynamic_port(pcb.local_endpoint.address))
+        }
+        Some(_) => None,
+        None => panic!("UDP: no PCB entry with specified id: {pcb_id}."),
+    };
+    let pcb = &mut pcbs.entries[pcb_id];
+    if let Some(port) = port {
+        pcb.local_endpoint.port = port;
+    }
+    pcb.remote_endpoint = Some(remote);
+}
+
+/// Sends a datagram to the remote endpoint of a connected PCB. Fails when not connected.
+pub fn send(
+    pcb_id: usize,
+    data: Vec<u8>,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+    pcbs: &mut ControlBlocks,
+) -> Result<(), ()> {
+    let Some(remote) = remote_endpoint(pcb_id, pcbs) else {
+        error!("UDP: PCB id: {pcb_id} is not connected for send.");
+        return Err(());
+    };
+    send_to(pcb_id, data, remote, device, contexts, pcbs)
+}
+
+/// Remote endpoint a PCB is connected to, if any.
+pub fn remote_endpoint(pcb_id: usize, pcbs: &ControlBlocks) -> Option<IPEndpoint> {
+    let remote = pcbs.udp_pcbs.get_by_id(pcb_id)?.remote_endpoint.as_ref()?;
+    Some(IPEndpoint {
+        address: remote.address,
+        port: remote.port,
+    })
+}
+
+/// Sets broadcast option (SO_BROADCAST) of a PCB: datagrams can be sent to the limited and
+/// subnet broadcast addresses, and broadcasts are received even when bound to a unicast address.
+pub fn set_broadcast(pcbs: &mut UdpPcbs, pcb_id: usize, enabled: bool) {
+    let pcb = pcbs
+        .get_mut_by_id(pcb_id)
+        .expect("UDP: no specified PCB entry for option.");
+    pcb.broadcast = enabled;
+}
+
+/// Sends a datagram to a remote endpoint. Fails when there is no route to it, or when it is a
+/// broadcast address and the PCB does not have broadcast option set.
 pub fn send_to(
     pcb_id: usize,
     data: Vec<u8>,
@@ -305,40 +605,226 @@ pub fn send_to(
     device: &mut NetDevice,
     contexts: &mut ProtocolContexts,
     pcbs: &mut ControlBlocks,
-) {
+) -> Result<(), ()> {
+    let interface = check_destination(pcb_id, &remote, device, contexts, pcbs)?;
+    let local_endpoint = local_endpoint_for(pcb_id, &interface, pcbs);
+    let ttl = ttl_for(pcb_id, remote.address, pcbs);
+    output_with_ttl(local_endpoint, remote, data, ttl, device, contexts, pcbs);
+    Ok(())
+}
+
+/// Sends a batch of datagrams (sendmmsg-style) under a single acquisition of the caller's locks.
+/// The local endpoint is resolved once per remote address, and datagrams `send_to` would fail
+/// for are skipped. Returns the number of datagrams sent.
+pub fn send_many(
+    pcb_id: usize,
+    datagrams: Vec<(Vec<u8>, IPEndpoint)>,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+    pcbs: &mut ControlBlocks,
+) -> usize {
+    let mut locals: Vec<(IPAdress, Option<IPEndpoint>)> = Vec::new();
+    let mut sent = 0;
+    for (data, remote) in datagrams {
+        let local = match locals.iter().find(|(addr, _)| *addr == remote.address) {
+            Some((_, local)) => local,
+            None => {
+                let local = check_destination(pcb_id, &remote, device, contexts, pcbs)
+                    .ok()
+                    .map(|interface| local_endpoint_for(pcb_id, &interface, pcbs));
+                locals.push((remote.address, local));
+                &locals.last().unwrap().1
+            }
+        };
+        let Some(local) = local else {
+            continue;
+        };
+        let local_endpoint = IPEndpoint {
+            address: local.address,
+            port: local.port,
+        };
+        let ttl = ttl_for(pcb_id, remote.address, pcbs);
+        output_with_ttl(local_endpoint, remote, data, ttl, device, contexts, pcbs);
+        sent += 1;
+    }
+    sent
+}
+
+/// Interface of the route to a remote, checking broadcast option of a PCB sending to a
+/// broadcast address.
+fn check_destination(
+    pcb_id: usize,
+    remote: &IPEndpoint,
+    device: &NetDevice,
+    contexts: &ProtocolContexts,
+    pcbs: &ControlBlocks,
+) -> Result<Arc<IPInterface>, ()> {
+    let pcb = pcbs
+        .udp_pcbs
+        .get_by_id(pcb_id)
+        .expect("UDP: no specified PCB entry for send.");
+    let Some(interface) = route_interface(remote.address, device, contexts) else {
+        error!("UDP: no route to {remote}.");
+        return Err(());
+    };
+    let broadcast = remote.address == IP_ADDR_BROADCAST || remote.address == interface.broadcast;
+    if broadcast && !pcb.broadcast {
+        error!("UDP: broadcast to {remote} without broadcast option of PCB id: {pcb_id}.");
+        return Err(());
+    }
+    Ok(interface)
+}
+
+/// TTL of datagrams of a PCB to an address: the multicast TTL of the PCB for groups.
+fn ttl_for(pcb_id: usize, dst: IPAdress, pcbs: &ControlBlocks) -> u8 {
+    match pcbs.udp_pcbs.get_by_id(pcb_id) {
+        Some(pcb) if igmp::is_multicast(dst) => pcb.multicast_ttl,
+        _ => IP_TTL_DEFAULT,
+    }
+}
+
+/// Local endpoint of a PCB for sending out of an interface, filling in its address and a
+/// dynamic port when the PCB is not bound to them.
+fn local_endpoint_for(pcb_id: usize, interface: &IPInterface, pcbs: &ControlBlocks) -> IPEndpoint {
     let pcb = pcbs
         .udp_pcbs
         .get_by_id(pcb_id)
         .expect("UDP: no specified PCB entry for send.");
 
     // Local address setup in case not set in PCB
-    let mut local_endpoint = IPEndpoint::new(pcb.local_endpoint.address, 0);
+    let mut local_endpoint = IPEndpoint {
+        address: pcb.local_endpoint.address,
+        port: pcb.local_endpoint.port,
+    };
     if local_endpoint.address == IP_ADDR_ANY {
-        let interface = contexts
-            .ip_routes
-            .get_interface(remote.address)
-            .expect("UDP: interface not found for remote address.");
         local_endpoint.address = interface.unicast;
     }
     // Local port setup in case not set in PCB
     if pcb.local_endpoint.port == 0 {
-        for p in UDP_SRC_PORT_MIN..UDP_SRC_PORT_MAX {
-            let is_used = pcbs.udp_pcbs.is_endpoint_used(local_endpoint.address, p);
-            if is_used == false {
-                info!("UDP: assigned a port number: {p}");
-                local_endpoint.port = p;
-                break;
-            }
+        local_endpoint.port = pcbs.udp_pcbs.dynamic_port(local_endpoint.address);
+    }
+    local_endpoint
+}
+
+/// Joins a multicast group for a PCB (IP_ADD_MEMBERSHIP). Datagrams to the group are received
+/// by PCBs bound to ANY or the group on the port.
+pub fn join_group(
+    pcb_id: usize,
+    group: IPAdress,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+    pcbs: &mut ControlBlocks,
+) -> Result<(), ()> {
+    let pcb = pcbs
+        .udp_pcbs
+        .get_mut_by_id(pcb_id)
+        .expect("UDP: no specified PCB entry to join a group.");
+    if pcb.groups.contains(&group) {
+        return Ok(());
+    }
+    igmp::join(group, device, contexts)?;
+    pcb.groups.push(group);
+    Ok(())
+}
+
+/// Leaves a multicast group joined for a PCB (IP_DROP_MEMBERSHIP).
+pub fn leave_group(
+    pcb_id: usize,
+    group: IPAdress,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+    pcbs: &mut ControlBlocks,
+) -> Result<(), ()> {
+    let pcb = pcbs
+        .udp_pcbs
+        .get_mut_by_id(pcb_id)
+        .expect("UDP: no specified PCB entry to leave a group.");
+    let Some(index) = pcb.groups.iter().position(|joined| *joined == group) else {
+        return Err(());
+    };
+    pcb.groups.remove(index);
+    igmp::leave(group, device, contexts)
+}
+
+/// Leaves all groups joined for a PCB, e.g. before close.
+pub fn leave_groups(
+    pcb_id: usize,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+    pcbs: &mut ControlBlocks,
+) {
+    let Some(pcb) = pcbs.udp_pcbs.get_mut_by_id(pcb_id) else {
+        return;
+    };
+    for group in std::mem::take(&mut pcb.groups) {
+        let _ = igmp::leave(group, device, contexts);
+    }
+}
+
+/// Sets TTL of datagrams to multicast groups (IP_MULTICAST_TTL), 1 by default.
+pub fn set_multicast_ttl(pcbs: &mut UdpPcbs, pcb_id: usize, ttl: u8) {
+    let pcb = pcbs
+        .get_mut_by_id(pcb_id)
+        .expect("UDP: no specified PCB entry for option.");
+    pcb.multicast_ttl = ttl;
+}
+
+/// Closes a PCB waking up a blocked receive.
+pub fn close(pcbs: &mut UdpPcbs, pcb_id: usize) {
+    pcbs.delete_entry(pcb_id);
+}
+
+/// Closes a PCB by an operator waking up blocked users with AdministrativelyClosed error.
+pub fn abort(pcbs: &mut UdpPcbs, pcb_id: usize) -> Result<(), ()> {
+    match pcbs.get_mut_by_id(pcb_id) {
+        Some(pcb) if pcb.state == UdpPcbState::Open => {
+            pcb.error = Some(SocketError::AdministrativelyClosed);
         }
-        if local_endpoint.port == 0 {
-            panic!("UDP: failed to dynamically assign port.")
+        _ => {
+            error!("UDP: no open PCB with id: {pcb_id} to abort.");
+            return Err(());
         }
     }
+    info!("UDP: aborting PCB id: {pcb_id}");
+    pcbs.delete_entry(pcb_id);
+    Ok(())
+}
+
+/// Returns the reason a PCB got closed under its users, if any.
+pub fn socket_error(pcbs: &UdpPcbs, pcb_id: usize) -> Option<SocketError> {
+    pcbs.get_by_id(pcb_id)?.error
+}
 
-    output(local_endpoint, remote, data, device, contexts, pcbs)
+/// Pops a received entry without blocking.
+pub fn try_receive_from(pcbs: &mut UdpPcbs, pcb_id: usize) -> Option<UdpDataEntry> {
+    let pcb = pcbs
+        .get_mut_by_id(pcb_id)
+        .expect("UDP: no specified PCB entry for receive.");
+    if pcb.state != UdpPcbState::Open {
+        return None;
+    }
+    pcb.data_entries.pop_front()
 }
 
+/// Blocks till a datagram is queued for the PCB. Returns None when the PCB gets closed.
 pub fn receive_from(pcb_id: usize, pcbs_arc: Arc<Mutex<ControlBlocks>>) -> Option<UdpDataEntry> {
+    wait_entry(pcb_id, pcbs_arc, None)
+}
+
+/// Same as receive_from, but returns None also when nothing arrives within the timeout.
+pub fn receive_from_timeout(
+    pcb_id: usize,
+    pcbs_arc: Arc<Mutex<ControlBlocks>>,
+    timeout: Duration,
+) -> Option<UdpDataEntry> {
+    wait_entry(pcb_id, pcbs_arc, Some(Instant::now() + timeout))
+}
+
+fn wait_entry(
+    pcb_id: usize,
+    pcbs_arc: Arc<Mutex<ControlBlocks>>,
+    deadline: Option<Instant>,
+) -> Option<UdpDataEntry> {
     let (sender, receiver) = mpsc::channel();
     {
         let pcbs = &mut pcbs_arc.lock().unwrap();
@@ -347,26 +833,477 @@ pub fn receive_from(pcb_id: usize, pcbs_arc: Arc<Mutex<ControlBlocks>>) -> Optio
             .get_mut_by_id(pcb_id)
             .expect("UDP: no specified PCB entry for receive.");
 
+        // Entries queued before receive are returned right away.
+        if let Some(entry) = pcb.data_entries.pop_front() {
+            return Some(entry);
+        }
         pcb.sender = Some(sender);
     }
 
     loop {
-        if !receiver.recv().unwrap() {
+        let wakeup = match deadline {
+            Some(deadline) => {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                receiver.recv_timeout(timeout).unwrap_or(false)
+            }
+            None => receiver.recv().unwrap_or(false),
+        };
+        if !wakeup {
             return None;
         }
 
-        {
-            let mut pcbs = pcbs_arc.lock().unwrap();
-            let pcb = pcbs
-                .udp_pcbs
-                .get_mut_by_id(pcb_id)
-                .expect("UDP: no specified PCB entry for receive.");
+        let pcbs = &mut pcbs_arc.lock().unwrap();
+        let pcb = pcbs
+            .udp_pcbs
+            .get_mut_by_id(pcb_id)
+            .expect("UDP: no specified PCB entry for receive.");
+        if pcb.state != UdpPcbState::Open {
+            warn!("UDP: PCB got closed for receive.");
+            return None;
+        }
+        if let Some(entry) = pcb.data_entries.pop_front() {
+            return Some(entry);
+        }
+    }
+}
 
-            if pcb.state != UdpPcbState::Open {
-                warn!("UDP: PCB got closed for receive.");
-                return None;
-            }
-            return pcb.data_entries.pop_front();
+#[cfg(test)]
+mod tests {
+    use super::{
+        abort, bind, bind_ports, connect, input, open, output, receive_from_timeout, send,
+        send_many, send_to, set_broadcast, socket_error, try_receive_from, UdpHeader,
+    };
+    use crate::{
+        devices::{
+            ethernet::{self, ETH_HDR_SIZE},
+            test_device, HwAddress, NetDevice,
+        },
+        net::NetInterfaceFamily,
+        protocols::ip::icmp::{self, ICMP_CODE_PORT_UNREACH, ICMP_TYPE_DEST_UNREACH},
+        protocols::ip::{self, ip_addr_to_bytes, IPEndpoint, IPRoute, IPRoutes, IP_ADDR_ANY},
+        protocols::{ControlBlocks, ProtocolContexts, SocketError},
+        utils::{byte::be_to_le_u16, bytes_to_struct},
+    };
+    use std::{
+        mem::size_of,
+        sync::{mpsc, Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    fn setup() -> (NetDevice, ProtocolContexts, ControlBlocks) {
+        let device = test_device("192.0.2.2/24");
+        let mut routes = IPRoutes::new();
+        let interface = device.get_interface(NetInterfaceFamily::IP).unwrap();
+        routes.register(IPRoute::interface_route(interface.clone()));
+        routes.set_default_gateway(Some(ip_addr_to_bytes("192.0.2.1").unwrap()), interface);
+        (device, ProtocolContexts::new(routes), ControlBlocks::new())
+    }
+
+    /// Sends a datagram from a peer on the subnet and feeds the transmitted frame to the test
+    /// device as input.
+    fn send_and_receive(
+        dst: &str,
+        device: &mut NetDevice,
+        contexts: &mut ProtocolContexts,
+        pcbs: &mut ControlBlocks,
+    ) -> Result<(), ()> {
+        let mut peer = test_device("192.0.2.1/24");
+        peer.address = HwAddress::from([0x02, 0, 0, 0, 0, 0x01]);
+        let mut routes = IPRoutes::new();
+        let interface = peer.get_interface(NetInterfaceFamily::IP).unwrap();
+        routes.register(IPRoute::interface_route(interface.clone()));
+        routes.set_default_gateway(Some(ip_addr_to_bytes("192.0.2.254").unwrap()), interface);
+        let mut peer_contexts = ProtocolContexts::new(routes);
+        peer_contexts.arp_table.update(
+            ip_addr_to_bytes("192.0.2.2").unwrap(),
+            [0x02, 0, 0, 0, 0, 0x02],
+        );
+        let src = IPEndpoint::new_from_str("192.0.2.1", 40000);
+        let dst = IPEndpoint::new_from_str(dst, 5000);
+        output(
+            src,
+            dst,
+            b"hello".to_vec(),
+            &mut peer,
+            &mut peer_contexts,
+            &mut ControlBlocks::new(),
+        );
+
+        let frame = peer
+            .driver_data
+            .as_mut()
+            .unwrap()
+            .tx_frames
+            .pop_front()
+            .unwrap();
+        device
+            .driver_data
+            .as_mut()
+            .unwrap()
+            .rx_frames
+            .push_back(frame);
+        let (_, data, len, vlan) = ethernet::read_data(device).unwrap();
+        ip::input(&data, len, vlan, device, contexts, pcbs)
+    }
+
+    #[test]
+    fn test_limited_broadcast_to_any() {
+        let (mut device, mut contexts, mut pcbs) = setup();
+        let pcb_id = open(&mut pcbs.udp_pcbs);
+        bind(
+            &mut pcbs.udp_pcbs,
+            pcb_id,
+            IPEndpoint::new_from_str("0.0.0.0", 5000),
+        );
+
+        send_and_receive("255.255.255.255", &mut device, &mut contexts, &mut pcbs).unwrap();
+        let entry = try_receive_from(&mut pcbs.udp_pcbs, pcb_id).unwrap();
+        assert_eq!(b"hello".to_vec(), entry.data);
+    }
+
+    #[test]
+    fn test_subnet_broadcast_with_option() {
+        let (mut device, mut contexts, mut pcbs) = setup();
+        let pcb_id = open(&mut pcbs.udp_pcbs);
+        bind(
+            &mut pcbs.udp_pcbs,
+            pcb_id,
+            IPEndpoint::new_from_str("192.0.2.2", 5000),
+        );
+
+        // Unicast-bound PCB without broadcast option does not receive.
+        assert!(send_and_receive("192.0.2.255", &mut device, &mut contexts, &mut pcbs).is_err());
+        assert!(try_receive_from(&mut pcbs.udp_pcbs, pcb_id).is_none());
+
+        set_broadcast(&mut pcbs.udp_pcbs, pcb_id, true);
+        send_and_receive("192.0.2.255", &mut device, &mut contexts, &mut pcbs).unwrap();
+        assert!(try_receive_from(&mut pcbs.udp_pcbs, pcb_id).is_some());
+    }
+
+    #[test]
+    fn test_port_range() {
+        let (mut device, mut contexts, mut pcbs) = setup();
+        let addr = ip_addr_to_bytes("192.0.2.2").unwrap();
+        let narrow = open(&mut pcbs.udp_pcbs);
+        bind_ports(&mut pcbs.udp_pcbs, narrow, addr, 1..=1023);
+        assert!(send_and_receive("192.0.2.2", &mut device, &mut contexts, &mut pcbs).is_err());
+
+        let sink = open(&mut pcbs.udp_pcbs);
+        bind_ports(&mut pcbs.udp_pcbs, sink, IP_ADDR_ANY, 1..=u16::MAX);
+        send_and_receive("192.0.2.2", &mut device, &mut contexts, &mut pcbs).unwrap();
+        let entry = try_receive_from(&mut pcbs.udp_pcbs, sink).unwrap();
+        assert_eq!(5000, entry.local_port);
+        assert_eq!(b"hello".to_vec(), entry.data);
+
+        // A socket bound to the port claims datagrams before the range.
+        let pcb_id = open(&mut pcbs.udp_pcbs);
+        bind(
+            &mut pcbs.udp_pcbs,
+            pcb_id,
+            IPEndpoint::new_from_str("192.0.2.2", 5000),
+        );
+        send_and_receive("192.0.2.2", &mut device, &mut contexts, &mut pcbs).unwrap();
+        assert!(try_receive_from(&mut pcbs.udp_pcbs, pcb_id).is_some());
+        assert!(try_receive_from(&mut pcbs.udp_pcbs, sink).is_none());
+    }
+
+    #[test]
+    fn test_send_many() {
+        let (mut device, mut contexts, mut pcbs) = setup();
+        contexts.arp_table.update(
+            ip_addr_to_bytes("192.0.2.1").unwrap(),
+            [0x02, 0, 0, 0, 0, 0x01],
+        );
+        let pcb_id = open(&mut pcbs.udp_pcbs);
+        let remote = || IPEndpoint::new_from_str("192.0.2.1", 5000);
+        let datagrams = vec![
+            (b"one".to_vec(), remote()),
+            (b"two".to_vec(), remote()),
+            (b"three".to_vec(), remote()),
+        ];
+
+        assert_eq!(
+            3,
+            send_many(pcb_id, datagrams, &mut device, &mut contexts, &mut pcbs)
+        );
+        let frames = &device.driver_data.as_ref().unwrap().tx_frames;
+        assert_eq!(3, frames.len());
+        // Dynamically assigned source port is shared by the batch.
+        let offset = size_of::<ethernet::EthernetHeader>() + ip::IP_HEADER_MIN_SIZE;
+        let ports: Vec<u16> = frames
+            .iter()
+            .map(|frame| {
+                let header = unsafe { bytes_to_struct::<UdpHeader>(&frame[offset..]) };
+                be_to_le_u16(header.src_port)
+            })
+            .collect();
+        assert!(ports.iter().all(|port| *port == ports[0]));
+    }
+
+    #[test]
+    fn test_broadcast_send() {
+        let (mut device, mut contexts, mut pcbs) = setup();
+        let mut routes = IPRoutes::new();
+        let interface = device.get_interface(NetInterfaceFamily::IP).unwrap();
+        routes.register(IPRoute::interface_route(interface));
+        contexts.ip_routes = routes; // limited broadcast is sent without default route
+        let pcb_id = open(&mut pcbs.udp_pcbs);
+        let remote = |address| IPEndpoint::new_from_str(address, 5000);
+
+        for address in ["255.255.255.255", "192.0.2.255"] {
+            let res = send_to(
+                pcb_id,
+                b"hi".to_vec(),
+                remote(address),
+                &mut device,
+                &mut contexts,
+                &mut pcbs,
+            );
+            assert!(res.is_err());
+        }
+        assert!(device.driver_data.as_ref().unwrap().tx_frames.is_empty());
+
+        set_broadcast(&mut pcbs.udp_pcbs, pcb_id, true);
+        for address in ["255.255.255.255", "192.0.2.255"] {
+            let res = send_to(
+                pcb_id,
+                b"hi".to_vec(),
+                remote(address),
+                &mut device,
+                &mut contexts,
+                &mut pcbs,
+            );
+            assert!(res.is_ok());
+            // To the broadcast MAC without ARP request
+            let frame = device
+                .driver_data
+                .as_mut()
+                .unwrap()
+                .tx_frames
+                .pop_front()
+                .unwrap();
+            let header = unsafe { bytes_to_struct::<ethernet::EthernetHeader>(&frame) };
+            assert_eq!([0xff; 6], header.dst);
+            let offset = size_of::<ethernet::EthernetHeader>();
+            assert_eq!(
+                ip_addr_to_bytes("192.0.2.2").unwrap().to_ne_bytes(),
+                frame[offset + 12..offset + 16]
+            );
         }
+        assert!(device.driver_data.as_ref().unwrap().tx_frames.is_empty());
+        assert!(contexts
+            .arp_table
+            .take_pending(ip_addr_to_bytes("255.255.255.255").unwrap())
+            .is_empty());
+    }
+
+    #[test]
+    fn test_connect() {
+        let (mut device, mut contexts, mut pcbs) = setup();
+        contexts.arp_table.update(
+            ip_addr_to_bytes("192.0.2.1").unwrap(),
+            [0x02, 0, 0, 0, 0, 0x01],
+        );
+        let pcb_id = open(&mut pcbs.udp_pcbs);
+        assert!(send(
+            pcb_id,
+            b"hi".to_vec(),
+            &mut device,
+            &mut contexts,
+            &mut pcbs
+        )
+        .is_err());
+        bind(
+            &mut pcbs.udp_pcbs,
+            pcb_id,
+            IPEndpoint::new_from_str("192.0.2.2", 5000),
+        );
+
+        // Datagrams from other than the peer connected are dropped.
+        let peer = |port| IPEndpoint::new_from_str("192.0.2.1", port);
+        connect(&mut pcbs.udp_pcbs, pcb_id, peer(40001));
+        assert!(send_and_receive("192.0.2.2", &mut device, &mut contexts, &mut pcbs).is_err());
+        assert!(try_receive_from(&mut pcbs.udp_pcbs, pcb_id).is_none());
+        connect(&mut pcbs.udp_pcbs, pcb_id, peer(40000));
+        send_and_receive("192.0.2.2", &mut device, &mut contexts, &mut pcbs).unwrap();
+        assert!(try_receive_from(&mut pcbs.udp_pcbs, pcb_id).is_some());
+        assert!(pcbs.udp_pcbs.netstat().contains("192.0.2.1:40000"));
+
+        // Sent to the peer without specifying it.
+        send(
+            pcb_id,
+            b"hi".to_vec(),
+            &mut device,
+            &mut contexts,
+            &mut pcbs,
+        )
+        .unwrap();
+        let frame = device.driver_data.as_mut().unwrap().tx_frames.pop_back();
+        let offset = size_of::<ethernet::EthernetHeader>() + ip::IP_HEADER_MIN_SIZE;
+        let header = unsafe { bytes_to_struct::<UdpHeader>(&frame.unwrap()[offset..]) };
+        assert_eq!(
+            (5000, 40000),
+            (be_to_le_u16(header.src_port), be_to_le_u16(header.dst_port))
+        );
+
+        // Connecting an unbound PCB binds a dynamic port.
+        let unbound_id = open(&mut pcbs.udp_pcbs);
+        connect(&mut pcbs.udp_pcbs, unbound_id, peer(40000));
+        let local = &pcbs.udp_pcbs.entries[unbound_id].local_endpoint;
+        assert!(be_to_le_u16(local.port) >= 49152);
+    }
+
+    #[test]
+    fn test_abort() {
+        let mut pcbs = ControlBlocks::new();
+        let pcb_id = open(&mut pcbs.udp_pcbs);
+        bind(
+            &mut pcbs.udp_pcbs,
+            pcb_id,
+            IPEndpoint::new_from_str("0.0.0.0", 5000),
+        );
+        let (sender, receiver) = mpsc::channel();
+        pcbs.udp_pcbs.entries[pcb_id].sender = Some(sender);
+
+        abort(&mut pcbs.udp_pcbs, pcb_id).unwrap();
+        assert!(!receiver.recv().unwrap());
+        assert_eq!(
+            Some(SocketError::AdministrativelyClosed),
+            socket_error(&pcbs.udp_pcbs, pcb_id)
+        );
+        assert!(abort(&mut pcbs.udp_pcbs, pcb_id).is_err());
+
+        // Error is cleared once the PCB is reused.
+        assert_eq!(pcb_id, open(&mut pcbs.udp_pcbs));
+        assert_eq!(None, socket_error(&pcbs.udp_pcbs, pcb_id));
+    }
+
+    #[test]
+    fn test_port_unreachable() {
+        let (mut device, mut contexts, mut pcbs) = setup();
+        contexts.arp_table.update(
+            ip_addr_to_bytes("192.0.2.1").unwrap(),
+            [0x02, 0, 0, 0, 0, 0x01],
+        );
+        assert!(send_and_receive("192.0.2.2", &mut device, &mut contexts, &mut pcbs).is_err());
+        let frame = device
+            .driver_data
+            .as_mut()
+            .unwrap()
+            .tx_frames
+            .pop_front()
+            .unwrap();
+        let icmp_data = &frame[ETH_HDR_SIZE + 20..];
+        assert_eq!(
+            &[ICMP_TYPE_DEST_UNREACH, ICMP_CODE_PORT_UNREACH],
+            &icmp_data[..2]
+        );
+        // IP header and UDP header of the datagram
+        assert_eq!(20 + 8, icmp_data.len() - 8);
+        assert_eq!(Some((40000, 5000)), icmp::quoted_udp_ports(&icmp_data[8..]));
+
+        // Broadcasts are dropped silently.
+        assert!(send_and_receive("192.0.2.255", &mut device, &mut contexts, &mut pcbs).is_err());
+        assert!(device.driver_data.as_ref().unwrap().tx_frames.is_empty());
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let (mut device, mut contexts, mut pcbs) = setup();
+        pcbs.memory_limit = Some(8);
+        let pcb_id = open(&mut pcbs.udp_pcbs);
+        bind(
+            &mut pcbs.udp_pcbs,
+            pcb_id,
+            IPEndpoint::new_from_str("0.0.0.0", 5000),
+        );
+
+        // The second datagram does not fit.
+        send_and_receive("192.0.2.2", &mut device, &mut contexts, &mut pcbs).unwrap();
+        send_and_receive("192.0.2.2", &mut device, &mut contexts, &mut pcbs).unwrap();
+        assert_eq!(5, pcbs.memory_used());
+        assert_eq!(3, pcbs.memory_free());
+        assert!(pcbs.memory_stats().contains("refused: 1"));
+        assert!(try_receive_from(&mut pcbs.udp_pcbs, pcb_id).is_some());
+        assert!(try_receive_from(&mut pcbs.udp_pcbs, pcb_id).is_none());
+        assert_eq!(0, pcbs.memory_used());
+    }
+
+    #[test]
+    fn test_receive_timeout() {
+        let pcbs_arc = Arc::new(Mutex::new(ControlBlocks::new()));
+        let pcb_id = {
+            let pcbs = &mut pcbs_arc.lock().unwrap();
+            let pcb_id = open(&mut pcbs.udp_pcbs);
+            bind(
+                &mut pcbs.udp_pcbs,
+                pcb_id,
+                IPEndpoint::new_from_str("0.0.0.0", 5000),
+            );
+            pcb_id
+        };
+        assert!(
+            receive_from_timeout(pcb_id, pcbs_arc.clone(), Duration::from_millis(10)).is_none()
+        );
+
+        let pcbs_clone = pcbs_arc.clone();
+        let delivery = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            let (mut device, mut contexts, _) = setup();
+            let pcbs = &mut pcbs_clone.lock().unwrap();
+            send_and_receive("192.0.2.2", &mut device, &mut contexts, pcbs).unwrap();
+        });
+        let received = receive_from_timeout(pcb_id, pcbs_arc, Duration::from_secs(5)).unwrap();
+        assert_eq!(b"hello".to_vec(), received.data);
+        delivery.join().unwrap();
+    }
+
+    #[test]
+    fn test_input_tolerance() {
+        let (mut device, mut contexts, mut pcbs) = setup();
+        let iface = device.get_interface(NetInterfaceFamily::IP).unwrap();
+        let pcb_id = open(&mut pcbs.udp_pcbs);
+        bind(
+            &mut pcbs.udp_pcbs,
+            pcb_id,
+            IPEndpoint::new_from_str("0.0.0.0", 5000),
+        );
+        let src = ip_addr_to_bytes("192.0.2.1").unwrap();
+        let dst = ip_addr_to_bytes("192.0.2.2").unwrap();
+        let mut input = |datagram: &[u8], pcbs: &mut ControlBlocks| {
+            input(
+                datagram,
+                datagram.len(),
+                src,
+                dst,
+                &mut device,
+                &iface,
+                &mut contexts,
+                pcbs,
+            )
+        };
+
+        // Checksum not computed, with padding beyond UDP length
+        let datagram = [0x9c, 0x40, 0x13, 0x88, 0, 10, 0, 0, b'h', b'i', 0, 0];
+        input(&datagram, &mut pcbs).unwrap();
+        assert_eq!(
+            b"hi".to_vec(),
+            try_receive_from(&mut pcbs.udp_pcbs, pcb_id).unwrap().data
+        );
+
+        // Counted drops
+        let mut bad_checksum = datagram;
+        bad_checksum[7] = 1;
+        input(&bad_checksum, &mut pcbs).unwrap();
+        let mut too_long = datagram;
+        too_long[5] = 13;
+        input(&too_long, &mut pcbs).unwrap();
+        input(&datagram[..6], &mut pcbs).unwrap();
+        assert!(try_receive_from(&mut pcbs.udp_pcbs, pcb_id).is_none());
+        assert_eq!(
+            "udp:\n  dropped for bad length: 2 bad checksum: 1\n",
+            pcbs.udp_pcbs.stats()
+        );
     }
 }
@@ -0,0 +1,387 @@
+use super::{
+    flush_pending, ipv6_addr_to_str, solicited_node_addr, IPV6Address, IPV6Interface,
+    IPV6NextHeader, IPV6_ADDR_ALL_NODES, IPV6_ADDR_ANY, IPV6_ADDR_LEN,
+};
+use crate::{
+    devices::{ethernet::ETH_ADDR_LEN, NetDevice},
+    protocols::{ControlBlocks, ProtocolContexts},
+    utils::byte::le_to_be_u32,
+    utils::{bytes_to_struct, cksum16, to_u8_slice},
+};
+use log::{debug, error, info, warn};
+use std::mem::size_of;
+
+pub const ICMPV6_TYPE_ECHO: u8 = 128;
+pub const ICMPV6_TYPE_ECHO_REPLY: u8 = 129;
+const ICMPV6_TYPE_NEIGHBOR_SOLICIT: u8 = 135;
+const ICMPV6_TYPE_NEIGHBOR_ADVERT: u8 = 136;
+
+const ND_OPT_SOURCE_LINK_ADDR: u8 = 1;
+const ND_OPT_TARGET_LINK_ADDR: u8 = 2;
+const ND_HOP_LIMIT: u8 = 255; // messages from off-link are discarded by hop limit check
+
+const ND_NA_FLAG_SOLICITED: u32 = 0x40000000;
+const ND_NA_FLAG_OVERRIDE: u32 = 0x20000000;
+
+#[repr(packed)]
+struct ICMPV6Header {
+    icmp_type: u8,
+    code: u8,
+    check_sum: u16,
+    values: u32, // echo: identifier + sequence | ND: flags + reserved
+}
+
+#[repr(packed)]
+struct PseudoHeader {
+    src: IPV6Address,
+    dst: IPV6Address,
+    len: u32,
+    zero: [u8; 3],
+    next_header: u8,
+}
+
+/// Neighbor Solicitation / Advertisement body after ICMPv6 header with link-layer address option.
+#[repr(packed)]
+struct NeighborMessage {
+    target: IPV6Address,
+    opt_type: u8,
+    opt_len: u8, // in units of 8 octets
+    link_addr: [u8; ETH_ADDR_LEN],
+}
+
+fn checksum(src: &IPV6Address, dst: &IPV6Address, data: &[u8]) -> u16 {
+    let pseudo_header = PseudoHeader {
+        src: *src,
+        dst: *dst,
+        len: le_to_be_u32(data.len() as u32),
+        zero: [0; 3],
+        next_header: IPV6NextHeader::Icmpv6 as u8,
+    };
+    let pseudo_hdr_bytes = unsafe { to_u8_slice(&pseudo_header) };
+    let pseudo_sum = cksum16(pseudo_hdr_bytes, pseudo_hdr_bytes.len(), 0);
+    cksum16(data, data.len(), !pseudo_sum as u32)
+}
+
+pub fn input(
+    data: &[u8],
+    src: IPV6Address,
+    dst: IPV6Address,
+    hop_limit: u8,
+    device: &mut NetDevice,
+    iface: &IPV6Interface,
+    contexts: &mut ProtocolContexts,
+    _pcbs: &mut ControlBlocks,
+) -> Result<(), ()> {
+    let hdr_size = size_of::<ICMPV6Header>();
+    if data.len() < hdr_size {
+        error!("ICMPv6: data is too short.");
+        return Err(());
+    }
+    let sum = checksum(&src, &dst, data);
+    if sum != 0 {
+        error!("ICMPv6: checksum failed: {sum}");
+        return Err(());
+    }
+    let hdr = unsafe { bytes_to_struct::<ICMPV6Header>(data) };
+    info!("ICMPv6: input type = {}", hdr.icmp_type);
+
+    match hdr.icmp_type {
+        // Answered from the interface address also when the request is to a multicast address
+        ICMPV6_TYPE_ECHO => output(
+            ICMPV6_TYPE_ECHO_REPLY,
+            0,
+            hdr.values,
+            data[hdr_size..].to_vec(),
+            iface.unicast,
+            src,
+            0,
+            device,
+            contexts,
+        ),
+        ICMPV6_TYPE_NEIGHBOR_SOLICIT | ICMPV6_TYPE_NEIGHBOR_ADVERT => {
+            if hop_limit != ND_HOP_LIMIT || hdr.code != 0 {
+                warn!("ICMPv6: invalid neighbor discovery message. Dropping...");
+                return Err(());
+            }
+            if data.len() < hdr_size + IPV6_ADDR_LEN {
+                error!("ICMPv6: neighbor discovery message is too short.");
+                return Err(());
+            }
+            let mut body = data[hdr_size..].to_vec();
+            body.resize(size_of::<NeighborMessage>(), 0); // option is absent in some messages
+            let msg = unsafe { bytes_to_struct::<NeighborMessage>(&body) };
+            let has_link_addr = msg.opt_len == 1;
+
+            if hdr.icmp_type == ICMPV6_TYPE_NEIGHBOR_SOLICIT {
+                if msg.target != iface.unicast {
+                    debug!("ICMPv6: solicitation not for this interface.");
+                    return Ok(());
+                }
+                if src == IPV6_ADDR_ANY {
+                    // Duplicate address detection: advertise to all nodes.
+                    return neighbor_advertise(device, iface, IPV6_ADDR_ALL_NODES, false, contexts);
+                }
+                if has_link_addr && msg.opt_type == ND_OPT_SOURCE_LINK_ADDR {
+                    contexts.neighbor_cache.update(src, msg.link_addr);
+                    flush_pending(src, msg.link_addr, device, contexts);
+                }
+                info!(
+                    "ICMPv6: neighbor solicitation from {}. Advertising...",
+                    ipv6_addr_to_str(&src)
+                );
+                neighbor_advertise(device, iface, src, true, contexts)
+            } else {
+                if has_link_addr && msg.opt_type == ND_OPT_TARGET_LINK_ADDR {
+                    let link_addr = msg.link_addr;
+                    info!(
+                        "ICMPv6: neighbor advertisement for {} HW Addr is {:x?}",
+                        ipv6_addr_to_str(&msg.target),
+                        link_addr
+                    );
+                    contexts.neighbor_cache.update(msg.target, msg.link_addr);
+                    flush_pending(msg.target, msg.link_addr, device, contexts);
+                }
+                Ok(())
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Sends a message from an address of the interface, which is covered by the checksum.
+pub fn output(
+    icmp_type: u8,
+    code: u8,
+    values: u32,
+    mut body: Vec<u8>,
+    src: IPV6Address,
+    dst: IPV6Address,
+    hop_limit: u8,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+) -> Result<(), ()> {
+    let hdr = ICMPV6Header {
+        icmp_type,
+        code,
+        check_sum: 0,
+        values,
+    };
+    let header_bytes = unsafe { to_u8_slice::<ICMPV6Header>(&hdr) };
+    let mut data = header_bytes.to_vec();
+    data.append(&mut body);
+
+    let check_sum = checksum(&src, &dst, &data);
+    data[2] = ((check_sum & 0xff00) >> 8) as u8;
+    data[3] = (check_sum & 0xff) as u8;
+
+    super::output(
+        IPV6NextHeader::Icmpv6,
+        hop_limit,
+        data,
+        src,
+        dst,
+        device,
+        contexts,
+    )
+}
+
+fn neighbor_message(target: IPV6Address, opt_type: u8, device: &NetDevice) -> Vec<u8> {
+    let msg = NeighborMessage {
+        target,
+        opt_type,
+        opt_len: 1,
+        link_addr: device
+            .address
+            .ethernet()
+            .expect("ICMPv6: device address size error."),
+    };
+    unsafe { to_u8_slice(&msg) }.to_vec()
+}
+
+/// Sends Neighbor Solicitation for a target to its solicited-node multicast address.
+pub fn neighbor_solicit(
+    device: &mut NetDevice,
+    iface: &IPV6Interface,
+    target: IPV6Address,
+    contexts: &mut ProtocolContexts,
+) -> Result<(), ()> {
+    let body = neighbor_message(target, ND_OPT_SOURCE_LINK_ADDR, device);
+    info!(
+        "ICMPv6: sending neighbor solicitation for IP: {}",
+        ipv6_addr_to_str(&target)
+    );
+    output(
+        ICMPV6_TYPE_NEIGHBOR_SOLICIT,
+        0,
+        0,
+        body,
+        iface.unicast,
+        solicited_node_addr(&target),
+        ND_HOP_LIMIT,
+        device,
+        contexts,
+    )
+}
+
+/// Sends Neighbor Advertisement of the interface address.
+pub fn neighbor_advertise(
+    device: &mut NetDevice,
+    iface: &IPV6Interface,
+    dst: IPV6Address,
+    solicited: bool,
+    contexts: &mut ProtocolContexts,
+) -> Result<(), ()> {
+    let mut flags = ND_NA_FLAG_OVERRIDE;
+    if solicited {
+        flags |= ND_NA_FLAG_SOLICITED;
+    }
+    let body = neighbor_message(iface.unicast, ND_OPT_TARGET_LINK_ADDR, device);
+    output(
+        ICMPV6_TYPE_NEIGHBOR_ADVERT,
+        0,
+        le_to_be_u32(flags),
+        body,
+        iface.unicast,
+        dst,
+        ND_HOP_LIMIT,
+        device,
+        contexts,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        checksum, input, output, ICMPV6Header, NeighborMessage, ICMPV6_TYPE_ECHO,
+        ICMPV6_TYPE_ECHO_REPLY, ICMPV6_TYPE_NEIGHBOR_ADVERT, ICMPV6_TYPE_NEIGHBOR_SOLICIT,
+        ND_HOP_LIMIT, ND_OPT_TARGET_LINK_ADDR,
+    };
+    use crate::{
+        devices::{ethernet::ETH_HDR_SIZE, test_device},
+        protocols::ip::IPRoutes,
+        protocols::ipv6::{link_local_addr, IPV6Interface},
+        protocols::{ControlBlocks, ProtocolContexts},
+        utils::{byte::le_to_be_u32, to_u8_slice},
+    };
+    use std::{mem::size_of, sync::Arc};
+
+    /// ICMPv6 message with a valid checksum.
+    fn message(icmp_type: u8, values: u32, body: &[u8], src: &[u8; 16], dst: &[u8; 16]) -> Vec<u8> {
+        let hdr = ICMPV6Header {
+            icmp_type,
+            code: 0,
+            check_sum: 0,
+            values,
+        };
+        let mut data = unsafe { to_u8_slice(&hdr) }.to_vec();
+        data.extend_from_slice(body);
+        let check_sum = checksum(src, dst, &data);
+        data[2..4].copy_from_slice(&check_sum.to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn test_pending_till_advertised() {
+        let mut device = test_device("192.0.2.2/24");
+        let iface = Arc::new(IPV6Interface::link_local([0x02, 0, 0, 0, 0, 0x02]));
+        device.register_ipv6_interface(iface.clone());
+        let mut contexts = ProtocolContexts::new(IPRoutes::new());
+        let mut pcbs = ControlBlocks::new();
+        let peer_hw_addr = [0x02, 0, 0, 0, 0, 0x01];
+        let peer = link_local_addr(peer_hw_addr);
+
+        let values = le_to_be_u32(1 << 16 | 1);
+        for _ in 0..2 {
+            let body = b"ping".to_vec();
+            output(
+                ICMPV6_TYPE_ECHO,
+                0,
+                values,
+                body,
+                iface.unicast,
+                peer,
+                0,
+                &mut device,
+                &mut contexts,
+            )
+            .unwrap();
+        }
+        // Only solicitations go out while the neighbor is unresolved.
+        let tx_frames = &mut device.driver_data.as_mut().unwrap().tx_frames;
+        assert_eq!(2, tx_frames.len());
+        for frame in tx_frames.iter() {
+            assert_eq!([0x33, 0x33, 0xff, 0, 0, 0x01], frame[..6]);
+            assert_eq!(ICMPV6_TYPE_NEIGHBOR_SOLICIT, frame[ETH_HDR_SIZE + 40]);
+        }
+        tx_frames.clear();
+
+        let advert = NeighborMessage {
+            target: peer,
+            opt_type: ND_OPT_TARGET_LINK_ADDR,
+            opt_len: 1,
+            link_addr: peer_hw_addr,
+        };
+        let body = unsafe { to_u8_slice(&advert) };
+        let data = message(ICMPV6_TYPE_NEIGHBOR_ADVERT, 0, body, &peer, &iface.unicast);
+        input(
+            &data,
+            peer,
+            iface.unicast,
+            ND_HOP_LIMIT,
+            &mut device,
+            &iface,
+            &mut contexts,
+            &mut pcbs,
+        )
+        .unwrap();
+        let tx_frames = &mut device.driver_data.as_mut().unwrap().tx_frames;
+        assert_eq!(2, tx_frames.len());
+        for frame in tx_frames.iter() {
+            assert_eq!(peer_hw_addr, frame[..6]);
+            assert_eq!(ICMPV6_TYPE_ECHO, frame[ETH_HDR_SIZE + 40]);
+        }
+        assert!(contexts.neighbor_cache.take_pending(peer).is_empty());
+    }
+
+    #[test]
+    fn test_echo_reply_source() {
+        let mut device = test_device("192.0.2.2/24");
+        let iface = Arc::new(IPV6Interface::link_local([0x02, 0, 0, 0, 0, 0x02]));
+        device.register_ipv6_interface(iface.clone());
+        let mut contexts = ProtocolContexts::new(IPRoutes::new());
+        let mut pcbs = ControlBlocks::new();
+        let peer_hw_addr = [0x02, 0, 0, 0, 0, 0x01];
+        let peer = link_local_addr(peer_hw_addr);
+        contexts.neighbor_cache.update(peer, peer_hw_addr);
+
+        // Echo request to all nodes is answered from the interface address.
+        let all_nodes = super::IPV6_ADDR_ALL_NODES;
+        let values = le_to_be_u32(1 << 16 | 1);
+        let data = message(ICMPV6_TYPE_ECHO, values, b"ping", &peer, &all_nodes);
+        input(
+            &data,
+            peer,
+            all_nodes,
+            64,
+            &mut device,
+            &iface,
+            &mut contexts,
+            &mut pcbs,
+        )
+        .unwrap();
+        let frame = device
+            .driver_data
+            .as_mut()
+            .unwrap()
+            .tx_frames
+            .pop_front()
+            .unwrap();
+        let ip_data = &frame[ETH_HDR_SIZE..];
+        assert_eq!(iface.unicast, ip_data[8..24]);
+        assert_eq!(peer, ip_data[24..40]);
+        let icmp_data = &ip_data[40..];
+        assert_eq!(ICMPV6_TYPE_ECHO_REPLY, icmp_data[0]);
+        assert_eq!(0, checksum(&iface.unicast, &peer, icmp_data));
+        assert_eq!(b"ping", &icmp_data[size_of::<ICMPV6Header>()..]);
+    }
+}
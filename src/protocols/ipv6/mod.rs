@@ -0,0 +1,416 @@
+pub mod icmpv6;
+
+use super::{ControlBlocks, ProtocolContexts, ProtocolType};
+use crate::{
+    devices::{ethernet::ETH_ADDR_LEN, NetDevice},
+    net::{NetInterface, NetInterfaceFamily},
+    utils::byte::{be_to_le_u16, be_to_le_u32, le_to_be_u16, le_to_be_u32},
+    utils::{bytes_to_struct, to_u8_slice},
+};
+use log::{debug, error, info, trace, warn};
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryInto,
+    mem::size_of,
+    time::SystemTime,
+};
+
+pub type IPV6Address = [u8; IPV6_ADDR_LEN];
+
+pub const IPV6_ADDR_LEN: usize = 16;
+const IPV6_VERSION: u8 = 6;
+const IPV6_HEADER_SIZE: usize = 40;
+const IPV6_DEFAULT_HOP_LIMIT: u8 = 64;
+
+const NEIGHBOR_CACHE_TIMEOUT_SECS: u64 = 60 * 60 * 4; // timeout: 4hr
+const NEIGHBOR_PENDING_QUEUE_LEN: usize = 32; // datagrams held per neighbor till resolution
+
+pub const IPV6_ADDR_ANY: IPV6Address = [0; IPV6_ADDR_LEN]; // ::
+pub const IPV6_ADDR_ALL_NODES: IPV6Address =
+    [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01]; // ff02::1
+
+// see https://www.iana.org/assignments/protocol-numbers/protocol-numbers.txt
+pub enum IPV6NextHeader {
+    Icmpv6 = 0x3a,
+    Unknown,
+}
+
+impl IPV6NextHeader {
+    pub fn from_u8(value: u8) -> IPV6NextHeader {
+        match value {
+            0x3a => IPV6NextHeader::Icmpv6,
+            _ => IPV6NextHeader::Unknown,
+        }
+    }
+}
+
+/// IPv6 Header (unit: octet)
+/// [ Version: 4 bits | Traffic Class: 8 bits | Flow Label: 20 bits ]
+/// [ Payload Length: 2 | Next Header: 1 | Hop Limit: 1 ]
+/// [ Source Address: 16 | Destination Address: 16 ]
+#[repr(packed)]
+pub struct IPV6Header {
+    ver_tc_flow: u32,
+    payload_len: u16,
+    next_header: u8,
+    hop_limit: u8,
+    src: IPV6Address,
+    dst: IPV6Address,
+}
+
+#[derive(Debug)]
+pub struct IPV6Interface {
+    pub interface: NetInterface,
+    pub unicast: IPV6Address,
+    pub prefix_len: u8,
+}
+
+impl IPV6Interface {
+    pub fn new(unicast: IPV6Address, prefix_len: u8) -> IPV6Interface {
+        IPV6Interface {
+            interface: NetInterface {
+                family: NetInterfaceFamily::IPV6,
+                next: None,
+            },
+            unicast,
+            prefix_len,
+        }
+    }
+
+    /// Creates an interface with link-local address (fe80::/64) generated from a MAC address.
+    pub fn link_local(hw_addr: [u8; ETH_ADDR_LEN]) -> IPV6Interface {
+        IPV6Interface::new(link_local_addr(hw_addr), 64)
+    }
+
+    /// Checks if a destination address is addressed to this interface.
+    pub fn accepts(&self, dst: &IPV6Address) -> bool {
+        *dst == self.unicast
+            || *dst == IPV6_ADDR_ALL_NODES
+            || *dst == solicited_node_addr(&self.unicast)
+    }
+}
+
+/// Generates link-local address with modified EUI-64 interface identifier (RFC 4291 Appendix A).
+pub fn link_local_addr(hw_addr: [u8; ETH_ADDR_LEN]) -> IPV6Address {
+    let mut addr = IPV6_ADDR_ANY;
+    addr[0] = 0xfe;
+    addr[1] = 0x80;
+    addr[8] = hw_addr[0] ^ 0x02; // flip universal/local bit
+    addr[9] = hw_addr[1];
+    addr[10] = hw_addr[2];
+    addr[11] = 0xff;
+    addr[12] = 0xfe;
+    addr[13] = hw_addr[3];
+    addr[14] = hw_addr[4];
+    addr[15] = hw_addr[5];
+    addr
+}
+
+/// Solicited-node multicast address: ff02::1:ff + lower 24 bits of unicast address.
+pub fn solicited_node_addr(addr: &IPV6Address) -> IPV6Address {
+    let mut res = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0xff, 0, 0, 0];
+    res[13..].copy_from_slice(&addr[13..]);
+    res
+}
+
+/// Ethernet multicast address for IPv6 multicast: 33:33 + lower 32 bits of address (RFC 2464).
+pub fn multicast_hw_addr(addr: &IPV6Address) -> [u8; ETH_ADDR_LEN] {
+    [0x33, 0x33, addr[12], addr[13], addr[14], addr[15]]
+}
+
+pub fn is_multicast(addr: &IPV6Address) -> bool {
+    addr[0] == 0xff
+}
+
+struct NeighborCacheEntry {
+    hw_address: [u8; ETH_ADDR_LEN],
+    timestamp: SystemTime,
+}
+
+/// Neighbor cache of Neighbor Discovery (counterpart of ARP table).
+pub struct NeighborCache {
+    entries: HashMap<IPV6Address, NeighborCacheEntry>,
+    pending: HashMap<IPV6Address, VecDeque<Vec<u8>>>, // datagrams waiting for advertisement
+}
+
+impl NeighborCache {
+    pub fn new() -> NeighborCache {
+        NeighborCache {
+            entries: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Holds an IPv6 datagram till the hardware address of the neighbor is resolved.
+    /// The oldest datagram is dropped when the queue of the neighbor is full.
+    pub fn enqueue(&mut self, ip: IPV6Address, data: Vec<u8>) {
+        let queue = self.pending.entry(ip).or_default();
+        if queue.len() >= NEIGHBOR_PENDING_QUEUE_LEN {
+            warn!(
+                "IPv6: pending queue for IP = {} is full. Dropping oldest datagram...",
+                ipv6_addr_to_str(&ip)
+            );
+            queue.pop_front();
+        }
+        queue.push_back(data);
+    }
+
+    /// Takes IPv6 datagrams waiting for the neighbor.
+    pub fn take_pending(&mut self, ip: IPV6Address) -> VecDeque<Vec<u8>> {
+        self.pending.remove(&ip).unwrap_or_default()
+    }
+
+    pub fn get(&mut self, ip: &IPV6Address) -> Option<[u8; ETH_ADDR_LEN]> {
+        let entry = self.entries.get(ip)?;
+        if entry.timestamp.elapsed().unwrap().as_secs() > NEIGHBOR_CACHE_TIMEOUT_SECS {
+            self.entries.remove(ip);
+            return None;
+        }
+        Some(entry.hw_address)
+    }
+
+    pub fn update(&mut self, ip: IPV6Address, resolved: [u8; ETH_ADDR_LEN]) {
+        self.entries.insert(
+            ip,
+            NeighborCacheEntry {
+                hw_address: resolved,
+                timestamp: SystemTime::now(),
+            },
+        );
+    }
+}
+
+pub fn output(
+    next_header: IPV6NextHeader,
+    hop_limit: u8,
+    mut data: Vec<u8>,
+    src: IPV6Address,
+    dst: IPV6Address,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+) -> Result<(), ()> {
+    let interface = match device.get_ipv6_interface() {
+        Some(interface) => interface,
+        None => {
+            error!("IPv6: no interface on device: {}", device.name);
+            return Err(());
+        }
+    };
+    let src = if src == IPV6_ADDR_ANY {
+        interface.unicast
+    } else {
+        src
+    };
+
+    let header = IPV6Header {
+        ver_tc_flow: le_to_be_u32((IPV6_VERSION as u32) << 28),
+        payload_len: le_to_be_u16(data.len() as u16),
+        next_header: next_header as u8,
+        hop_limit: if hop_limit == 0 {
+            IPV6_DEFAULT_HOP_LIMIT
+        } else {
+            hop_limit
+        },
+        src,
+        dst,
+    };
+    let header_bytes = unsafe { to_u8_slice::<IPV6Header>(&header) };
+    let mut ip_data = header_bytes.to_vec();
+    ip_data.append(&mut data);
+    let ip_data_len = ip_data.len();
+
+    let mut hw_addr: [u8; ETH_ADDR_LEN] = [0; ETH_ADDR_LEN];
+    if device.capabilities.needs_arp {
+        if is_multicast(&dst) {
+            hw_addr = multicast_hw_addr(&dst);
+        } else if let Some(resolved) = contexts.neighbor_cache.get(&dst) {
+            hw_addr = resolved;
+        } else {
+            // Held till an advertisement of the neighbor flushes it (see flush_pending).
+            info!("IPv6: neighbor not resolved. Sending neighbor solicitation...");
+            contexts.neighbor_cache.enqueue(dst, ip_data);
+            return icmpv6::neighbor_solicit(device, &interface, dst, contexts);
+        }
+    }
+
+    trace!(
+        "IPv6: output src = {} dst = {}",
+        ipv6_addr_to_str(&src),
+        ipv6_addr_to_str(&dst)
+    );
+    device.transmit(ProtocolType::IPV6, ip_data, ip_data_len, hw_addr)
+}
+
+/// Sends datagrams held for a neighbor whose hardware address has been resolved.
+pub fn flush_pending(
+    ip: IPV6Address,
+    hw_addr: [u8; ETH_ADDR_LEN],
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+) {
+    for ip_data in contexts.neighbor_cache.take_pending(ip) {
+        info!(
+            "IPv6: sending pending datagram to IP = {}",
+            ipv6_addr_to_str(&ip)
+        );
+        let len = ip_data.len();
+        if device
+            .transmit(ProtocolType::IPV6, ip_data, len, hw_addr)
+            .is_err()
+        {
+            error!(
+                "IPv6: failed to send pending datagram to IP = {}",
+                ipv6_addr_to_str(&ip)
+            );
+        }
+    }
+}
+
+pub fn input(
+    data: &[u8],
+    len: usize,
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+    pcbs: &mut ControlBlocks,
+) -> Result<(), ()> {
+    if len < IPV6_HEADER_SIZE {
+        error!("IPv6: data is too short.");
+        return Err(());
+    }
+    let header = unsafe { bytes_to_struct::<IPV6Header>(data) };
+    let version = (be_to_le_u32(header.ver_tc_flow) >> 28) as u8;
+    if version != IPV6_VERSION {
+        error!("IPv6: version error with value: {version}");
+        return Err(());
+    }
+    let payload_len = be_to_le_u16(header.payload_len) as usize;
+    if len < IPV6_HEADER_SIZE + payload_len {
+        error!("IPv6: payload length error.");
+        return Err(());
+    }
+    let interface = match device.get_ipv6_interface() {
+        Some(interface) => interface,
+        None => return Ok(()),
+    };
+    let dst = header.dst;
+    if !interface.accepts(&dst) {
+        debug!("IPv6: not my address: {}", ipv6_addr_to_str(&dst));
+        return Ok(());
+    }
+    let sub_data = &data[size_of::<IPV6Header>()..IPV6_HEADER_SIZE + payload_len];
+    match IPV6NextHeader::from_u8(header.next_header) {
+        IPV6NextHeader::Icmpv6 => icmpv6::input(
+            sub_data,
+            header.src,
+            dst,
+            header.hop_limit,
+            device,
+            &interface,
+            contexts,
+            pcbs,
+        ),
+        IPV6NextHeader::Unknown => Ok(()),
+    }
+}
+
+/// Parses IPv6 text representation including "::" zero compression.
+pub fn ipv6_addr_from_str(addr: &str) -> Option<IPV6Address> {
+    fn parse_groups(part: &str) -> Option<Vec<u16>> {
+        if part.is_empty() {
+            return Some(vec![]);
+        }
+        part.split(':')
+            .map(|g| u16::from_str_radix(g, 16).ok())
+            .collect()
+    }
+    let groups = match addr.split_once("::") {
+        Some((head, tail)) => {
+            let head = parse_groups(head)?;
+            let tail = parse_groups(tail)?;
+            if head.len() + tail.len() > 7 {
+                return None;
+            }
+            let mut groups = head;
+            groups.resize(8 - tail.len(), 0);
+            groups.extend(tail);
+            groups
+        }
+        None => parse_groups(addr)?,
+    };
+    if groups.len() != 8 {
+        return None;
+    }
+    let mut res = IPV6_ADDR_ANY;
+    for (i, g) in groups.iter().enumerate() {
+        res[i * 2..i * 2 + 2].copy_from_slice(&g.to_be_bytes());
+    }
+    Some(res)
+}
+
+/// Formats IPv6 address compressing the longest run of zero groups.
+pub fn ipv6_addr_to_str(addr: &IPV6Address) -> String {
+    let groups: Vec<u16> = addr
+        .chunks(2)
+        .map(|c| u16::from_be_bytes(c.try_into().unwrap()))
+        .collect();
+    let (mut best_start, mut best_len) = (0, 0);
+    let mut i = 0;
+    while i < groups.len() {
+        if groups[i] == 0 {
+            let start = i;
+            while i < groups.len() && groups[i] == 0 {
+                i += 1;
+            }
+            if i - start > best_len {
+                best_start = start;
+                best_len = i - start;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    let to_hex = |gs: &[u16]| {
+        gs.iter()
+            .map(|g| format!("{g:x}"))
+            .collect::<Vec<String>>()
+            .join(":")
+    };
+    if best_len < 2 {
+        return to_hex(&groups);
+    }
+    format!(
+        "{}::{}",
+        to_hex(&groups[..best_start]),
+        to_hex(&groups[best_start + best_len..])
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ipv6_addr_from_str, ipv6_addr_to_str, link_local_addr, multicast_hw_addr,
+        solicited_node_addr,
+    };
+
+    #[test]
+    fn test_ipv6_addr_str() {
+        let addr = ipv6_addr_from_str("fe80::1:ff00:2").unwrap();
+        assert_eq!([0xfe, 0x80], addr[..2]);
+        assert_eq!([0x00, 0x01, 0xff, 0x00, 0x00, 0x02], addr[10..]);
+        assert_eq!("fe80::1:ff00:2", ipv6_addr_to_str(&addr));
+        assert_eq!("::", ipv6_addr_to_str(&ipv6_addr_from_str("::").unwrap()));
+        assert!(ipv6_addr_from_str("fe80::1::2").is_none());
+    }
+
+    #[test]
+    fn test_link_local_and_multicast() {
+        let addr = link_local_addr([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+        assert_eq!("fe80::211:22ff:fe33:4455", ipv6_addr_to_str(&addr));
+        let solicited = solicited_node_addr(&addr);
+        assert_eq!("ff02::1:ff33:4455", ipv6_addr_to_str(&solicited));
+        assert_eq!(
+            [0x33, 0x33, 0xff, 0x33, 0x44, 0x55],
+            multicast_hw_addr(&solicited)
+        );
+    }
+}
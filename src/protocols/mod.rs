@@ -1,22 +1,40 @@
 pub mod arp;
 pub mod ip;
+pub mod ipv6;
 
 use self::{
-    arp::ArpTable,
-    ip::{tcp::TcpPcbs, udp::UdpPcbs, IPHeaderIdManager, IPRoutes},
+    arp::{ArpTable, GatewayMonitor},
+    ip::{
+        icmp::IcmpPcbs,
+        igmp::IgmpGroups,
+        l4::L4Protocols,
+        nat::{self, Nat},
+        tcp::TcpPcbs,
+        udp::UdpPcbs,
+        IPHeaderIdManager, IPRoutes, MartianCounters,
+    },
+    ipv6::NeighborCache,
 };
 use crate::{
     devices::{NetDevice, NetDevices},
     utils::list::List,
 };
-use log::{info, trace};
-use std::{collections::VecDeque, sync::Arc};
+use log::{info, trace, warn};
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+// Age of queued input data regarded as missed by SIGUSR1 handling (e.g. coalesced signals)
+const INPUT_SWEEP_AGE_MILLIS: u64 = 50;
 
 #[derive(PartialEq, Debug)]
 pub enum ProtocolType {
     Arp = 0x0806,
     IP = 0x0800,
-    // IPV6 = 0x86dd,
+    IPV6 = 0x86dd,
     Unknown,
 }
 
@@ -25,20 +43,87 @@ impl ProtocolType {
         match value {
             0x0800 => ProtocolType::IP,
             0x0806 => ProtocolType::Arp,
+            0x86dd => ProtocolType::IPV6,
             _ => ProtocolType::Unknown,
         }
     }
+
+    /// Order of input handling, lower first. ARP goes ahead of IP so that resolutions, which
+    /// output queued for them waits on, are not delayed behind a flood of datagrams.
+    fn input_priority(&self) -> u8 {
+        match self {
+            ProtocolType::Arp => 0,
+            _ => 1,
+        }
+    }
 }
 
 pub struct ProtocolData {
     irq: i32,
     data: Option<Arc<Vec<u8>>>, // accessed from input/output threads for loopback
     len: usize,
+    queued_at: Instant,
+    flow_hash: Option<u32>, // of IP data (ip::flow_hash)
+    vlan: Option<u16>,      // VLAN id of the frame tag
 }
 
 impl ProtocolData {
-    pub fn new(irq: i32, data: Option<Arc<Vec<u8>>>, len: usize) -> ProtocolData {
-        ProtocolData { irq, data, len }
+    pub fn new(
+        irq: i32,
+        data: Option<Arc<Vec<u8>>>,
+        len: usize,
+        flow_hash: Option<u32>,
+        vlan: Option<u16>,
+    ) -> ProtocolData {
+        ProtocolData {
+            irq,
+            data,
+            len,
+            queued_at: Instant::now(),
+            flow_hash,
+            vlan,
+        }
+    }
+
+    /// Index of a worker out of `workers` to process the data on, the same for all data of a
+    /// flow so that segments of a connection stay in order. Data without flows go to the first.
+    pub fn worker(&self, workers: usize) -> usize {
+        self.flow_hash
+            .map_or(0, |hash| hash as usize % workers.max(1))
+    }
+}
+
+/// Latency from queueing input data on an IRQ to processing it, and data found left in queues
+/// by the periodic sweep as SIGUSR1 raised for it got coalesced with another one.
+#[derive(Default)]
+pub struct InputLatency {
+    processed: u64,
+    total: Duration,
+    max: Duration,
+    swept: u64,  // data processed by the sweep
+    sweeps: u64, // sweeps finding data left
+}
+
+impl InputLatency {
+    fn record(&mut self, queued_at: Instant) {
+        let latency = queued_at.elapsed();
+        self.processed += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+    }
+}
+
+impl fmt::Display for InputLatency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let avg = match self.processed {
+            0 => Duration::ZERO,
+            n => self.total / n as u32,
+        };
+        writeln!(
+            f,
+            "input latency: processed: {} avg: {:?} max: {:?} left by missed signals: {} in {} sweeps",
+            self.processed, avg, self.max, self.swept, self.sweeps
+        )
     }
 }
 
@@ -62,30 +147,60 @@ impl NetProtocol {
         devices: &mut NetDevices,
         contexts: &mut ProtocolContexts,
         pcbs: &mut ControlBlocks,
+        latency: &mut InputLatency,
     ) {
         loop {
             if self.input_head.is_empty() {
                 break;
             }
             let proto_data = self.input_head.pop_front().unwrap();
-            let data = proto_data.data.unwrap();
-            let len = proto_data.len;
+            latency.record(proto_data.queued_at);
+            let mut data = proto_data.data.unwrap();
+            let mut len = proto_data.len;
 
             // let devices = proto_stack.devices.lock().unwrap();
-            for device in devices.entries.iter_mut() {
-                if device.irq_entry.irq == proto_data.irq {
-                    self.input(data.as_slice(), len, device, contexts, pcbs);
-                    break;
+            let Some(index) = devices
+                .entries
+                .iter()
+                .position(|device| device.irq_entry.irq == proto_data.irq)
+            else {
+                continue;
+            };
+            if self.protocol_type == ProtocolType::IP && contexts.ip_forwarding {
+                // Replies of masqueraded flows are forwarded to the inside endpoints.
+                let device = devices.entries.iter().nth(index).unwrap();
+                if let Some(translated) = nat::inbound(&data, device, contexts) {
+                    len = translated.len();
+                    data = Arc::new(translated);
+                }
+            }
+            if self.protocol_type == ProtocolType::IP
+                && contexts.ip_forwarding
+                && ip::is_transit(&data, devices)
+            {
+                if ip::forward(&data, len, index, devices, contexts, pcbs).is_err() {
+                    trace!("Protocol: IP forwarding failed.");
                 }
+                continue;
             }
+            let device = devices.entries.iter_mut().nth(index).unwrap();
+            self.input(
+                data.as_slice(),
+                len,
+                proto_data.vlan,
+                device,
+                contexts,
+                pcbs,
+            );
         }
     }
 
-    /// Handles input data per a protocol type.
+    /// Handles input data per a protocol type, of a frame tagged for a VLAN id if any.
     pub fn input(
         &self,
         data: &[u8],
         len: usize,
+        vlan: Option<u16>,
         device: &mut NetDevice,
         contexts: &mut ProtocolContexts,
         pcbs: &mut ControlBlocks,
@@ -95,11 +210,25 @@ impl NetProtocol {
         match self.protocol_type {
             ProtocolType::Arp => {
                 trace!("Protocol: ARP | Received: {:02x?}", data);
-                arp::input(data, len, device, contexts).unwrap();
+                arp::input(data, len, vlan, device, contexts).unwrap();
             }
             ProtocolType::IP => {
                 trace!("Protocol: IP | Received: {:02x?}", data);
-                ip::input(data, len, device, contexts, pcbs).unwrap();
+                // Datagrams dropped, e.g. to closed UDP ports, are not fatal to input.
+                if ip::input(data, len, vlan, device, contexts, pcbs).is_err() {
+                    trace!("Protocol: IP input failed.");
+                }
+            }
+            ProtocolType::IPV6 => {
+                trace!("Protocol: IPv6 | Received: {:02x?}", data);
+                // IPv6 interfaces are not bound to VLANs.
+                if vlan.is_some() {
+                    trace!("Protocol: IPv6 datagram of VLAN dropped.");
+                    return;
+                }
+                if ipv6::input(data, len, device, contexts, pcbs).is_err() {
+                    trace!("Protocol: IPv6 input failed.");
+                }
             }
             ProtocolType::Unknown => {
                 trace!("Protocol: Unknown | Received: {:x?}", data);
@@ -111,12 +240,14 @@ impl NetProtocol {
 
 pub struct NetProtocols {
     pub entries: List<NetProtocol>,
+    pub latency: InputLatency,
 }
 
 impl NetProtocols {
     pub fn new() -> NetProtocols {
         NetProtocols {
             entries: List::<NetProtocol>::new(),
+            latency: InputLatency::default(),
         }
     }
 
@@ -130,27 +261,295 @@ impl NetProtocols {
         contexts: &mut ProtocolContexts,
         pcbs: &mut ControlBlocks,
     ) {
-        for protocol in self.entries.iter_mut() {
-            protocol.handle_input(devices, contexts, pcbs);
+        let mut protocols: Vec<&mut NetProtocol> = self.entries.iter_mut().collect();
+        protocols.sort_by_key(|protocol| protocol.protocol_type.input_priority());
+        for protocol in protocols {
+            protocol.handle_input(devices, contexts, pcbs, &mut self.latency);
         }
     }
+
+    /// Processes input data left queued for long, which SIGUSR1 got missed for. Called
+    /// periodically by the timer thread. Returns the number of data processed.
+    pub fn sweep(
+        &mut self,
+        devices: &mut NetDevices,
+        contexts: &mut ProtocolContexts,
+        pcbs: &mut ControlBlocks,
+    ) -> usize {
+        let age = Duration::from_millis(INPUT_SWEEP_AGE_MILLIS);
+        let left: usize = self
+            .entries
+            .iter()
+            .filter(|protocol| {
+                protocol
+                    .input_head
+                    .front()
+                    .is_some_and(|data| data.queued_at.elapsed() >= age)
+            })
+            .map(|protocol| protocol.input_head.len())
+            .sum();
+        if left == 0 {
+            return 0;
+        }
+        warn!("Protocol: {left} input data left unprocessed (SIGUSR1 coalesced?). Sweeping...");
+        self.latency.swept += left as u64;
+        self.latency.sweeps += 1;
+        self.handle_data(devices, contexts, pcbs);
+        left
+    }
+}
+/// Reason reported to socket users woken up without data.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum SocketError {
+    AdministrativelyClosed,
+    ConnectionRefused,  // ICMP protocol / port unreachable
+    NetworkUnreachable, // ICMP net unreachable
+    HostUnreachable,    // ICMP host unreachable / source route failed
+    TimeExceeded,       // ICMP time exceeded in transit
+    TimedOut,           // no reply to SYN retransmissions
+    Closed,             // listener closed while accepting
 }
+
 pub struct ProtocolContexts {
     pub arp_table: ArpTable,
     pub ip_routes: IPRoutes,
     pub ip_id_manager: IPHeaderIdManager,
+    pub neighbor_cache: NeighborCache,
+    pub martians: MartianCounters,
+    pub gateway_monitor: GatewayMonitor,
+    pub l4_protocols: L4Protocols,
+    pub igmp_groups: IgmpGroups,
+    pub ip_forwarding: bool, // forwards datagrams to other hosts (ip::forward)
+    pub nat: Option<Nat>,    // masquerades forwarded flows out of a device
+}
+
+impl ProtocolContexts {
+    pub fn new(ip_routes: IPRoutes) -> ProtocolContexts {
+        ProtocolContexts {
+            arp_table: ArpTable::new(),
+            ip_routes,
+            ip_id_manager: IPHeaderIdManager::new(),
+            neighbor_cache: NeighborCache::new(),
+            martians: MartianCounters::default(),
+            gateway_monitor: GatewayMonitor::new(),
+            l4_protocols: L4Protocols::new(),
+            igmp_groups: IgmpGroups::new(),
+            ip_forwarding: false,
+            nat: None,
+        }
+    }
 }
 
 pub struct ControlBlocks {
+    pub icmp_pcbs: IcmpPcbs,
     pub udp_pcbs: UdpPcbs,
     pub tcp_pcbs: TcpPcbs,
+    pub memory_limit: Option<usize>, // bytes buffered by PCBs of all protocols (--max-memory)
+    memory_refused: u64,             // data and connections refused for the limit
 }
 
 impl ControlBlocks {
     pub fn new() -> ControlBlocks {
         ControlBlocks {
+            icmp_pcbs: IcmpPcbs::new(),
             udp_pcbs: UdpPcbs::new(),
             tcp_pcbs: TcpPcbs::new(),
+            memory_limit: None,
+            memory_refused: 0,
         }
     }
+
+    /// Bytes buffered by PCBs of all protocols: TCP data to read, to send or acknowledge and
+    /// arrived out of order, and UDP datagrams and ICMP messages queued to read.
+    pub fn memory_used(&self) -> usize {
+        self.memory_usage().iter().map(|(_, _, bytes)| bytes).sum()
+    }
+
+    /// Bytes left under the memory limit, unlimited without one.
+    pub fn memory_free(&self) -> usize {
+        self.memory_limit
+            .map_or(usize::MAX, |limit| limit.saturating_sub(self.memory_used()))
+    }
+
+    /// Whether `len` more bytes fit under the memory limit, counting a refusal if not. New
+    /// connections ask for a byte so that they are refused once the limit is reached.
+    pub fn memory_admits(&mut self, len: usize) -> bool {
+        if len <= self.memory_free() {
+            return true;
+        }
+        self.memory_refused += 1;
+        false
+    }
+
+    fn memory_usage(&self) -> Vec<(&str, usize, usize)> {
+        let tcp = self.tcp_pcbs.memory_usage().into_iter();
+        let udp = self.udp_pcbs.memory_usage().into_iter();
+        let icmp = self.icmp_pcbs.memory_usage().into_iter();
+        tcp.map(|(id, bytes)| ("tcp", id, bytes))
+            .chain(udp.map(|(id, bytes)| ("udp", id, bytes)))
+            .chain(icmp.map(|(id, bytes)| ("icmp", id, bytes)))
+            .collect()
+    }
+
+    /// Bytes buffered in total against the limit, and by each PCB in use.
+    pub fn memory_stats(&self) -> String {
+        let limit = self
+            .memory_limit
+            .map_or(String::from("none"), |limit| limit.to_string());
+        let mut text = format!(
+            "memory:\n  used: {} limit: {limit} refused: {}\n",
+            self.memory_used(),
+            self.memory_refused
+        );
+        for (protocol, id, bytes) in self.memory_usage() {
+            text += &format!("  {protocol:<4} {id:>3}: {bytes}\n");
+        }
+        text
+    }
+
+    /// TCP connections and UDP PCBs in netstat format.
+    pub fn netstat(&self) -> String {
+        format!(
+            "{:<5} {:<23} {:<23} State\n",
+            "Proto", "Local Address", "Foreign Address"
+        ) + &self.tcp_pcbs.netstat()
+            + &self.udp_pcbs.netstat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ControlBlocks, NetProtocol, NetProtocols, ProtocolContexts, ProtocolData, ProtocolType,
+    };
+    use crate::{
+        devices::{ethernet::ETH_HDR_SIZE, test_device, HwAddress, NetDevices},
+        net::NetInterfaceFamily,
+        protocols::{
+            arp,
+            ip::{ip_addr_to_bytes, udp, IPEndpoint, IPRoute, IPRoutes},
+        },
+    };
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn test_sweep() {
+        let mut devices = NetDevices::new();
+        let device = test_device("192.0.2.2/24");
+        let irq = device.irq_entry.irq;
+        devices.register(device);
+        let mut contexts = ProtocolContexts::new(IPRoutes::new());
+        let mut pcbs = ControlBlocks::new();
+        let mut protocols = NetProtocols::new();
+        protocols.register(NetProtocol::new(ProtocolType::Unknown));
+        let data = || ProtocolData::new(irq, Some(Arc::new(vec![0; 4])), 4, None, None);
+
+        // Data just queued is left for SIGUSR1 handling.
+        protocols
+            .entries
+            .iter_mut()
+            .next()
+            .unwrap()
+            .input_head
+            .push_back(data());
+        assert_eq!(0, protocols.sweep(&mut devices, &mut contexts, &mut pcbs));
+
+        // Data left by a coalesced signal
+        let mut old = data();
+        old.queued_at = Instant::now() - Duration::from_millis(200);
+        let protocol = protocols.entries.iter_mut().next().unwrap();
+        protocol.input_head.push_front(old);
+        assert_eq!(2, protocols.sweep(&mut devices, &mut contexts, &mut pcbs));
+        assert!(protocols
+            .entries
+            .iter_mut()
+            .next()
+            .unwrap()
+            .input_head
+            .is_empty());
+        assert_eq!(2, protocols.latency.processed);
+        assert_eq!(2, protocols.latency.swept);
+        assert!(protocols.latency.max >= Duration::from_millis(200));
+        assert!(protocols
+            .latency
+            .to_string()
+            .contains("left by missed signals: 2 in 1 sweeps"));
+    }
+
+    #[test]
+    fn test_arp_before_ip() {
+        let mut device = test_device("192.0.2.2/24");
+        let irq = device.irq_entry.irq;
+        let interface = device.get_interface(NetInterfaceFamily::IP).unwrap();
+        let mut routes = IPRoutes::new();
+        routes.register(IPRoute::interface_route(interface));
+        let mut contexts = ProtocolContexts::new(routes);
+        let mut pcbs = ControlBlocks::new();
+
+        // A peer floods datagrams to a closed port and then asks for the HW address.
+        let mut peer = test_device("192.0.2.1/24");
+        peer.address = HwAddress::from([0x02, 0, 0, 0, 0, 0x01]);
+        let peer_interface = peer.get_interface(NetInterfaceFamily::IP).unwrap();
+        let mut peer_routes = IPRoutes::new();
+        peer_routes.register(IPRoute::interface_route(peer_interface.clone()));
+        let mut peer_contexts = ProtocolContexts::new(peer_routes);
+        let unicast = ip_addr_to_bytes("192.0.2.2").unwrap();
+        peer_contexts
+            .arp_table
+            .update(unicast, [0x02, 0, 0, 0, 0, 0x02]);
+        for _ in 0..20 {
+            udp::output(
+                IPEndpoint::new_from_str("192.0.2.1", 40000),
+                IPEndpoint::new_from_str("192.0.2.2", 9),
+                b"flood".to_vec(),
+                &mut peer,
+                &mut peer_contexts,
+                &mut ControlBlocks::new(),
+            );
+        }
+        arp::arp_request(&mut peer, peer_interface, unicast).unwrap();
+
+        // Registered after IP and queued last, ARP is handled first.
+        let mut protocols = NetProtocols::new();
+        protocols.register(NetProtocol::new(ProtocolType::IP));
+        protocols.register(NetProtocol::new(ProtocolType::Arp));
+        let frames = peer.driver_data.as_mut().unwrap().tx_frames.drain(..);
+        for frame in frames.collect::<Vec<_>>() {
+            let proto_type = ProtocolType::from_u16(u16::from_be_bytes([frame[12], frame[13]]));
+            let data = frame[ETH_HDR_SIZE..].to_vec();
+            let len = data.len();
+            for protocol in protocols.entries.iter_mut() {
+                if protocol.protocol_type == proto_type {
+                    let data = ProtocolData::new(irq, Some(Arc::new(data)), len, None, None);
+                    protocol.input_head.push_back(data);
+                    break;
+                }
+            }
+        }
+        let mut devices = NetDevices::new();
+        devices.register(device);
+        protocols.handle_data(&mut devices, &mut contexts, &mut pcbs);
+
+        // The ARP reply goes out first and Port Unreachable errors follow without waiting for
+        // resolutions, as many as the rate limit of errors to the peer allows.
+        let device = devices.entries.iter().next().unwrap();
+        let tx_frames = &device.driver_data.as_ref().unwrap().tx_frames;
+        let ether_types: Vec<u16> = tx_frames
+            .iter()
+            .map(|frame| u16::from_be_bytes([frame[12], frame[13]]))
+            .collect();
+        assert_eq!(
+            1 + pcbs.icmp_pcbs.error_limit.dest_burst as usize,
+            ether_types.len()
+        );
+        assert_eq!(ProtocolType::Arp as u16, ether_types[0]);
+        let op = &tx_frames[0][ETH_HDR_SIZE + 6..ETH_HDR_SIZE + 8];
+        assert_eq!(&[0, 2], op);
+        assert!(ether_types[1..]
+            .iter()
+            .all(|ether_type| *ether_type == ProtocolType::IP as u16));
+    }
 }
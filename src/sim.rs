@@ -0,0 +1,299 @@
+use crate::{
+    app::NetApp,
+    devices::{
+        ethernet::{self, VLAN_TAG_SIZE},
+        stats::FrameProtocol,
+        NetDevice,
+    },
+    drivers::{pair, DriverType},
+    net::NetInterfaceFamily,
+    protocols::{
+        arp,
+        ip::{l4, IPAdress, IPEndpoint, IPInterface, IPRoute, IPRoutes, IP_ADDR_ANY},
+        ControlBlocks, NetProtocol, ProtocolContexts,
+    },
+    socket::{TcpStream, UdpSocket},
+    timer,
+};
+use std::{
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+const SETTLE_ROUNDS_MAX: usize = 1000;
+const TRANSFER_READ_SIZE: usize = 4096;
+const TRANSFER_CLIENT_PORT: u16 = 49152;
+
+/// Stack on a device of the memory or pair driver driven only by `poll` on the caller's thread.
+/// No signal, IRQ or timer thread runs, and the clock of protocol timers (`timer::now`) stands
+/// at the time of the last poll, so that scenarios like handshakes and retransmissions can be
+/// stepped through at exact times instead of sleeping in threads. Protocol functions called
+/// between polls, e.g. `tcp::start_connect`, see that time too.
+pub struct SimStack {
+    pub device: NetDevice,
+    pub contexts: ProtocolContexts,
+    pub pcbs: ControlBlocks,
+}
+
+impl SimStack {
+    /// Opens a device with an interface of an address and its subnet, e.g. "192.0.2.1/24",
+    /// routed to directly.
+    pub fn new(mut device: NetDevice, cidr: &str) -> SimStack {
+        device.open().unwrap();
+        let interface = Arc::new(IPInterface::new(cidr));
+        device.register_interface(interface.clone());
+        let mut routes = IPRoutes::new();
+        routes.register(IPRoute::interface_route(interface));
+        SimStack {
+            device,
+            contexts: ProtocolContexts::new(routes),
+            pcbs: ControlBlocks::new(),
+        }
+    }
+
+    /// Moves the clock of this thread to `now`, handles frames received and then timers due.
+    /// Returns the number of frames handled.
+    pub fn poll(&mut self, now: SystemTime) -> usize {
+        timer::set_simulated_now(Some(now));
+        let frames = input(&mut self.device, &mut self.contexts, &mut self.pcbs);
+        l4::timer_tick(&mut self.device, &mut self.contexts, &mut self.pcbs);
+        arp::timer(&mut self.device, &mut self.contexts.arp_table);
+        arp::monitor_gateways(&mut self.device, &mut self.contexts);
+        frames
+    }
+}
+
+/// Handles frames pending on a device of the memory or pair driver as the IRQ and protocol
+/// handlers would for other drivers. Returns the number of frames handled, none for devices of
+/// other drivers.
+pub fn input(
+    device: &mut NetDevice,
+    contexts: &mut ProtocolContexts,
+    pcbs: &mut ControlBlocks,
+) -> usize {
+    let mut frames = 0;
+    while pending(device) {
+        frames += 1;
+        let Some((proto_type, data, len, vlan)) = ethernet::read_data(device) else {
+            continue;
+        };
+        let protocol = FrameProtocol::classify(&proto_type, &data);
+        let tag_len = vlan.map_or(0, |_| VLAN_TAG_SIZE);
+        device
+            .stats
+            .rx
+            .record(len + device.header_len as usize + tag_len, protocol);
+        NetProtocol::new(proto_type).input(&data, len, vlan, device, contexts, pcbs);
+    }
+    frames
+}
+
+fn pending(device: &NetDevice) -> bool {
+    match device.driver_type.as_ref() {
+        Some(DriverType::Memory) => {
+            let driver_data = device.driver_data.as_ref().unwrap();
+            !driver_data.rx_frames.is_empty()
+        }
+        Some(DriverType::Pair(_)) => pair::pending(device),
+        _ => false,
+    }
+}
+
+/// Two stacks on the ends of a device pair (see `ethernet::init_pair`) in a subnet.
+pub fn pair(a: &str, b: &str) -> (SimStack, SimStack) {
+    let (device_a, device_b) = ethernet::init_pair(1);
+    (SimStack::new(device_a, a), SimStack::new(device_b, b))
+}
+
+/// Two started `NetApp`s on the ends of a device pair in a subnet (see `NetApp::with_device`),
+/// talking through sockets as applications do while their threads run on the wall clock.
+pub fn app_pair(a: &str, b: &str) -> (NetApp, NetApp) {
+    let (device_a, device_b) = ethernet::init_pair(1);
+    let apps = (
+        NetApp::with_device(device_a, a),
+        NetApp::with_device(device_b, b),
+    );
+    apps.0.start().unwrap();
+    apps.1.start().unwrap();
+    apps
+}
+
+/// Unicast address of the interface of a stack from `app_pair`.
+fn unicast(app: &NetApp) -> IPAdress {
+    let devices = app.devices.lock().unwrap();
+    let device = devices.entries.iter().next().unwrap();
+    device
+        .get_interface(NetInterfaceFamily::IP)
+        .unwrap()
+        .unicast
+}
+
+/// Sends data over a TCP connection from a fixed port of one stack to a port of the other,
+/// which accepts it and reads till the connection is closed. Returns the data read.
+pub fn tcp_transfer(from: &NetApp, to: &NetApp, port: u16, data: &[u8]) -> Vec<u8> {
+    let local = IPEndpoint::new(unicast(to), port);
+    let server = to.clone();
+    let receiver = thread::spawn(move || {
+        let stream = TcpStream::accept(&server, local).expect("Sim: accept failed.");
+        let mut received = vec![];
+        while let Some(data) = stream.read(TRANSFER_READ_SIZE) {
+            if data.is_empty() {
+                break;
+            }
+            received.extend(data);
+        }
+        received
+    });
+
+    // Till the listener is bound
+    let remote = IPEndpoint::new(unicast(to), port);
+    while to
+        .pcbs
+        .lock()
+        .unwrap()
+        .tcp_pcbs
+        .select(&remote, None)
+        .is_none()
+    {
+        thread::sleep(Duration::from_millis(1));
+    }
+    let local = IPEndpoint::new(unicast(from), TRANSFER_CLIENT_PORT);
+    let stream = TcpStream::connect(from, local, remote).expect("Sim: connect failed.");
+    stream.write_all(data.to_vec());
+    stream.close().ok();
+    receiver.join().unwrap()
+}
+
+/// Sends a UDP datagram from a fixed port of one stack to a port of the other. Returns the data
+/// received within a second, if any.
+pub fn udp_transfer(from: &NetApp, to: &NetApp, port: u16, data: &[u8]) -> Option<Vec<u8>> {
+    let socket = UdpSocket::bind(to, IPEndpoint::new(IP_ADDR_ANY, port));
+    let sender = UdpSocket::bind(from, IPEndpoint::new(unicast(from), TRANSFER_CLIENT_PORT));
+    let remote = IPEndpoint::new(unicast(to), port);
+    sender.send_to(data.to_vec(), remote).ok()?;
+    let entry = socket.recv_from_timeout(Duration::from_secs(1))?;
+    Some(entry.data)
+}
+
+/// Polls stacks in turn at `now` till no frame is left in flight between them. Returns the
+/// number of frames handled.
+pub fn settle(stacks: &mut [&mut SimStack], now: SystemTime) -> usize {
+    let mut frames = 0;
+    for _ in 0..SETTLE_ROUNDS_MAX {
+        let handled: usize = stacks.iter_mut().map(|stack| stack.poll(now)).sum();
+        if handled == 0 {
+            return frames;
+        }
+        frames += handled;
+    }
+    panic!("Sim: frames kept flowing for {SETTLE_ROUNDS_MAX} rounds.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{app_pair, pair, settle, tcp_transfer, udp_transfer, SimStack};
+    use crate::{
+        net::NetInterfaceFamily,
+        protocols::{
+            ip::{tcp, IPEndpoint},
+            SocketError,
+        },
+    };
+    use std::time::{Duration, SystemTime};
+
+    fn start() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000)
+    }
+
+    fn stacks() -> (SimStack, SimStack) {
+        let (mut client, mut server) = pair("192.0.2.1/24", "192.0.2.2/24");
+        // Gratuitous ARPs of the bring-up
+        settle(&mut [&mut client, &mut server], start());
+        (client, server)
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (mut client, mut server) = stacks();
+        let server_endpoint = IPEndpoint::new_from_str("192.0.2.2", 7);
+        let listener = tcp::open(&mut server.pcbs);
+        tcp::bind(
+            listener,
+            IPEndpoint::new_from_str("192.0.2.2", 7),
+            &mut server.pcbs,
+        );
+        tcp::listen(listener, &mut server.pcbs);
+
+        let pcb_id = tcp::open(&mut client.pcbs);
+        let events = tcp::subscribe(pcb_id, &mut client.pcbs);
+        tcp::start_connect(
+            pcb_id,
+            &server_endpoint,
+            &mut client.device,
+            &mut client.contexts,
+            &mut client.pcbs,
+        );
+        assert!(events.try_recv().is_err());
+
+        // ARP request and reply, then SYN, SYN/ACK and ACK without any time passing
+        assert_eq!(5, settle(&mut [&mut client, &mut server], start()));
+        assert_eq!(Ok(tcp::TcpEvent::Connected), events.try_recv());
+        let child_id = tcp::try_accept(listener, &mut server.pcbs).unwrap();
+        assert!(child_id.is_some());
+        assert_eq!(Ok(None), tcp::try_accept(listener, &mut server.pcbs));
+    }
+
+    #[test]
+    fn test_syn_retransmission() {
+        let (mut client, mut server) = stacks();
+        let interface = server.device.get_interface(NetInterfaceFamily::IP);
+        client.contexts.arp_table.add_static(
+            interface.unwrap().unicast,
+            server.device.address.as_bytes().try_into().unwrap(),
+        );
+        client.pcbs.tcp_pcbs.syn_retries = 2;
+
+        // The server is not polled and does not answer.
+        let pcb_id = tcp::open(&mut client.pcbs);
+        let events = tcp::subscribe(pcb_id, &mut client.pcbs);
+        tcp::start_connect(
+            pcb_id,
+            &IPEndpoint::new_from_str("192.0.2.2", 7),
+            &mut client.device,
+            &mut client.contexts,
+            &mut client.pcbs,
+        );
+        let sent = client.device.stats.tx.frames;
+        let millis = |ms| start() + Duration::from_millis(ms);
+
+        // First after the SYN RTO, then doubled
+        client.poll(millis(999));
+        assert_eq!(sent, client.device.stats.tx.frames);
+        client.poll(millis(1000));
+        assert_eq!(sent + 1, client.device.stats.tx.frames);
+        client.poll(millis(2999));
+        assert_eq!(sent + 1, client.device.stats.tx.frames);
+        client.poll(millis(3000));
+        assert_eq!(sent + 2, client.device.stats.tx.frames);
+
+        client.poll(millis(6999));
+        assert!(events.try_recv().is_err());
+        client.poll(millis(7000));
+        assert_eq!(
+            Ok(tcp::TcpEvent::Closed(Some(SocketError::TimedOut))),
+            events.try_recv()
+        );
+        assert_eq!(sent + 2, client.device.stats.tx.frames);
+    }
+
+    #[test]
+    fn test_app_pair() {
+        let (a, b) = app_pair("192.0.2.1/24", "192.0.2.2/24");
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        assert_eq!(data, tcp_transfer(&a, &b, 7, &data));
+        assert_eq!(Some(b"hello".to_vec()), udp_transfer(&b, &a, 9, b"hello"));
+        a.stop();
+        b.stop();
+    }
+}
@@ -0,0 +1,422 @@
+//! Blocking sockets over PCBs of a running stack. Each call locks the shared state of `NetApp`
+//! the same way CLI commands do, so sockets can be used from any application thread.
+
+use crate::app::NetApp;
+use crate::devices::NetDeviceType;
+use crate::protocols::ip::icmp::{self, IcmpDataEntry, ICMP_TYPE_ECHO, ICMP_TYPE_ECHOREPLY};
+use crate::protocols::ip::tcp::{self, TcpConnStats, TcpEvent, UrgentPolicy};
+use crate::protocols::ip::udp::{self, UdpDataEntry};
+use crate::protocols::ip::{IPAdress, IPEndpoint, IP_ADDR_ANY};
+use crate::protocols::SocketError;
+use crate::utils::byte::le_to_be_u32;
+use log::warn;
+use std::net::Shutdown;
+use std::ops::RangeInclusive;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// UDP socket bound to a local endpoint.
+pub struct UdpSocket {
+    app: NetApp,
+    pcb_id: usize,
+}
+
+impl UdpSocket {
+    pub fn bind(app: &NetApp, local: IPEndpoint) -> UdpSocket {
+        let pcbs = &mut app.pcbs.lock().unwrap();
+        let pcb_id = udp::open(&mut pcbs.udp_pcbs);
+        udp::bind(&mut pcbs.udp_pcbs, pcb_id, local);
+        UdpSocket {
+            app: app.clone(),
+            pcb_id,
+        }
+    }
+
+    /// Binds to a range of ports on an address (`1..=65535` for all ports) receiving datagrams
+    /// not claimed by other sockets. `UdpDataEntry::local_port` tells the port targeted.
+    pub fn bind_ports(app: &NetApp, address: IPAdress, ports: RangeInclusive<u16>) -> UdpSocket {
+        let pcbs = &mut app.pcbs.lock().unwrap();
+        let pcb_id = udp::open(&mut pcbs.udp_pcbs);
+        udp::bind_ports(&mut pcbs.udp_pcbs, pcb_id, address, ports);
+        UdpSocket {
+            app: app.clone(),
+            pcb_id,
+        }
+    }
+
+    /// Sends to a remote endpoint. Fails without a route to it, or when it is a broadcast
+    /// address and `set_broadcast` is not on.
+    pub fn send_to(&self, data: Vec<u8>, remote: IPEndpoint) -> Result<(), ()> {
+        let devices = &mut self.app.devices.lock().unwrap();
+        let contexts = &mut self.app.contexts.lock().unwrap();
+        let pcbs = &mut self.app.pcbs.lock().unwrap();
+        let device = devices
+            .get_mut_by_dst(remote.address, &contexts.ip_routes)
+            .unwrap();
+        udp::send_to(self.pcb_id, data, remote, device, contexts, pcbs)
+    }
+
+    /// Allows sending to broadcast addresses and receiving broadcasts when bound to a unicast
+    /// address (SO_BROADCAST).
+    pub fn set_broadcast(&self, enabled: bool) {
+        let pcbs = &mut self.app.pcbs.lock().unwrap();
+        udp::set_broadcast(&mut pcbs.udp_pcbs, self.pcb_id, enabled);
+    }
+
+    /// Receives only datagrams from a remote endpoint and sends to it with `send`.
+    pub fn connect(&self, remote: IPEndpoint) {
+        let pcbs = &mut self.app.pcbs.lock().unwrap();
+        udp::connect(&mut pcbs.udp_pcbs, self.pcb_id, remote);
+    }
+
+    /// Joins a multicast group, receiving datagrams to it on the port bound. Fails for an
+    /// address other than a group.
+    pub fn join_multicast(&self, group: IPAdress) -> Result<(), ()> {
+        let devices = &mut self.app.devices.lock().unwrap();
+        let contexts = &mut self.app.contexts.lock().unwrap();
+        let pcbs = &mut self.app.pcbs.lock().unwrap();
+        let device = devices.get_mut_by_dst(group, &contexts.ip_routes).unwrap();
+        udp::join_group(self.pcb_id, group, device, contexts, pcbs)
+    }
+
+    pub fn leave_multicast(&self, group: IPAdress) -> Result<(), ()> {
+        let devices = &mut self.app.devices.lock().unwrap();
+        let contexts = &mut self.app.contexts.lock().unwrap();
+        let pcbs = &mut self.app.pcbs.lock().unwrap();
+        let device = devices.get_mut_by_dst(group, &contexts.ip_routes).unwrap();
+        udp::leave_group(self.pcb_id, group, device, contexts, pcbs)
+    }
+
+    /// Sets TTL of datagrams sent to multicast groups, 1 by default.
+    pub fn set_multicast_ttl(&self, ttl: u8) {
+        let pcbs = &mut self.app.pcbs.lock().unwrap();
+        udp::set_multicast_ttl(&mut pcbs.udp_pcbs, self.pcb_id, ttl);
+    }
+
+    /// Sends to the remote endpoint connected. Fails when not connected.
+    pub fn send(&self, data: Vec<u8>) -> Result<(), ()> {
+        let devices = &mut self.app.devices.lock().unwrap();
+        let contexts = &mut self.app.contexts.lock().unwrap();
+        let pcbs = &mut self.app.pcbs.lock().unwrap();
+        let remote =
+            udp::remote_endpoint(self.pcb_id, pcbs).map_or(IP_ADDR_ANY, |remote| remote.address);
+        let device = devices.get_mut_by_dst(remote, &contexts.ip_routes).unwrap();
+        udp::send(self.pcb_id, data, device, contexts, pcbs)
+    }
+
+    /// Sends a batch of datagrams locking the stack once. Returns the number of datagrams sent.
+    pub fn send_many(&self, datagrams: Vec<(Vec<u8>, IPEndpoint)>) -> usize {
+        let devices = &mut self.app.devices.lock().unwrap();
+        let contexts = &mut self.app.contexts.lock().unwrap();
+        let pcbs = &mut self.app.pcbs.lock().unwrap();
+        // A batch out of each device, datagrams in the order given within a batch
+        let mut outs: Vec<Option<usize>> = datagrams
+            .iter()
+            .map(|(_, remote)| devices.index_by_dst(remote.address, &contexts.ip_routes))
+            .collect();
+        let mut datagrams: Vec<_> = outs.iter().copied().zip(datagrams).collect();
+        outs.sort();
+        outs.dedup();
+        let mut sent = 0;
+        for index in outs.into_iter().flatten() {
+            let (batch, rest): (Vec<_>, Vec<_>) = datagrams
+                .into_iter()
+                .partition(|(out, _)| *out == Some(index));
+            datagrams = rest;
+            let batch = batch.into_iter().map(|(_, datagram)| datagram).collect();
+            let device = devices.get_mut_by_index(index).unwrap();
+            sent += udp::send_many(self.pcb_id, batch, device, contexts, pcbs);
+        }
+        sent
+    }
+
+    /// Blocks till a datagram arrives. Returns None when the socket gets closed.
+    pub fn recv_from(&self) -> Option<UdpDataEntry> {
+        udp::receive_from(self.pcb_id, self.app.pcbs.clone())
+    }
+
+    /// Same as recv_from, but returns None also when nothing arrives within the timeout.
+    pub fn recv_from_timeout(&self, timeout: Duration) -> Option<UdpDataEntry> {
+        udp::receive_from_timeout(self.pcb_id, self.app.pcbs.clone(), timeout)
+    }
+
+    /// Returns a datagram already arrived, if any, without blocking.
+    pub fn try_recv_from(&self) -> Option<UdpDataEntry> {
+        let pcbs = &mut self.app.pcbs.lock().unwrap();
+        udp::try_receive_from(&mut pcbs.udp_pcbs, self.pcb_id)
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        let devices = &mut self.app.devices.lock().unwrap();
+        let contexts = &mut self.app.contexts.lock().unwrap();
+        let pcbs = &mut self.app.pcbs.lock().unwrap();
+        if let Some(device) = devices.get_mut_by_type(NetDeviceType::Ethernet) {
+            udp::leave_groups(self.pcb_id, device, contexts, pcbs);
+        }
+        udp::close(&mut pcbs.udp_pcbs, self.pcb_id);
+    }
+}
+
+/// TCP connection opened actively with `connect` or passively with `accept`.
+pub struct TcpStream {
+    app: NetApp,
+    pcb_id: usize,
+    closed: bool,
+}
+
+impl TcpStream {
+    /// Blocks till the connection to a remote endpoint is established. Fails with `TimedOut`
+    /// when SYN retransmissions go unanswered, or an error of ICMP or RST reply.
+    pub fn connect(
+        app: &NetApp,
+        local: IPEndpoint,
+        remote: IPEndpoint,
+    ) -> Result<TcpStream, SocketError> {
+        TcpStream::open(app, local, Some(remote), true)
+    }
+
+    /// Blocks till a connection to a local endpoint is established.
+    pub fn accept(app: &NetApp, local: IPEndpoint) -> Option<TcpStream> {
+        TcpStream::open(app, local, None, false).ok()
+    }
+
+    fn open(
+        app: &NetApp,
+        local: IPEndpoint,
+        remote: Option<IPEndpoint>,
+        active: bool,
+    ) -> Result<TcpStream, SocketError> {
+        let pcb_id = tcp::rfc793_open(
+            local,
+            remote,
+            active,
+            app.pcbs.clone(),
+            app.devices.clone(),
+            app.contexts.clone(),
+        )?;
+        Ok(TcpStream {
+            app: app.clone(),
+            pcb_id,
+            closed: false,
+        })
+    }
+
+    /// Queues data to send as far as buffers allow and returns the length queued. Returns None
+    /// when the connection is gone.
+    pub fn write(&self, data: Vec<u8>) -> Option<usize> {
+        let devices = &mut self.app.devices.lock().unwrap();
+        let contexts = &mut self.app.contexts.lock().unwrap();
+        let pcbs = self.app.pcbs.lock().unwrap();
+        let device = tcp::pcb_device(self.pcb_id, devices, contexts, &pcbs).unwrap();
+        drop(pcbs); // locked again by send
+        tcp::send(
+            self.pcb_id,
+            data,
+            device,
+            contexts,
+            &mut self.app.pcbs.clone(),
+        )
+    }
+
+    /// Blocks till all data is queued to send. Returns None when the connection is gone.
+    pub fn write_all(&self, data: Vec<u8>) -> Option<usize> {
+        tcp::send_all(
+            self.pcb_id,
+            data,
+            self.app.pcbs.clone(),
+            self.app.devices.clone(),
+            self.app.contexts.clone(),
+        )
+    }
+
+    /// Sets the send buffer size (SO_SNDBUF).
+    pub fn set_send_buffer_size(&self, size: usize) {
+        let pcbs = &mut self.app.pcbs.lock().unwrap();
+        tcp::set_send_buffer_size(self.pcb_id, size, pcbs);
+    }
+
+    /// Sends small writes right away instead of coalescing them while data is in flight
+    /// (TCP_NODELAY).
+    pub fn set_nodelay(&self, no_delay: bool) {
+        let pcbs = &mut self.app.pcbs.lock().unwrap();
+        tcp::set_no_delay(self.pcb_id, no_delay, pcbs);
+    }
+
+    /// Holds writes smaller than MSS till more of them make a full segment, `flush` or a short
+    /// timeout, so that many small writes do not each become a segment (TCP_CORK).
+    pub fn set_coalesce(&self, coalesce: bool) {
+        let devices = &mut self.app.devices.lock().unwrap();
+        let contexts = &mut self.app.contexts.lock().unwrap();
+        let pcbs = &mut self.app.pcbs.lock().unwrap();
+        let device = tcp::pcb_device(self.pcb_id, devices, contexts, pcbs).unwrap();
+        tcp::set_coalesce(self.pcb_id, coalesce, pcbs, device, contexts);
+    }
+
+    /// Sends data written without waiting for more writes to coalesce.
+    pub fn flush(&self) {
+        let devices = &mut self.app.devices.lock().unwrap();
+        let contexts = &mut self.app.contexts.lock().unwrap();
+        let pcbs = &mut self.app.pcbs.lock().unwrap();
+        let device = tcp::pcb_device(self.pcb_id, devices, contexts, pcbs).unwrap();
+        tcp::push(self.pcb_id, pcbs, device, contexts);
+    }
+
+    /// Sets the receive buffer size (SO_RCVBUF). 65535 bytes at most.
+    pub fn set_recv_buffer_size(&self, size: usize) {
+        let pcbs = &mut self.app.pcbs.lock().unwrap();
+        tcp::set_recv_buffer_size(self.pcb_id, size, pcbs);
+    }
+
+    /// Selects whether the last byte of urgent data stays in the stream (default), is read with
+    /// `read_oob` or is dropped.
+    pub fn set_urgent_policy(&self, policy: UrgentPolicy) {
+        let pcbs = &mut self.app.pcbs.lock().unwrap();
+        tcp::set_urgent_policy(self.pcb_id, policy, pcbs);
+    }
+
+    /// Takes the urgent byte received out of band without blocking.
+    pub fn read_oob(&self) -> Option<u8> {
+        let pcbs = &mut self.app.pcbs.lock().unwrap();
+        tcp::receive_oob(self.pcb_id, pcbs)
+    }
+
+    /// Counters of the connection such as segments, retransmits and RTT estimate. None once
+    /// the connection is released.
+    pub fn stats(&self) -> Option<TcpConnStats> {
+        let pcbs = &self.app.pcbs.lock().unwrap();
+        tcp::stats(self.pcb_id, pcbs)
+    }
+
+    /// Returns why the connection is gone, or else an ICMP error reported meanwhile, which is
+    /// cleared.
+    pub fn take_error(&self) -> Option<SocketError> {
+        let pcbs = &mut self.app.pcbs.lock().unwrap();
+        tcp::take_error(self.pcb_id, pcbs)
+    }
+
+    /// Subscribes to events of the connection instead of blocking on `read`, replacing the
+    /// previous subscription. Events that happened already come first, and the channel closes
+    /// after `TcpEvent::Closed`.
+    pub fn events(&self) -> mpsc::Receiver<TcpEvent> {
+        let pcbs = &mut self.app.pcbs.lock().unwrap();
+        tcp::subscribe(self.pcb_id, pcbs)
+    }
+
+    /// Calls back for each event of the connection on a thread of its own, so the callback may
+    /// use the stream without deadlocking the stack.
+    pub fn on_event<F>(&self, callback: F)
+    where
+        F: FnMut(TcpEvent) + Send + 'static,
+    {
+        let events = self.events();
+        thread::spawn(move || events.into_iter().for_each(callback));
+    }
+
+    /// Blocks till data of `size` bytes at most is received. Returns None when the connection
+    /// is gone, and no data after receiving is shut down.
+    pub fn read(&self, size: usize) -> Option<Vec<u8>> {
+        tcp::receive(
+            self.pcb_id,
+            size,
+            self.app.pcbs.clone(),
+            self.app.devices.clone(),
+            self.app.contexts.clone(),
+        )
+    }
+
+    /// Shuts down sending (FIN after data queued), receiving (reads return no data) or both.
+    /// Fails when the connection is gone.
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), ()> {
+        let devices = &mut self.app.devices.lock().unwrap();
+        let contexts = &mut self.app.contexts.lock().unwrap();
+        let pcbs = &mut self.app.pcbs.lock().unwrap();
+        let device = tcp::pcb_device(self.pcb_id, devices, contexts, pcbs).unwrap();
+        tcp::shutdown(self.pcb_id, how, pcbs, device, contexts)
+    }
+
+    /// Sends FIN after data queued and blocks till the peer acknowledges it and closes its side
+    /// too. Dropping a stream closes it the same way without waiting.
+    pub fn close(mut self) -> Result<(), SocketError> {
+        self.closed = true;
+        tcp::close_blocking(
+            self.pcb_id,
+            self.app.pcbs.clone(),
+            self.app.devices.clone(),
+            self.app.contexts.clone(),
+        )
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        if self.closed {
+            return;
+        }
+        let devices = &mut self.app.devices.lock().unwrap();
+        let contexts = &mut self.app.contexts.lock().unwrap();
+        let pcbs = &mut self.app.pcbs.lock().unwrap();
+        // Panicking in drop would abort an unwinding thread.
+        let Some(device) = tcp::pcb_device(self.pcb_id, devices, contexts, pcbs) else {
+            warn!(
+                "Socket: no device to close TCP connection of PCB {}. Skipping...",
+                self.pcb_id
+            );
+            return;
+        };
+        tcp::close(self.pcb_id, pcbs, device, contexts);
+    }
+}
+
+/// ICMP socket sending echo requests and receiving replies and errors for an identifier.
+pub struct IcmpSocket {
+    app: NetApp,
+    pcb_id: usize,
+    id: u16,
+}
+
+impl IcmpSocket {
+    pub fn open(app: &NetApp, id: u16) -> IcmpSocket {
+        let pcbs = &mut app.pcbs.lock().unwrap();
+        let pcb_id = icmp::open(&mut pcbs.icmp_pcbs, ICMP_TYPE_ECHOREPLY, id);
+        IcmpSocket {
+            app: app.clone(),
+            pcb_id,
+            id,
+        }
+    }
+
+    pub fn echo_request(&self, dst: IPAdress, seq: u16, data: Vec<u8>) {
+        let devices = &mut self.app.devices.lock().unwrap();
+        let contexts = &mut self.app.contexts.lock().unwrap();
+        let pcbs = &mut self.app.pcbs.lock().unwrap();
+        let device = devices.get_mut_by_dst(dst, &contexts.ip_routes).unwrap();
+        let len = data.len();
+        icmp::output(
+            ICMP_TYPE_ECHO,
+            0,
+            le_to_be_u32((self.id as u32) << 16 | seq as u32),
+            data,
+            len,
+            IP_ADDR_ANY,
+            dst,
+            device,
+            contexts,
+            pcbs,
+        );
+    }
+
+    /// Blocks till a reply or an error arrives. Returns None on timeout.
+    pub fn receive_timeout(&self, timeout: Duration) -> Option<IcmpDataEntry> {
+        icmp::receive_timeout(self.pcb_id, self.app.pcbs.clone(), timeout)
+    }
+}
+
+impl Drop for IcmpSocket {
+    fn drop(&mut self) {
+        let pcbs = &mut self.app.pcbs.lock().unwrap();
+        icmp::close(&mut pcbs.icmp_pcbs, self.pcb_id);
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    cell::Cell,
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    hash::Hash,
+    time::{Duration, SystemTime},
+};
+
+thread_local! {
+    // Time set by a simulation driving the stack on this thread, see `sim::SimStack::poll`.
+    static SIMULATED_NOW: Cell<Option<SystemTime>> = const { Cell::new(None) };
+}
+
+/// Current time of protocol timers: the wall clock, or the time a simulation on this thread
+/// advanced to last.
+pub fn now() -> SystemTime {
+    SIMULATED_NOW
+        .with(|now| now.get())
+        .unwrap_or_else(SystemTime::now)
+}
+
+/// Time passed since `at`, zero when it is still ahead.
+pub fn since(at: SystemTime) -> Duration {
+    now().duration_since(at).unwrap_or_default()
+}
+
+/// Whether `at` has come.
+pub fn is_due(at: SystemTime) -> bool {
+    now() >= at
+}
+
+/// Freezes the clock of this thread at `now` till set again, or goes back to the wall clock
+/// with None.
+pub fn set_simulated_now(now: Option<SystemTime>) {
+    SIMULATED_NOW.with(|simulated| simulated.set(now));
+}
+
+/// Deadlines of protocol timers keyed e.g. by PCB id or IP, so that the timer thread only
+/// visits timers due instead of scanning all entries every tick. A key has one deadline at a
+/// time: scheduling it again replaces the previous one, which is left in the heap and skipped
+/// when popped.
+pub struct TimerQueue<K> {
+    heap: BinaryHeap<Reverse<(SystemTime, K)>>,
+    deadlines: HashMap<K, SystemTime>,
+}
+
+impl<K: Copy + Eq + Hash + Ord> TimerQueue<K> {
+    pub fn new() -> TimerQueue<K> {
+        TimerQueue {
+            heap: BinaryHeap::new(),
+            deadlines: HashMap::new(),
+        }
+    }
+
+    /// Sets the deadline of a key replacing the previous one.
+    pub fn schedule(&mut self, key: K, at: SystemTime) {
+        if self.deadlines.insert(key, at) != Some(at) {
+            self.heap.push(Reverse((at, key)));
+        }
+    }
+
+    pub fn cancel(&mut self, key: K) {
+        self.deadlines.remove(&key);
+    }
+
+    pub fn deadline(&self, key: K) -> Option<SystemTime> {
+        self.deadlines.get(&key).copied()
+    }
+
+    /// The earliest deadline, e.g. for a timer thread to sleep till.
+    pub fn next_deadline(&mut self) -> Option<SystemTime> {
+        self.discard_stale();
+        self.heap.peek().map(|Reverse((at, _))| *at)
+    }
+
+    /// Takes keys due at `now` in the order of deadlines. They are not scheduled anymore.
+    pub fn expired(&mut self, now: SystemTime) -> Vec<K> {
+        let mut keys = vec![];
+        while let Some(at) = self.next_deadline() {
+            if at > now {
+                break;
+            }
+            let Reverse((_, key)) = self.heap.pop().unwrap();
+            self.deadlines.remove(&key);
+            keys.push(key);
+        }
+        keys
+    }
+
+    /// Number of keys scheduled.
+    pub fn len(&self) -> usize {
+        self.deadlines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deadlines.is_empty()
+    }
+
+    fn discard_stale(&mut self) {
+        while let Some(Reverse((at, key))) = self.heap.peek() {
+            if self.deadlines.get(key) == Some(at) {
+                return;
+            }
+            self.heap.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_due, now, set_simulated_now, since, TimerQueue};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_timer_queue() {
+        let mut timers = TimerQueue::new();
+        let now = SystemTime::now();
+        let secs = Duration::from_secs;
+        timers.schedule(1, now + secs(3));
+        timers.schedule(2, now + secs(1));
+        timers.schedule(3, now + secs(2));
+        assert_eq!(Some(now + secs(1)), timers.next_deadline());
+        assert!(timers.expired(now).is_empty());
+
+        // Rescheduled and canceled keys leave stale deadlines skipped.
+        timers.schedule(2, now + secs(4));
+        timers.cancel(3);
+        assert_eq!(Some(now + secs(3)), timers.next_deadline());
+        assert_eq!(2, timers.len());
+        assert_eq!(vec![1], timers.expired(now + secs(3)));
+        assert_eq!(None, timers.deadline(1));
+        assert_eq!(Some(now + secs(4)), timers.deadline(2));
+        assert_eq!(vec![2], timers.expired(now + secs(10)));
+        assert!(timers.is_empty());
+        assert_eq!(None, timers.next_deadline());
+    }
+
+    #[test]
+    fn test_simulated_clock() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        set_simulated_now(Some(start));
+        assert_eq!(start, now());
+        assert!(is_due(start));
+        assert!(!is_due(start + Duration::from_millis(1)));
+        assert_eq!(Duration::ZERO, since(start + Duration::from_secs(1)));
+
+        set_simulated_now(Some(start + Duration::from_secs(5)));
+        assert_eq!(Duration::from_secs(5), since(start));
+
+        // Other threads keep the wall clock.
+        let wall = std::thread::spawn(now).join().unwrap();
+        assert!(wall > start + Duration::from_secs(5));
+        set_simulated_now(None);
+        assert!(now() > start + Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,75 @@
+//! Runtime checks of raw byte/struct conversions enabled by the `safety-audit` feature.
+//!
+//! Violations are logged as a structured error line, e.g.
+//! `safety-audit: kind=out-of-bounds type=IPHeader need=20 have=14 at=src/protocols/ip/mod.rs:420:27`,
+//! before panicking so that the offending access never reads past the buffer.
+
+use log::error;
+use std::{
+    any::type_name,
+    mem::{align_of, size_of},
+    panic::Location,
+};
+
+/// Asserts that `b` holds enough bytes, suitably aligned, to be read as `T`.
+#[track_caller]
+pub fn check_read<T: Sized>(b: &[u8]) {
+    let need = size_of::<T>();
+    if b.len() < need {
+        violation::<T>("out-of-bounds", need, b.len());
+    }
+    let align = align_of::<T>();
+    let offset = b.as_ptr() as usize % align;
+    if offset != 0 {
+        violation::<T>("misaligned", align, offset);
+    }
+}
+
+#[track_caller]
+fn violation<T>(kind: &str, need: usize, have: usize) -> ! {
+    let message = format!(
+        "safety-audit: kind={kind} type={} need={need} have={have} at={}",
+        short_type_name::<T>(),
+        Location::caller()
+    );
+    error!("{message}");
+    panic!("{message}");
+}
+
+fn short_type_name<T>() -> &'static str {
+    let name = type_name::<T>();
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check_read;
+    use crate::utils::bytes_to_struct;
+
+    #[repr(packed)]
+    struct Header {
+        _kind: u8,
+        _len: u16,
+    }
+
+    #[test]
+    fn test_in_bounds_read() {
+        let header: Header = unsafe { bytes_to_struct(&[1, 0x12, 0x34, 0x56]) };
+        let len = header._len;
+        assert_eq!(u16::from_ne_bytes([0x12, 0x34]), len);
+    }
+
+    #[test]
+    #[should_panic(expected = "kind=out-of-bounds type=Header need=3 have=2")]
+    fn test_out_of_bounds_read() {
+        let _: Header = unsafe { bytes_to_struct(&[1, 0]) };
+    }
+
+    #[test]
+    #[should_panic(expected = "kind=misaligned type=u32 need=4 have=1")]
+    fn test_misaligned_read() {
+        let bytes = [0u32; 2];
+        let bytes = unsafe { std::slice::from_raw_parts(bytes.as_ptr() as *const u8, 8) };
+        check_read::<u32>(&bytes[1..]);
+    }
+}
@@ -57,6 +57,22 @@ impl<T> List<T> {
         }
     }
 
+    /// Keeps only elements for which a predicate returns true.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&T) -> bool,
+    {
+        let mut kept = List::new();
+        let mut link = self.head.take();
+        while let Some(mut node) = link {
+            link = node.next.take();
+            if f(&node.elem) {
+                kept.push(node.elem);
+            }
+        }
+        *self = kept;
+    }
+
     pub fn iter(&self) -> Iter<'_, T> {
         Iter {
             next: self.head.as_deref(),
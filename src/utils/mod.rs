@@ -1,13 +1,29 @@
+#[cfg(feature = "safety-audit")]
+pub mod audit;
 pub mod byte;
 pub mod list;
 
 /// Converts a struct to u8 slice.
+///
+/// # Safety
+///
+/// `T` needs to be a plain struct without padding (e.g. `#[repr(packed)]` headers).
 pub unsafe fn to_u8_slice<T: Sized>(p: &T) -> &[u8] {
     ::std::slice::from_raw_parts((p as *const T) as *const u8, ::std::mem::size_of::<T>())
 }
 
 /// Converts u8 slice to a struct.
+///
+/// With the `safety-audit` feature, length and alignment of `b` are checked and violations are
+/// logged with the caller location before panicking.
+///
+/// # Safety
+///
+/// `b` needs to hold at least `size_of::<T>()` bytes forming a valid `T`.
+#[cfg_attr(feature = "safety-audit", track_caller)]
 pub unsafe fn bytes_to_struct<T: Sized>(b: &[u8]) -> T {
+    #[cfg(feature = "safety-audit")]
+    audit::check_read::<T>(b);
     let s: T = std::ptr::read(b.as_ptr() as *const _);
     s
 }
@@ -48,4 +64,17 @@ mod test {
         assert_eq!(iteration.next(), Some(&2));
         assert_eq!(iteration.next(), Some(&3));
     }
+
+    #[test]
+    fn test_list_retain() {
+        let mut list = List::new();
+        list.push(1);
+        list.push(2);
+        list.push(3);
+        list.retain(|e| *e != 2);
+        let mut iteration = list.iter();
+        assert_eq!(iteration.next(), Some(&1));
+        assert_eq!(iteration.next(), Some(&3));
+        assert_eq!(iteration.next(), None);
+    }
 }
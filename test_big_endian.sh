@@ -0,0 +1,13 @@
+#!/usr/bin/env bash
+# Runs the library unit tests (wire serialization included) on a big-endian target under QEMU
+# with cross (https://github.com/cross-rs/cross). Requires docker or podman.
+set -e
+TARGET=${TARGET:-s390x-unknown-linux-gnu}
+
+if ! command -v cross > /dev/null; then
+    echo "Installing cross..."
+    cargo install cross --git https://github.com/cross-rs/cross
+fi
+
+echo "Running unit tests on $TARGET..."
+cross test --target "$TARGET" --lib
@@ -0,0 +1,353 @@
+//! Wire-level compatibility tests between the user-space TCP and the Linux kernel TCP.
+//!
+//! The stack runs as a child process on tap0 (192.0.2.2) and the kernel side uses the host
+//! address of tap0 (192.0.2.1). Tests need root and the TAP device created by `set_tap.sh`:
+//!
+//! `sudo -E cargo test --test kernel_compat -- --ignored --test-threads=1`
+//!
+//! First packet latency tests print the time from the stack start with a cold ARP cache to the
+//! first segment / datagram at the kernel (`--nocapture` to see them).
+
+use nix::{sys::signal, unistd::Pid};
+use std::{
+    fs,
+    fs::File,
+    io::{ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+const KERNEL_IP: &str = "192.0.2.1";
+const STACK_ADDR: &str = "192.0.2.2:7";
+const TAP_DEVICE: &str = "tap0";
+const TRANSFER_LEN: usize = 4 * 1024 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(60);
+// Stack start, ARP resolution and the first packet: below the ARP request interval (1s)
+const FIRST_PACKET_DEADLINE: Duration = Duration::from_millis(900);
+
+// Only one stack can own tap0 at a time.
+static TAP_LOCK: Mutex<()> = Mutex::new(());
+
+/// Stack process terminated with SIGTERM on drop.
+struct Stack {
+    child: Child,
+}
+
+impl Stack {
+    fn start(args: &[&str]) -> Stack {
+        Stack::spawn(args, Stdio::null())
+    }
+
+    /// Starts the stack writing its log to a file.
+    fn start_logged(args: &[&str], log: &PathBuf) -> Stack {
+        Stack::spawn(args, File::create(log).unwrap().into())
+    }
+
+    fn spawn(args: &[&str], stdout: Stdio) -> Stack {
+        let child = Command::new(env!("CARGO_BIN_EXE_rust-user-net"))
+            .args(args)
+            .stdout(stdout)
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start rust-user-net");
+        Stack { child }
+    }
+
+    fn terminate(&mut self) {
+        signal::kill(Pid::from_raw(self.child.id() as i32), signal::SIGTERM).ok();
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_secs(5) {
+            if let Ok(Some(_)) = self.child.try_wait() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        self.child.kill().ok();
+        self.child.wait().ok();
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        self.terminate();
+    }
+}
+
+/// netem qdisc on tap0 removed on drop.
+struct Netem;
+
+impl Netem {
+    fn loss(percent: u32) -> Netem {
+        let status = Command::new("tc")
+            .args(["qdisc", "add", "dev", TAP_DEVICE, "root", "netem", "loss"])
+            .arg(format!("{percent}%"))
+            .status()
+            .expect("failed to run tc");
+        assert!(
+            status.success(),
+            "failed to add netem qdisc on {TAP_DEVICE}"
+        );
+        Netem
+    }
+}
+
+impl Drop for Netem {
+    fn drop(&mut self) {
+        Command::new("tc")
+            .args(["qdisc", "del", "dev", TAP_DEVICE, "root"])
+            .status()
+            .ok();
+    }
+}
+
+fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rust-user-net-{}-{name}", std::process::id()))
+}
+
+/// Connects to the stack retrying until its listener is up.
+fn connect_to_stack() -> TcpStream {
+    let addr: SocketAddr = STACK_ADDR.parse().unwrap();
+    let started = Instant::now();
+    loop {
+        match TcpStream::connect_timeout(&addr, Duration::from_secs(1)) {
+            Ok(stream) => {
+                stream
+                    .set_read_timeout(Some(Duration::from_secs(5)))
+                    .unwrap();
+                return stream;
+            }
+            Err(e) if started.elapsed() > Duration::from_secs(10) => {
+                panic!("failed to connect to stack: {e}")
+            }
+            Err(_) => thread::sleep(Duration::from_millis(200)),
+        }
+    }
+}
+
+fn kernel_listener() -> (TcpListener, u16) {
+    let listener = TcpListener::bind((KERNEL_IP, 0)).expect("tap0 is not configured");
+    let port = listener.local_addr().unwrap().port();
+    (listener, port)
+}
+
+/// Accepts a connection from the stack and reads until `len` bytes or the stack closes.
+fn accept_and_read(listener: TcpListener, len: usize) -> Vec<u8> {
+    listener.set_nonblocking(true).unwrap();
+    let started = Instant::now();
+    let mut stream = loop {
+        match listener.accept() {
+            Ok((stream, _)) => break stream,
+            Err(e) if e.kind() == ErrorKind::WouldBlock && started.elapsed() < TIMEOUT => {
+                thread::sleep(Duration::from_millis(50))
+            }
+            Err(e) => panic!("no connection from stack: {e}"),
+        }
+    };
+    stream.set_nonblocking(false).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let mut received = vec![];
+    let mut buf = [0; 65536];
+    while received.len() < len && started.elapsed() < TIMEOUT {
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => received.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => break,
+            Err(e) => panic!("read from stack failed: {e}"),
+        }
+    }
+    received
+}
+
+/// Waits for the stack to write `len` bytes to the output file.
+fn wait_for_file(path: &PathBuf, len: usize) -> Vec<u8> {
+    let started = Instant::now();
+    while started.elapsed() < TIMEOUT {
+        if let Ok(data) = fs::read(path) {
+            if data.len() >= len {
+                return data;
+            }
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+    fs::read(path).unwrap_or_default()
+}
+
+/// Sends data from the kernel to the stack which writes it to a file.
+fn kernel_to_stack(data: &[u8]) -> Vec<u8> {
+    let output = temp_path("received");
+    let _stack = Stack::start(&[
+        "tcp",
+        "receive",
+        "0.0.0.0",
+        "7",
+        "--output",
+        output.to_str().unwrap(),
+    ]);
+    let mut stream = connect_to_stack();
+    stream.write_all(data).unwrap();
+    let received = wait_for_file(&output, data.len());
+    fs::remove_file(&output).ok();
+    received
+}
+
+/// Sends a file from the stack to a kernel listener.
+fn stack_to_kernel(data: &[u8]) -> Vec<u8> {
+    let input = temp_path("sent");
+    fs::write(&input, data).unwrap();
+    let (listener, port) = kernel_listener();
+    let _stack = Stack::start(&[
+        "tcp",
+        "send",
+        KERNEL_IP,
+        &port.to_string(),
+        &format!("@{}", input.to_str().unwrap()),
+    ]);
+    let received = accept_and_read(listener, data.len());
+    fs::remove_file(&input).ok();
+    received
+}
+
+#[test]
+#[ignore = "needs root and tap0 (see set_tap.sh); run with --ignored"]
+fn test_handshake_with_kernel() {
+    let _lock = TAP_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let (listener, port) = kernel_listener();
+    let _stack = Stack::start(&["tcp", "send", KERNEL_IP, &port.to_string(), "hello"]);
+    assert_eq!(b"hello".to_vec(), accept_and_read(listener, 5));
+}
+
+#[test]
+#[ignore = "needs root and tap0 (see set_tap.sh); run with --ignored"]
+fn test_handshake_from_kernel() {
+    let _lock = TAP_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let _stack = Stack::start(&["tcp", "receive", "0.0.0.0", "7"]);
+    let stream = connect_to_stack();
+    assert_eq!(
+        STACK_ADDR.parse::<SocketAddr>().unwrap(),
+        stream.peer_addr().unwrap()
+    );
+}
+
+#[test]
+#[ignore = "needs root and tap0 (see set_tap.sh); run with --ignored"]
+fn test_transfer_from_kernel() {
+    let _lock = TAP_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let data = payload(TRANSFER_LEN);
+    let received = kernel_to_stack(&data);
+    assert_eq!(data.len(), received.len());
+    assert!(data == received, "data corrupted in transfer from kernel");
+}
+
+#[test]
+#[ignore = "needs root and tap0 (see set_tap.sh); run with --ignored"]
+fn test_transfer_to_kernel() {
+    let _lock = TAP_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let data = payload(TRANSFER_LEN);
+    let received = stack_to_kernel(&data);
+    assert_eq!(data.len(), received.len());
+    assert!(data == received, "data corrupted in transfer to kernel");
+}
+
+#[test]
+#[ignore = "needs root and tap0 (see set_tap.sh); run with --ignored"]
+fn test_graceful_close_from_kernel() {
+    let _lock = TAP_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let _stack = Stack::start(&["tcp", "receive", "0.0.0.0", "7"]);
+    let mut stream = connect_to_stack();
+    stream.write_all(b"bye").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+
+    // FIN is acknowledged: the connection must not be reset by the stack.
+    let mut buf = [0; 16];
+    match stream.read(&mut buf) {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+        Err(e) => panic!("connection closed abortively: {e}"),
+    }
+}
+
+#[test]
+#[ignore = "needs root and tap0 (see set_tap.sh); run with --ignored"]
+fn test_recovery_from_loss() {
+    let _lock = TAP_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let _netem = Netem::loss(5);
+    let data = payload(TRANSFER_LEN / 4);
+
+    let received = kernel_to_stack(&data);
+    assert!(
+        data == received,
+        "data corrupted in lossy transfer from kernel"
+    );
+
+    let received = stack_to_kernel(&data);
+    assert!(
+        data == received,
+        "data corrupted in lossy transfer to kernel"
+    );
+}
+
+#[test]
+#[ignore = "needs root and tap0 (see set_tap.sh); run with --ignored"]
+fn test_first_segment_latency() {
+    let _lock = TAP_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let log = temp_path("first-segment.log");
+    let (listener, port) = kernel_listener();
+    let started = Instant::now();
+    let mut stack = Stack::start_logged(
+        &["tcp", "send", KERNEL_IP, &port.to_string(), "hello"],
+        &log,
+    );
+    let received = accept_and_read(listener, 5);
+    let latency = started.elapsed();
+    stack.terminate();
+    let log_text = fs::read_to_string(&log).unwrap_or_default();
+    fs::remove_file(&log).ok();
+
+    eprintln!("first TCP segment latency (cold ARP cache): {latency:?}");
+    assert_eq!(b"hello".to_vec(), received);
+    // SYN held for the ARP reply instead of being lost and retransmitted
+    assert!(
+        !log_text.contains("retransmitting a segment"),
+        "segments retransmitted on cold start"
+    );
+    assert!(
+        latency < FIRST_PACKET_DEADLINE,
+        "slow first segment: {latency:?}"
+    );
+}
+
+#[test]
+#[ignore = "needs root and tap0 (see set_tap.sh); run with --ignored"]
+fn test_first_datagram_latency() {
+    let _lock = TAP_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let socket = UdpSocket::bind((KERNEL_IP, 0)).expect("tap0 is not configured");
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let port = socket.local_addr().unwrap().port();
+    let started = Instant::now();
+    let _stack = Stack::start(&["udp", "send", KERNEL_IP, &port.to_string(), "hello"]);
+
+    // The first datagram is held for the ARP reply: no resend needed.
+    let mut buf = [0; 64];
+    let len = socket
+        .recv(&mut buf)
+        .expect("first datagram lost on cold ARP cache");
+    let latency = started.elapsed();
+    eprintln!("first UDP datagram latency (cold ARP cache): {latency:?}");
+    assert_eq!(b"hello", &buf[..len]);
+    assert!(
+        latency < FIRST_PACKET_DEADLINE,
+        "slow first datagram: {latency:?}"
+    );
+}